@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+
+use crate::expand::single_quote;
+
+/// Aliases defined with `alias name=value`, kept sorted for listing.
+#[derive(Debug, Default)]
+pub struct Aliases {
+    values: BTreeMap<String, String>,
+}
+
+impl Aliases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Removes an alias, returning whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.values.remove(name).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// One `alias name='value'` line per alias, in a form that can be run
+    /// again to restore them.
+    pub fn listing(&self) -> String {
+        self.values
+            .keys()
+            .map(|name| self.definition(name).unwrap_or_default())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// The `alias name='value'` line for a single alias.
+    pub fn definition(&self, name: &str) -> Option<String> {
+        self.get(name)
+            .map(|value| format!("alias {}={}", name, single_quote(value)))
+    }
+}
+
+/// Whether `name` can be used as an alias name. Quotes, `$`, `/`, `=` and
+/// whitespace would make the alias impossible to type or list back.
+pub fn is_valid_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '/' | '$' | '=' | '\'' | '"' | '\\' | '`'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing_round_trips_quotes() {
+        let mut aliases = Aliases::new();
+        aliases.set("ll", "ls -l");
+        aliases.set("say", "echo 'hi there'");
+        assert_eq!(
+            aliases.listing(),
+            "alias ll='ls -l'\nalias say='echo '\\''hi there'\\'''"
+        );
+        assert!(aliases.remove("ll"));
+        assert!(!aliases.remove("ll"));
+        assert_eq!(aliases.get("say"), Some("echo 'hi there'"));
+    }
+
+    #[test]
+    fn test_alias_names() {
+        assert!(is_valid_alias_name("ll"));
+        assert!(is_valid_alias_name("g-st"));
+        assert!(!is_valid_alias_name(""));
+        assert!(!is_valid_alias_name("a/b"));
+        assert!(!is_valid_alias_name("a b"));
+    }
+}
@@ -0,0 +1,2 @@
+pub use alias::*;
+pub mod alias;
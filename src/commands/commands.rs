@@ -1,19 +1,41 @@
 use anyhow::{Result, anyhow};
 use is_executable::IsExecutable;
 use os_pipe::{PipeReader, pipe};
+use std::env;
+use std::ffi::OsStr;
 use std::fs;
 use std::io::Write;
 use std::io::{BufRead, BufReader};
-use std::os::unix::io::FromRawFd;
-use std::os::unix::io::IntoRawFd;
-use std::path::PathBuf;
-use std::process::{Command as CmdCommand, Stdio};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{ChildStderr, ChildStdout, Command as CmdCommand, Stdio};
 use std::thread;
-use std::{env, process};
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, EnumString};
+use thiserror::Error;
 
-use crate::{History, Output};
+use crate::completer::CompletionSpec;
+use crate::dirstack::is_stack_index;
+use crate::expand::single_quote;
+use crate::files::{basename, dirname, io_message, mkdir, rm, rmdir, touch};
+#[cfg(windows)]
+use crate::finder::{candidate_names, path_extensions};
+use crate::functions::FunctionStreams;
+use crate::getopts::{Getopt, GetoptsCursor, getopts};
+use crate::ls::{LsOptions, list};
+use crate::os_text::{bytes_to_text, os_to_string, string_to_os, text_bytes};
+use crate::parser::{is_valid_name, parse_command};
+use crate::printf::printf;
+use crate::prompt::abbreviate_home;
+use crate::time::Timer;
+use crate::trap::{RESET, signal_name};
+use crate::{
+    CaptureOutput, HistorySync, Job, Output, ScriptLocation, ShellOptions, ShellState, Spawned,
+    Spawner, Value, is_valid_alias_name, run_script, source_file,
+};
 
 #[derive(Debug, EnumString, EnumIter, PartialEq)]
 pub enum CommandKind {
@@ -29,25 +51,338 @@ pub enum CommandKind {
     Cd,
     #[strum(serialize = "history")]
     History,
+    #[strum(serialize = "hash")]
+    Hash,
+    #[strum(serialize = "clear")]
+    Clear,
+    #[strum(serialize = "set")]
+    Set,
+    #[strum(serialize = "mapfile", serialize = "readarray")]
+    Mapfile,
+    #[strum(serialize = "trap")]
+    Trap,
+    #[strum(serialize = "umask")]
+    Umask,
+    #[strum(serialize = "jobs")]
+    Jobs,
+    #[strum(serialize = "disown")]
+    Disown,
+    #[strum(serialize = "time")]
+    Time,
+    #[strum(serialize = "export")]
+    Export,
+    #[strum(serialize = "alias")]
+    Alias,
+    #[strum(serialize = "unalias")]
+    Unalias,
+    #[strum(serialize = "source", serialize = ".")]
+    Source,
+    #[strum(serialize = "complete")]
+    Complete,
+    #[strum(serialize = "ls")]
+    Ls,
+    #[strum(serialize = "mkdir")]
+    Mkdir,
+    #[strum(serialize = "rmdir")]
+    Rmdir,
+    #[strum(serialize = "rm")]
+    Rm,
+    #[strum(serialize = "touch")]
+    Touch,
+    #[strum(serialize = "basename")]
+    Basename,
+    #[strum(serialize = "dirname")]
+    Dirname,
+    #[strum(serialize = "seq")]
+    Seq,
+    #[strum(serialize = "repeat")]
+    Repeat,
+    #[strum(serialize = "getopts")]
+    Getopts,
+    #[strum(serialize = "return")]
+    Return,
+    #[strum(serialize = "local")]
+    Local,
+    #[strum(serialize = "shift")]
+    Shift,
+    #[strum(serialize = "wait")]
+    Wait,
+    #[strum(serialize = "printf")]
+    Printf,
+    #[strum(serialize = "pushd")]
+    Pushd,
+    #[strum(serialize = "popd")]
+    Popd,
+    #[strum(serialize = "dirs")]
+    Dirs,
+    #[strum(serialize = "builtin")]
+    Builtin,
+    #[strum(serialize = "readonly")]
+    Readonly,
+    #[strum(serialize = "unset")]
+    Unset,
 }
 
 #[derive(Debug)]
 pub enum Command {
     Exit,
+    /// `return [n]`: leaves the running function with status `n`, or with
+    /// the status of the last command.
+    Return(Option<i32>),
+    /// `local name[=value] ...`: variables that last until the running
+    /// function returns.
+    Local(Vec<String>),
+    /// `readonly name[=value] ...`, or `readonly [-p]` to list them.
+    Readonly(Vec<String>),
+    /// `unset [-v] name ...`.
+    Unset(Vec<String>),
+    /// `shift [n]`: drops the first `n` positional parameters.
+    Shift(usize),
     Echo {
-        text: String,
+        args: Vec<String>,
         interpret_escapes: bool,
+        /// Cleared by `echo -n`.
+        newline: bool,
+        /// Put between arguments; a space unless given with `echo -s`.
+        separator: String,
+    },
+    Type {
+        names: Vec<String>,
+        all: bool,
     },
-    Type(String),
     Exec {
         command: String,
         args: Vec<String>,
     },
-    Pwd,
-    Cd(String),
+    Pwd {
+        /// `pwd -P`: resolve symlinks instead of printing the logical path.
+        physical: bool,
+        /// `pwd -t`: show the home directory as `~`, like the prompt's `\w`.
+        tilde: bool,
+    },
+    Cd {
+        path: String,
+        /// `cd -P`: follow symlinks and record the resolved path.
+        physical: bool,
+    },
     History {
         lines_count: Option<u32>,
     },
+    /// `history -a`, `-r` or `-w`, with the file to use instead of
+    /// `$HISTFILE`.
+    HistoryFile {
+        sync: HistorySync,
+        file: Option<String>,
+    },
+    Hash {
+        reset: bool,
+        names: Vec<String>,
+    },
+    Clear,
+    Set(Vec<String>),
+    Mapfile {
+        name: String,
+    },
+    Trap(Vec<String>),
+    Umask {
+        mask: Option<u32>,
+    },
+    Jobs {
+        /// `jobs -l`: show each job's PID.
+        long: bool,
+    },
+    Printf {
+        format: String,
+        args: Vec<String>,
+    },
+    /// `pushd [dir | +N | -N]`: `None` swaps the top two entries.
+    Pushd(Option<String>),
+    /// `popd [+N | -N]`: `None` drops the top entry.
+    Popd(Option<String>),
+    Dirs {
+        /// `dirs -c`: empty the stack.
+        clear: bool,
+        /// `dirs -v`: one numbered entry per line.
+        verbose: bool,
+        /// `dirs -p`: one entry per line.
+        per_line: bool,
+        /// `dirs -l`: no `~` for the home directory.
+        long: bool,
+    },
+    /// `wait [jobspec ...]`: waits for the given background jobs, or all
+    /// of them.
+    Wait(Vec<String>),
+    Export(Vec<String>),
+    Alias(Vec<String>),
+    Unalias(Vec<String>),
+    Complete(Vec<String>),
+    Ls {
+        options: LsOptions,
+        paths: Vec<String>,
+    },
+    Mkdir {
+        /// `mkdir -p`: create missing parents, accept existing directories.
+        parents: bool,
+        paths: Vec<String>,
+    },
+    Rmdir(Vec<String>),
+    Rm {
+        recursive: bool,
+        /// `rm -f`: ignore missing files.
+        force: bool,
+        paths: Vec<String>,
+    },
+    Touch(Vec<String>),
+    Basename {
+        path: String,
+        suffix: Option<String>,
+    },
+    Dirname(Vec<String>),
+    Seq {
+        first: i64,
+        step: i64,
+        last: i64,
+        /// `seq -w`: pad with zeros to the width of the longest number.
+        equal_width: bool,
+        /// `seq -z` or `seq -0`: end each number with a NUL instead of a
+        /// newline, for `xargs -0`.
+        null_separated: bool,
+    },
+    /// `repeat N command`: runs the raw command words `count` times,
+    /// expanding them afresh each time.
+    Repeat {
+        count: u32,
+        words: Vec<String>,
+    },
+    /// `getopts optstring name [arg ...]`: reads the next flag of `args`,
+    /// or of the positional parameters when there are none, into `$name`.
+    Getopts {
+        optstring: String,
+        name: String,
+        args: Vec<String>,
+    },
+    /// Runs each line of `path` in the current shell.
+    Source {
+        path: String,
+        /// Positional parameters for the file, if any are given.
+        args: Vec<String>,
+    },
+    /// A call to a function defined in the shell, with its arguments as
+    /// the positional parameters.
+    Function {
+        name: String,
+        args: Vec<String>,
+    },
+    /// `time` on a single stage; a leading `time` times the whole pipeline.
+    Time(Option<Box<Command>>),
+    Disown {
+        all: bool,
+        specs: Vec<String>,
+    },
+    Assign {
+        name: String,
+        value: Value,
+    },
+    /// `NAME=value ...` before a command, exported to that command alone,
+    /// or on their own, setting shell variables.
+    Assignments {
+        assignments: Vec<(String, String)>,
+        command: Option<Box<Command>>,
+    },
+}
+
+impl Command {
+    /// Whether this starts a program rather than running in the shell.
+    pub fn runs_program(&self) -> bool {
+        match self {
+            Command::Exec { .. } => true,
+            Command::Assignments {
+                command: Some(command),
+                ..
+            } => command.runs_program(),
+            _ => false,
+        }
+    }
+}
+
+/// Failures that give a command a specific exit status rather than the
+/// generic 1.
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("{0}: command not found")]
+    NotFound(String),
+    /// The program was found but could not be started.
+    #[error("{command}: {message}")]
+    CannotExecute {
+        command: String,
+        message: String,
+        status: i32,
+    },
+    /// A builtin that has already reported what went wrong and only needs
+    /// to fail, like `type` for an unknown name.
+    #[error("exit status {0}")]
+    Status(i32),
+    /// Like `Status`, for a stage whose output still goes on to the next
+    /// stage of the pipeline.
+    #[error("exit status {status}")]
+    Piped { status: i32, output: PipeReader },
+}
+
+impl CommandError {
+    pub fn status(&self) -> i32 {
+        match self {
+            CommandError::NotFound(_) => 127,
+            CommandError::CannotExecute { status, .. } => *status,
+            CommandError::Status(status) | CommandError::Piped { status, .. } => *status,
+        }
+    }
+}
+
+/// Prints the message of a failed command to `stderr`, after the line of
+/// the sourced file it came from, unless the command has reported the
+/// failure itself.
+pub fn report_error(
+    error: &anyhow::Error,
+    location: Option<&ScriptLocation>,
+    stderr: &mut dyn Output,
+) {
+    if matches!(
+        error.downcast_ref(),
+        Some(CommandError::Status(_) | CommandError::Piped { .. })
+    ) {
+        return;
+    }
+    match location {
+        Some(location) => stderr.print(&format!("{}: {}", location, error)),
+        None => stderr.print(&error.to_string()),
+    }
+}
+
+/// Finishes a builtin that printed its own errors: status 1 if any occurred.
+fn reported_status(failed: bool) -> Result<Option<PipeReader>> {
+    if failed {
+        Err(CommandError::Status(1).into())
+    } else {
+        Ok(None)
+    }
+}
+
+/// Finishes a builtin whose `output` feeds the next stage, failing with
+/// `status` unless it is 0 while still passing the output on.
+fn piped_status(output: Option<PipeReader>, status: i32) -> Result<Option<PipeReader>> {
+    match output {
+        _ if status == 0 => Ok(output),
+        Some(output) => Err(CommandError::Piped { status, output }.into()),
+        None => Err(CommandError::Status(status).into()),
+    }
+}
+
+/// The exit status of a command that failed with `error`: 127 or 126 for
+/// commands that could not be run, 1 for everything else.
+pub fn error_status(error: &anyhow::Error) -> i32 {
+    error
+        .downcast_ref::<CommandError>()
+        .map_or(1, CommandError::status)
 }
 
 fn is_built_in(command: &str) -> bool {
@@ -65,12 +400,36 @@ pub fn execute_command(
     input: Option<PipeReader>,
     stdout_output: Option<&mut dyn Output>,
     stderr_output: &mut dyn Output,
-    history: &History,
+    state: &mut ShellState,
 ) -> Result<Option<PipeReader>> {
+    let warned = std::mem::take(&mut state.exit_warned);
     match command {
-        Command::Exit => process::exit(0),
+        Command::Exit => {
+            // Like bash, an interactive shell refuses the first `exit` while
+            // jobs are running.
+            if state.interactive && !warned && state.jobs.has_running() {
+                stderr_output.print("There are running jobs.");
+                state.exit_warned = true;
+                return Err(CommandError::Status(1).into());
+            }
+            // The caller runs the EXIT trap and leaves once the line is done.
+            state.exit_requested = true;
+            Ok(None)
+        }
+        Command::Return(status) => {
+            if state.function_depth == 0 {
+                return Err(anyhow!("return: can only `return' from a function"));
+            }
+            // The function's remaining lines are skipped once this is set.
+            state.returning = true;
+            match status.unwrap_or(state.last_status) {
+                0 => Ok(None),
+                status => Err(CommandError::Status(status).into()),
+            }
+        }
         Command::History { lines_count } => {
-            let line = history
+            let line = state
+                .history
                 .items
                 .iter()
                 .enumerate()
@@ -87,29 +446,98 @@ pub fn execute_command(
                 pipe_string(line)
             }
         }
-        Command::Cd(path) => {
-            cd(&path)?;
+        Command::HistoryFile { sync, file } => {
+            let path = file
+                .or_else(|| state.vars.get("HISTFILE").map(str::to_string))
+                .ok_or_else(|| anyhow!("history: HISTFILE not set"))?;
+            state
+                .history
+                .sync(sync, Path::new(&path))
+                .map_err(|e| anyhow!("history: {}: {}", path, io_message(&e)))?;
+            Ok(None)
+        }
+        Command::Cd { path, physical } => {
+            cd(&path, physical, state)?;
             Ok(None)
         }
         Command::Echo {
-            text,
+            args,
             interpret_escapes,
+            newline,
+            separator,
         } => {
+            // Each argument keeps its own (quoted) whitespace; only the
+            // separation between arguments is normalized.
+            let text = args.join(&separator);
             let output = if interpret_escapes {
                 interpret_escape_sequences(&text)
             } else {
                 text
             };
+            match stdout_output {
+                Some(out) if newline => out.print(&output),
+                Some(out) => out.write(&output),
+                None if newline => return pipe_string(output),
+                None => return pipe_bytes(text_bytes(&output)),
+            }
+            Ok(None)
+        }
+        Command::Pwd { physical, tilde } => {
+            let dir = if physical {
+                fs::canonicalize(env::current_dir()?)?
+            } else {
+                logical_pwd(state)?
+            };
+            let text = if tilde {
+                abbreviate_home(&dir, state.vars.get("HOME").map(Path::new))
+            } else {
+                dir.display().to_string()
+            };
             if let Some(out) = stdout_output {
-                out.print(&output);
+                out.print(&text);
                 Ok(None)
             } else {
-                pipe_string(output)
+                pipe_string(text)
+            }
+        }
+        Command::Type { names, all } => {
+            let mut failed = false;
+            let text = names
+                .iter()
+                .map(|name| {
+                    describe_type(name, all, state).unwrap_or_else(|| {
+                        failed = true;
+                        format!("{}: not found", name)
+                    })
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                reported_status(failed)
+            } else {
+                piped_status(pipe_string(text)?, i32::from(failed))
             }
         }
-        Command::Pwd => {
-            let dir = fs::canonicalize(env::current_dir()?)?;
-            let text = dir.display().to_string();
+        Command::Hash { reset, names } => {
+            if reset {
+                state.hash.clear();
+            }
+            let mut failed = false;
+            for name in &names {
+                if !is_built_in(name) && state.hash.lookup(name, state.vars.get("PATH")).is_none() {
+                    stderr_output.print(&format!("hash: {}: not found", name));
+                    failed = true;
+                }
+            }
+            if reset || !names.is_empty() {
+                return reported_status(failed);
+            }
+            let text = if state.hash.is_empty() {
+                "hash: hash table empty".to_string()
+            } else {
+                state.hash.listing()
+            };
             if let Some(out) = stdout_output {
                 out.print(&text);
                 Ok(None)
@@ -117,13 +545,20 @@ pub fn execute_command(
                 pipe_string(text)
             }
         }
-        Command::Type(cmd) => {
-            let text = if is_built_in(&cmd) {
-                format!("{} is a shell builtin", cmd)
-            } else if let Some(path) = find_in_path(&cmd) {
-                format!("{} is {}", cmd, path.display())
+        Command::Clear => {
+            // Clear the screen and move the cursor home; sent as-is even when
+            // redirected, like bash's `clear`.
+            let sequence = "\x1b[2J\x1b[H";
+            if let Some(out) = stdout_output {
+                out.write(sequence);
+                Ok(None)
             } else {
-                format!("{}: not found", cmd)
+                pipe_bytes(sequence.as_bytes().to_vec())
+            }
+        }
+        Command::Set(args) => {
+            let Some(text) = set_options(&args, state)? else {
+                return Ok(None);
             };
             if let Some(out) = stdout_output {
                 out.print(&text);
@@ -132,156 +567,2242 @@ pub fn execute_command(
                 pipe_string(text)
             }
         }
-        Command::Exec { command, args } => {
-            let is_final = stdout_output.is_some();
-            exec_piped(
-                &command,
-                &args,
-                input,
-                is_final,
-                stdout_output,
-                stderr_output,
-            )
+        Command::Mapfile { name } => {
+            let lines: Vec<String> = match input {
+                Some(reader) => BufReader::new(reader)
+                    .lines()
+                    .collect::<std::io::Result<_>>()?,
+                None => std::io::stdin()
+                    .lock()
+                    .lines()
+                    .collect::<std::io::Result<_>>()?,
+            };
+            state.vars.set_array(&name, lines)?;
+            Ok(None)
         }
-    }
-}
-
-fn cd(path: &str) -> Result<()> {
-    let target = match path {
-        "" | "~" => dirs::home_dir(),
-        p if p.starts_with("~/") => dirs::home_dir().map(|home| home.join(&p[2..])),
-        p => Some(PathBuf::from(p)),
-    };
-
-    match target {
-        Some(t) => {
-            env::set_current_dir(t).map_err(|_| anyhow!("cd: {}: No such file or directory", path))
+        Command::Trap(args) => {
+            let Some(text) = trap(&args, state)? else {
+                return Ok(None);
+            };
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
+            }
         }
-        None => Err(anyhow!("cd: {}: No such file or directory", path)),
-    }
-}
-
-fn exec_piped(
-    command: &str,
-    args: &[String],
-    input: Option<PipeReader>,
-    is_final: bool,
-    stdout_output: Option<&mut dyn Output>,
-    stderr_output: &mut dyn Output,
-) -> Result<Option<PipeReader>> {
-    find_in_path(command).ok_or_else(|| anyhow!("{}: command not found", command))?;
-
-    let stdin_cfg = match input {
-        Some(reader) => unsafe { Stdio::from_raw_fd(reader.into_raw_fd()) },
-        None => Stdio::inherit(),
-    };
-
-    let is_stdout_redirected = stdout_output
-        .as_ref()
-        .map(|o| o.is_redirected())
-        .unwrap_or(false);
-
-    let stdout_cfg = if is_final && !is_stdout_redirected {
-        Stdio::inherit()
-    } else {
-        Stdio::piped()
-    };
-
-    let is_stderr_redirected = stderr_output.is_redirected();
-    let stderr_cfg = if is_stderr_redirected {
-        Stdio::piped()
-    } else {
-        Stdio::inherit()
-    };
-
-    let mut child = CmdCommand::new(command)
-        .args(args)
-        .stdin(stdin_cfg)
-        .stdout(stdout_cfg)
-        .stderr(stderr_cfg)
-        .spawn()?;
-
-    // Handle stderr if redirected
-    if is_stderr_redirected {
-        if let Some(stderr) = child.stderr.take() {
-            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
-                stderr_output.print(&line);
+        Command::Umask { mask } => {
+            let current = umask(mask);
+            if mask.is_some() {
+                return Ok(None);
+            }
+            let text = format!("{:04o}", current);
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
             }
         }
-    }
-
-    if is_final && is_stdout_redirected {
-        if let Some(stdout) = child.stdout.take() {
+        Command::Time(inner) => {
+            let timer = Timer::start();
+            let output = match inner {
+                Some(inner) => execute_command(*inner, input, stdout_output, stderr_output, state)?,
+                None => None,
+            };
+            // A stage that is not feeding a pipe can safely wait for the
+            // processes started so far.
+            if output.is_none() {
+                state.foreground.wait();
+            }
+            stderr_output.write(&timer.report());
+            Ok(output)
+        }
+        Command::Repeat { count, words } => {
+            // Mid-pipeline, every iteration is collected and handed on at once.
+            let mut captured = CaptureOutput::new();
+            let piped = stdout_output.is_none();
+            let out: &mut dyn Output = match stdout_output {
+                Some(out) => out,
+                None => &mut captured,
+            };
+            let mut input = input;
+            for i in 1..=count {
+                let (command, _) = parse_command(words.clone(), state)?;
+                let result =
+                    execute_command(command, input.take(), Some(&mut *out), stderr_output, state);
+                // The last iteration's status is the status of `repeat`.
+                if i == count && !piped {
+                    return result;
+                }
+                if let Err(e) = result {
+                    report_error(&e, state.location.as_ref(), stderr_output);
+                }
+                state.foreground.wait();
+            }
+            if piped {
+                pipe_bytes(text_bytes(&captured.contents()))
+            } else {
+                Ok(None)
+            }
+        }
+        Command::Getopts {
+            optstring,
+            name,
+            args,
+        } => {
+            let args = if args.is_empty() {
+                state.positional.clone()
+            } else {
+                args
+            };
+            if next_option(&optstring, &name, &args, stderr_output, state)? {
+                Ok(None)
+            } else {
+                Err(CommandError::Status(1).into())
+            }
+        }
+        Command::Shift(count) => {
+            // Like bash, shifting past the last parameter fails quietly and
+            // leaves them as they were.
+            if count > state.positional.len() {
+                return Err(CommandError::Status(1).into());
+            }
+            state.positional.drain(..count);
+            Ok(None)
+        }
+        Command::Local(args) => {
+            if state.function_depth == 0 {
+                return Err(anyhow!("local: can only be used in a function"));
+            }
+            let mut failed = false;
+            for arg in &args {
+                let (name, value) = match arg.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (arg.as_str(), None),
+                };
+                if !is_valid_name(name) {
+                    stderr_output.print(&format!("local: `{}': not a valid identifier", arg));
+                    failed = true;
+                    continue;
+                }
+                if let Err(e) = state.vars.make_local(name) {
+                    stderr_output.print(&format!("local: {}", e));
+                    failed = true;
+                    continue;
+                }
+                if let Some(value) = value {
+                    state.vars.set(name, value)?;
+                }
+            }
+            reported_status(failed)
+        }
+        Command::Readonly(args) => {
+            let names: Vec<&String> = args.iter().filter(|arg| *arg != "-p").collect();
+            if names.is_empty() {
+                let text = readonly_listing(state);
+                if let Some(out) = stdout_output {
+                    out.print(&text);
+                    return Ok(None);
+                }
+                return pipe_string(text);
+            }
+            let mut failed = false;
+            for arg in names {
+                let (name, value) = match arg.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (arg.as_str(), None),
+                };
+                if !is_valid_name(name) {
+                    stderr_output.print(&format!("readonly: `{}': not a valid identifier", arg));
+                    failed = true;
+                    continue;
+                }
+                if let Some(value) = value
+                    && let Err(e) = state.vars.set(name, value)
+                {
+                    stderr_output.print(&e.to_string());
+                    failed = true;
+                    continue;
+                }
+                state.vars.set_readonly(name);
+            }
+            reported_status(failed)
+        }
+        Command::Unset(args) => {
+            // `-f` removes functions and `-v` variables. Without either, a
+            // name that is not a variable is removed as a function, as in
+            // bash. With no namerefs, `-n` is the same as `-v`.
+            let mut only = None;
+            let mut names = args.as_slice();
+            while let Some(arg) = names
+                .first()
+                .filter(|arg| arg.len() > 1 && arg.starts_with('-'))
+            {
+                names = &names[1..];
+                if arg == "--" {
+                    break;
+                }
+                for flag in arg[1..].chars() {
+                    only = match flag {
+                        'f' => Some('f'),
+                        'v' | 'n' => Some('v'),
+                        _ => {
+                            stderr_output.print(&format!("unset: -{}: invalid option", flag));
+                            stderr_output.print("unset: usage: unset [-f] [-v] [-n] [name ...]");
+                            return Err(CommandError::Status(2).into());
+                        }
+                    };
+                }
+            }
+            let mut failed = false;
+            for name in names {
+                let variable = match only {
+                    Some(kind) => kind == 'v',
+                    None => state.vars.get_value(name).is_some() || !state.functions.contains(name),
+                };
+                if !variable {
+                    state.functions.remove(name);
+                } else if state.vars.unset(name).is_err() {
+                    stderr_output
+                        .print(&format!("unset: {}: cannot unset: readonly variable", name));
+                    failed = true;
+                }
+            }
+            reported_status(failed)
+        }
+        Command::Export(args) => {
+            let names: Vec<&String> = args.iter().filter(|arg| *arg != "-p").collect();
+            if names.is_empty() {
+                let text = export_listing(state);
+                if let Some(out) = stdout_output {
+                    out.print(&text);
+                    return Ok(None);
+                }
+                return pipe_string(text);
+            }
+            let mut failed = false;
+            for arg in names {
+                let (name, value) = match arg.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (arg.as_str(), None),
+                };
+                if !is_valid_name(name) {
+                    stderr_output.print(&format!("export: `{}': not a valid identifier", arg));
+                    failed = true;
+                    continue;
+                }
+                if let Some(value) = value
+                    && let Err(e) = state.vars.set(name, value)
+                {
+                    stderr_output.print(&e.to_string());
+                    failed = true;
+                    continue;
+                }
+                state.vars.export(name);
+            }
+            reported_status(failed)
+        }
+        Command::Alias(args) => {
+            let mut lines = Vec::new();
+            if args.is_empty() && !state.aliases.listing().is_empty() {
+                lines.push(state.aliases.listing());
+            }
+            for arg in &args {
+                match arg.split_once('=') {
+                    Some((name, _)) if !is_valid_alias_name(name) => {
+                        stderr_output.print(&format!("alias: `{}': invalid alias name", name));
+                    }
+                    Some((name, value)) => state.aliases.set(name, value),
+                    None => match state.aliases.definition(arg) {
+                        Some(definition) => lines.push(definition),
+                        None => stderr_output.print(&format!("alias: {}: not found", arg)),
+                    },
+                }
+            }
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            let text = lines.join("\n");
             if let Some(out) = stdout_output {
-                for line in BufReader::new(stdout).lines().map_while(Result::ok) {
-                    out.print(&line);
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
+            }
+        }
+        Command::Unalias(args) => {
+            if args.first().map(|arg| arg.as_str()) == Some("-a") {
+                state.aliases.clear();
+                return Ok(None);
+            }
+            for name in &args {
+                if !state.aliases.remove(name) {
+                    stderr_output.print(&format!("unalias: {}: not found", name));
                 }
             }
+            Ok(None)
         }
-        child.wait()?;
-        Ok(None)
-    } else if is_final {
-        child.wait()?;
-        Ok(None)
-    } else {
-        let stdout = child.stdout.take().expect("stdout was piped");
-        let reader = unsafe { PipeReader::from_raw_fd(stdout.into_raw_fd()) };
-
-        thread::spawn(move || {
-            child.wait().ok();
-        });
-
-        Ok(Some(reader))
-    }
-}
-
-fn find_in_path(executable: &str) -> Option<PathBuf> {
-    env::var_os("PATH").and_then(|paths| {
-        env::split_paths(&paths).find_map(|dir| {
-            let full_path = dir.join(executable);
-            if full_path.is_file() && full_path.is_executable() {
-                fs::canonicalize(&full_path).ok()
+        Command::Complete(args) => {
+            let Some(text) = complete(&args, state)? else {
+                return Ok(None);
+            };
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
             } else {
-                None
+                pipe_string(text)
             }
-        })
-    })
-}
-
-fn pipe_string(text: String) -> Result<Option<PipeReader>> {
-    let (reader, mut writer) = pipe()?;
-    thread::spawn(move || {
-        let _ = writeln!(writer, "{}", text);
-    });
-    Ok(Some(reader))
-}
-
-fn interpret_escape_sequences(s: &str) -> String {
-    let mut result = String::new();
-    let mut chars = s.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        if c == '\\' {
-            match chars.next() {
-                Some('n') => result.push('\n'),
-                Some('t') => result.push('\t'),
-                Some('r') => result.push('\r'),
-                Some('\\') => result.push('\\'),
-                Some('a') => result.push('\x07'),
-                Some('b') => result.push('\x08'),
-                Some('f') => result.push('\x0C'),
-                Some('v') => result.push('\x0B'),
-                Some('e') => result.push('\x1B'),
-                Some(other) => {
-                    result.push('\\');
-                    result.push(other);
+        }
+        Command::Ls { options, paths } => {
+            let terminal = stdout_output.as_ref().is_some_and(|out| out.is_terminal());
+            let paths = if paths.is_empty() {
+                vec![".".to_string()]
+            } else {
+                paths
+            };
+            let mut blocks = Vec::new();
+            let mut failed = false;
+            for path in &paths {
+                match list(path, &options, terminal) {
+                    // Several directories each get a `name:` header.
+                    Ok(text) if paths.len() > 1 && Path::new(path).is_dir() => {
+                        blocks.push(format!("{}:\n{}", path, text))
+                    }
+                    Ok(text) if text.is_empty() => {}
+                    Ok(text) => blocks.push(text),
+                    Err(e) => {
+                        stderr_output.print(&e.to_string());
+                        failed = true;
+                    }
                 }
-                None => result.push('\\'),
             }
-        } else {
-            result.push(c);
+            if blocks.is_empty() {
+                return reported_status(failed);
+            }
+            let text = blocks.join("\n\n");
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                reported_status(failed)
+            } else {
+                piped_status(pipe_string(text)?, i32::from(failed))
+            }
         }
-    }
+        Command::Mkdir { parents, paths } => {
+            let mut failed = false;
+            for path in &paths {
+                if let Err(e) = mkdir(path, parents) {
+                    stderr_output.print(&e.to_string());
+                    failed = true;
+                }
+            }
+            reported_status(failed)
+        }
+        Command::Rmdir(paths) => {
+            let mut failed = false;
+            for path in &paths {
+                if let Err(e) = rmdir(path) {
+                    stderr_output.print(&e.to_string());
+                    failed = true;
+                }
+            }
+            reported_status(failed)
+        }
+        Command::Rm {
+            recursive,
+            force,
+            paths,
+        } => {
+            let mut failed = false;
+            for path in &paths {
+                if let Err(e) = rm(path, recursive, force) {
+                    stderr_output.print(&e.to_string());
+                    failed = true;
+                }
+            }
+            reported_status(failed)
+        }
+        Command::Touch(paths) => {
+            let mut failed = false;
+            for path in &paths {
+                if let Err(e) = touch(path) {
+                    stderr_output.print(&e.to_string());
+                    failed = true;
+                }
+            }
+            reported_status(failed)
+        }
+        Command::Basename { path, suffix } => {
+            let text = basename(&path, suffix.as_deref());
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
+            }
+        }
+        Command::Dirname(paths) => {
+            let text = paths
+                .iter()
+                .map(|path| dirname(path))
+                .collect::<Vec<String>>()
+                .join("\n");
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
+            }
+        }
+        Command::Seq {
+            first,
+            step,
+            last,
+            equal_width,
+            null_separated,
+        } => {
+            let separator = if null_separated { '\0' } else { '\n' };
+            let numbers = seq(first, step, last, equal_width)
+                .map(move |number| format!("{}{}", number, separator));
+            // The numbers are made as they are written, so a long count never
+            // sits in memory and stops as soon as the reader goes away.
+            let Some(out) = stdout_output else {
+                let (reader, writer) = pipe()?;
+                thread::spawn(move || {
+                    let mut writer = std::io::BufWriter::new(writer);
+                    for number in numbers {
+                        if writer.write_all(number.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                    let _ = writer.flush();
+                });
+                return Ok(Some(reader));
+            };
+            let mut batch = String::new();
+            for number in numbers {
+                batch.push_str(&number);
+                if batch.len() >= SEQ_BATCH {
+                    out.write(&std::mem::take(&mut batch));
+                }
+            }
+            if !batch.is_empty() {
+                out.write(&batch);
+            }
+            Ok(None)
+        }
+        Command::Source { path, args } => {
+            // The sourced lines run their own pipelines; keep this one's job.
+            let outer = std::mem::take(&mut state.foreground);
+            let outer_args = if args.is_empty() {
+                None
+            } else {
+                Some(std::mem::replace(&mut state.positional, args))
+            };
+            let result = source_file(Path::new(&path), state);
+            if let Some(outer_args) = outer_args {
+                state.positional = outer_args;
+            }
+            state.foreground = outer;
+            result?;
+            Ok(None)
+        }
+        Command::Function { name, args } => {
+            let body = state
+                .functions
+                .get(&name)
+                .map(str::to_string)
+                .ok_or_else(|| CommandError::NotFound(name.clone()))?;
+            // Output going anywhere but the terminal is handed to the body's
+            // commands, since they open their own streams. A call piped to
+            // the next stage without a pipe to write to (see `run_stages`)
+            // has its output collected and passed on once the body is done.
+            let collected = stdout_output.is_none().then(CaptureOutput::new);
+            let stdout: Option<Box<dyn Output>> = match (&stdout_output, &collected) {
+                (_, Some(collected)) => Some(Box::new(collected.clone())),
+                (Some(out), None) if out.is_redirected() => Some(out.duplicate()?),
+                _ => None,
+            };
+            let stderr = if stderr_output.is_redirected() {
+                Some(stderr_output.duplicate()?)
+            } else {
+                None
+            };
+            let streams = FunctionStreams {
+                stdout,
+                stderr,
+                input,
+            };
+            let outer_streams = std::mem::replace(&mut state.function_streams, streams);
+            let outer_args = std::mem::replace(&mut state.positional, args);
+            let outer = std::mem::take(&mut state.foreground);
+            state.function_depth += 1;
+            state.vars.push_scope();
+            run_script(&body, state);
+            state.vars.pop_scope();
+            state.function_depth -= 1;
+            state.returning = false;
+            state.foreground = outer;
+            state.positional = outer_args;
+            state.function_streams = outer_streams;
+
+            if let Some(collected) = collected {
+                let output = pipe_bytes(text_bytes(&collected.contents()))?;
+                return piped_status(output, state.last_status);
+            }
+            match state.last_status {
+                0 => Ok(None),
+                status => Err(CommandError::Status(status).into()),
+            }
+        }
+        Command::Printf { format, args } => {
+            let printed = printf(&format, &args);
+            for error in &printed.errors {
+                stderr_output.print(&format!("printf: {}", error));
+            }
+            let Some(out) = stdout_output else {
+                let status = i32::from(!printed.errors.is_empty());
+                return piped_status(pipe_bytes(printed.bytes)?, status);
+            };
+            out.write(&bytes_to_text(&printed.bytes));
+            reported_status(!printed.errors.is_empty())
+        }
+        Command::Pushd(arg) => {
+            pushd(arg.as_deref(), state)?;
+            let text = dirs_listing(state, false, false, false)?;
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
+            }
+        }
+        Command::Popd(arg) => {
+            popd(arg.as_deref(), state)?;
+            let text = dirs_listing(state, false, false, false)?;
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
+            }
+        }
+        Command::Dirs {
+            clear,
+            verbose,
+            per_line,
+            long,
+        } => {
+            if clear {
+                state.dir_stack.clear();
+                return Ok(None);
+            }
+            let text = dirs_listing(state, verbose, per_line, long)?;
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
+            }
+        }
+        Command::Wait(specs) => {
+            if specs.is_empty() {
+                state.jobs.wait_all();
+                return Ok(None);
+            }
+            // Like bash, the status is that of the last job waited for, and
+            // 127 for a job that does not exist.
+            let mut status = 0;
+            for spec in &specs {
+                status = match state.jobs.resolve(spec) {
+                    Ok(id) => state.jobs.wait(id).unwrap_or_default(),
+                    Err(e) => {
+                        stderr_output.print(&format!("wait: {}", e));
+                        127
+                    }
+                };
+            }
+            match status {
+                0 => Ok(None),
+                status => Err(CommandError::Status(status).into()),
+            }
+        }
+        Command::Jobs { long } => {
+            let text = state.jobs.listing(long);
+            if text.is_empty() {
+                return Ok(None);
+            }
+            if let Some(out) = stdout_output {
+                out.print(&text);
+                Ok(None)
+            } else {
+                pipe_string(text)
+            }
+        }
+        Command::Disown { all, specs } => {
+            if all {
+                state.jobs.clear();
+                return Ok(None);
+            }
+            let specs = if specs.is_empty() {
+                vec!["%%".to_string()]
+            } else {
+                specs
+            };
+            for spec in &specs {
+                // Dropping the entry leaves the processes running untracked.
+                match state.jobs.resolve(spec) {
+                    Ok(id) => {
+                        state.jobs.remove(id);
+                    }
+                    Err(e) => stderr_output.print(&format!("disown: {}", e)),
+                }
+            }
+            Ok(None)
+        }
+        Command::Assign { name, value } => {
+            state.vars.set_value(&name, value)?;
+            Ok(None)
+        }
+        Command::Assignments {
+            assignments,
+            command,
+        } => {
+            let Some(command) = command else {
+                for (name, value) in &assignments {
+                    state.vars.set(name, value)?;
+                }
+                return Ok(None);
+            };
+            // The command sees the values in its environment; the old ones
+            // come back once it has started.
+            state.vars.push_scope();
+            for (name, value) in &assignments {
+                let assigned = state
+                    .vars
+                    .make_local(name)
+                    .and_then(|_| state.vars.set(name, value));
+                if let Err(e) = assigned {
+                    state.vars.pop_scope();
+                    return Err(e);
+                }
+                state.vars.export(name);
+            }
+            state.exit_warned = warned;
+            let result = execute_command(*command, input, stdout_output, stderr_output, state);
+            state.vars.pop_scope();
+            result
+        }
+        Command::Exec { command, args } => {
+            let Some(path) = state.hash.lookup(&command, state.vars.get("PATH")) else {
+                if state.handling_not_found || !state.functions.contains(COMMAND_NOT_FOUND_HANDLE) {
+                    return Err(CommandError::NotFound(command).into());
+                }
+                let handler = Command::Function {
+                    name: COMMAND_NOT_FOUND_HANDLE.to_string(),
+                    args: std::iter::once(command).chain(args).collect(),
+                };
+                state.handling_not_found = true;
+                let result = execute_command(handler, input, stdout_output, stderr_output, state);
+                state.handling_not_found = false;
+                return result;
+            };
+            if state.options.showpath {
+                let resolved = std::path::absolute(&path).unwrap_or_else(|_| path.clone());
+                stderr_output.print(&format!("{} -> {}", command, resolved.display()));
+            }
+            let mut cmd = CmdCommand::new(path);
+            #[cfg(unix)]
+            cmd.arg0(string_to_os(&command));
+            cmd.args(args.iter().map(|arg| string_to_os(arg)))
+                .env_clear()
+                .envs(state.vars.environment());
+            exec_piped(
+                cmd,
+                input,
+                stdout_output,
+                stderr_output,
+                &*state.spawner,
+                &mut state.foreground,
+            )
+        }
+    }
+}
+
+/// The function run, like bash, with the name and arguments of a command
+/// that could not be found.
+const COMMAND_NOT_FOUND_HANDLE: &str = "command_not_found_handle";
+
+/// How many bytes of `seq` output are gathered before each write.
+const SEQ_BATCH: usize = 8192;
+
+/// What `type` says about `name`, or `None` when it is not a command.
+fn describe_type(name: &str, all: bool, state: &mut ShellState) -> Option<String> {
+    let mut lines = Vec::new();
+    if let Some(value) = state.aliases.get(name) {
+        lines.push(format!("{} is aliased to `{}'", name, value));
+    }
+    if state.functions.contains(name) {
+        lines.push(format!("{} is a function", name));
+    }
+    if is_built_in(name) {
+        lines.push(format!("{} is a shell builtin", name));
+    }
+    if !all
+        && lines.is_empty()
+        && let Some(path) = state.hash.get(name).filter(|path| path.is_file())
+    {
+        lines.push(format!("{} is hashed ({})", name, quoted_path(path)));
+    }
+    if all || lines.is_empty() {
+        // PATH is looked up as the shell has it, like commands are run.
+        let dirs = state.vars.get("PATH").map(string_to_os).unwrap_or_default();
+        let paths = if name.contains('/') {
+            // A path is not looked up in PATH, and is shown as typed.
+            resolve_executable(Path::new(name))
+                .map(|_| PathBuf::from(name))
+                .into_iter()
+                .collect()
+        } else if all {
+            find_all_in_dirs(name, &dirs)
+        } else {
+            find_in_dirs(name, &dirs).into_iter().collect()
+        };
+        lines.extend(
+            paths
+                .into_iter()
+                .map(|path| format!("{} is {}", name, quoted_path(&path))),
+        );
+    }
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// `path` escaped as the shell would need it typed, so that a path with
+/// spaces reads back as one word.
+fn quoted_path(path: &Path) -> String {
+    crate::printf::quote(&os_to_string(path.as_os_str()))
+}
+
+/// Runs `trap`, returning the listing to print for `trap` and `trap -p`.
+fn trap(args: &[String], state: &mut ShellState) -> Result<Option<String>> {
+    match args {
+        [] => state.traps.listing(&[]).map(Some),
+        [flag, names @ ..] if flag == "-p" => state.traps.listing(names).map(Some),
+        // A lone signal is reset, as with `trap - name`.
+        [name] if signal_name(name).is_some() => state
+            .traps
+            .set(name, RESET)
+            .map(|_| None)
+            .map_err(|e| anyhow!("trap: {}", e)),
+        [command, names @ ..] if !names.is_empty() => {
+            for name in names {
+                state
+                    .traps
+                    .set(name, command)
+                    .map_err(|e| anyhow!("trap: {}", e))?;
+            }
+            Ok(None)
+        }
+        _ => Err(anyhow!("trap: usage: trap [-p] [arg signal_spec ...]")),
+    }
+}
+
+/// Runs `getopts`, returning whether a flag was read. `OPTIND` says which
+/// argument to look at next; `state.getopts` remembers the position within
+/// a cluster of flags for as long as `OPTIND` is left alone.
+///
+/// `$name` is set to the flag, or to `?` for an unknown one and at the end.
+/// A leading `:` in `optstring` selects silent error reporting: instead of
+/// a message, `$OPTARG` holds the offending flag and a missing argument sets
+/// `$name` to `:`.
+fn next_option(
+    optstring: &str,
+    name: &str,
+    args: &[String],
+    stderr: &mut dyn Output,
+    state: &mut ShellState,
+) -> Result<bool> {
+    let index = state
+        .vars
+        .get("OPTIND")
+        .and_then(|index| index.trim().parse().ok())
+        .unwrap_or(1);
+    let mut cursor = if state.getopts.index == index {
+        state.getopts
+    } else {
+        GetoptsCursor::new(index)
+    };
+    let (silent, optstring) = match optstring.strip_prefix(':') {
+        Some(optstring) => (true, optstring),
+        None => (false, optstring),
+    };
+    let result = getopts(optstring, args, &mut cursor);
+    state.getopts = cursor;
+    state.vars.set("OPTIND", &cursor.index.to_string())?;
+    state.vars.unset("OPTARG")?;
+
+    let (value, argument) = match result {
+        Getopt::Found { option, argument } => (option, argument),
+        Getopt::Done => {
+            state.vars.set(name, "?")?;
+            return Ok(false);
+        }
+        Getopt::Unknown(option) if silent => ('?', Some(option.to_string())),
+        Getopt::MissingArgument(option) if silent => (':', Some(option.to_string())),
+        Getopt::Unknown(option) => {
+            stderr.print(&format!("getopts: illegal option -- {}", option));
+            ('?', None)
+        }
+        Getopt::MissingArgument(option) => {
+            stderr.print(&format!(
+                "getopts: option requires an argument -- {}",
+                option
+            ));
+            ('?', None)
+        }
+    };
+    state.vars.set(name, &value.to_string())?;
+    if let Some(argument) = argument {
+        state.vars.set("OPTARG", &argument)?;
+    }
+    Ok(true)
+}
+
+/// Registers, removes or lists argument completions: wordlists
+/// (`complete -W`), directories (`-d`) or files (`-f`).
+fn complete(args: &[String], state: &mut ShellState) -> Result<Option<String>> {
+    let mut specs = state
+        .completions
+        .lock()
+        .map_err(|_| anyhow!("complete: completion specs unavailable"))?;
+    match args {
+        // With no specs there is nothing to list, not even a blank line.
+        [] => Ok(Some(specs.listing(&[]).join("\n")).filter(|text| !text.is_empty())),
+        [flag, names @ ..] if flag == "-p" => {
+            if let Some(name) = names.iter().find(|name| specs.get(name).is_none()) {
+                return Err(anyhow!("complete: {}: no completion specification", name));
+            }
+            Ok(Some(specs.listing(names).join("\n")).filter(|text| !text.is_empty()))
+        }
+        [flag] if flag == "-r" => {
+            specs.clear();
+            Ok(None)
+        }
+        [flag, names @ ..] if flag == "-r" => {
+            for name in names {
+                if !specs.remove(name) {
+                    return Err(anyhow!("complete: {}: no completion specification", name));
+                }
+            }
+            Ok(None)
+        }
+        [flag, words, names @ ..] if flag == "-W" && !names.is_empty() => {
+            let words: Vec<String> = words.split_whitespace().map(String::from).collect();
+            for name in names {
+                specs.set(name, CompletionSpec::Words(words.clone()));
+            }
+            Ok(None)
+        }
+        [flag, names @ ..] if (flag == "-d" || flag == "-f") && !names.is_empty() => {
+            let spec = if flag == "-d" {
+                CompletionSpec::Directories
+            } else {
+                CompletionSpec::Files
+            };
+            for name in names {
+                specs.set(name, spec.clone());
+            }
+            Ok(None)
+        }
+        _ => Err(anyhow!(
+            "complete: usage: complete [-p] [-r] [-d] [-f] [-W wordlist] [name ...]"
+        )),
+    }
+}
+
+/// The numbers from `first` to `last` in steps of `step`, which may be
+/// negative to count down. `step` must not be zero.
+fn seq(first: i64, step: i64, last: i64, equal_width: bool) -> impl Iterator<Item = String> {
+    let width = if equal_width {
+        first.to_string().len().max(last.to_string().len())
+    } else {
+        0
+    };
+    std::iter::successors(Some(first), move |number| number.checked_add(step))
+        .take_while(move |n| if step > 0 { *n <= last } else { *n >= last })
+        .map(move |number| {
+            // Pad after the sign, so -5 with width 3 is -05.
+            if number < 0 {
+                format!("-{:0>1$}", number.unsigned_abs(), width.saturating_sub(1))
+            } else {
+                format!("{:0>1$}", number, width)
+            }
+        })
+}
+
+/// Sets the process umask when `mask` is given and returns the mask now in
+/// effect. Files created by redirects pick it up through the OS.
+#[cfg(unix)]
+fn umask(mask: Option<u32>) -> u32 {
+    // umask(2) can only be read by setting it, so put the old value back.
+    // SAFETY: umask has no preconditions and cannot fail.
+    unsafe {
+        match mask {
+            Some(mask) => {
+                libc::umask(mask as libc::mode_t);
+                mask
+            }
+            None => {
+                let current = libc::umask(0);
+                libc::umask(current);
+                current as u32
+            }
+        }
+    }
+}
+
+/// Without a umask to set, the mask is only echoed back.
+#[cfg(not(unix))]
+fn umask(mask: Option<u32>) -> u32 {
+    mask.unwrap_or(0)
+}
+
+/// The flag behind a `set -o` option. `history` lives with the history
+/// itself; the rest are in `ShellOptions`.
+fn option_flag<'a>(state: &'a mut ShellState, name: &str) -> Option<&'a mut bool> {
+    match name {
+        "history" => Some(&mut state.history.enabled),
+        _ => state.options.flag_mut(name),
+    }
+}
+
+/// Every option name with its current state, sorted by name.
+fn option_states(state: &mut ShellState) -> Vec<(&'static str, bool)> {
+    let mut names: Vec<&'static str> = ShellOptions::NAMES.to_vec();
+    names.push("history");
+    names.sort_unstable();
+    names
+        .into_iter()
+        .filter_map(|name| Some((name, *option_flag(state, name)?)))
+        .collect()
+}
+
+/// Runs `set`. A bare `set -o` lists options as `name on|off` and `set +o`
+/// lists them as commands that restore the current settings.
+fn set_options(args: &[String], state: &mut ShellState) -> Result<Option<String>> {
+    if let [flag] = args
+        && (flag == "-o" || flag == "+o")
+    {
+        let lines: Vec<String> = option_states(state)
+            .into_iter()
+            .map(|(name, on)| match (flag.as_str(), on) {
+                ("-o", on) => format!("{:<15}\t{}", name, if on { "on" } else { "off" }),
+                (_, true) => format!("set -o {}", name),
+                (_, false) => format!("set +o {}", name),
+            })
+            .collect();
+        return Ok(Some(lines.join("\n")));
+    }
+
+    let mut iter = args.iter();
+    while let Some(flag) = iter.next() {
+        if flag == "--" {
+            // Everything after `--` replaces the positional parameters.
+            state.positional = iter.cloned().collect();
+            break;
+        }
+        let (enable, letters) = match flag.split_at_checked(1) {
+            Some(("-", letters)) if !letters.is_empty() => (true, letters),
+            Some(("+", letters)) if !letters.is_empty() => (false, letters),
+            _ => return Err(anyhow!("set: {}: invalid option", flag)),
+        };
+        if letters != "o" {
+            // Single-letter options such as `set -u`.
+            for letter in letters.chars() {
+                let option = ShellOptions::letter_name(letter)
+                    .and_then(|name| option_flag(state, name))
+                    .ok_or_else(|| anyhow!("set: {}{}: invalid option", &flag[..1], letter))?;
+                *option = enable;
+            }
+            continue;
+        }
+        let name = iter
+            .next()
+            .ok_or_else(|| anyhow!("set: {}: option name required", flag))?;
+        match option_flag(state, name) {
+            Some(option) => *option = enable,
+            None => return Err(anyhow!("set: {}: invalid option name", name)),
+        }
+    }
+    Ok(None)
+}
+
+/// `export -p` output: one `export NAME='value'` line per exported variable,
+/// quoted so that running the output restores them.
+fn export_listing(state: &ShellState) -> String {
+    state
+        .vars
+        .exported()
+        .into_iter()
+        .map(|(name, value)| match value {
+            Some(value) => format!("export {}={}", name, single_quote(value)),
+            None => format!("export {}", name),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn readonly_listing(state: &ShellState) -> String {
+    state
+        .vars
+        .readonly()
+        .into_iter()
+        .map(|(name, value)| match value {
+            Some(value) => format!("readonly {}={}", name, single_quote(value)),
+            None => format!("readonly {}", name),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The logical working directory: `$PWD` while it still names the current
+/// directory (possibly through symlinks), otherwise the physical path.
+pub(crate) fn logical_pwd(state: &ShellState) -> Result<PathBuf> {
+    let physical = env::current_dir()?;
+    if let Some(pwd) = state.vars.get("PWD").map(PathBuf::from)
+        && pwd.is_absolute()
+        && same_file(&pwd, &physical)
+    {
+        return Ok(pwd);
+    }
+    Ok(physical)
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Appends `target` to `base`, resolving `.` and `..` by editing the path
+/// rather than by following symlinks.
+fn normalize_logical(base: &Path, target: &Path) -> PathBuf {
+    let mut result = if target.is_absolute() {
+        PathBuf::new()
+    } else {
+        base.to_path_buf()
+    };
+    for component in target.components() {
+        match component {
+            Component::RootDir => result.push("/"),
+            Component::ParentDir => {
+                result.pop();
+            }
+            Component::Normal(name) => result.push(name),
+            Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    result
+}
+
+/// Changes directory and records the new `PWD` (and the previous one as
+/// `OLDPWD`). The logical path keeps symlinks as written unless `physical`.
+fn cd(path: &str, physical: bool, state: &mut ShellState) -> Result<()> {
+    let not_found = || anyhow!("cd: {}: No such file or directory", path);
+    let target = match path {
+        // No argument, or only whitespace, means `$HOME`, as in bash.
+        p if p.trim().is_empty() => match state.vars.get("HOME") {
+            Some(home) if !home.is_empty() => Some(PathBuf::from(string_to_os(home))),
+            _ => return Err(anyhow!("cd: HOME not set")),
+        },
+        "~" => dirs::home_dir(),
+        p if p.starts_with("~/") => dirs::home_dir().map(|home| home.join(&p[2..])),
+        p => Some(PathBuf::from(string_to_os(p))),
+    }
+    .ok_or_else(not_found)?;
+
+    let old = logical_pwd(state).ok();
+    let pwd = if physical {
+        env::set_current_dir(&target).map_err(|_| not_found())?;
+        fs::canonicalize(env::current_dir()?)?
+    } else {
+        let base = old.clone().unwrap_or_default();
+        let logical = normalize_logical(&base, &target);
+        env::set_current_dir(&logical).map_err(|_| not_found())?;
+        logical
+    };
+
+    if let Some(old) = old {
+        state.vars.set("OLDPWD", &old.display().to_string())?;
+    }
+    state.vars.set("PWD", &pwd.display().to_string())?;
+    Ok(())
+}
+
+/// Runs `pushd`: changes to `arg`, saving the current directory below it,
+/// or rotates entry `+N`/`-N` to the top and changes there.
+fn pushd(arg: Option<&str>, state: &mut ShellState) -> Result<()> {
+    let cwd = logical_pwd(state)?;
+    let mut entries = state.dir_stack.entries(&cwd);
+    match arg {
+        Some(dir) if !is_stack_index(dir) => {
+            cd(dir, false, state).map_err(|e| anyhow!("pushd: {}", strip_cd(&e)))?;
+            state.dir_stack.push(cwd);
+            return Ok(());
+        }
+        Some(spec) => {
+            let n = state
+                .dir_stack
+                .index(spec)
+                .ok_or_else(|| anyhow!("pushd: {}: directory stack index out of range", spec))?;
+            entries.rotate_left(n);
+        }
+        None if state.dir_stack.is_empty() => return Err(anyhow!("pushd: no other directory")),
+        None => entries.swap(0, 1),
+    }
+    change_to_entry(&entries[0], "pushd", state)?;
+    state.dir_stack.set_entries(entries);
+    Ok(())
+}
+
+/// Runs `popd`: drops the top entry and changes to the next, or drops
+/// entry `+N`/`-N` without changing directory.
+fn popd(arg: Option<&str>, state: &mut ShellState) -> Result<()> {
+    if state.dir_stack.is_empty() {
+        return Err(anyhow!("popd: directory stack empty"));
+    }
+    let cwd = logical_pwd(state)?;
+    let mut entries = state.dir_stack.entries(&cwd);
+    let n = match arg {
+        Some(spec) if is_stack_index(spec) => state
+            .dir_stack
+            .index(spec)
+            .ok_or_else(|| anyhow!("popd: {}: directory stack index out of range", spec))?,
+        Some(arg) => return Err(anyhow!("popd: {}: invalid argument", arg)),
+        None => 0,
+    };
+    entries.remove(n);
+    if n == 0 {
+        change_to_entry(&entries[0], "popd", state)?;
+    }
+    state.dir_stack.set_entries(entries);
+    Ok(())
+}
+
+fn change_to_entry(dir: &Path, builtin: &str, state: &mut ShellState) -> Result<()> {
+    cd(&os_to_string(dir.as_os_str()), false, state)
+        .map_err(|e| anyhow!("{}: {}", builtin, strip_cd(&e)))
+}
+
+/// A `cd` error message without its `cd: ` prefix, for reuse by `pushd`.
+fn strip_cd(error: &anyhow::Error) -> String {
+    let message = error.to_string();
+    message
+        .strip_prefix("cd: ")
+        .map(str::to_string)
+        .unwrap_or(message)
+}
+
+/// What `dirs` prints: the stack on one line, one entry per line, or
+/// numbered entries for `verbose`.
+fn dirs_listing(state: &ShellState, verbose: bool, per_line: bool, long: bool) -> Result<String> {
+    let cwd = logical_pwd(state)?;
+    let home = state.vars.get("HOME").map(Path::new);
+    let names = state.dir_stack.entries(&cwd).into_iter().map(|dir| {
+        if long {
+            dir.display().to_string()
+        } else {
+            abbreviate_home(&dir, home)
+        }
+    });
+    Ok(if verbose {
+        names
+            .enumerate()
+            .map(|(i, name)| format!("{:2}  {}", i, name))
+            .collect::<Vec<_>>()
+            .join("\n")
+    } else {
+        names
+            .collect::<Vec<_>>()
+            .join(if per_line { "\n" } else { " " })
+    })
+}
+
+fn exec_piped(
+    mut cmd: CmdCommand,
+    input: Option<PipeReader>,
+    stdout_output: Option<&mut dyn Output>,
+    stderr_output: &mut dyn Output,
+    spawner: &dyn Spawner,
+    job: &mut Job,
+) -> Result<Option<PipeReader>> {
+    let stdin_cfg = match input {
+        Some(reader) => Stdio::from(reader),
+        None => Stdio::inherit(),
+    };
+
+    // `2>&1` into the pipe to the next stage needs one pipe for both.
+    let mut merged = None;
+    if stdout_output.is_none() && stderr_output.follows_stdout() {
+        merged = Some(pipe()?);
+    }
+    // Redirects backed by a real file (or /dev/null) are handed to the child
+    // directly; anything else is piped back and forwarded line by line.
+    let (stdout_cfg, forward_stdout) = match &stdout_output {
+        None if let Some((_, writer)) = &merged => (Stdio::from(writer.try_clone()?), false),
+        None => (Stdio::piped(), false),
+        Some(out) if !out.is_redirected() => (Stdio::inherit(), false),
+        Some(out) => match out.as_stdio() {
+            Some(stdio) => (stdio, false),
+            None => (Stdio::piped(), true),
+        },
+    };
+
+    let (stderr_cfg, forward_stderr) = if let Some((_, writer)) = &merged {
+        (Stdio::from(writer.try_clone()?), false)
+    } else if !stderr_output.is_redirected() {
+        (Stdio::inherit(), false)
+    } else {
+        match stderr_output.as_stdio() {
+            Some(stdio) => (stdio, false),
+            None => (Stdio::piped(), true),
+        }
+    };
+
+    cmd.stdin(stdin_cfg).stdout(stdout_cfg).stderr(stderr_cfg);
+    job.prepare(&mut cmd);
+    // A program that vanished after lookup is 127, one that exists but
+    // can't be run (permissions, bad format) is 126, as in bash.
+    let spawned = spawner
+        .spawn(&mut cmd)
+        .map_err(|e| CommandError::CannotExecute {
+            command: cmd.get_program().to_string_lossy().to_string(),
+            message: io_message(&e),
+            status: if e.kind() == std::io::ErrorKind::NotFound {
+                127
+            } else {
+                126
+            },
+        })?;
+    let Spawned {
+        process,
+        stdout,
+        stderr,
+    } = spawned;
+    // The child is reaped with the rest of the pipeline in `handle_pipeline`.
+    job.add(process);
+
+    if let Some(out) = stdout_output {
+        match (
+            stdout.filter(|_| forward_stdout),
+            stderr.filter(|_| forward_stderr),
+        ) {
+            (Some(stdout), Some(stderr)) => forward_both(stdout, out, stderr, stderr_output),
+            (Some(stdout), None) => forward(stdout, out),
+            (None, Some(stderr)) => forward(stderr, stderr_output),
+            (None, None) => {}
+        }
+        return Ok(None);
+    }
+    if forward_stderr && let Some(stderr) = stderr {
+        forward(stderr, stderr_output);
+    }
+    if let Some((reader, _)) = merged {
+        // The shell's own copies of the writer are dropped on return, so
+        // the reader sees the end of the child's output.
+        Ok(Some(reader))
+    } else {
+        Ok(stdout.map(child_pipe))
+    }
+}
+
+/// Turns a child's piped stdout into a reader for the next stage.
+#[cfg(unix)]
+fn child_pipe(stdout: ChildStdout) -> PipeReader {
+    PipeReader::from(std::os::fd::OwnedFd::from(stdout))
+}
+
+#[cfg(windows)]
+fn child_pipe(stdout: ChildStdout) -> PipeReader {
+    PipeReader::from(std::os::windows::io::OwnedHandle::from(stdout))
+}
+
+/// The file names `executable` may have on disk. On Windows a name without
+/// a `PATHEXT` extension is tried with each of them (`git` -> `git.EXE`).
+fn executable_names(executable: &str) -> Vec<String> {
+    #[cfg(windows)]
+    {
+        let extensions = path_extensions(env::var("PATHEXT").ok().as_deref());
+        candidate_names(executable, &extensions)
+    }
+    #[cfg(not(windows))]
+    {
+        vec![executable.to_string()]
+    }
+}
+
+pub(crate) fn find_in_dirs(executable: &str, paths: &OsStr) -> Option<PathBuf> {
+    // An empty PATH would otherwise split into a single "" entry and resolve
+    // against the current directory.
+    if paths.is_empty() {
+        return None;
+    }
+    let names = executable_names(executable);
+    env::split_paths(paths).find_map(|dir| {
+        names
+            .iter()
+            .find_map(|name| resolve_executable(&dir.join(string_to_os(name))))
+    })
+}
+
+/// Like `find_in_dirs`, but returns every match in PATH order instead of the first one.
+fn find_all_in_dirs(executable: &str, paths: &OsStr) -> Vec<PathBuf> {
+    let mut found: Vec<PathBuf> = Vec::new();
+    if paths.is_empty() {
+        return found;
+    }
+    let names = executable_names(executable);
+    for path in env::split_paths(paths).flat_map(|dir| {
+        names
+            .iter()
+            .filter_map(|name| resolve_executable(&dir.join(string_to_os(name))))
+            .collect::<Vec<_>>()
+    }) {
+        // Symlinked PATH entries (e.g. /bin -> /usr/bin) canonicalize to the same file.
+        if !found.contains(&path) {
+            found.push(path);
+        }
+    }
+    found
+}
+
+/// Returns the canonical path of `full_path` if it is a regular file with the
+/// executable bit set. Symlinks are followed, so a link is judged by its target
+/// and a directory sharing the command's name is never a match.
+fn resolve_executable(full_path: &Path) -> Option<PathBuf> {
+    let metadata = fs::metadata(full_path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let target = fs::canonicalize(full_path).ok()?;
+    target.is_executable().then_some(target)
+}
+
+/// Feeds `text` and a newline to the next stage, as `print` would.
+fn pipe_string(text: String) -> Result<Option<PipeReader>> {
+    let mut bytes = text_bytes(&text);
+    bytes.push(b'\n');
+    pipe_bytes(bytes)
+}
+
+/// Feeds exactly `bytes` to the next stage from a separate thread, so a
+/// large output can't fill the pipe and block the shell.
+fn pipe_bytes(bytes: Vec<u8>) -> Result<Option<PipeReader>> {
+    let (reader, mut writer) = pipe()?;
+    thread::spawn(move || {
+        let _ = writer.write_all(&bytes);
+    });
+    Ok(Some(reader))
+}
+
+/// Copies a child's output to `output` line by line, keeping a missing final
+/// newline missing and invalid UTF-8 intact.
+fn forward(reader: impl std::io::Read, output: &mut dyn Output) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    while matches!(reader.read_until(b'\n', &mut line), Ok(read) if read > 0) {
+        output.write(&bytes_to_text(&line));
+        line.clear();
+    }
+}
+
+/// Copies a child's stdout and stderr to their outputs as lines arrive.
+/// Each pipe is read on its own thread, so a child that fills one while the
+/// shell is waiting on the other doesn't stall.
+fn forward_both(
+    stdout: ChildStdout,
+    out: &mut dyn Output,
+    stderr: ChildStderr,
+    err: &mut dyn Output,
+) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let read_lines = |reader: Box<dyn std::io::Read + Send>, is_stderr: bool| {
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let mut line = Vec::new();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(read) if read > 0 && sender.send((is_stderr, line)).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+    };
+    read_lines(Box::new(stdout), false);
+    read_lines(Box::new(stderr), true);
+    drop(sender);
+    for (is_stderr, line) in receiver {
+        let text = bytes_to_text(&line);
+        if is_stderr {
+            err.write(&text);
+        } else {
+            out.write(&text);
+        }
+    }
+}
+
+fn interpret_escape_sequences(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('r') => result.push('\r'),
+                Some('\\') => result.push('\\'),
+                Some('a') => result.push('\x07'),
+                Some('b') => result.push('\x08'),
+                Some('f') => result.push('\x0C'),
+                Some('v') => result.push('\x0B'),
+                Some('e') => result.push('\x1B'),
+                Some(other) => {
+                    result.push('\\');
+                    result.push(other);
+                }
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CaptureOutput;
+    use crate::parser::{parse_pipeline, tokenize};
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[cfg(unix)]
+    fn create_executable(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::canonicalize(path).unwrap()
+    }
+
+    #[test]
+    fn test_clear_writes_escape_sequence() {
+        let mut out = CaptureOutput::new();
+        let mut err = CaptureOutput::new();
+        execute_command(
+            Command::Clear,
+            None,
+            Some(&mut out),
+            &mut err,
+            &mut ShellState::new(),
+        )
+        .unwrap();
+        assert_eq!(out.contents(), "\x1b[2J\x1b[H");
+    }
+
+    #[test]
+    fn test_set_toggles_history() {
+        let mut state = ShellState::new();
+        set_options(&["+o".into(), "history".into()], &mut state).unwrap();
+        assert!(!state.history.should_record("echo hi"));
+
+        set_options(&["-o".into(), "history".into()], &mut state).unwrap();
+        assert!(state.history.should_record("echo hi"));
+
+        assert!(set_options(&["-o".into(), "bogus".into()], &mut state).is_err());
+    }
+
+    #[test]
+    fn test_history_file_defaults_to_histfile() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("history");
+        let mut state = ShellState::new();
+        state.vars.unset("HISTFILE").unwrap();
+        let stages = parse_pipeline(tokenize("history -w").unwrap(), &mut state).unwrap();
+        let (command, _) = stages.into_iter().next().unwrap();
+        let error = execute_command(command, None, None, &mut CaptureOutput::new(), &mut state);
+        assert_eq!(error.unwrap_err().to_string(), "history: HISTFILE not set");
+
+        state
+            .vars
+            .set("HISTFILE", &path.display().to_string())
+            .unwrap();
+        state.history.add_history_item("echo saved").unwrap();
+        run_line_captured("history -a", &mut state);
+        state.history.items.clear();
+        run_line_captured(&format!("history -r {}", path.display()), &mut state);
+        assert_eq!(state.history.items, ["echo saved"]);
+    }
+
+    #[test]
+    fn test_umask_sets_and_reports_octal() {
+        // The mask is process-wide, so put it back even if an assert fails.
+        struct RestoreUmask(u32);
+        impl Drop for RestoreUmask {
+            fn drop(&mut self) {
+                umask(Some(self.0));
+            }
+        }
+        let _restore = RestoreUmask(umask(None));
+        let mut state = ShellState::new();
+        let stages = parse_pipeline(tokenize("umask 027").unwrap(), &mut state).unwrap();
+        let Some((Command::Umask { mask }, _)) = stages.into_iter().next() else {
+            panic!("expected umask");
+        };
+        assert_eq!(mask, Some(0o027));
+        execute_command(
+            Command::Umask { mask },
+            None,
+            None,
+            &mut CaptureOutput::new(),
+            &mut state,
+        )
+        .unwrap();
+
+        let mut out = CaptureOutput::new();
+        execute_command(
+            Command::Umask { mask: None },
+            None,
+            Some(&mut out),
+            &mut CaptureOutput::new(),
+            &mut state,
+        )
+        .unwrap();
+        assert_eq!(out.contents(), "0027\n");
+    }
+
+    #[test]
+    fn test_set_o_toggles_and_lists_options() {
+        let mut state = ShellState::new();
+        let listing = set_options(&["-o".into()], &mut state).unwrap().unwrap();
+        assert!(listing.contains("noclobber      \toff"));
+        assert!(listing.contains("history        \ton"));
+
+        set_options(&["-o".into(), "noclobber".into()], &mut state).unwrap();
+        assert!(state.options.noclobber);
+        let listing = set_options(&["-o".into()], &mut state).unwrap().unwrap();
+        assert!(listing.contains("noclobber      \ton"));
+
+        set_options(&["+o".into(), "noclobber".into()], &mut state).unwrap();
+        assert_eq!(state.options, ShellOptions::new());
+        let restore = set_options(&["+o".into()], &mut state).unwrap().unwrap();
+        assert_eq!(
+            restore,
+            "set +o debugparse\nset +o failglob\nset -o history\nset +o mkdirredirect\nset +o noclobber\nset +o nounset\nset +o nullglob\nset +o posix\nset +o showpath\nset +o xpg_echo"
+        );
+    }
+
+    #[test]
+    fn test_set_u_toggles_nounset() {
+        let mut state = ShellState::new();
+        set_options(&["-u".into()], &mut state).unwrap();
+        assert!(state.options.nounset);
+        let Err(error) = parse_pipeline(tokenize("echo $MISSING").unwrap(), &mut state) else {
+            panic!("expanding an unset variable should fail under set -u");
+        };
+        assert_eq!(error.to_string(), "MISSING: unbound variable");
+
+        set_options(&["+u".into()], &mut state).unwrap();
+        assert!(!state.options.nounset);
+        assert_eq!(run_line_captured("echo $MISSING", &mut state), "\n");
+
+        let error = set_options(&["-q".into()], &mut state).unwrap_err();
+        assert_eq!(error.to_string(), "set: -q: invalid option");
+    }
+
+    /// Puts the process back in its original directory when a test that
+    /// changes it finishes, even by panicking. Holding it also keeps other
+    /// such tests from changing directory at the same time.
+    struct RestoreCwd {
+        dir: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl RestoreCwd {
+        fn new() -> Self {
+            static CWD_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+            let guard = CWD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            Self {
+                dir: env::current_dir().unwrap(),
+                _lock: guard,
+            }
+        }
+    }
+
+    impl Drop for RestoreCwd {
+        fn drop(&mut self) {
+            env::set_current_dir(&self.dir).ok();
+        }
+    }
+
+    #[cfg(unix)]
+    fn run_pwd(physical: bool, state: &mut ShellState) -> String {
+        let mut out = CaptureOutput::new();
+        execute_command(
+            Command::Pwd {
+                physical,
+                tilde: false,
+            },
+            None,
+            Some(&mut out),
+            &mut CaptureOutput::new(),
+            state,
+        )
+        .unwrap();
+        out.contents().trim_end().to_string()
+    }
+
+    #[test]
+    fn test_normalize_logical_keeps_symlinks() {
+        let base = Path::new("/home/user/link");
+        assert_eq!(
+            normalize_logical(base, Path::new("../other/./dir")),
+            PathBuf::from("/home/user/other/dir")
+        );
+        assert_eq!(
+            normalize_logical(base, Path::new("/tmp/../var")),
+            PathBuf::from("/var")
+        );
+    }
+
+    #[test]
+    fn test_cd_without_argument_goes_home() {
+        let _restore = RestoreCwd::new();
+        let dir = TempDir::new().unwrap();
+        let home = fs::canonicalize(dir.path()).unwrap();
+        let mut state = ShellState::new();
+        state.vars.set("HOME", home.to_str().unwrap()).unwrap();
+
+        for line in ["cd", "cd   ", "cd '  '"] {
+            cd("/", false, &mut state).unwrap();
+            run_line_captured(line, &mut state);
+            assert_eq!(env::current_dir().unwrap(), home, "{:?}", line);
+        }
+
+        state.vars.unset("HOME").unwrap();
+        let error = cd("", false, &mut state).unwrap_err();
+        assert_eq!(error.to_string(), "cd: HOME not set");
+        assert_eq!(env::current_dir().unwrap(), home);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cd_into_symlink_keeps_logical_pwd() {
+        let _restore = RestoreCwd::new();
+        let dir = TempDir::new().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir(root.join("real")).unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+        let mut state = ShellState::new();
+
+        let link = root.join("link");
+        cd(&link.display().to_string(), false, &mut state).unwrap();
+        assert_eq!(state.vars.get("PWD"), Some(link.to_str().unwrap()));
+        assert_eq!(run_pwd(false, &mut state), link.display().to_string());
+        assert_eq!(
+            run_pwd(true, &mut state),
+            root.join("real").display().to_string()
+        );
+
+        // `..` leaves the symlink the way it came in.
+        cd("..", false, &mut state).unwrap();
+        assert_eq!(run_pwd(false, &mut state), root.display().to_string());
+        assert_eq!(state.vars.get("OLDPWD"), Some(link.to_str().unwrap()));
+
+        cd("link", true, &mut state).unwrap();
+        assert_eq!(
+            run_pwd(false, &mut state),
+            root.join("real").display().to_string()
+        );
+    }
+
+    #[test]
+    fn test_pushd_rotates_and_dirs_clears() {
+        let _restore = RestoreCwd::new();
+        let dir = TempDir::new().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::create_dir(root.join(name)).unwrap();
+        }
+        let mut state = ShellState::new();
+        let path = |name: &str| root.join(name).display().to_string();
+        cd(&path("a"), false, &mut state).unwrap();
+        run_line_captured(&format!("pushd {}", path("b")), &mut state);
+        let listing = run_line_captured(&format!("pushd {}", path("c")), &mut state);
+        assert_eq!(
+            listing,
+            format!("{} {} {}\n", path("c"), path("b"), path("a"))
+        );
+
+        // `+N` counts from the top, `-N` from the bottom.
+        let listing = run_line_captured("pushd +2", &mut state);
+        assert_eq!(
+            listing,
+            format!("{} {} {}\n", path("a"), path("c"), path("b"))
+        );
+        assert_eq!(logical_pwd(&state).unwrap(), root.join("a"));
+        run_line_captured("pushd -0", &mut state);
+        assert_eq!(logical_pwd(&state).unwrap(), root.join("b"));
+        assert_eq!(
+            run_line_captured("dirs -v", &mut state),
+            format!(" 0  {}\n 1  {}\n 2  {}\n", path("b"), path("a"), path("c"))
+        );
+
+        let (command, _) = parse_command(tokenize("pushd +3").unwrap(), &mut state).unwrap();
+        let mut err = CaptureOutput::new();
+        let result = execute_command(command, None, None, &mut err, &mut state);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "pushd: +3: directory stack index out of range"
+        );
+        assert_eq!(logical_pwd(&state).unwrap(), root.join("b"));
+
+        run_line_captured("popd", &mut state);
+        assert_eq!(logical_pwd(&state).unwrap(), root.join("a"));
+        run_line_captured("dirs -c", &mut state);
+        assert_eq!(
+            run_line_captured("dirs -l", &mut state),
+            format!("{}\n", path("a"))
+        );
+        assert!(state.dir_stack.is_empty());
+    }
+
+    #[test]
+    fn test_pwd_tilde_abbreviates_home() {
+        let _restore = RestoreCwd::new();
+        let dir = TempDir::new().unwrap();
+        let home = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir(home.join("sub")).unwrap();
+        let mut state = ShellState::new();
+        state.vars.set("HOME", home.to_str().unwrap()).unwrap();
+
+        cd(home.to_str().unwrap(), false, &mut state).unwrap();
+        assert_eq!(run_line_captured("pwd -t", &mut state), "~\n");
+        cd("sub", false, &mut state).unwrap();
+        assert_eq!(run_line_captured("pwd -t", &mut state), "~/sub\n");
+        assert_eq!(
+            run_line_captured("pwd", &mut state),
+            format!("{}\n", home.join("sub").display())
+        );
+        state.vars.set("HOME", "/nonexistent").unwrap();
+        assert_eq!(
+            run_line_captured("pwd -t", &mut state),
+            format!("{}\n", home.join("sub").display())
+        );
+    }
+
+    fn run_line_captured(line: &str, state: &mut ShellState) -> String {
+        let mut out = CaptureOutput::new();
+        for (command, _) in parse_pipeline(tokenize(line).unwrap(), state).unwrap() {
+            execute_command(
+                command,
+                None,
+                Some(&mut out),
+                &mut CaptureOutput::new(),
+                state,
+            )
+            .unwrap();
+        }
+        out.contents()
+    }
+
+    #[test]
+    fn test_export_listing_round_trips_quotes() {
+        let mut state = ShellState::new();
+        state.vars = crate::Variables::new();
+        run_line_captured(r#"export QUOTED="it's a \"test\"" EMPTY="#, &mut state);
+        run_line_captured("export PENDING", &mut state);
+        let listing = run_line_captured("export -p", &mut state);
+        assert_eq!(
+            listing,
+            "export EMPTY=''\nexport PENDING\nexport QUOTED='it'\\''s a \"test\"'\n"
+        );
+
+        let mut restored = ShellState::new();
+        restored.vars = crate::Variables::new();
+        for line in listing.lines() {
+            run_line_captured(line, &mut restored);
+        }
+        assert_eq!(restored.vars.get("QUOTED"), Some("it's a \"test\""));
+        assert_eq!(run_line_captured("export", &mut restored), listing);
+    }
+
+    #[test]
+    fn test_readonly_rejects_assignment_and_unset() {
+        let mut state = ShellState::new();
+        state.vars = crate::Variables::new();
+        let run = |line: &str, state: &mut ShellState| {
+            let (command, _) = parse_pipeline(tokenize(line).unwrap(), state)
+                .unwrap()
+                .remove(0);
+            let mut err = CaptureOutput::new();
+            let result = execute_command(command, None, None, &mut err, state);
+            (result.map(|_| ()), err.contents())
+        };
+        run_line_captured("readonly FOO=bar", &mut state);
+        let (result, _) = run("FOO=baz", &mut state);
+        assert_eq!(result.unwrap_err().to_string(), "FOO: readonly variable");
+        assert_eq!(state.vars.get("FOO"), Some("bar"));
+
+        let (result, err) = run("unset FOO", &mut state);
+        assert!(result.is_err());
+        assert_eq!(err, "unset: FOO: cannot unset: readonly variable\n");
+        assert_eq!(state.vars.get("FOO"), Some("bar"));
+
+        run_line_captured("OTHER=1", &mut state);
+        run_line_captured("unset OTHER", &mut state);
+        assert_eq!(state.vars.get("OTHER"), None);
+
+        // Builtins that assign are refused too.
+        run_line_captured("readonly opt=z", &mut state);
+        let (result, _) = run("getopts a opt -a", &mut state);
+        assert_eq!(result.unwrap_err().to_string(), "opt: readonly variable");
+        assert_eq!(state.vars.get("opt"), Some("z"));
+
+        assert_eq!(
+            run_line_captured("readonly", &mut state),
+            "readonly FOO='bar'\nreadonly opt='z'\n"
+        );
+    }
+
+    #[test]
+    fn test_unset_flags_pick_functions_or_variables() {
+        let mut state = ShellState::new();
+        state.functions.define("both", "true");
+        state.functions.define("func", "true");
+        run_line_captured("both=1", &mut state);
+
+        run_line_captured("unset -f both", &mut state);
+        assert!(!state.functions.contains("both"));
+        assert_eq!(state.vars.get("both"), Some("1"));
+        run_line_captured("unset -n both", &mut state);
+        assert_eq!(state.vars.get("both"), None);
+
+        // A plain name that is not a variable is taken as a function.
+        run_line_captured("unset func", &mut state);
+        assert!(!state.functions.contains("func"));
+
+        let (command, _) = parse_pipeline(tokenize("unset -x name").unwrap(), &mut state)
+            .unwrap()
+            .remove(0);
+        let mut err = CaptureOutput::new();
+        let error = execute_command(command, None, None, &mut err, &mut state).unwrap_err();
+        assert_eq!(error_status(&error), 2);
+        assert!(err.contents().starts_with("unset: -x: invalid option\n"));
+    }
+
+    #[test]
+    fn test_exported_variable_reaches_child() {
+        let mut state = ShellState::new();
+        run_line_captured("export GREETING=hello", &mut state);
+        let out = CaptureOutput::new();
+        let mut stages =
+            parse_pipeline(tokenize("sh -c 'echo $GREETING'").unwrap(), &mut state).unwrap();
+        stages[0].1.stdout = Box::new(out.clone());
+        crate::handle_pipeline(stages, &mut state);
+        assert_eq!(out.contents(), "hello\n");
+    }
+
+    #[test]
+    fn test_showpath_reports_resolved_command() {
+        let mut state = ShellState::new();
+        let line = "sh -c 'echo ran'";
+        let run = |state: &mut ShellState| {
+            let (out, err) = (CaptureOutput::new(), CaptureOutput::new());
+            let mut stages = parse_pipeline(tokenize(line).unwrap(), state).unwrap();
+            stages[0].1 = crate::OutputStreams::new(Box::new(out.clone()), Box::new(err.clone()));
+            crate::handle_pipeline(stages, state);
+            (out.contents(), err.contents())
+        };
+        assert_eq!(run(&mut state), ("ran\n".to_string(), String::new()));
+
+        run_line_captured("set -o showpath", &mut state);
+        let sh = find_in_dirs("sh", &env::var_os("PATH").unwrap()).unwrap();
+        assert_eq!(
+            run(&mut state),
+            ("ran\n".to_string(), format!("sh -> {}\n", sh.display()))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_type_escapes_path_with_spaces() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let bin = dir.path().join("my tools");
+        fs::create_dir(&bin).unwrap();
+        let tool = bin.join("tool");
+        fs::write(&tool, "").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut state = ShellState::new();
+        state
+            .hash
+            .lookup_with("tool", bin.as_os_str(), find_in_dirs)
+            .unwrap();
+        let expected = format!("{}/my\\ tools/tool", dir.path().display());
+        assert_eq!(
+            describe_type("tool", false, &mut state),
+            Some(format!("tool is hashed ({})", expected))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_type_reports_relative_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _restore = RestoreCwd::new();
+        let dir = tempfile::TempDir::new().unwrap();
+        let script = dir.path().join("script.sh");
+        fs::write(&script, "").unwrap();
+        env::set_current_dir(dir.path()).unwrap();
+
+        let mut state = ShellState::new();
+        assert_eq!(describe_type("./script.sh", false, &mut state), None);
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            describe_type("./script.sh", false, &mut state),
+            Some("./script.sh is ./script.sh".to_string())
+        );
+        assert_eq!(describe_type("./missing", true, &mut state), None);
+    }
+
+    #[test]
+    fn test_set_double_dash_sets_positionals() {
+        let mut state = ShellState::new();
+        run_line_captured("set -- x y", &mut state);
+        assert_eq!(run_line_captured("echo $2", &mut state), "y\n");
+        assert_eq!(run_line_captured("echo $#", &mut state), "2\n");
+
+        run_line_captured("set --", &mut state);
+        assert_eq!(run_line_captured("echo $#", &mut state), "0\n");
+    }
+
+    #[test]
+    fn test_shift_renumbers_positionals() {
+        let mut state = ShellState::new();
+        run_line_captured("set -- a b c", &mut state);
+        run_line_captured("shift", &mut state);
+        assert_eq!(run_line_captured("echo $1", &mut state), "b\n");
+        assert_eq!(run_line_captured("echo $#", &mut state), "2\n");
+
+        // Shifting past the end fails and leaves the parameters alone.
+        crate::run_line("shift 3", &mut state);
+        assert_eq!(state.last_status, 1);
+        assert_eq!(run_line_captured("echo $#", &mut state), "2\n");
+    }
+
+    #[test]
+    fn test_complete_lists_specs() {
+        let mut state = ShellState::new();
+        assert_eq!(run_line_captured("complete", &mut state), "");
+        assert_eq!(run_line_captured("complete -p", &mut state), "");
+        run_line_captured("complete -W 'start stop' svc", &mut state);
+        run_line_captured("complete -d pushd", &mut state);
+        assert_eq!(
+            run_line_captured("complete", &mut state),
+            "complete -d pushd\ncomplete -W 'start stop' svc\n"
+        );
+        assert_eq!(
+            run_line_captured("complete -p svc", &mut state),
+            "complete -W 'start stop' svc\n"
+        );
+    }
+
+    #[test]
+    fn test_seq_ranges() {
+        let seq = |first, step, last, equal_width| -> Vec<String> {
+            seq(first, step, last, equal_width).collect()
+        };
+        assert_eq!(seq(1, 1, 5, false), ["1", "2", "3", "4", "5"]);
+        assert_eq!(seq(2, 3, 10, false), ["2", "5", "8"]);
+        assert_eq!(seq(5, -2, 0, false), ["5", "3", "1"]);
+        assert!(seq(5, 1, 1, false).is_empty());
+        assert_eq!(seq(8, 1, 10, true), ["08", "09", "10"]);
+        assert_eq!(seq(-10, 5, 0, true), ["-10", "-05", "000"]);
+        assert_eq!(seq(i64::MAX - 1, 1, i64::MAX, false).len(), 2);
+    }
+
+    #[test]
+    fn test_seq_streams_into_a_pipe() {
+        let mut state = ShellState::new();
+        for null_separated in [false, true] {
+            let command = Command::Seq {
+                first: 1,
+                step: 1,
+                last: i64::MAX,
+                equal_width: false,
+                null_separated,
+            };
+            let mut err = CaptureOutput::new();
+            let reader = execute_command(command, None, None, &mut err, &mut state)
+                .unwrap()
+                .unwrap();
+            let mut first = Vec::new();
+            BufReader::new(reader).read_until(b'2', &mut first).unwrap();
+            assert_eq!(first, if null_separated { b"1\x002" } else { b"1\n2" });
+        }
+    }
+
+    #[test]
+    fn test_seq_arguments() {
+        let mut state = ShellState::new();
+        assert_eq!(run_line_captured("seq 3", &mut state), "1\n2\n3\n");
+        assert_eq!(run_line_captured("seq -w 9 11", &mut state), "09\n10\n11\n");
+        assert_eq!(run_line_captured("seq 3 -1 1", &mut state), "3\n2\n1\n");
+        for (line, message) in [
+            ("seq 1 x", "seq: invalid argument: 'x'"),
+            ("seq 1 0 3", "seq: invalid zero increment value: '0'"),
+            ("seq", "seq: missing operand"),
+        ] {
+            let Err(error) = parse_pipeline(tokenize(line).unwrap(), &mut state) else {
+                panic!("{} should fail", line);
+            };
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_seq_null_separated_output() {
+        let mut state = ShellState::new();
+        assert_eq!(run_line_captured("seq -z 3", &mut state), "1\x002\x003\x00");
+        assert_eq!(
+            run_line_captured("seq -0 -w 9 10", &mut state).as_bytes(),
+            b"09\x0010\x00"
+        );
+
+        // Piped, the NULs reach the next command unchanged.
+        let out = CaptureOutput::new();
+        let mut stages = parse_pipeline(
+            tokenize("seq -z 2 | xargs -0 printf '<%s>'").unwrap(),
+            &mut state,
+        )
+        .unwrap();
+        stages[1].1.stdout = Box::new(out.clone());
+        crate::handle_pipeline(stages, &mut state);
+        assert_eq!(out.contents(), "<1><2>");
+    }
+
+    #[test]
+    fn test_repeat_runs_command_each_time() {
+        let mut state = ShellState::new();
+        run_line_captured("set -o showpath", &mut state);
+        let run = |line: &str, state: &mut ShellState| {
+            let err = CaptureOutput::new();
+            let mut stages = parse_pipeline(tokenize(line).unwrap(), state).unwrap();
+            stages[0].1 =
+                crate::OutputStreams::new(Box::new(CaptureOutput::new()), Box::new(err.clone()));
+            crate::handle_pipeline(stages, state);
+            err.contents()
+        };
+        let reports = run("repeat 3 true", &mut state);
+        assert_eq!(reports.lines().count(), 3);
+        assert!(reports.lines().all(|line| line.starts_with("true -> ")));
+        assert_eq!(state.last_status, 0);
+
+        run("repeat 2 false", &mut state);
+        assert_eq!(state.last_status, 1);
+    }
+
+    #[test]
+    fn test_repeat_output() {
+        let mut state = ShellState::new();
+        assert_eq!(
+            run_line_captured("repeat 3 echo hi", &mut state),
+            "hi\nhi\nhi\n"
+        );
+        // Mid-pipeline, every run feeds the next stage.
+        let out = CaptureOutput::new();
+        let mut stages =
+            parse_pipeline(tokenize("repeat 2 echo a | cat").unwrap(), &mut state).unwrap();
+        stages[1].1.stdout = Box::new(out.clone());
+        crate::handle_pipeline(stages, &mut state);
+        assert_eq!(out.contents(), "a\na\n");
+        assert_eq!(run_line_captured("repeat 0 echo hi", &mut state), "");
+        let Err(error) = parse_pipeline(tokenize("repeat x echo").unwrap(), &mut state) else {
+            panic!("a non-numeric count should fail");
+        };
+        assert_eq!(error.to_string(), "repeat: x: numeric argument required");
+    }
+
+    #[test]
+    fn test_getopts_walks_arguments() {
+        let mut state = ShellState::new();
+        let err = CaptureOutput::new();
+        let call = |line: &str, state: &mut ShellState| {
+            let (command, _) = parse_pipeline(tokenize(line).unwrap(), state)
+                .unwrap()
+                .remove(0);
+            let result = execute_command(command, None, None, &mut err.clone(), state);
+            result.map_or_else(|e| error_status(&e), |_| 0)
+        };
+        let line = "getopts ab:c opt -ac -bvalue -b next -x file";
+        let mut seen = Vec::new();
+        for _ in 0..6 {
+            let status = call(line, &mut state);
+            let vars = run_line_captured("echo $opt:${OPTARG-unset}:$OPTIND", &mut state);
+            seen.push(format!("{} {}", status, vars));
+        }
+        assert_eq!(
+            seen.concat(),
+            "0 a:unset:1\n0 c:unset:2\n0 b:value:3\n0 b:next:5\n0 ?:unset:6\n1 ?:unset:6\n"
+        );
+        assert_eq!(err.contents(), "getopts: illegal option -- x\n");
+
+        // Resetting OPTIND starts over; a leading `:` reports quietly.
+        run_line_captured("export OPTIND=1", &mut state);
+        assert_eq!(call("getopts :a opt -q", &mut state), 0);
+        assert_eq!(run_line_captured("echo $opt $OPTARG", &mut state), "? q\n");
+        run_line_captured("export OPTIND=1", &mut state);
+        assert_eq!(call("getopts :a: opt -a", &mut state), 0);
+        assert_eq!(run_line_captured("echo $opt $OPTARG", &mut state), ": a\n");
+        assert_eq!(err.contents(), "getopts: illegal option -- x\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_all_in_dirs_returns_every_match() {
+        let first = TempDir::new().unwrap();
+        let second = TempDir::new().unwrap();
+        let first_tool = create_executable(first.path(), "tool");
+        let second_tool = create_executable(second.path(), "tool");
+
+        let paths = env::join_paths([first.path(), second.path()]).unwrap();
+        assert_eq!(
+            find_all_in_dirs("tool", &paths),
+            vec![first_tool, second_tool]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_in_dirs_skips_directory_named_like_command() {
+        let shadow = TempDir::new().unwrap();
+        let bin = TempDir::new().unwrap();
+        let subdir = shadow.path().join("tool");
+        fs::create_dir(&subdir).unwrap();
+        fs::set_permissions(&subdir, fs::Permissions::from_mode(0o755)).unwrap();
+        let tool = create_executable(bin.path(), "tool");
+
+        let paths = env::join_paths([shadow.path(), bin.path()]).unwrap();
+        assert_eq!(find_in_dirs("tool", &paths), Some(tool.clone()));
+        assert_eq!(find_all_in_dirs("tool", &paths), vec![tool]);
+    }
+
+    #[test]
+    fn test_forwards_large_stdout_and_stderr_together() {
+        let mut state = ShellState::new();
+        let (command, _) = parse_pipeline(
+            tokenize("sh -c 'seq 1 30000; echo done >&2'").unwrap(),
+            &mut state,
+        )
+        .unwrap()
+        .remove(0);
+        let (mut out, mut err) = (CaptureOutput::new(), CaptureOutput::new());
+        execute_command(command, None, Some(&mut out), &mut err, &mut state).unwrap();
+        state.foreground.wait();
+        // Far more than a pipe buffer holds.
+        assert!(out.contents().len() > 64 * 1024);
+        assert!(out.contents().ends_with("\n30000\n"));
+        assert_eq!(err.contents(), "done\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_runs_command_with_non_utf8_names() {
+        use crate::os_text::os_to_string;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new().unwrap();
+        let tool_name = OsStr::from_bytes(b"t\xf6ol");
+        let tool = dir.path().join(tool_name);
+        fs::write(&tool, "#!/bin/sh\ncat \"$1\"\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let data = dir.path().join(OsStr::from_bytes(b"d\xe4ta"));
+        fs::write(&data, "bytes intact\n").unwrap();
+
+        let paths = env::join_paths([dir.path()]).unwrap();
+        let found = find_in_dirs(&os_to_string(tool_name), &paths);
+        assert_eq!(found, Some(fs::canonicalize(&tool).unwrap()));
+
+        let line = format!(
+            "{} {}",
+            os_to_string(tool.as_os_str()),
+            os_to_string(data.as_os_str())
+        );
+        let mut state = ShellState::new();
+        let out = CaptureOutput::new();
+        let mut stages = parse_pipeline(tokenize(&line).unwrap(), &mut state).unwrap();
+        stages[0].1.stdout = Box::new(out.clone());
+        crate::handle_pipeline(stages, &mut state);
+        assert_eq!(out.contents(), "bytes intact\n");
+    }
+
+    #[test]
+    fn test_find_in_dirs_only_directory_match() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("tool")).unwrap();
+
+        let paths = env::join_paths([dir.path()]).unwrap();
+        assert_eq!(find_in_dirs("tool", &paths), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_in_dirs_follows_symlink_to_executable() {
+        let target_dir = TempDir::new().unwrap();
+        let link_dir = TempDir::new().unwrap();
+        let tool = create_executable(target_dir.path(), "real-tool");
+        std::os::unix::fs::symlink(&tool, link_dir.path().join("tool")).unwrap();
+
+        let paths = env::join_paths([link_dir.path()]).unwrap();
+        assert_eq!(find_in_dirs("tool", &paths), Some(tool));
+    }
+
+    #[test]
+    fn test_find_in_dirs_skips_non_executable_file() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("tool"), "data").unwrap();
+
+        let paths = env::join_paths([dir.path()]).unwrap();
+        assert_eq!(find_in_dirs("tool", &paths), None);
+    }
+
+    #[test]
+    fn test_find_in_dirs_empty_path() {
+        assert_eq!(find_in_dirs("sh", OsStr::new("")), None);
+        assert!(find_all_in_dirs("sh", OsStr::new("")).is_empty());
+    }
+
+    #[test]
+    fn test_find_all_in_dirs_no_match() {
+        let dir = TempDir::new().unwrap();
+        let paths = env::join_paths([dir.path()]).unwrap();
+        assert!(find_all_in_dirs("tool", &paths).is_empty());
+    }
+}
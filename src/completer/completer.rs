@@ -1,19 +1,114 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
 use rustyline::{
     Helper,
     completion::{Completer, Pair},
     highlight::Highlighter,
     hint::Hinter,
-    validate::Validator,
+    validate::{ValidationContext, ValidationResult, Validator},
 };
 
+use crate::expand::single_quote;
+use crate::os_text::{display_lossy, os_to_string, string_to_os};
+use crate::parser::{is_word_separator, logical_lines, needs_continuation};
+
+/// What `complete` registered for a command's arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompletionSpec {
+    /// `complete -W`: words from a list.
+    Words(Vec<String>),
+    /// `complete -d`: directory names only.
+    Directories,
+    /// `complete -f`: file names. Directories stay candidates, ending in
+    /// `/`, so that a path can be completed one directory at a time.
+    Files,
+}
+
+/// Argument completions registered with the `complete` builtin, keyed by
+/// command name.
+#[derive(Debug, Default)]
+pub struct CompletionSpecs {
+    specs: BTreeMap<String, CompletionSpec>,
+}
+
+/// Completion specs shared between the shell state and the line editor.
+pub type SharedCompletionSpecs = Arc<Mutex<CompletionSpecs>>;
+
+impl CompletionSpecs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, command: &str, spec: CompletionSpec) {
+        self.specs.insert(command.to_string(), spec);
+    }
+
+    pub fn get(&self, command: &str) -> Option<&CompletionSpec> {
+        self.specs.get(command)
+    }
+
+    /// Removes the spec for `command`, returning whether there was one.
+    pub fn remove(&mut self, command: &str) -> bool {
+        self.specs.remove(command).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.specs.clear();
+    }
+
+    /// `complete` lines that recreate the specs for `names`, or for every
+    /// command when `names` is empty.
+    pub fn listing(&self, names: &[String]) -> Vec<String> {
+        self.specs
+            .iter()
+            .filter(|(command, _)| names.is_empty() || names.contains(command))
+            .map(|(command, spec)| match spec {
+                CompletionSpec::Words(words) => {
+                    format!("complete -W {} {}", single_quote(&words.join(" ")), command)
+                }
+                CompletionSpec::Directories => format!("complete -d {}", command),
+                CompletionSpec::Files => format!("complete -f {}", command),
+            })
+            .collect()
+    }
+}
+
+/// How many completions are listed before the editor asks first and pages
+/// through them.
+pub const DEFAULT_COMPLETION_LIMIT: usize = 100;
+
+/// The completion limit for a `COMPLETION_LIMIT` value; anything but a
+/// positive number gives the default.
+pub fn completion_limit(value: Option<&str>) -> usize {
+    value
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|&limit| limit > 0)
+        .unwrap_or(DEFAULT_COMPLETION_LIMIT)
+}
+
 pub struct ShellCompleter {
     commands: Vec<String>,
+    specs: SharedCompletionSpecs,
 }
 
 impl ShellCompleter {
     pub fn new(mut commands: Vec<String>) -> Self {
         commands.sort();
-        Self { commands }
+        Self {
+            commands,
+            specs: SharedCompletionSpecs::default(),
+        }
+    }
+
+    /// Consults `specs` when completing arguments, so specs registered with
+    /// `complete` later on are picked up.
+    pub fn with_specs(mut self, specs: SharedCompletionSpecs) -> Self {
+        self.specs = specs;
+        self
     }
 }
 
@@ -26,21 +121,179 @@ impl Completer for ShellCompleter {
         pos: usize,
         _ctx: &rustyline::Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
-        let word_start = line[..pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
-        let word = &line[word_start..pos];
+        let cwd = env::current_dir().unwrap_or_default();
+        Ok(self.complete_in(line, pos, &cwd))
+    }
+}
 
-        let matches: Vec<Pair> = self
-            .commands
-            .iter()
-            .filter(|cmd| cmd.starts_with(word))
-            .map(|cmd| Pair {
-                display: cmd.clone(),
-                replacement: format!("{} ", cmd),
+impl ShellCompleter {
+    fn complete_in(&self, line: &str, pos: usize, cwd: &Path) -> (usize, Vec<Pair>) {
+        let (word_start, word) = current_word(&line[..pos]);
+        let before = line[..word_start].trim_end_matches(is_word_separator);
+        let is_command_position = before.is_empty() || before.ends_with('|');
+
+        let matches = if is_command_position && !word.contains('/') {
+            complete_words(&self.commands, &word)
+        } else {
+            match self.argument_spec(before) {
+                Some(CompletionSpec::Words(words)) => complete_words(&words, &word),
+                spec => complete_path(&word, cwd, dirs::home_dir().as_deref(), spec.as_ref()),
+            }
+        };
+
+        (word_start, matches)
+    }
+
+    /// The spec registered for the command of the stage being completed.
+    fn argument_spec(&self, before: &str) -> Option<CompletionSpec> {
+        let stage = before.rsplit('|').next().unwrap_or_default();
+        let first = stage
+            .split(is_word_separator)
+            .find(|word| !word.is_empty())?;
+        let (_, command) = current_word(first);
+        let specs = self.specs.lock().ok()?;
+        specs.get(&command).cloned()
+    }
+}
+
+fn complete_words(words: &[String], prefix: &str) -> Vec<Pair> {
+    words
+        .iter()
+        .filter(|word| word.starts_with(prefix))
+        .map(|word| Pair {
+            display: display_lossy(word),
+            replacement: format!("{} ", escape_word(word)),
+        })
+        .collect()
+}
+
+/// Finds the word being completed, returning its byte offset in `line` and its
+/// text with quotes and backslash escapes removed.
+fn current_word(line: &str) -> (usize, String) {
+    let mut start = 0;
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = line.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => word.push(c),
+            None => match c {
+                c if is_word_separator(c) || c == '|' => {
+                    start = i + c.len_utf8();
+                    word.clear();
+                }
+                '\'' | '"' => quote = Some(c),
+                '\\' => {
+                    if let Some((_, next)) = chars.next() {
+                        word.push(next);
+                    }
+                }
+                _ => word.push(c),
+            },
+        }
+    }
+
+    (start, word)
+}
+
+/// Completes file names, keeping only directories when `spec` asks for
+/// that. A leading `~/` looks in `home` and stays in the completed word.
+fn complete_path(
+    word: &str,
+    cwd: &Path,
+    home: Option<&Path>,
+    spec: Option<&CompletionSpec>,
+) -> Vec<Pair> {
+    let (dir_part, file_prefix) = match word.rfind('/') {
+        Some(i) => word.split_at(i + 1),
+        None => ("", word),
+    };
+    let (tilde, dir) = match (dir_part.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => ("~/", home.join(string_to_os(rest))),
+        _ => match dir_part {
+            "" => ("", cwd.to_path_buf()),
+            d if d.starts_with('/') => ("", PathBuf::from(string_to_os(d))),
+            d => ("", cwd.join(string_to_os(d))),
+        },
+    };
+    let dir_part = &dir_part[tilde.len()..];
+
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    let mut matches: Vec<Pair> = entries
+        .flatten()
+        .filter_map(|entry| {
+            // Kept byte-exact so the completed word names the real file;
+            // only the displayed candidate is lossy.
+            let name = os_to_string(&entry.file_name());
+            if !name.starts_with(file_prefix)
+                || (name.starts_with('.') && !file_prefix.starts_with('.'))
+            {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            if spec == Some(&CompletionSpec::Directories) && !is_dir {
+                return None;
+            }
+            let suffix = if is_dir { "/" } else { " " };
+            Some(Pair {
+                display: if is_dir {
+                    format!("{}/", display_lossy(&name))
+                } else {
+                    display_lossy(&name)
+                },
+                replacement: format!(
+                    "{}{}{}",
+                    tilde,
+                    escape_word(&format!("{}{}", dir_part, name)),
+                    suffix
+                ),
             })
-            .collect();
+        })
+        .collect();
+    matches.sort_by(|a, b| a.display.cmp(&b.display));
+    matches
+}
 
-        Ok((word_start, matches))
+/// Backslash-escapes characters that `parse_prompt` would otherwise treat
+/// specially, so the result tokenizes back to `word`.
+fn escape_word(word: &str) -> String {
+    let mut escaped = String::with_capacity(word.len());
+    for c in word.chars() {
+        if is_word_separator(c)
+            || matches!(
+                c,
+                '\\' | '\''
+                    | '"'
+                    | '|'
+                    | '&'
+                    | ';'
+                    | '<'
+                    | '>'
+                    | '('
+                    | ')'
+                    | '$'
+                    | '`'
+                    | '*'
+                    | '?'
+                    | '['
+                    | ']'
+                    | '#'
+                    | '~'
+                    | '{'
+                    | '}'
+                    | '!'
+            )
+        {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
 }
 impl Hinter for ShellCompleter {
     type Hint = String;
@@ -50,5 +303,174 @@ impl Hinter for ShellCompleter {
     }
 }
 impl Highlighter for ShellCompleter {}
-impl Validator for ShellCompleter {}
+/// Whether the last logical line of `input` still needs more lines.
+fn input_incomplete(input: &str) -> bool {
+    logical_lines(input)
+        .last()
+        .is_some_and(|line| needs_continuation(line))
+}
+
+impl Validator for ShellCompleter {
+    /// Keeps reading while the input ends in a line continuation (`\`), an
+    /// open function body or an unfinished `case` statement. Only the last
+    /// logical line counts, so a pasted block of finished commands is run
+    /// as soon as it is entered.
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if input_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
 impl Helper for ShellCompleter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_prompt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_completion_limit_from_env_value() {
+        assert_eq!(completion_limit(Some("25")), 25);
+        assert_eq!(completion_limit(None), DEFAULT_COMPLETION_LIMIT);
+        assert_eq!(completion_limit(Some("0")), DEFAULT_COMPLETION_LIMIT);
+        assert_eq!(completion_limit(Some("lots")), DEFAULT_COMPLETION_LIMIT);
+    }
+
+    #[test]
+    fn test_pasted_block_waits_only_for_open_last_line() {
+        assert!(!input_incomplete("echo a\necho b"));
+        assert!(!input_incomplete("f() {\n  echo\n}\necho b"));
+        assert!(input_incomplete("echo a\nf() {"));
+        assert!(input_incomplete("echo a\necho b \\"));
+    }
+
+    #[test]
+    fn test_completes_commands_in_first_word() {
+        let completer = ShellCompleter::new(vec!["echo".into(), "exit".into()]);
+        let (start, matches) = completer.complete_in("ec", 2, Path::new("/"));
+        assert_eq!(start, 0);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].replacement, "echo ");
+    }
+
+    #[test]
+    fn test_completion_escapes_spaces_in_paths() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("my file.txt"), "").unwrap();
+        let completer = ShellCompleter::new(vec![]);
+
+        let line = "cat my";
+        let (start, matches) = completer.complete_in(line, line.len(), dir.path());
+        assert_eq!(start, 4);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].display, "my file.txt");
+        assert_eq!(matches[0].replacement, "my\\ file.txt ");
+
+        let completed = format!("{}{}", &line[..start], matches[0].replacement);
+        assert_eq!(parse_prompt(&completed), vec!["cat", "my file.txt"]);
+
+        // A tab separates words just like a space.
+        let line = "cat\tmy";
+        let (start, matches) = completer.complete_in(line, line.len(), dir.path());
+        assert_eq!((start, matches.len()), (4, 1));
+    }
+
+    #[test]
+    fn test_completion_continues_escaped_word() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("my dir")).unwrap();
+        let completer = ShellCompleter::new(vec![]);
+
+        let line = "cd my\\ d";
+        let (start, matches) = completer.complete_in(line, line.len(), dir.path());
+        assert_eq!(start, 3);
+        assert_eq!(matches[0].replacement, "my\\ dir/");
+    }
+
+    #[test]
+    fn test_wordlist_completes_command_arguments() {
+        let specs = SharedCompletionSpecs::default();
+        let words = ["start", "stop", "restart"].map(String::from).to_vec();
+        specs
+            .lock()
+            .unwrap()
+            .set("myservice", CompletionSpec::Words(words));
+        let completer = ShellCompleter::new(vec![]).with_specs(specs);
+
+        let line = "myservice st";
+        let (start, matches) = completer.complete_in(line, line.len(), Path::new("/"));
+        assert_eq!(start, 10);
+        let displays: Vec<&str> = matches.iter().map(|m| m.display.as_str()).collect();
+        assert_eq!(displays, vec!["start", "stop"]);
+        assert_eq!(matches[0].replacement, "start ");
+
+        // Other commands still complete paths.
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("state.txt"), "").unwrap();
+        let (_, matches) = completer.complete_in("cat st", 6, dir.path());
+        assert_eq!(matches[0].display, "state.txt");
+    }
+
+    #[test]
+    fn test_keeps_tilde_in_completed_path() {
+        let home = TempDir::new().unwrap();
+        fs::create_dir_all(home.path().join("Documents/notes")).unwrap();
+        let cwd = Path::new("/");
+
+        let matches = complete_path("~/Doc", cwd, Some(home.path()), None);
+        assert_eq!(matches[0].replacement, "~/Documents/");
+        let matches = complete_path("~/Documents/n", cwd, Some(home.path()), None);
+        assert_eq!(matches[0].replacement, "~/Documents/notes/");
+    }
+
+    #[test]
+    fn test_directory_spec_filters_out_files() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("setup.sh"), "").unwrap();
+        let specs = SharedCompletionSpecs::default();
+        specs.lock().unwrap().set("cd", CompletionSpec::Directories);
+        specs.lock().unwrap().set("run", CompletionSpec::Files);
+        let completer = ShellCompleter::new(vec![]).with_specs(specs);
+        let displays = |line: &str| -> Vec<String> {
+            let (_, matches) = completer.complete_in(line, line.len(), dir.path());
+            matches.into_iter().map(|m| m.display).collect()
+        };
+
+        assert_eq!(displays("cd s"), ["src/"]);
+        assert_eq!(displays("cat s"), ["setup.sh", "src/"]);
+
+        // Files still lead into directories, without a space after the `/`.
+        assert_eq!(displays("run s"), ["setup.sh", "src/"]);
+        let (_, matches) = completer.complete_in("run sr", 6, dir.path());
+        assert_eq!(matches[0].replacement, "src/");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_completes_non_utf8_file_name() {
+        use crate::expand::expand_word;
+        use crate::os_text::string_to_os;
+        use crate::parser::tokenize;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new().unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9 menu.txt");
+        fs::write(dir.path().join(name), "").unwrap();
+        let completer = ShellCompleter::new(vec![]);
+
+        let line = "cat caf";
+        let (start, matches) = completer.complete_in(line, line.len(), dir.path());
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].display, "caf\u{fffd} menu.txt");
+
+        // The completed word expands back to the exact bytes on disk.
+        let completed = format!("{}{}", &line[..start], matches[0].replacement);
+        let tokens = tokenize(&completed).unwrap();
+        let expanded = expand_word(&tokens[1], &mut crate::ShellState::new()).unwrap();
+        assert_eq!(expanded.len(), 1);
+        assert_eq!(string_to_os(&expanded[0]), name);
+    }
+}
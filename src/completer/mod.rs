@@ -1,2 +1,5 @@
-pub use completer::ShellCompleter;
+pub use completer::{
+    CompletionSpec, CompletionSpecs, DEFAULT_COMPLETION_LIMIT, SharedCompletionSpecs,
+    ShellCompleter, completion_limit,
+};
 mod completer;
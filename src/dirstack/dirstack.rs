@@ -0,0 +1,107 @@
+use std::path::{Path, PathBuf};
+
+/// The directory stack of `pushd`, `popd` and `dirs`. Entry 0, the top, is
+/// always the current directory, so only the entries below it are kept.
+#[derive(Debug, Default)]
+pub struct DirStack {
+    below: Vec<PathBuf>,
+}
+
+impl DirStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the stack holds nothing but the current directory.
+    pub fn is_empty(&self) -> bool {
+        self.below.is_empty()
+    }
+
+    /// `dirs -c`: drops every entry but the current directory.
+    pub fn clear(&mut self) {
+        self.below.clear();
+    }
+
+    /// Puts `dir` right below the top, as `pushd dir` does with the
+    /// directory it leaves.
+    pub fn push(&mut self, dir: PathBuf) {
+        self.below.insert(0, dir);
+    }
+
+    /// The whole stack, with `top` (the current directory) as entry 0.
+    pub fn entries(&self, top: &Path) -> Vec<PathBuf> {
+        let mut entries = vec![top.to_path_buf()];
+        entries.extend(self.below.iter().cloned());
+        entries
+    }
+
+    /// Replaces the stack with `entries`; the first one is the new top,
+    /// which the caller has already changed into.
+    pub fn set_entries(&mut self, mut entries: Vec<PathBuf>) {
+        if !entries.is_empty() {
+            entries.remove(0);
+        }
+        self.below = entries;
+    }
+
+    /// Resolves `+N`, counting from the top at 0, or `-N`, counting from
+    /// the bottom, to an entry index. `None` when out of range.
+    pub fn index(&self, spec: &str) -> Option<usize> {
+        let len = self.below.len() + 1;
+        let n: usize = spec.get(1..)?.parse().ok()?;
+        match spec.chars().next()? {
+            '+' if n < len => Some(n),
+            '-' if n < len => Some(len - 1 - n),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `arg` is an entry spec like `+2` or `-0` rather than a directory.
+pub fn is_stack_index(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with(['+', '-']) && arg[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(dirs: &[&str]) -> DirStack {
+        let mut stack = DirStack::new();
+        for dir in dirs.iter().rev() {
+            stack.push(PathBuf::from(dir));
+        }
+        stack
+    }
+
+    #[test]
+    fn test_index_counts_from_either_end() {
+        // Entries: /top /a /b /c
+        let stack = stack(&["/a", "/b", "/c"]);
+        assert_eq!(stack.index("+0"), Some(0));
+        assert_eq!(stack.index("+3"), Some(3));
+        assert_eq!(stack.index("-0"), Some(3));
+        assert_eq!(stack.index("-3"), Some(0));
+        assert_eq!(stack.index("+4"), None);
+        assert_eq!(stack.index("-4"), None);
+        assert!(is_stack_index("+12") && is_stack_index("-0"));
+        assert!(!is_stack_index("-") && !is_stack_index("+x") && !is_stack_index("dir"));
+    }
+
+    #[test]
+    fn test_rotation_and_clear() {
+        let mut stack = stack(&["/a", "/b", "/c"]);
+        let mut entries = stack.entries(Path::new("/top"));
+        entries.rotate_left(stack.index("+2").unwrap());
+        assert_eq!(entries, ["/b", "/c", "/top", "/a"].map(PathBuf::from));
+        stack.set_entries(entries);
+        assert_eq!(
+            stack.entries(Path::new("/b")),
+            ["/b", "/c", "/top", "/a"].map(PathBuf::from)
+        );
+
+        stack.clear();
+        assert!(stack.is_empty());
+        assert_eq!(stack.entries(Path::new("/b")), [PathBuf::from("/b")]);
+    }
+}
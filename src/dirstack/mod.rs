@@ -0,0 +1,2 @@
+pub use dirstack::*;
+pub mod dirstack;
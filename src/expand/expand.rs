@@ -0,0 +1,1281 @@
+use anyhow::{Result, anyhow};
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::glob::{Pattern, glob_paths};
+use crate::parser::is_valid_name;
+use crate::{ShellState, Value, process_substitution};
+
+const DEFAULT_IFS: &str = " \t\n";
+
+/// Expands a raw word (as produced by `tokenize`) into zero or more fields.
+///
+/// Variables are substituted, the results of unquoted substitutions are split
+/// on `IFS`, fields with unquoted wildcards are matched against the
+/// filesystem, and quotes and escapes are removed.
+pub fn expand_word(word: &str, state: &mut ShellState) -> Result<Vec<String>> {
+    let mut expander = WordExpander::new(Some(state));
+    expander.expand(word)?;
+    expander.finish_globbed()
+}
+
+/// Expands a raw word into a single string, without globbing, as for the
+/// word of a `case` statement.
+pub fn expand_text(word: &str, state: &mut ShellState) -> Result<String> {
+    WordExpander::new(Some(state)).expand_text(word)
+}
+
+/// Expands the raw value of a `name=value` assignment into a single string.
+/// As in bash, an unquoted `~` at the start of the value or after a `:`
+/// becomes `$HOME`, so `PATH=~/bin:~/tools` names two home directories.
+pub fn expand_assignment(value: &str, state: &mut ShellState) -> Result<String> {
+    let home = match state.vars.get("HOME") {
+        Some(home) => Some(home.to_string()),
+        None => dirs::home_dir().map(|home| home.display().to_string()),
+    };
+    let Some(home) = home else {
+        return expand_text(value, state);
+    };
+    let mut raw = String::new();
+    let mut quote = None;
+    let mut chars = value.chars().peekable();
+    let mut at_start = true;
+    while let Some(c) = chars.next() {
+        if at_start && c == '~' && chars.peek().is_none_or(|next| matches!(next, '/' | ':')) {
+            raw.push_str(&single_quote(&home));
+            at_start = false;
+            continue;
+        }
+        raw.push(c);
+        match (quote, c) {
+            (None, '\\') | (Some('"'), '\\') => raw.extend(chars.next()),
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), _) if open == c => quote = None,
+            _ => {}
+        }
+        at_start = quote.is_none() && c == ':';
+    }
+    expand_text(&raw, state)
+}
+
+/// Expands a raw word into a glob pattern in which quoted wildcards match
+/// themselves.
+pub fn expand_pattern(word: &str, state: &mut ShellState) -> Result<String> {
+    WordExpander::new(Some(state)).expand_pattern(word)
+}
+
+/// Removes quotes and escapes from a raw word without expanding anything.
+pub fn unquote(word: &str) -> String {
+    let mut expander = WordExpander::new(None);
+    // Without a state there is nothing to substitute, so this cannot fail.
+    let _ = expander.expand(word);
+    expander.finish().concat()
+}
+
+/// Quotes `text` in single quotes so that the shell reads it back unchanged,
+/// writing embedded single quotes as `'\''` like bash.
+pub fn single_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// The result of a single `$` substitution.
+enum Expansion {
+    Text(String),
+    /// Separate words, as produced by `${arr[@]}`.
+    Items(Vec<String>),
+}
+
+/// Strips the shortest (or longest) prefix of `value` matching `pattern`,
+/// leaving `value` unchanged when nothing matches.
+fn remove_prefix<'a>(value: &'a str, pattern: &Pattern, longest: bool) -> &'a str {
+    let mut ends: Vec<usize> = value.char_indices().map(|(i, _)| i).collect();
+    ends.push(value.len());
+    if longest {
+        ends.reverse();
+    }
+    ends.into_iter()
+        .find(|&end| pattern.matches(&value[..end]))
+        .map_or(value, |end| &value[end..])
+}
+
+/// Strips the shortest (or longest) suffix of `value` matching `pattern`,
+/// leaving `value` unchanged when nothing matches.
+fn remove_suffix<'a>(value: &'a str, pattern: &Pattern, longest: bool) -> &'a str {
+    let mut starts: Vec<usize> = value.char_indices().map(|(i, _)| i).collect();
+    starts.push(value.len());
+    if !longest {
+        starts.reverse();
+    }
+    starts
+        .into_iter()
+        .find(|&start| pattern.matches(&value[start..]))
+        .map_or(value, |start| &value[..start])
+}
+
+/// Applies `${name^pattern}` and its kin to `value`: every char, or only
+/// the first when `all` is false, that matches `pattern` is upper- or
+/// lowercased. A missing pattern matches any char.
+fn modify_case(value: &str, pattern: Option<&Pattern>, upper: bool, all: bool) -> String {
+    let mut text = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let selected = (all || i == 0)
+            && pattern.is_none_or(|pattern| pattern.matches(c.encode_utf8(&mut [0; 4])));
+        if !selected {
+            text.push(c);
+        } else if upper {
+            text.extend(c.to_uppercase());
+        } else {
+            text.extend(c.to_lowercase());
+        }
+    }
+    text
+}
+
+/// Applies `offset[:length]` from `${name:offset:length}` to `value`.
+///
+/// Offsets and lengths count chars, not bytes, so slicing never splits a
+/// multi-byte character. A negative offset counts back from the end (bash
+/// needs a space or parentheses to tell it from `:-`), and a negative length
+/// marks the end relative to the end of the value. Out-of-range offsets give
+/// an empty string.
+fn substring(value: &str, range: &str) -> Option<String> {
+    let (offset, length) = match range.split_once(':') {
+        Some((offset, length)) => (offset, Some(length)),
+        None => (range, None),
+    };
+    let count = value.chars().count() as i64;
+    let offset = parse_offset(offset)?;
+    let start = if offset < 0 { count + offset } else { offset };
+    if start < 0 || start > count {
+        return Some(String::new());
+    }
+    let end = match length {
+        None => count,
+        Some(length) => {
+            let length = parse_offset(length)?;
+            if length < 0 {
+                count + length
+            } else {
+                (start + length).min(count)
+            }
+        }
+    };
+    if end < start {
+        return None;
+    }
+    Some(
+        value
+            .chars()
+            .skip(start as usize)
+            .take((end - start) as usize)
+            .collect(),
+    )
+}
+
+/// Parses a substring offset or length, allowing surrounding spaces and
+/// parentheses such as `${x: -2}` or `${x:(-2)}`. An empty string is zero.
+fn parse_offset(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+        .unwrap_or(text)
+        .trim();
+    if text.is_empty() {
+        return Some(0);
+    }
+    text.parse().ok()
+}
+
+/// Length of the parameter name (with any `[subscript]`) at the start of the
+/// text inside `${...}`.
+fn parameter_len(content: &str) -> usize {
+    let name_len = content
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(content.len());
+    if content[name_len..].starts_with('[')
+        && let Some(close) = content[name_len..].find(']')
+    {
+        return name_len + close + 1;
+    }
+    name_len
+}
+
+/// Reads the command of a `<(command)` word up to its closing `)`, which
+/// is consumed.
+fn enclosed_command(chars: &mut Peekable<Chars>) -> Result<String> {
+    let mut command = String::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    for c in chars.by_ref() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') if depth == 0 => return Ok(command),
+            (None, ')') => depth -= 1,
+            _ => {}
+        }
+        command.push(c);
+    }
+    Err(anyhow!("<({}: missing `)'", command))
+}
+
+/// Splits `name[subscript]` into its parts.
+fn split_subscript(parameter: &str) -> Option<(&str, &str)> {
+    parameter
+        .strip_suffix(']')
+        .and_then(|rest| rest.split_once('['))
+}
+
+struct WordExpander<'a> {
+    state: Option<&'a mut ShellState>,
+    fields: Vec<String>,
+    /// Each field as a glob pattern, with quoted wildcards escaped.
+    patterns: Vec<String>,
+    current: String,
+    pattern: String,
+    // Distinguishes an empty quoted word ("") from no word at all.
+    has_current: bool,
+}
+
+impl<'a> WordExpander<'a> {
+    fn new(state: Option<&'a mut ShellState>) -> Self {
+        Self {
+            state,
+            fields: Vec::new(),
+            patterns: Vec::new(),
+            current: String::new(),
+            pattern: String::new(),
+            has_current: false,
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if self.has_current {
+            self.end_field();
+        }
+        self.fields
+    }
+
+    /// Like `finish`, but returns each field as a glob pattern.
+    fn finish_patterns(mut self) -> Vec<String> {
+        if self.has_current {
+            self.end_field();
+        }
+        self.patterns
+    }
+
+    /// Finishes the word, replacing fields with unquoted wildcards by the
+    /// paths they match. What happens without a match follows `nullglob` and
+    /// `failglob`.
+    fn finish_globbed(mut self) -> Result<Vec<String>> {
+        if self.has_current {
+            self.end_field();
+        }
+        let (nullglob, failglob) = self.state.as_deref().map_or((false, false), |state| {
+            (state.options.nullglob, state.options.failglob)
+        });
+
+        let mut fields = Vec::new();
+        for (field, pattern) in self.fields.into_iter().zip(self.patterns) {
+            if !Pattern::new(&pattern).has_wildcards() {
+                fields.push(field);
+                continue;
+            }
+            let matches = glob_paths(&pattern);
+            if !matches.is_empty() {
+                fields.extend(matches);
+            } else if failglob {
+                return Err(anyhow!("no match: {}", field));
+            } else if !nullglob {
+                fields.push(field);
+            }
+        }
+        Ok(fields)
+    }
+
+    /// Adds an unquoted character, which keeps any glob meaning it has.
+    fn push(&mut self, c: char) {
+        self.current.push(c);
+        self.pattern.push(c);
+        self.has_current = true;
+    }
+
+    /// Adds a quoted character, which always matches itself in a glob.
+    fn push_quoted(&mut self, c: char) {
+        self.current.push(c);
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(c);
+    }
+
+    fn push_quoted_str(&mut self, text: &str) {
+        for c in text.chars() {
+            self.push_quoted(c);
+        }
+    }
+
+    fn end_field(&mut self) {
+        self.fields.push(std::mem::take(&mut self.current));
+        self.patterns.push(std::mem::take(&mut self.pattern));
+        self.has_current = false;
+    }
+
+    fn expand(&mut self, word: &str) -> Result<()> {
+        let mut chars = word.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => {
+                    self.has_current = true;
+                    for c in chars.by_ref() {
+                        if c == '\'' {
+                            break;
+                        }
+                        self.push_quoted(c);
+                    }
+                }
+                '"' => {
+                    self.has_current = true;
+                    self.expand_double_quoted(&mut chars)?;
+                }
+                '\\' => {
+                    if let Some(next) = chars.next() {
+                        self.has_current = true;
+                        self.push_quoted(next);
+                    }
+                }
+                '$' => match self.parameter(&mut chars)? {
+                    Some(Expansion::Text(value)) => self.push_split(&value),
+                    Some(Expansion::Items(items)) => {
+                        for (i, item) in items.iter().enumerate() {
+                            if i > 0 && self.has_current {
+                                self.end_field();
+                            }
+                            self.push_split(item);
+                        }
+                    }
+                    None => self.push('$'),
+                },
+                '<' if chars.peek() == Some(&'(') && self.state.is_some() => {
+                    chars.next();
+                    let command = enclosed_command(&mut chars)?;
+                    let path = match self.state.as_deref_mut() {
+                        Some(state) => process_substitution(&command, state)?,
+                        None => String::new(),
+                    };
+                    self.has_current = true;
+                    self.push_quoted_str(&path);
+                }
+                _ => self.push(c),
+            }
+        }
+        Ok(())
+    }
+
+    fn expand_double_quoted(&mut self, chars: &mut Peekable<Chars>) -> Result<()> {
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                // The tokenizer keeps escapes raw, so `\$` arrives here intact and
+                // is emitted as a literal `$` rather than starting an expansion.
+                '\\' => match chars.peek() {
+                    Some(&next) if matches!(next, '\\' | '"' | '$' | '`' | '\n') => {
+                        chars.next();
+                        if next != '\n' {
+                            self.push_quoted(next);
+                        }
+                    }
+                    _ => self.push_quoted('\\'),
+                },
+                '$' => match self.parameter(chars)? {
+                    Some(Expansion::Text(value)) => self.push_quoted_str(&value),
+                    // "${arr[@]}" keeps each element as its own field.
+                    Some(Expansion::Items(items)) => {
+                        for (i, item) in items.iter().enumerate() {
+                            if i > 0 {
+                                self.end_field();
+                                self.has_current = true;
+                            }
+                            self.push_quoted_str(item);
+                        }
+                    }
+                    None => self.push_quoted('$'),
+                },
+                _ => self.push_quoted(c),
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the parameter following a `$` and returns its value, or `None`
+    /// when the `$` does not start an expansion and should stay literal.
+    fn parameter(&mut self, chars: &mut Peekable<Chars>) -> Result<Option<Expansion>> {
+        if self.state.is_none() {
+            return Ok(None);
+        }
+
+        let name = match chars.peek() {
+            Some('{') => {
+                chars.next();
+                let mut content = String::new();
+                let mut depth = 0;
+                let mut quote: Option<char> = None;
+                loop {
+                    let Some(c) = chars.next() else {
+                        return Err(anyhow!("${{{}: bad substitution", content));
+                    };
+                    match (quote, c) {
+                        (Some(q), c) if c == q => quote = None,
+                        (Some(_), _) => {}
+                        (None, '\'' | '"') => quote = Some(c),
+                        (None, '{') => depth += 1,
+                        (None, '}') if depth == 0 => break,
+                        (None, '}') => depth -= 1,
+                        _ => {}
+                    }
+                    content.push(c);
+                }
+                return self.braced(&content).map(Some);
+            }
+            Some(&c) if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                name
+            }
+            // Positional parameters past `$9` need braces: `${10}`.
+            Some(&c) if c.is_ascii_digit() => {
+                chars.next();
+                c.to_string()
+            }
+            Some('@') => {
+                chars.next();
+                return Ok(Some(Expansion::Items(self.positional())));
+            }
+            Some(&c @ ('!' | '?' | '#' | '*')) => {
+                chars.next();
+                return Ok(Some(Expansion::Text(self.special(c))));
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(Expansion::Text(self.required(&name)?)))
+    }
+
+    /// The value of a special parameter: `$!`, `$?`, `$#` or `$*`.
+    fn special(&self, name: char) -> String {
+        let Some(state) = self.state.as_deref() else {
+            return String::new();
+        };
+        match name {
+            '!' => state
+                .last_background_pid
+                .map(|pid| pid.to_string())
+                .unwrap_or_default(),
+            '?' => state.last_status.to_string(),
+            '#' => state.positional.len().to_string(),
+            '*' => self.join_fields(&state.positional),
+            _ => String::new(),
+        }
+    }
+
+    /// The positional parameters, for `$@`.
+    fn positional(&self) -> Vec<String> {
+        self.state
+            .as_deref()
+            .map(|state| state.positional.clone())
+            .unwrap_or_default()
+    }
+
+    /// Evaluates the text inside `${...}`.
+    fn braced(&mut self, content: &str) -> Result<Expansion> {
+        if let Some(c @ ('!' | '?' | '#' | '*')) = content.chars().next()
+            && content.len() == 1
+        {
+            return Ok(Expansion::Text(self.special(c)));
+        }
+        if content == "@" {
+            return Ok(Expansion::Items(self.positional()));
+        }
+        if let Some(reference) = content.strip_prefix('!') {
+            // `${!name}` expands the parameter that `name` holds the name of.
+            let (name, operation) = reference.split_at(parameter_len(reference));
+            if name.is_empty() {
+                return Err(anyhow!("${{{}}}: bad substitution", content));
+            }
+            let target = self.required(name)?;
+            if target.is_empty() {
+                return Ok(Expansion::Text(String::new()));
+            }
+            if parameter_len(&target) != target.len() {
+                return Err(anyhow!("{}: invalid indirect expansion", target));
+            }
+            return self.braced(&format!("{}{}", target, operation));
+        }
+        if let Some(parameter) = content.strip_prefix('#')
+            && let Some((name, "@" | "*")) = split_subscript(parameter)
+        {
+            let count = self.items(name).len();
+            return Ok(Expansion::Text(count.to_string()));
+        }
+        if let Some(parameter) = content.strip_prefix('#')
+            && !parameter.is_empty()
+            && parameter_len(parameter) == parameter.len()
+        {
+            // Lengths count chars rather than bytes, so multi-byte text
+            // measures the way it reads.
+            let length = self.required(parameter)?.chars().count();
+            return Ok(Expansion::Text(length.to_string()));
+        }
+
+        let (parameter, operation) = content.split_at(parameter_len(content));
+        if parameter.is_empty() {
+            return Err(anyhow!("${{{}}}: bad substitution", content));
+        }
+        if operation.is_empty() {
+            return match split_subscript(parameter) {
+                Some((name, "@")) => Ok(Expansion::Items(self.items(name))),
+                _ => Ok(Expansion::Text(self.required(parameter)?)),
+            };
+        }
+
+        let value = self.value(parameter)?;
+        if let Some(side @ ('#' | '%')) = operation.chars().next() {
+            let longest = operation[1..].starts_with(side);
+            let pattern = if longest {
+                &operation[2..]
+            } else {
+                &operation[1..]
+            };
+            let pattern = Pattern::new(&self.expand_pattern(pattern)?);
+            let value = self.required(parameter)?;
+            let text = if side == '#' {
+                remove_prefix(&value, &pattern, longest)
+            } else {
+                remove_suffix(&value, &pattern, longest)
+            };
+            return Ok(Expansion::Text(text.to_string()));
+        }
+        if let Some(side @ ('^' | ',')) = operation.chars().next() {
+            let all = operation[1..].starts_with(side);
+            let pattern = if all {
+                &operation[2..]
+            } else {
+                &operation[1..]
+            };
+            let pattern = if pattern.is_empty() {
+                None
+            } else {
+                Some(Pattern::new(&self.expand_pattern(pattern)?))
+            };
+            let value = self.required(parameter)?;
+            let text = modify_case(&value, pattern.as_ref(), side == '^', all);
+            return Ok(Expansion::Text(text));
+        }
+        if let Some(range) = operation.strip_prefix(':')
+            && !range.starts_with(['-', '=', '+', '?'])
+        {
+            let text = substring(&self.required(parameter)?, range)
+                .ok_or_else(|| anyhow!("{}: bad substitution", range.trim()))?;
+            return Ok(Expansion::Text(text));
+        }
+        // The `:` forms treat an empty value the same as an unset one.
+        let (check_null, operation) = match operation.strip_prefix(':') {
+            Some(rest) if rest.starts_with(['-', '=', '+', '?']) => (true, rest),
+            _ => (false, operation),
+        };
+        let is_set = value
+            .as_ref()
+            .is_some_and(|value| !(check_null && value.is_empty()));
+        let word = &operation[1..];
+
+        let text = match operation.chars().next() {
+            Some('-') if !is_set => self.expand_text(word)?,
+            Some('=') if !is_set => {
+                if !is_valid_name(parameter) {
+                    return Err(anyhow!("${}: cannot assign in this way", parameter));
+                }
+                let text = self.expand_text(word)?;
+                if let Some(state) = self.state.as_deref_mut() {
+                    state.vars.set(parameter, &text)?;
+                }
+                text
+            }
+            Some('+') if is_set => self.expand_text(word)?,
+            Some('+') => String::new(),
+            Some('?') if !is_set => {
+                let message = if word.is_empty() {
+                    "parameter null or not set".to_string()
+                } else {
+                    self.expand_text(word)?
+                };
+                return Err(anyhow!("{}: {}", parameter, message));
+            }
+            Some('-' | '=' | '?') => value.unwrap_or_default(),
+            _ => return Err(anyhow!("${{{}}}: bad substitution", content)),
+        };
+        Ok(Expansion::Text(text))
+    }
+
+    /// The value of a parameter, which may carry an array subscript.
+    fn value(&self, parameter: &str) -> Result<Option<String>> {
+        let Some((name, subscript)) = split_subscript(parameter) else {
+            return Ok(self.lookup(parameter));
+        };
+        match subscript {
+            "@" => {
+                let items = self.items(name);
+                Ok((!items.is_empty()).then(|| items.join(" ")))
+            }
+            "*" => {
+                let items = self.items(name);
+                Ok((!items.is_empty()).then(|| self.join_fields(&items)))
+            }
+            _ => {
+                let index = subscript
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("{}: bad array subscript", parameter))?;
+                let state = self.state.as_deref();
+                Ok(state
+                    .and_then(|state| state.vars.get_index(name, index))
+                    .map(str::to_string))
+            }
+        }
+    }
+
+    /// The value of a parameter that is expanded without a default. Unset
+    /// parameters expand to nothing, or are an error under `set -u`.
+    fn required(&self, parameter: &str) -> Result<String> {
+        let nounset = self
+            .state
+            .as_deref()
+            .is_some_and(|state| state.options.nounset);
+        match self.value(parameter)? {
+            Some(value) => Ok(value),
+            None if nounset => Err(anyhow!("{}: unbound variable", parameter)),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Expands the word of a `${name:-word}`-style operator into a single string.
+    fn expand_text(&mut self, word: &str) -> Result<String> {
+        let mut nested = WordExpander::new(self.state.as_deref_mut());
+        nested.expand(word)?;
+        Ok(nested.finish().join(" "))
+    }
+
+    /// Expands the pattern of `${name#pattern}` and friends, keeping quoted
+    /// wildcards literal.
+    fn expand_pattern(&mut self, word: &str) -> Result<String> {
+        let mut nested = WordExpander::new(self.state.as_deref_mut());
+        nested.expand(word)?;
+        Ok(nested.finish_patterns().join(" "))
+    }
+
+    /// All elements of an array; a set scalar counts as one element.
+    fn items(&self, name: &str) -> Vec<String> {
+        let Some(state) = self.state.as_deref() else {
+            return Vec::new();
+        };
+        match state.vars.get_value(name) {
+            Some(Value::Array(items)) => items.clone(),
+            Some(Value::Scalar(value)) => vec![value.clone()],
+            None => Vec::new(),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        let state = self.state.as_deref()?;
+        if let Ok(index) = name.parse::<usize>() {
+            let index = index.checked_sub(1)?;
+            return state.positional.get(index).cloned();
+        }
+        state.vars.get(name).map(str::to_string)
+    }
+
+    /// Joins the words of `$*` or `${arr[*]}` with the first char of `IFS`:
+    /// a space when it is unset, nothing when it is empty.
+    fn join_fields(&self, items: &[String]) -> String {
+        let separator = match self.lookup("IFS") {
+            Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+            None => " ".to_string(),
+        };
+        items.join(&separator)
+    }
+
+    /// Appends an unquoted substitution, starting a new field at each `IFS`
+    /// delimiter. Runs of IFS whitespace count as a single delimiter, while
+    /// every other IFS character delimits a (possibly empty) field.
+    fn push_split(&mut self, value: &str) {
+        let ifs = self
+            .lookup("IFS")
+            .unwrap_or_else(|| DEFAULT_IFS.to_string());
+        let mut after_whitespace = false;
+
+        for c in value.chars() {
+            if !ifs.contains(c) {
+                self.push(c);
+                after_whitespace = false;
+            } else if c.is_whitespace() {
+                if self.has_current {
+                    self.end_field();
+                    after_whitespace = true;
+                }
+            } else if after_whitespace {
+                after_whitespace = false;
+            } else {
+                self.end_field();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with(vars: &[(&str, &str)]) -> ShellState {
+        let mut state = ShellState::new();
+        state.vars.unset("IFS").unwrap();
+        for (name, value) in vars {
+            state.vars.set(name, value).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn test_expands_variable() {
+        let mut state = state_with(&[("NAME", "world")]);
+        assert_eq!(
+            expand_word("hello-$NAME", &mut state).unwrap(),
+            vec!["hello-world"]
+        );
+        assert_eq!(expand_word("${NAME}s", &mut state).unwrap(), vec!["worlds"]);
+    }
+
+    #[test]
+    fn test_default_ifs_splits_on_whitespace() {
+        let mut state = state_with(&[("DIRS", "/bin:/usr/bin"), ("WORDS", " a  b\tc ")]);
+        assert_eq!(
+            expand_word("$DIRS", &mut state).unwrap(),
+            vec!["/bin:/usr/bin"]
+        );
+        assert_eq!(
+            expand_word("$WORDS", &mut state).unwrap(),
+            vec!["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn test_custom_ifs_splits_on_colons() {
+        let mut state = state_with(&[("DIRS", "/bin:/usr/bin::/sbin"), ("IFS", ":")]);
+        assert_eq!(
+            expand_word("$DIRS", &mut state).unwrap(),
+            vec!["/bin", "/usr/bin", "", "/sbin"]
+        );
+    }
+
+    #[test]
+    fn test_ifs_whitespace_around_delimiter() {
+        let mut state = state_with(&[("LIST", "a : b"), ("IFS", " :")]);
+        assert_eq!(expand_word("$LIST", &mut state).unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_quoted_expansion_ignores_ifs() {
+        let mut state = state_with(&[("DIRS", "/bin:/usr/bin"), ("IFS", ":")]);
+        assert_eq!(
+            expand_word("\"$DIRS\"", &mut state).unwrap(),
+            vec!["/bin:/usr/bin"]
+        );
+    }
+
+    #[test]
+    fn test_empty_ifs_disables_splitting() {
+        let mut state = state_with(&[("WORDS", "a b"), ("IFS", "")]);
+        assert_eq!(expand_word("$WORDS", &mut state).unwrap(), vec!["a b"]);
+    }
+
+    #[test]
+    fn test_unset_variable_expands_to_nothing() {
+        let mut state = state_with(&[]);
+        assert!(expand_word("$MISSING", &mut state).unwrap().is_empty());
+        assert_eq!(expand_word("\"$MISSING\"", &mut state).unwrap(), vec![""]);
+    }
+
+    #[test]
+    fn test_nounset_rejects_unset_variable() {
+        let mut state = state_with(&[("SET", "value"), ("EMPTY", "")]);
+        state.options.nounset = true;
+        for word in ["$MISSING", "\"${MISSING}\"", "${#MISSING}", "${MISSING%x}"] {
+            let error = expand_word(word, &mut state).unwrap_err();
+            assert_eq!(error.to_string(), "MISSING: unbound variable");
+        }
+        assert_eq!(expand_word("$SET", &mut state).unwrap(), vec!["value"]);
+        assert_eq!(expand_word("\"$EMPTY\"", &mut state).unwrap(), vec![""]);
+        // The operators that handle unset variables still work.
+        assert_eq!(
+            expand_word("${MISSING:-other}", &mut state).unwrap(),
+            vec!["other"]
+        );
+        assert!(expand_word("${MISSING+x}", &mut state).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_indirect_expansion() {
+        let mut state = state_with(&[("TARGET", "value"), ("REF", "TARGET"), ("OUTER", "REF")]);
+        assert_eq!(expand_word("${!REF}", &mut state).unwrap(), vec!["value"]);
+        // Each `!` follows one name: OUTER names REF, which names TARGET.
+        assert_eq!(
+            expand_word("${!OUTER}", &mut state).unwrap(),
+            vec!["TARGET"]
+        );
+        let next = expand_text("${!OUTER}", &mut state).unwrap();
+        state.vars.set("NEXT", &next).unwrap();
+        assert_eq!(expand_word("${!NEXT}", &mut state).unwrap(), vec!["value"]);
+        assert_eq!(
+            expand_word("${!REF:-other}", &mut state).unwrap(),
+            vec!["value"]
+        );
+        assert!(expand_word("${!MISSING}", &mut state).unwrap().is_empty());
+
+        state.vars.set("DANGLING", "NOWHERE").unwrap();
+        assert!(expand_word("${!DANGLING}", &mut state).unwrap().is_empty());
+        state.options.nounset = true;
+        let error = expand_word("${!DANGLING}", &mut state).unwrap_err();
+        assert_eq!(error.to_string(), "NOWHERE: unbound variable");
+    }
+
+    fn expand_line(line: &str, state: &mut ShellState) -> Vec<String> {
+        let mut fields = Vec::new();
+        for token in crate::parser::tokenize(line).unwrap() {
+            fields.extend(expand_word(&token, state).unwrap());
+        }
+        fields
+    }
+
+    #[test]
+    fn test_escaped_dollar_in_double_quotes_stays_literal() {
+        let mut state = state_with(&[("HOME", "/home/user")]);
+        assert_eq!(
+            expand_line("echo \"\\$HOME\"", &mut state),
+            vec!["echo", "$HOME"]
+        );
+        assert_eq!(
+            expand_line("echo \"$HOME\"", &mut state),
+            vec!["echo", "/home/user"]
+        );
+        assert_eq!(
+            expand_line("echo \"\\$HOME is $HOME\"", &mut state),
+            vec!["echo", "$HOME is /home/user"]
+        );
+    }
+
+    #[test]
+    fn test_escaped_dollar_outside_quotes_stays_literal() {
+        let mut state = state_with(&[("HOME", "/home/user")]);
+        assert_eq!(
+            expand_line("echo \\$HOME", &mut state),
+            vec!["echo", "$HOME"]
+        );
+        assert_eq!(
+            expand_line("echo '$HOME'", &mut state),
+            vec!["echo", "$HOME"]
+        );
+    }
+
+    #[test]
+    fn test_escaped_backslash_before_dollar_still_expands() {
+        let mut state = state_with(&[("HOME", "/home/user")]);
+        assert_eq!(
+            expand_line("echo \"\\\\$HOME\"", &mut state),
+            vec!["echo", "\\/home/user"]
+        );
+    }
+
+    #[test]
+    fn test_array_element_access() {
+        let mut state = state_with(&[]);
+        state
+            .vars
+            .set_array("arr", vec!["zero".into(), "one two".into()])
+            .unwrap();
+        assert_eq!(expand_word("${arr[0]}", &mut state).unwrap(), vec!["zero"]);
+        assert_eq!(
+            expand_word("\"${arr[1]}\"", &mut state).unwrap(),
+            vec!["one two"]
+        );
+        assert!(expand_word("${arr[5]}", &mut state).unwrap().is_empty());
+        assert_eq!(expand_word("$arr", &mut state).unwrap(), vec!["zero"]);
+    }
+
+    #[test]
+    fn test_array_all_elements() {
+        let mut state = state_with(&[]);
+        state
+            .vars
+            .set_array("arr", vec!["a".into(), "b c".into(), "d".into()])
+            .unwrap();
+        assert_eq!(
+            expand_word("${arr[@]}", &mut state).unwrap(),
+            vec!["a", "b", "c", "d"]
+        );
+        assert_eq!(
+            expand_word("\"${arr[@]}\"", &mut state).unwrap(),
+            vec!["a", "b c", "d"]
+        );
+        assert_eq!(
+            expand_word("\"x${arr[@]}y\"", &mut state).unwrap(),
+            vec!["xa", "b c", "dy"]
+        );
+        assert_eq!(
+            expand_word("\"${arr[*]}\"", &mut state).unwrap(),
+            vec!["a b c d"]
+        );
+    }
+
+    #[test]
+    fn test_star_joins_with_first_ifs_char() {
+        let mut state = state_with(&[("IFS", ",:")]);
+        state
+            .vars
+            .set_array("a", vec!["x".into(), "y z".into()])
+            .unwrap();
+        state.positional = vec!["1".into(), "2".into()];
+        assert_eq!(
+            expand_word("\"${a[*]}\"", &mut state).unwrap(),
+            vec!["x,y z"]
+        );
+        assert_eq!(expand_word("\"$*\"", &mut state).unwrap(), vec!["1,2"]);
+        assert_eq!(expand_word("\"${*}\"", &mut state).unwrap(), vec!["1,2"]);
+
+        state.vars.set("IFS", "").unwrap();
+        assert_eq!(
+            expand_word("\"${a[*]}\"", &mut state).unwrap(),
+            vec!["xy z"]
+        );
+        assert_eq!(expand_word("\"$*\"", &mut state).unwrap(), vec!["12"]);
+
+        state.vars.unset("IFS").unwrap();
+        assert_eq!(expand_word("\"$*\"", &mut state).unwrap(), vec!["1 2"]);
+    }
+
+    #[test]
+    fn test_array_length() {
+        let mut state = state_with(&[("scalar", "value")]);
+        state
+            .vars
+            .set_array("arr", vec!["a".into(), "b".into(), "c".into()])
+            .unwrap();
+        assert_eq!(expand_word("${#arr[@]}", &mut state).unwrap(), vec!["3"]);
+        assert_eq!(expand_word("${#arr[*]}", &mut state).unwrap(), vec!["3"]);
+        assert_eq!(expand_word("${#scalar[@]}", &mut state).unwrap(), vec!["1"]);
+        assert_eq!(
+            expand_word("${#missing[@]}", &mut state).unwrap(),
+            vec!["0"]
+        );
+    }
+
+    #[test]
+    fn test_default_value_modifier() {
+        let mut state = state_with(&[("SET", "value"), ("EMPTY", "")]);
+        assert_eq!(
+            expand_word("${SET:-other}", &mut state).unwrap(),
+            vec!["value"]
+        );
+        assert_eq!(
+            expand_word("${EMPTY:-other}", &mut state).unwrap(),
+            vec!["other"]
+        );
+        assert_eq!(
+            expand_word("${UNSET:-other}", &mut state).unwrap(),
+            vec!["other"]
+        );
+        // Without the colon only an unset variable takes the default.
+        assert_eq!(
+            expand_word("\"${EMPTY-other}\"", &mut state).unwrap(),
+            vec![""]
+        );
+        assert_eq!(
+            expand_word("${UNSET-other}", &mut state).unwrap(),
+            vec!["other"]
+        );
+        assert_eq!(
+            expand_word("${UNSET:-$SET}", &mut state).unwrap(),
+            vec!["value"]
+        );
+        assert_eq!(expand_word("${UNSET:-'}'}", &mut state).unwrap(), vec!["}"]);
+    }
+
+    #[test]
+    fn test_assign_default_modifier() {
+        let mut state = state_with(&[("EMPTY", "")]);
+        assert_eq!(
+            expand_word("${NEW:=fresh}", &mut state).unwrap(),
+            vec!["fresh"]
+        );
+        assert_eq!(state.vars.get("NEW"), Some("fresh"));
+        assert_eq!(
+            expand_word("${NEW:=other}", &mut state).unwrap(),
+            vec!["fresh"]
+        );
+
+        assert_eq!(
+            expand_word("${EMPTY:=filled}", &mut state).unwrap(),
+            vec!["filled"]
+        );
+        assert_eq!(state.vars.get("EMPTY"), Some("filled"));
+    }
+
+    #[test]
+    fn test_alternate_value_modifier() {
+        let mut state = state_with(&[("SET", "value"), ("EMPTY", "")]);
+        assert_eq!(expand_word("${SET:+alt}", &mut state).unwrap(), vec!["alt"]);
+        assert!(expand_word("${EMPTY:+alt}", &mut state).unwrap().is_empty());
+        assert!(expand_word("${UNSET:+alt}", &mut state).unwrap().is_empty());
+        assert_eq!(
+            expand_word("${EMPTY+alt}", &mut state).unwrap(),
+            vec!["alt"]
+        );
+    }
+
+    #[test]
+    fn test_error_if_unset_modifier() {
+        let mut state = state_with(&[("SET", "value"), ("EMPTY", "")]);
+        assert_eq!(
+            expand_word("${SET:?missing}", &mut state).unwrap(),
+            vec!["value"]
+        );
+        assert_eq!(
+            expand_word("${UNSET:?must be set}", &mut state)
+                .unwrap_err()
+                .to_string(),
+            "UNSET: must be set"
+        );
+        assert_eq!(
+            expand_word("${EMPTY:?}", &mut state)
+                .unwrap_err()
+                .to_string(),
+            "EMPTY: parameter null or not set"
+        );
+        assert!(expand_word("${EMPTY?}", &mut state).is_ok());
+    }
+
+    #[test]
+    fn test_substring_offset_and_length() {
+        let mut state = state_with(&[("WORD", "abcdefgh")]);
+        assert_eq!(expand_word("${WORD:2:3}", &mut state).unwrap(), vec!["cde"]);
+        assert_eq!(expand_word("${WORD:5}", &mut state).unwrap(), vec!["fgh"]);
+        assert_eq!(expand_word("${WORD:6:10}", &mut state).unwrap(), vec!["gh"]);
+        assert!(expand_word("${WORD:20}", &mut state).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_substring_negative_offset() {
+        let mut state = state_with(&[("WORD", "abcdefgh")]);
+        assert_eq!(expand_word("${WORD: -3}", &mut state).unwrap(), vec!["fgh"]);
+        assert_eq!(
+            expand_word("${WORD:(-3):2}", &mut state).unwrap(),
+            vec!["fg"]
+        );
+        assert_eq!(
+            expand_word("${WORD:1:-2}", &mut state).unwrap(),
+            vec!["bcdef"]
+        );
+        assert!(expand_word("${WORD: -20}", &mut state).unwrap().is_empty());
+        // Without a space this is still the default-value modifier.
+        assert_eq!(
+            expand_word("${WORD:-3}", &mut state).unwrap(),
+            vec!["abcdefgh"]
+        );
+    }
+
+    #[test]
+    fn test_length_expansion() {
+        let mut state = state_with(&[("WORD", "hello"), ("WIDE", "héllo→")]);
+        assert_eq!(expand_word("${#WORD}", &mut state).unwrap(), vec!["5"]);
+        assert_eq!(expand_word("${#WIDE}", &mut state).unwrap(), vec!["6"]);
+        assert_eq!(expand_word("${WIDE:1:2}", &mut state).unwrap(), vec!["él"]);
+        assert_eq!(expand_word("${#UNSET}", &mut state).unwrap(), vec!["0"]);
+    }
+
+    #[test]
+    fn test_remove_suffix_pattern() {
+        let mut state = state_with(&[("FILE", "report.tar.gz")]);
+        assert_eq!(
+            expand_word("${FILE%.gz}", &mut state).unwrap(),
+            vec!["report.tar"]
+        );
+        assert_eq!(
+            expand_word("${FILE%.*}", &mut state).unwrap(),
+            vec!["report.tar"]
+        );
+        assert_eq!(
+            expand_word("${FILE%%.*}", &mut state).unwrap(),
+            vec!["report"]
+        );
+    }
+
+    #[test]
+    fn test_remove_prefix_pattern() {
+        let mut state = state_with(&[("PATH_VAR", "/usr/local/bin/tool")]);
+        assert_eq!(
+            expand_word("${PATH_VAR##*/}", &mut state).unwrap(),
+            vec!["tool"]
+        );
+        assert_eq!(
+            expand_word("${PATH_VAR#*/}", &mut state).unwrap(),
+            vec!["usr/local/bin/tool"]
+        );
+    }
+
+    #[test]
+    fn test_case_modification() {
+        let mut state = state_with(&[("NAME", "hello World"), ("SHOUT", "ÉCOLE Loud")]);
+        assert_eq!(
+            expand_word("\"${NAME^^}\"", &mut state).unwrap(),
+            vec!["HELLO WORLD"]
+        );
+        assert_eq!(
+            expand_word("\"${SHOUT,,}\"", &mut state).unwrap(),
+            vec!["école loud"]
+        );
+        assert_eq!(
+            expand_word("\"${NAME^}\"", &mut state).unwrap(),
+            vec!["Hello World"]
+        );
+        assert_eq!(
+            expand_word("\"${SHOUT,}\"", &mut state).unwrap(),
+            vec!["éCOLE Loud"]
+        );
+        // A pattern limits which chars change.
+        assert_eq!(
+            expand_word("\"${NAME^^[lo]}\"", &mut state).unwrap(),
+            vec!["heLLO WOrLd"]
+        );
+        assert_eq!(
+            expand_word("${NAME^[w]}", &mut state).unwrap(),
+            vec!["hello", "World"]
+        );
+    }
+
+    #[test]
+    fn test_remove_pattern_without_match() {
+        let mut state = state_with(&[("FILE", "notes.md")]);
+        assert_eq!(
+            expand_word("${FILE%.txt}", &mut state).unwrap(),
+            vec!["notes.md"]
+        );
+        assert_eq!(
+            expand_word("${FILE##x*}", &mut state).unwrap(),
+            vec!["notes.md"]
+        );
+    }
+
+    #[test]
+    fn test_quoted_pattern_removal_is_literal() {
+        let mut state = state_with(&[("STARS", "**x")]);
+        assert_eq!(
+            expand_word("${STARS#\"*\"}", &mut state).unwrap(),
+            vec!["*x"]
+        );
+        assert_eq!(
+            expand_word("${STARS#\\*\\*}", &mut state).unwrap(),
+            vec!["x"]
+        );
+    }
+
+    fn glob_dir() -> tempfile::TempDir {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in ["a.txt", "b.txt", "c.md", ".hidden.txt"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_glob_matches_files() {
+        let dir = glob_dir();
+        let base = dir.path().display().to_string();
+        let mut state = state_with(&[]);
+        assert_eq!(
+            expand_word(&format!("{}/*.txt", base), &mut state).unwrap(),
+            vec![format!("{}/a.txt", base), format!("{}/b.txt", base)]
+        );
+        assert_eq!(
+            expand_word(&format!("'{}'/?.md", base), &mut state).unwrap(),
+            vec![format!("{}/c.md", base)]
+        );
+        // Quoted wildcards are not expanded.
+        let quoted = format!("'{}/*.txt'", base);
+        assert_eq!(
+            expand_word(&quoted, &mut state).unwrap(),
+            vec![format!("{}/*.txt", base)]
+        );
+    }
+
+    #[test]
+    fn test_glob_without_match_stays_literal() {
+        let dir = glob_dir();
+        let pattern = format!("{}/*.rs", dir.path().display());
+        let mut state = state_with(&[]);
+        assert_eq!(expand_word(&pattern, &mut state).unwrap(), vec![pattern]);
+    }
+
+    #[test]
+    fn test_nullglob_removes_unmatched_pattern() {
+        let dir = glob_dir();
+        let pattern = format!("{}/*.rs", dir.path().display());
+        let mut state = state_with(&[]);
+        state.options.nullglob = true;
+        assert!(expand_word(&pattern, &mut state).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failglob_rejects_unmatched_pattern() {
+        let dir = glob_dir();
+        let pattern = format!("{}/*.rs", dir.path().display());
+        let mut state = state_with(&[]);
+        state.options.failglob = true;
+        assert_eq!(
+            expand_word(&pattern, &mut state).unwrap_err().to_string(),
+            format!("no match: {}", pattern)
+        );
+    }
+
+    #[test]
+    fn test_last_background_pid() {
+        let mut state = state_with(&[]);
+        assert!(expand_word("$!", &mut state).unwrap().is_empty());
+        state.last_background_pid = Some(4242);
+        assert_eq!(expand_word("$!", &mut state).unwrap(), vec!["4242"]);
+        assert_eq!(expand_word("${!}", &mut state).unwrap(), vec!["4242"]);
+    }
+
+    #[test]
+    fn test_assignment_expands_leading_tildes() {
+        let mut state = state_with(&[("HOME", "/home/user")]);
+        let mut expand = |value| expand_assignment(value, &mut state).unwrap();
+        assert_eq!(expand("~"), "/home/user");
+        assert_eq!(
+            expand("~/bin:~/tools:~x"),
+            "/home/user/bin:/home/user/tools:~x"
+        );
+        assert_eq!(expand("a~/b"), "a~/b");
+        assert_eq!(expand("'~'/b:\"~\":\\~/c"), "~/b:~:~/c");
+    }
+
+    #[test]
+    fn test_unquote_keeps_dollar() {
+        assert_eq!(unquote("'a b'\"$HOME\"\\ c"), "a b$HOME c");
+    }
+}
@@ -0,0 +1,2 @@
+pub use expand::*;
+pub mod expand;
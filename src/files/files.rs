@@ -0,0 +1,286 @@
+use anyhow::{Result, anyhow};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::os_text::{display_lossy, string_to_os};
+
+/// The short, coreutils-style description of an I/O error, without the
+/// `(os error N)` suffix.
+pub fn io_message(error: &io::Error) -> String {
+    match error.kind() {
+        io::ErrorKind::NotFound => "No such file or directory".to_string(),
+        io::ErrorKind::PermissionDenied => "Permission denied".to_string(),
+        io::ErrorKind::AlreadyExists => "File exists".to_string(),
+        io::ErrorKind::DirectoryNotEmpty => "Directory not empty".to_string(),
+        io::ErrorKind::IsADirectory => "Is a directory".to_string(),
+        io::ErrorKind::NotADirectory => "Not a directory".to_string(),
+        _ => {
+            let text = error.to_string();
+            match text.rfind(" (os error ") {
+                Some(index) => text[..index].to_string(),
+                None => text,
+            }
+        }
+    }
+}
+
+/// Creates the directory `path`; with `parents` (`mkdir -p`) missing parents
+/// are created too and an existing directory is not an error.
+pub fn mkdir(path: &str, parents: bool) -> Result<()> {
+    let result = if parents {
+        fs::create_dir_all(string_to_os(path))
+    } else {
+        fs::create_dir(string_to_os(path))
+    };
+    result.map_err(|e| {
+        anyhow!(
+            "mkdir: cannot create directory '{}': {}",
+            display_lossy(path),
+            io_message(&e)
+        )
+    })
+}
+
+/// Removes the empty directory `path`.
+pub fn rmdir(path: &str) -> Result<()> {
+    fs::remove_dir(string_to_os(path)).map_err(|e| {
+        anyhow!(
+            "rmdir: failed to remove '{}': {}",
+            display_lossy(path),
+            io_message(&e)
+        )
+    })
+}
+
+/// Removes `path`. Directories need `recursive` (`rm -r`); with `force`
+/// (`rm -f`) a missing path is silently ignored.
+///
+/// Like coreutils, `rm -r` refuses `.`, `..` and anything that resolves to
+/// the root directory.
+pub fn rm(path: &str, recursive: bool, force: bool) -> Result<()> {
+    if recursive {
+        let name = path.trim_end_matches('/').rsplit('/').next();
+        if matches!(name, Some("." | "..")) {
+            return Err(anyhow!(
+                "rm: refusing to remove '.' or '..' directory: skipping '{}'",
+                display_lossy(path)
+            ));
+        }
+        if fs::canonicalize(string_to_os(path)).is_ok_and(|path| path.parent().is_none()) {
+            return Err(anyhow!(
+                "rm: it is dangerous to operate recursively on '{}'\n\
+                 rm: use --no-preserve-root to override this failsafe",
+                display_lossy(path)
+            ));
+        }
+    }
+    let error = |e: io::Error| {
+        anyhow!(
+            "rm: cannot remove '{}': {}",
+            display_lossy(path),
+            io_message(&e)
+        )
+    };
+    let os_path = string_to_os(path);
+    // Don't follow a symlink to a directory: the link itself is removed.
+    let metadata = match fs::symlink_metadata(&os_path) {
+        Ok(metadata) => metadata,
+        Err(e) if force && e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(error(e)),
+    };
+    if !metadata.is_dir() {
+        return fs::remove_file(&os_path).map_err(error);
+    }
+    if !recursive {
+        return Err(error(io::ErrorKind::IsADirectory.into()));
+    }
+    fs::remove_dir_all(os_path).map_err(error)
+}
+
+/// Creates `path` if it is missing, otherwise sets its modification time to
+/// now.
+pub fn touch(path: &str) -> Result<()> {
+    let error = |e: io::Error| {
+        anyhow!(
+            "touch: cannot touch '{}': {}",
+            display_lossy(path),
+            io_message(&e)
+        )
+    };
+    let os_path = string_to_os(path);
+    if Path::new(&os_path).is_dir() {
+        // Directories can't be opened for writing; reopening read-only is
+        // enough to set the time.
+        return File::open(&os_path)
+            .and_then(|dir| dir.set_modified(SystemTime::now()))
+            .map_err(error);
+    }
+    File::options()
+        .create(true)
+        .append(true)
+        .open(os_path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .map_err(error)
+}
+
+/// The last component of `path` with `suffix` removed, as coreutils
+/// `basename` prints it. Trailing slashes are ignored, and a suffix that is
+/// the whole name is kept.
+pub fn basename(path: &str, suffix: Option<&str>) -> String {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return if path.is_empty() { "" } else { "/" }.to_string();
+    }
+    let name = trimmed.rsplit('/').next().unwrap_or(trimmed);
+    match suffix {
+        Some(suffix) if !suffix.is_empty() && name != suffix => {
+            name.strip_suffix(suffix).unwrap_or(name).to_string()
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// `path` without its last component, as coreutils `dirname` prints it:
+/// `.` when there is no slash, and `/` for components directly under the
+/// root.
+pub fn dirname(path: &str) -> String {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return if path.is_empty() { "." } else { "/" }.to_string();
+    }
+    match trimmed.rfind('/') {
+        None => ".".to_string(),
+        Some(index) => match trimmed[..index].trim_end_matches('/') {
+            "" => "/".to_string(),
+            parent => parent.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn path_in(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_mkdir_parents_creates_nested_dirs() {
+        let dir = TempDir::new().unwrap();
+        let nested = path_in(&dir, "a/b/c");
+        assert!(mkdir(&nested, false).is_err());
+        mkdir(&nested, true).unwrap();
+        assert!(Path::new(&nested).is_dir());
+        // Existing directories are fine with -p but not without.
+        mkdir(&nested, true).unwrap();
+        let error = mkdir(&nested, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("mkdir: cannot create directory '{}': File exists", nested)
+        );
+    }
+
+    #[test]
+    fn test_rm_force_ignores_missing_file() {
+        let dir = TempDir::new().unwrap();
+        let missing = path_in(&dir, "missing");
+        rm(&missing, false, true).unwrap();
+        let error = rm(&missing, false, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("rm: cannot remove '{}': No such file or directory", missing)
+        );
+    }
+
+    #[test]
+    fn test_rm_recursive_and_rmdir() {
+        let dir = TempDir::new().unwrap();
+        let tree = path_in(&dir, "tree");
+        mkdir(&format!("{}/sub", tree), true).unwrap();
+        touch(&format!("{}/sub/file", tree)).unwrap();
+
+        assert!(
+            rm(&tree, false, false)
+                .unwrap_err()
+                .to_string()
+                .ends_with("Is a directory")
+        );
+        assert!(rmdir(&tree).is_err());
+        rm(&tree, true, false).unwrap();
+        assert!(!Path::new(&tree).exists());
+
+        mkdir(&tree, false).unwrap();
+        rmdir(&tree).unwrap();
+        assert!(!Path::new(&tree).exists());
+    }
+
+    #[test]
+    fn test_rm_recursive_refuses_root_and_dots() {
+        let dir = TempDir::new().unwrap();
+        let sub = path_in(&dir, "sub");
+        mkdir(&sub, false).unwrap();
+
+        for path in [
+            format!("{}/.", sub),
+            format!("{}/..", sub),
+            "./".to_string(),
+        ] {
+            let error = rm(&path, true, true).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "rm: refusing to remove '.' or '..' directory: skipping '{}'",
+                    path
+                )
+            );
+        }
+        for path in ["/", "//"] {
+            let error = rm(path, true, true).unwrap_err().to_string();
+            assert!(
+                error.contains("dangerous to operate recursively"),
+                "{}",
+                error
+            );
+        }
+        assert!(Path::new(&sub).is_dir());
+    }
+
+    #[test]
+    fn test_touch_creates_and_keeps_contents() {
+        let dir = TempDir::new().unwrap();
+        let file = path_in(&dir, "file");
+        touch(&file).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "");
+        fs::write(&file, "kept").unwrap();
+        touch(&file).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "kept");
+        assert!(touch(&path_in(&dir, "missing/file")).is_err());
+    }
+
+    #[test]
+    fn test_basename_strips_suffix() {
+        assert_eq!(basename("/a/b/c.txt", Some(".txt")), "c");
+        assert_eq!(basename("/a/b/c.txt", None), "c.txt");
+        assert_eq!(basename("dir/", None), "dir");
+        assert_eq!(basename(".txt", Some(".txt")), ".txt");
+        assert_eq!(basename("plain", Some("x")), "plain");
+        assert_eq!(basename("//", None), "/");
+        assert_eq!(basename("", None), "");
+    }
+
+    #[test]
+    fn test_dirname_of_root_and_relative_paths() {
+        assert_eq!(dirname("/a/b/c"), "/a/b");
+        assert_eq!(dirname("/a/b/"), "/a");
+        assert_eq!(dirname("/usr"), "/");
+        assert_eq!(dirname("/"), "/");
+        assert_eq!(dirname("//x"), "/");
+        assert_eq!(dirname("a//b"), "a");
+        assert_eq!(dirname("file"), ".");
+        assert_eq!(dirname(""), ".");
+    }
+}
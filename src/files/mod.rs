@@ -0,0 +1,2 @@
+pub use files::*;
+pub mod files;
@@ -1,9 +1,49 @@
 use anyhow::Result;
 use std::{collections::HashSet, env, fs, path::Path};
 
+use crate::os_text::os_to_string;
+
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 
+/// Extensions Windows uses when `PATHEXT` is unset.
+pub const DEFAULT_PATHEXT: &str = ".COM;.EXE;.BAT;.CMD";
+
+/// Parses a `PATHEXT` value into lowercase extensions with their leading dot,
+/// falling back to `DEFAULT_PATHEXT`. Empty entries are skipped.
+pub fn path_extensions(pathext: Option<&str>) -> Vec<String> {
+    let pathext = pathext
+        .filter(|value| !value.trim().is_empty())
+        .unwrap_or(DEFAULT_PATHEXT);
+    pathext
+        .split(';')
+        .map(|ext| ext.trim().to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| {
+            if ext.starts_with('.') {
+                ext
+            } else {
+                format!(".{}", ext)
+            }
+        })
+        .collect()
+}
+
+/// The names to look for when running `name` on Windows: the name itself if
+/// it already ends in one of `extensions`, otherwise the name with each
+/// extension appended, in order.
+pub fn candidate_names(name: &str, extensions: &[String]) -> Vec<String> {
+    let lower = name.to_lowercase();
+    if extensions.iter().any(|ext| lower.ends_with(ext.as_str())) {
+        return vec![name.to_string()];
+    }
+    extensions
+        .iter()
+        .map(|ext| format!("{}{}", name, ext))
+        .collect()
+}
+
+#[derive(Default)]
 pub struct ExecutablesFinder {}
 
 impl ExecutablesFinder {
@@ -12,7 +52,7 @@ impl ExecutablesFinder {
     }
 
     pub fn find_executables_in_path(&self) -> Result<Vec<String>> {
-        let path_env = env::var("PATH")?;
+        let path_env = env::var_os("PATH").unwrap_or_default();
         let mut binaries = HashSet::new();
 
         for path in env::split_paths(&path_env) {
@@ -20,10 +60,11 @@ impl ExecutablesFinder {
                 for entry in entries.flatten() {
                     let path = entry.path();
 
-                    if path.is_file() && self.is_executable(&path) {
-                        if let Some(name) = path.file_name() {
-                            binaries.insert(name.to_string_lossy().to_string());
-                        }
+                    if path.is_file()
+                        && self.is_executable(&path)
+                        && let Some(name) = path.file_name()
+                    {
+                        binaries.insert(os_to_string(name));
                     }
                 }
             }
@@ -47,11 +88,59 @@ impl ExecutablesFinder {
 
         #[cfg(windows)]
         {
-            if let Some(ext) = path.extension() {
-                let ext = ext.to_string_lossy().to_lowercase();
-                return ext == "exe" || ext == "bat" || ext == "cmd" || ext == "com";
-            }
-            false
+            let extensions = path_extensions(env::var("PATHEXT").ok().as_deref());
+            has_executable_extension(path, &extensions)
         }
     }
 }
+
+/// Whether `path` ends in one of `extensions` (as from `path_extensions`),
+/// ignoring case the way Windows does.
+pub fn has_executable_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension().is_some_and(|ext| {
+        let ext = format!(".{}", ext.to_string_lossy().to_lowercase());
+        extensions.contains(&ext)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidate_names_append_extensions() {
+        let extensions = path_extensions(Some(".EXE;.BAT"));
+        assert_eq!(candidate_names("git", &extensions), ["git.exe", "git.bat"]);
+        assert_eq!(candidate_names("run.BAT", &extensions), ["run.BAT"]);
+        // An extension outside PATHEXT is just part of the name.
+        assert_eq!(
+            candidate_names("archive.tar", &extensions),
+            ["archive.tar.exe", "archive.tar.bat"]
+        );
+    }
+
+    #[test]
+    fn test_path_extensions_custom_value() {
+        let extensions = path_extensions(Some(".PY; .Ps1;;rb"));
+        assert_eq!(extensions, [".py", ".ps1", ".rb"]);
+        assert!(has_executable_extension(Path::new("tool.PY"), &extensions));
+        assert!(has_executable_extension(
+            Path::new(r"C:\bin\run.rb"),
+            &extensions
+        ));
+        assert!(!has_executable_extension(
+            Path::new("setup.exe"),
+            &extensions
+        ));
+        assert!(!has_executable_extension(
+            Path::new("Makefile"),
+            &extensions
+        ));
+    }
+
+    #[test]
+    fn test_path_extensions_default() {
+        assert_eq!(path_extensions(None), [".com", ".exe", ".bat", ".cmd"]);
+        assert_eq!(path_extensions(Some("  ")), path_extensions(None));
+    }
+}
@@ -0,0 +1,45 @@
+use os_pipe::PipeReader;
+use std::collections::BTreeMap;
+
+use crate::Output;
+
+/// Shell functions defined with `name() { ... }`, each kept as the source
+/// text of its body and run afresh on every call.
+#[derive(Debug, Default)]
+pub struct Functions {
+    bodies: BTreeMap<String, String>,
+}
+
+impl Functions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defines `name`, replacing any earlier definition.
+    pub fn define(&mut self, name: &str, body: &str) {
+        self.bodies.insert(name.to_string(), body.to_string());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.bodies.get(name).map(String::as_str)
+    }
+
+    /// Forgets `name`, returning whether it was defined.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.bodies.remove(name).is_some()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.bodies.contains_key(name)
+    }
+}
+
+/// Where the commands of a running function read and write when they have
+/// no redirects of their own: the call's redirect targets, or the pipe to
+/// the next stage. `None` leaves a stream on the terminal.
+#[derive(Default)]
+pub struct FunctionStreams {
+    pub stdout: Option<Box<dyn Output>>,
+    pub stderr: Option<Box<dyn Output>>,
+    pub input: Option<PipeReader>,
+}
@@ -0,0 +1,2 @@
+pub use functions::*;
+pub mod functions;
@@ -0,0 +1,172 @@
+/// How far `getopts` has got through its arguments. `index` is `OPTIND`,
+/// the 1-based number of the next argument, and `offset` is the byte
+/// position of the next flag within a cluster such as `-abc`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GetoptsCursor {
+    pub index: usize,
+    pub offset: usize,
+}
+
+impl GetoptsCursor {
+    /// A cursor at the start of argument `index`.
+    pub fn new(index: usize) -> Self {
+        Self { index, offset: 1 }
+    }
+
+    /// Moves to the start of the argument `skip` words further on.
+    fn next_word(&mut self, skip: usize) {
+        *self = Self::new(self.index + skip);
+    }
+}
+
+impl Default for GetoptsCursor {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+/// The outcome of one `getopts` call.
+#[derive(Debug, PartialEq)]
+pub enum Getopt {
+    /// A flag from the option string, with its argument if it takes one.
+    Found {
+        option: char,
+        argument: Option<String>,
+    },
+    /// A flag that is not in the option string.
+    Unknown(char),
+    /// A flag that takes an argument but came last.
+    MissingArgument(char),
+    /// No flags are left: the next argument is not one, or is `--`.
+    Done,
+}
+
+/// Reads the next flag from `args`, POSIX `getopts` style, and moves
+/// `cursor` past it. In `optstring`, a letter followed by `:` takes an
+/// argument, either the rest of its cluster (`-ofile`) or the next word
+/// (`-o file`).
+pub fn getopts(optstring: &str, args: &[String], cursor: &mut GetoptsCursor) -> Getopt {
+    let Some(arg) = cursor.index.checked_sub(1).and_then(|i| args.get(i)) else {
+        return Getopt::Done;
+    };
+    if cursor.offset == 1 {
+        if arg == "--" {
+            cursor.next_word(1);
+            return Getopt::Done;
+        }
+        if arg.len() < 2 || !arg.starts_with('-') {
+            return Getopt::Done;
+        }
+    }
+    let Some(option) = arg
+        .get(cursor.offset..)
+        .and_then(|rest| rest.chars().next())
+    else {
+        return Getopt::Done;
+    };
+    let rest = &arg[cursor.offset + option.len_utf8()..];
+
+    // `None` for a flag missing from the option string.
+    let takes_argument = match optstring.find(option) {
+        Some(i) if option != ':' => Some(optstring[i + option.len_utf8()..].starts_with(':')),
+        _ => None,
+    };
+    if takes_argument != Some(true) {
+        if rest.is_empty() {
+            cursor.next_word(1);
+        } else {
+            cursor.offset += option.len_utf8();
+        }
+        return match takes_argument {
+            Some(_) => Getopt::Found {
+                option,
+                argument: None,
+            },
+            None => Getopt::Unknown(option),
+        };
+    }
+    if !rest.is_empty() {
+        let argument = rest.to_string();
+        cursor.next_word(1);
+        return Getopt::Found {
+            option,
+            argument: Some(argument),
+        };
+    }
+    match args.get(cursor.index).cloned() {
+        Some(argument) => {
+            cursor.next_word(2);
+            Getopt::Found {
+                option,
+                argument: Some(argument),
+            }
+        }
+        None => {
+            cursor.next_word(1);
+            Getopt::MissingArgument(option)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn found(option: char, argument: Option<&str>) -> Getopt {
+        Getopt::Found {
+            option,
+            argument: argument.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_reads_clusters_and_arguments() {
+        let args = words(&["-ab", "-c", "value", "-cinline", "file"]);
+        let mut cursor = GetoptsCursor::default();
+        let mut results = Vec::new();
+        loop {
+            let result = getopts("abc:", &args, &mut cursor);
+            if result == Getopt::Done {
+                break;
+            }
+            results.push(result);
+        }
+        assert_eq!(
+            results,
+            [
+                found('a', None),
+                found('b', None),
+                found('c', Some("value")),
+                found('c', Some("inline")),
+            ]
+        );
+        // OPTIND points at the first operand.
+        assert_eq!(cursor.index, 5);
+    }
+
+    #[test]
+    fn test_double_dash_ends_options() {
+        let args = words(&["-a", "--", "-b"]);
+        let mut cursor = GetoptsCursor::default();
+        assert_eq!(getopts("ab", &args, &mut cursor), found('a', None));
+        assert_eq!(getopts("ab", &args, &mut cursor), Getopt::Done);
+        assert_eq!(cursor.index, 3);
+    }
+
+    #[test]
+    fn test_unknown_and_missing_arguments() {
+        let args = words(&["-xa", "-c"]);
+        let mut cursor = GetoptsCursor::default();
+        assert_eq!(getopts("ac:", &args, &mut cursor), Getopt::Unknown('x'));
+        assert_eq!(getopts("ac:", &args, &mut cursor), found('a', None));
+        assert_eq!(
+            getopts("ac:", &args, &mut cursor),
+            Getopt::MissingArgument('c')
+        );
+        assert_eq!(getopts("ac:", &args, &mut cursor), Getopt::Done);
+    }
+}
@@ -0,0 +1,2 @@
+pub use getopts::*;
+pub mod getopts;
@@ -0,0 +1,270 @@
+use std::fs;
+
+use crate::os_text::{os_to_string, string_to_os};
+
+/// One element of a compiled glob pattern.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(char),
+    /// `?`: any single character.
+    Any,
+    /// `*`: any run of characters, including none.
+    Star,
+    /// `[...]`: a set of characters and ranges, possibly negated.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+/// A shell glob pattern supporting `*`, `?`, `[...]` classes (with ranges and
+/// `!`/`^` negation) and backslash escapes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '*' => tokens.push(Token::Star),
+                '?' => tokens.push(Token::Any),
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    tokens.push(Token::Literal(chars[i]));
+                }
+                '[' => match parse_class(&chars[i + 1..]) {
+                    Some((token, used)) => {
+                        tokens.push(token);
+                        i += used;
+                    }
+                    // An unclosed `[` is just a character.
+                    None => tokens.push(Token::Literal('[')),
+                },
+                c => tokens.push(Token::Literal(c)),
+            }
+            i += 1;
+        }
+        Self { tokens }
+    }
+
+    /// Whether the whole of `text` matches the pattern.
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let (mut t, mut p) = (0, 0);
+        // Where to resume after the most recent `*` if the rest fails.
+        let mut backtrack: Option<(usize, usize)> = None;
+        while t < text.len() {
+            match self.tokens.get(p) {
+                Some(Token::Star) => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                Some(token) if token_matches(token, text[t]) => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            }
+        }
+        self.tokens[p..].iter().all(|token| *token == Token::Star)
+    }
+
+    /// The text the pattern matches when it has no wildcards.
+    pub fn literal(&self) -> Option<String> {
+        self.tokens
+            .iter()
+            .map(|token| match token {
+                Token::Literal(c) => Some(*c),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether the pattern uses any wildcard, as opposed to only literals.
+    pub fn has_wildcards(&self) -> bool {
+        self.tokens
+            .iter()
+            .any(|token| !matches!(token, Token::Literal(_)))
+    }
+}
+
+/// Expands a pathname pattern such as `src/*.rs` against the filesystem,
+/// returning the matching paths in sorted order.
+///
+/// Wildcards never match a `/`, and only match a leading `.` when the pattern
+/// component itself starts with one.
+pub fn glob_paths(pattern: &str) -> Vec<String> {
+    let mut paths = vec![if pattern.starts_with('/') {
+        "/".to_string()
+    } else {
+        String::new()
+    }];
+    for component in pattern.split('/').filter(|component| !component.is_empty()) {
+        let compiled = Pattern::new(component);
+        let mut next = Vec::new();
+        for base in &paths {
+            if let Some(literal) = compiled.literal() {
+                next.push(join(base, &literal));
+                continue;
+            }
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            let Ok(entries) = fs::read_dir(string_to_os(dir)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = os_to_string(&entry.file_name());
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if compiled.matches(&name) {
+                    next.push(join(base, &name));
+                }
+            }
+        }
+        paths = next;
+    }
+
+    let want_dir = pattern.ends_with('/');
+    let mut matches: Vec<String> = paths
+        .into_iter()
+        .filter(|path| match fs::metadata(string_to_os(path)) {
+            Ok(metadata) => !want_dir || metadata.is_dir(),
+            Err(_) => false,
+        })
+        .map(|path| if want_dir { path + "/" } else { path })
+        .collect();
+    matches.sort();
+    matches
+}
+
+fn join(base: &str, name: &str) -> String {
+    if base.is_empty() || base.ends_with('/') {
+        format!("{}{}", base, name)
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
+
+/// Matches `text` against the glob `pattern` as a whole.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    Pattern::new(pattern).matches(text)
+}
+
+fn token_matches(token: &Token, c: char) -> bool {
+    match token {
+        Token::Literal(literal) => *literal == c,
+        Token::Any => true,
+        Token::Star => false,
+        Token::Class { negated, ranges } => {
+            ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != *negated
+        }
+    }
+}
+
+/// Parses the body of a `[...]` class following the `[`. Returns the token and
+/// the number of chars consumed, including the closing `]`.
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let c = *chars.get(i)?;
+        // A `]` straight after the opening (or negation) is a member.
+        if c == ']' && !first {
+            return Some((Token::Class { negated, ranges }, i + 1));
+        }
+        first = false;
+        let low = if c == '\\' {
+            i += 1;
+            *chars.get(i)?
+        } else {
+            c
+        };
+        if chars.get(i + 1) == Some(&'-')
+            && let Some(&high) = chars.get(i + 2)
+            && high != ']'
+        {
+            ranges.push((low, high));
+            i += 3;
+        } else {
+            ranges.push((low, low));
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_star_and_question_mark() {
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("*.txt", "notes.txt.bak"));
+        assert!(glob_match("?at", "cat"));
+        assert!(!glob_match("?at", "at"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(glob_match("[abc]x", "bx"));
+        assert!(glob_match("[a-z][0-9]", "q7"));
+        assert!(!glob_match("[!a-z]", "q"));
+        assert!(glob_match("[^a-z]", "Q"));
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("[", "["));
+    }
+
+    #[test]
+    fn test_glob_paths_walks_directories() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let base = dir.path().display().to_string();
+        std::fs::create_dir_all(dir.path().join("src/nested")).unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "").unwrap();
+        std::fs::write(dir.path().join("src/nested/lib.rs"), "").unwrap();
+        std::fs::write(dir.path().join("src/.hidden.rs"), "").unwrap();
+
+        assert_eq!(
+            glob_paths(&format!("{}/*/*.rs", base)),
+            vec![format!("{}/src/main.rs", base)]
+        );
+        assert_eq!(
+            glob_paths(&format!("{}/src/*/", base)),
+            vec![format!("{}/src/nested/", base)]
+        );
+        assert_eq!(
+            glob_paths(&format!("{}/src/.*.rs", base)),
+            vec![format!("{}/src/.hidden.rs", base)]
+        );
+        assert!(glob_paths(&format!("{}/*.md", base)).is_empty());
+    }
+
+    #[test]
+    fn test_escapes_are_literal() {
+        assert!(glob_match("\\*", "*"));
+        assert!(!glob_match("\\*", "x"));
+        assert!(!Pattern::new("a\\?").has_wildcards());
+        assert!(Pattern::new("a?").has_wildcards());
+    }
+}
@@ -0,0 +1,2 @@
+pub use glob::*;
+pub mod glob;
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+
+use crate::commands::find_in_dirs;
+use crate::os_text::string_to_os;
+
+struct HashEntry {
+    path: PathBuf,
+    hits: u32,
+}
+
+/// Remembers where commands were found in PATH, like bash's hash table.
+///
+/// The table is tied to the PATH value it was filled from and is cleared as
+/// soon as PATH changes.
+#[derive(Default)]
+pub struct CommandHash {
+    entries: HashMap<String, HashEntry>,
+    path: Option<OsString>,
+}
+
+impl CommandHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Finds `name` in the directories of `path`, the shell's `$PATH`.
+    pub fn lookup(&mut self, name: &str, path: Option<&str>) -> Option<PathBuf> {
+        let paths = path.map(string_to_os).unwrap_or_default();
+        self.lookup_with(name, &paths, find_in_dirs)
+    }
+
+    /// Returns the cached location of `name`, falling back to `resolve` when it
+    /// is not cached yet or the cached file has disappeared.
+    pub fn lookup_with<F>(&mut self, name: &str, paths: &OsStr, resolve: F) -> Option<PathBuf>
+    where
+        F: FnOnce(&str, &OsStr) -> Option<PathBuf>,
+    {
+        if self.path.as_deref() != Some(paths) {
+            self.entries.clear();
+            self.path = Some(paths.to_os_string());
+        }
+
+        if let Some(entry) = self.entries.get_mut(name) {
+            if entry.path.is_file() {
+                entry.hits += 1;
+                return Some(entry.path.clone());
+            }
+            self.entries.remove(name);
+        }
+
+        let path = resolve(name, paths)?;
+        self.entries.insert(
+            name.to_string(),
+            HashEntry {
+                path: path.clone(),
+                hits: 1,
+            },
+        );
+        Some(path)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PathBuf> {
+        self.entries.get(name).map(|entry| &entry.path)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Formats the table the way `hash` prints it, sorted by command name.
+    pub fn listing(&self) -> String {
+        let mut names: Vec<&String> = self.entries.keys().collect();
+        names.sort();
+
+        let mut lines = vec!["hits\tcommand".to_string()];
+        lines.extend(names.into_iter().map(|name| {
+            let entry = &self.entries[name];
+            format!("{:>4}\t{}", entry.hits, entry.path.display())
+        }));
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cached_lookup_does_not_rescan_path() {
+        let dir = TempDir::new().unwrap();
+        let tool = dir.path().join("tool");
+        fs::write(&tool, "").unwrap();
+
+        let scans = Cell::new(0);
+        let resolve = |_: &str, _: &OsStr| {
+            scans.set(scans.get() + 1);
+            Some(tool.clone())
+        };
+
+        let mut hash = CommandHash::new();
+        let paths = OsStr::new("/bin");
+        assert_eq!(hash.lookup_with("tool", paths, resolve), Some(tool.clone()));
+        assert_eq!(hash.lookup_with("tool", paths, resolve), Some(tool.clone()));
+        assert_eq!(hash.lookup_with("tool", paths, resolve), Some(tool.clone()));
+        assert_eq!(scans.get(), 1);
+    }
+
+    #[test]
+    fn test_path_change_invalidates_cache() {
+        let dir = TempDir::new().unwrap();
+        let tool = dir.path().join("tool");
+        fs::write(&tool, "").unwrap();
+
+        let scans = Cell::new(0);
+        let resolve = |_: &str, _: &OsStr| {
+            scans.set(scans.get() + 1);
+            Some(tool.clone())
+        };
+
+        let mut hash = CommandHash::new();
+        hash.lookup_with("tool", OsStr::new("/bin"), resolve);
+        hash.lookup_with("tool", OsStr::new("/usr/bin"), resolve);
+        assert_eq!(scans.get(), 2);
+    }
+
+    #[test]
+    fn test_missing_cached_path_is_dropped() {
+        let dir = TempDir::new().unwrap();
+        let tool = dir.path().join("tool");
+        fs::write(&tool, "").unwrap();
+
+        let mut hash = CommandHash::new();
+        let paths = OsStr::new("/bin");
+        hash.lookup_with("tool", paths, |_, _| Some(tool.clone()));
+        fs::remove_file(&tool).unwrap();
+
+        assert_eq!(hash.lookup_with("tool", paths, |_, _| None), None);
+        assert!(hash.get("tool").is_none());
+    }
+
+    #[test]
+    fn test_clear_and_listing() {
+        let dir = TempDir::new().unwrap();
+        let tool = dir.path().join("tool");
+        fs::write(&tool, "").unwrap();
+
+        let mut hash = CommandHash::new();
+        let paths = OsStr::new("/bin");
+        hash.lookup_with("tool", paths, |_, _| Some(tool.clone()));
+        hash.lookup_with("tool", paths, |_, _| None);
+        assert_eq!(
+            hash.listing(),
+            format!("hits\tcommand\n   2\t{}", tool.display())
+        );
+
+        hash.clear();
+        assert!(hash.is_empty());
+    }
+}
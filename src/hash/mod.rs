@@ -0,0 +1,2 @@
+pub use hash::*;
+pub mod hash;
@@ -1,16 +1,165 @@
 use anyhow::Result;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// How `history -a`, `-r` and `-w` sync with a history file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistorySync {
+    /// `-a`: append the lines not yet in the file.
+    Append,
+    /// `-r`: read the file into the session.
+    Read,
+    /// `-w`: overwrite the file with the whole history.
+    Write,
+}
 
 pub struct History {
+    /// Lines exactly as typed, never their expanded form.
     pub items: Vec<String>,
+    /// Toggled by `set -o history` / `set +o history`.
+    pub enabled: bool,
+    /// Skip lines starting with a space, like bash's `HISTCONTROL=ignorespace`.
+    pub ignore_space: bool,
+    /// How many leading items the history file already has, so `-a` only
+    /// appends lines typed since.
+    synced: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            enabled: true,
+            ignore_space: true,
+            synced: 0,
+        }
+    }
 }
 
 impl History {
     pub fn new() -> Self {
-        Self { items: Vec::new() }
+        Self::default()
+    }
+
+    pub fn should_record(&self, line: &str) -> bool {
+        self.enabled && !(self.ignore_space && line.starts_with(' '))
     }
 
     pub fn add_history_item(&mut self, line: &str) -> Result<()> {
         self.items.push(line.to_string());
         Ok(())
     }
+
+    pub fn sync(&mut self, sync: HistorySync, path: &Path) -> io::Result<()> {
+        // Items may have been dropped since the last sync.
+        self.synced = self.synced.min(self.items.len());
+        match sync {
+            HistorySync::Append => {
+                // Only this session's new lines, so lines other shells
+                // appended in the meantime are kept.
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                for item in &self.items[self.synced..] {
+                    writeln!(file, "{}", item)?;
+                }
+            }
+            HistorySync::Read => {
+                // Lines read from the file go before the unsynced ones, so
+                // a later `-a` does not write them back.
+                let text = fs::read_to_string(path)?;
+                let lines: Vec<String> = text.lines().map(str::to_string).collect();
+                let count = lines.len();
+                self.items.splice(self.synced..self.synced, lines);
+                self.synced += count;
+                return Ok(());
+            }
+            HistorySync::Write => {
+                let text: String = self
+                    .items
+                    .iter()
+                    .map(|item| format!("{}\n", item))
+                    .collect();
+                fs::write(path, text)?;
+            }
+        }
+        self.synced = self.items.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::tokenize;
+
+    #[test]
+    fn test_space_prefixed_lines_are_skipped() {
+        let mut history = History::new();
+        assert!(history.should_record("echo visible"));
+        assert!(!history.should_record(" echo secret"));
+
+        history.ignore_space = false;
+        assert!(history.should_record(" echo secret"));
+    }
+
+    #[test]
+    fn test_recalled_line_tokenizes_like_the_original() {
+        let line = r#"echo "a  b" 'c $HOME' d\ e > "out file""#;
+        let mut history = History::new();
+        history.add_history_item(line).unwrap();
+
+        let recalled = history.items.last().unwrap();
+        assert_eq!(recalled, line);
+        assert_eq!(tokenize(recalled).unwrap(), tokenize(line).unwrap());
+    }
+
+    #[test]
+    fn test_append_writes_only_new_lines() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("history");
+        let mut history = History::new();
+        history.add_history_item("echo one").unwrap();
+        history.sync(HistorySync::Append, &path).unwrap();
+        // Another session writes to the file in between.
+        fs::write(&path, "echo one\necho other\n").unwrap();
+
+        history.add_history_item("echo two").unwrap();
+        history.sync(HistorySync::Append, &path).unwrap();
+        history.sync(HistorySync::Append, &path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "echo one\necho other\necho two\n"
+        );
+    }
+
+    #[test]
+    fn test_read_loads_file_and_write_replaces_it() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("history");
+        fs::write(&path, "ls\npwd\n").unwrap();
+        let mut history = History::new();
+        history.add_history_item("echo new").unwrap();
+        history.sync(HistorySync::Read, &path).unwrap();
+        assert_eq!(history.items, ["ls", "pwd", "echo new"]);
+
+        // The lines read are not appended back.
+        history.sync(HistorySync::Append, &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ls\npwd\necho new\n");
+
+        history.items.remove(0);
+        history.sync(HistorySync::Write, &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "pwd\necho new\n");
+        assert!(
+            history
+                .sync(HistorySync::Read, &dir.path().join("missing"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_disabled_history_records_nothing() {
+        let mut history = History::new();
+        history.enabled = false;
+        assert!(!history.should_record("echo hi"));
+    }
 }
@@ -0,0 +1,411 @@
+use anyhow::{Result, anyhow};
+use std::fmt;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStderr, ChildStdout, Command as CmdCommand, ExitStatus};
+
+use crate::substitution::Substitution;
+use crate::time::Timer;
+
+/// A started process, as a job tracks it.
+pub trait Process: fmt::Debug + Send {
+    fn id(&self) -> u32;
+    /// The exit status if the process has ended, without blocking.
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+    fn wait(&mut self) -> io::Result<ExitStatus>;
+}
+
+impl Process for Child {
+    fn id(&self) -> u32 {
+        Child::id(self)
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        Child::wait(self)
+    }
+}
+
+/// A process just started, with the ends of whichever of its streams were
+/// piped.
+pub struct Spawned {
+    pub process: Box<dyn Process>,
+    pub stdout: Option<ChildStdout>,
+    pub stderr: Option<ChildStderr>,
+}
+
+/// How programs are started. The shell uses [`StdSpawner`]; tests put in
+/// processes whose lifetime they control.
+pub trait Spawner {
+    fn spawn(&self, command: &mut CmdCommand) -> io::Result<Spawned>;
+}
+
+/// Starts real processes with [`CmdCommand::spawn`].
+#[derive(Debug, Default)]
+pub struct StdSpawner;
+
+impl Spawner for StdSpawner {
+    fn spawn(&self, command: &mut CmdCommand) -> io::Result<Spawned> {
+        let mut child = command.spawn()?;
+        Ok(Spawned {
+            stdout: child.stdout.take(),
+            stderr: child.stderr.take(),
+            process: Box::new(child),
+        })
+    }
+}
+
+/// The processes started for one pipeline.
+///
+/// With job control on, every stage joins the process group of the first one,
+/// and that group owns the terminal until the pipeline finishes, so Ctrl-C
+/// reaches each stage and not the shell. Background jobs get their own group
+/// but leave the terminal with the shell.
+#[derive(Debug, Default)]
+pub struct Job {
+    job_control: bool,
+    background: bool,
+    pgid: Option<u32>,
+    children: Vec<Box<dyn Process>>,
+}
+
+impl Job {
+    pub fn new(job_control: bool) -> Self {
+        Self {
+            job_control,
+            ..Self::default()
+        }
+    }
+
+    /// A job started with `&`, which never takes the terminal.
+    pub fn background(job_control: bool) -> Self {
+        Self {
+            job_control,
+            background: true,
+            ..Self::default()
+        }
+    }
+
+    /// Whether no external process was started, e.g. for a builtin.
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// How many processes have been started.
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    /// The PID of the last process in the pipeline.
+    pub fn last_pid(&self) -> Option<u32> {
+        self.children.last().map(|child| child.id())
+    }
+
+    /// Reaps whichever processes have exited, returning whether all have.
+    pub fn is_finished(&mut self) -> bool {
+        self.children
+            .iter_mut()
+            .all(|child| matches!(child.try_wait(), Ok(Some(_)) | Err(_)))
+    }
+
+    /// Places `command` in the job's process group, creating the group when
+    /// this is the first process.
+    #[cfg_attr(not(unix), expect(unused_variables))]
+    pub fn prepare(&self, command: &mut CmdCommand) {
+        #[cfg(unix)]
+        if self.job_control {
+            command.process_group(self.pgid.unwrap_or(0) as i32);
+        }
+    }
+
+    /// Tracks a spawned stage; the first one becomes the group leader.
+    pub fn add(&mut self, child: Box<dyn Process>) {
+        if cfg!(unix) && self.job_control && self.pgid.is_none() {
+            self.pgid = Some(child.id());
+            if !self.background {
+                set_foreground(child.id() as i32);
+            }
+        }
+        self.children.push(child);
+    }
+
+    /// Waits for every process in the job and hands the terminal back to the
+    /// shell. Returns the status of the last process.
+    pub fn wait(&mut self) -> Option<ExitStatus> {
+        self.wait_all().pop().flatten()
+    }
+
+    /// Waits like `wait`, returning the status of each process in the order
+    /// they were started.
+    pub fn wait_all(&mut self) -> Vec<Option<ExitStatus>> {
+        let statuses = self
+            .children
+            .drain(..)
+            .map(|mut child| child.wait().ok())
+            .collect();
+        if self.pgid.take().is_some() && !self.background {
+            set_foreground(shell_pgid());
+        }
+        statuses
+    }
+}
+
+/// The shell's view of how a process ended: its exit code, or 128 plus the
+/// signal that killed it.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// A background job, numbered the way `%n` job specs refer to it.
+#[derive(Debug)]
+pub struct JobEntry {
+    pub id: usize,
+    pub command: String,
+    pub job: Job,
+    /// The `<(...)` words of the pipeline, kept readable until the job is
+    /// dropped.
+    pub substitutions: Vec<Substitution>,
+    /// Set for a `time ... &` job, whose timing summary is printed once it
+    /// has been reaped.
+    pub timer: Option<Timer>,
+}
+
+impl JobEntry {
+    /// Prints the timing summary of a `time ... &` job that has finished.
+    fn report_time(&self) {
+        if let Some(timer) = &self.timer {
+            eprint!("{}", timer.report());
+        }
+    }
+}
+
+/// Jobs started with `&` that the shell still tracks.
+#[derive(Debug, Default)]
+pub struct JobTable {
+    entries: Vec<JobEntry>,
+}
+
+impl JobTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a job, numbering it one past the highest job still tracked.
+    pub fn add(&mut self, command: &str, job: Job) -> usize {
+        let id = self.entries.last().map_or(1, |entry| entry.id + 1);
+        self.entries.push(JobEntry {
+            id,
+            command: command.to_string(),
+            job,
+            substitutions: Vec::new(),
+            timer: None,
+        });
+        id
+    }
+
+    /// Hands job `id` the `<(...)` words its pipeline reads, so that they
+    /// are closed and their processes reaped along with the job rather
+    /// than while the shell waits.
+    pub fn keep_substitutions(&mut self, id: usize, substitutions: Vec<Substitution>) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
+            entry.substitutions.extend(substitutions);
+        }
+    }
+
+    /// Times job `id` with `timer`, which was started along with it.
+    pub fn keep_timer(&mut self, id: usize, timer: Timer) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
+            entry.timer = Some(timer);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether any job is still running.
+    pub fn has_running(&mut self) -> bool {
+        self.entries
+            .iter_mut()
+            .any(|entry| !entry.job.is_finished())
+    }
+
+    /// Resolves a job spec: `%n`, or `%%`/`%+` for the current (most recent)
+    /// job and `%-` for the one before it.
+    pub fn resolve(&self, spec: &str) -> Result<usize> {
+        let not_found = || anyhow!("{}: no such job", spec);
+        let index = match spec.strip_prefix('%').unwrap_or(spec) {
+            "%" | "+" | "" => self.entries.len().checked_sub(1),
+            "-" => self.entries.len().checked_sub(2),
+            number => {
+                let id = number.parse::<usize>().map_err(|_| not_found())?;
+                self.entries.iter().position(|entry| entry.id == id)
+            }
+        };
+        index
+            .map(|index| self.entries[index].id)
+            .ok_or_else(not_found)
+    }
+
+    /// Stops tracking a job, leaving its processes running.
+    pub fn remove(&mut self, id: usize) -> Option<JobEntry> {
+        let index = self.entries.iter().position(|entry| entry.id == id)?;
+        Some(self.entries.remove(index))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Waits for job `id` to finish and stops tracking it, returning its
+    /// exit status.
+    pub fn wait(&mut self, id: usize) -> Option<i32> {
+        let mut entry = self.remove(id)?;
+        let status = entry.job.wait().map_or(0, exit_code);
+        entry.report_time();
+        Some(status)
+    }
+
+    /// Waits for every job to finish and stops tracking them all.
+    pub fn wait_all(&mut self) {
+        for mut entry in self.entries.drain(..) {
+            entry.job.wait();
+            entry.report_time();
+        }
+    }
+
+    /// The `+`/`-` marker `jobs` shows next to the current and previous job.
+    fn marker(&self, index: usize) -> char {
+        match self.entries.len() - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+
+    /// `jobs` output, with each job's PID for `jobs -l`. Finished jobs are
+    /// reported as `Done` and then dropped.
+    pub fn listing(&mut self, long: bool) -> String {
+        let mut lines = Vec::new();
+        for index in 0..self.entries.len() {
+            let marker = self.marker(index);
+            let entry = &mut self.entries[index];
+            let pid = match entry.job.last_pid() {
+                Some(pid) if long => format!(" {:>5}", pid),
+                _ => " ".to_string(),
+            };
+            let line = if entry.job.is_finished() {
+                format!(
+                    "[{}]{}{} {:<24}{}",
+                    entry.id, marker, pid, "Done", entry.command
+                )
+            } else {
+                format!(
+                    "[{}]{}{} {:<24}{} &",
+                    entry.id, marker, pid, "Running", entry.command
+                )
+            };
+            lines.push(line);
+        }
+        self.take_finished();
+        lines.join("\n")
+    }
+
+    /// Drops finished jobs, returning the `Done` notices bash prints for them.
+    pub fn take_finished(&mut self) -> Vec<String> {
+        let mut notices = Vec::new();
+        let mut index = 0;
+        while index < self.entries.len() {
+            if self.entries[index].job.is_finished() {
+                let marker = self.marker(index);
+                let entry = self.entries.remove(index);
+                entry.report_time();
+                notices.push(format!(
+                    "[{}]{}  {:<24}{}",
+                    entry.id, marker, "Done", entry.command
+                ));
+            } else {
+                index += 1;
+            }
+        }
+        notices
+    }
+}
+
+#[cfg(unix)]
+fn shell_pgid() -> i32 {
+    // SAFETY: getpgrp has no preconditions.
+    unsafe { libc::getpgrp() }
+}
+
+#[cfg(not(unix))]
+fn shell_pgid() -> i32 {
+    0
+}
+
+/// Makes `pgid` the terminal's foreground process group.
+#[cfg(not(unix))]
+fn set_foreground(_pgid: i32) {}
+
+/// Makes `pgid` the terminal's foreground process group.
+#[cfg(unix)]
+fn set_foreground(pgid: libc::pid_t) {
+    // A shell outside the foreground group gets SIGTTOU when it takes the
+    // terminal back, so ignore it for the duration of the call.
+    // SAFETY: only signal dispositions and the terminal's group are changed.
+    unsafe {
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(commands: &[&str]) -> JobTable {
+        let mut jobs = JobTable::new();
+        for command in commands {
+            jobs.add(command, Job::default());
+        }
+        jobs
+    }
+
+    #[test]
+    fn test_resolve_job_specs() {
+        let jobs = table(&["sleep 1", "sleep 2", "sleep 3"]);
+        assert_eq!(jobs.resolve("%1").unwrap(), 1);
+        assert_eq!(jobs.resolve("%%").unwrap(), 3);
+        assert_eq!(jobs.resolve("%+").unwrap(), 3);
+        assert_eq!(jobs.resolve("%-").unwrap(), 2);
+        assert_eq!(
+            jobs.resolve("%7").unwrap_err().to_string(),
+            "%7: no such job"
+        );
+        assert!(jobs.resolve("%x").is_err());
+        assert!(JobTable::new().resolve("%%").is_err());
+    }
+
+    #[test]
+    fn test_remove_by_job_spec() {
+        let mut jobs = table(&["sleep 1", "sleep 2"]);
+        let id = jobs.resolve("%1").unwrap();
+        assert_eq!(jobs.remove(id).unwrap().command, "sleep 1");
+        assert!(jobs.resolve("%1").is_err());
+        // Numbers are not reused while later jobs are still tracked.
+        assert_eq!(jobs.add("sleep 3", Job::default()), 3);
+        assert_eq!(jobs.resolve("%%").unwrap(), 3);
+    }
+}
@@ -0,0 +1,2 @@
+pub use job::*;
+pub mod job;
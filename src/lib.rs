@@ -1,50 +1,1731 @@
+#![allow(clippy::module_inception)]
+
+pub mod alias;
 pub mod commands;
 pub mod completer;
+pub mod dirstack;
+pub mod expand;
+pub mod files;
 pub mod finder;
+pub mod functions;
+pub mod getopts;
+pub mod glob;
+pub mod hash;
 pub mod history;
+pub mod job;
+pub mod ls;
+pub mod options;
+pub mod os_text;
 pub mod output;
 pub mod parser;
+pub mod printf;
+pub mod prompt;
+pub mod repl;
+pub mod state;
+pub mod substitution;
+pub mod terminal;
+pub mod time;
+pub mod trap;
+pub mod variables;
 
 use os_pipe::PipeReader;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::expand::{expand_pattern, expand_text};
+use crate::functions::FunctionStreams;
+use crate::glob::glob_match;
+use crate::os_text::text_bytes;
+use crate::parser::{
+    CaseStatement, FunctionDefinition, ParseError, case_branches, case_statement,
+    function_definition, logical_lines, needs_continuation, parse_pipeline, split_list,
+    take_background, take_time, tokenize,
+};
+use crate::repl::{LineReader, run_repl};
+use crate::substitution::Substitution;
+use crate::time::Timer;
 
+pub use crate::alias::*;
 pub use crate::commands::*;
+pub use crate::hash::*;
 pub use crate::history::*;
-pub use crate::output::{FileOutput, Output, OutputStreams, StdErrOutput, StdOutput};
+pub use crate::job::*;
+pub use crate::options::*;
+pub use crate::output::{
+    CaptureOutput, FileOutput, NullOutput, Output, OutputStreams, PipeOutput, StdErrOutput,
+    StdOutput,
+};
+pub use crate::state::*;
+pub use crate::trap::*;
+pub use crate::variables::*;
+
+/// Startup file name, looked up in the home directory.
+pub const RC_FILE: &str = ".shellrc";
+/// Profile read by every login shell.
+pub const SYSTEM_PROFILE: &str = "/etc/profile";
+/// Profiles a login shell looks for in the home directory; only the first
+/// one found is read.
+pub const PROFILE_FILES: [&str; 2] = [".shell_profile", ".profile"];
+
+/// The crate version, as reported by `--version`.
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// A shell session, as `main.rs` drives it. It owns the [`ShellState`]
+/// that the free functions below take, and those stay callable for code
+/// that holds the state on its own.
+#[derive(Default)]
+pub struct Shell {
+    pub state: ShellState,
+}
+
+impl Shell {
+    /// Starts a session. As in bash, an inherited `$PWD` is kept only if it
+    /// names the working directory, since the prompt shows it.
+    pub fn new() -> Self {
+        let mut state = ShellState::new();
+        if let Ok(pwd) = logical_pwd(&state) {
+            let _ = state.vars.set("PWD", &pwd.display().to_string());
+        }
+        Self { state }
+    }
+
+    /// Runs one line of input, returning `false` once the shell should exit.
+    pub fn run_line(&mut self, line: &str) -> bool {
+        run_line(line, &mut self.state)
+    }
+
+    /// Runs each line of `text` in turn, as for a pasted block.
+    pub fn run_script(&mut self, text: &str) {
+        run_script(text, &mut self.state)
+    }
+
+    /// Sources a startup file, skipping it if it is missing.
+    pub fn source_rc(&mut self, path: &Path) {
+        source_rc(path, &mut self.state)
+    }
+
+    /// Prompts for and runs lines until end of input or `exit`.
+    pub fn run_repl(&mut self, reader: &mut impl LineReader) {
+        run_repl(reader, &mut self.state)
+    }
+
+    /// Ends the session by running the EXIT trap, unless `exit` already
+    /// did.
+    pub fn finish(&mut self) {
+        run_exit_trap(&mut self.state)
+    }
+
+    pub fn last_status(&self) -> i32 {
+        self.state.last_status
+    }
+}
+
+/// Runs one line of input, returning `false` once the shell should exit.
+///
+/// Pipelines separated by `;` run one after the other, after any function
+/// definition or `case` statement the line starts with. Errors are reported
+/// on stderr. Traps for signals received while the line ran are run
+/// afterwards, and the EXIT trap runs when the line calls `exit`.
+pub fn run_line(line: &str, state: &mut ShellState) -> bool {
+    let line = line.trim();
+    if let Some(FunctionDefinition {
+        name,
+        body: Some(body),
+        rest,
+    }) = function_definition(line)
+    {
+        state.functions.define(name, body);
+        state.last_status = 0;
+        let rest = rest.trim_start();
+        return run_line(rest.strip_prefix(';').unwrap_or(rest), state);
+    }
+    if let Some(CaseStatement {
+        word,
+        body: Some(body),
+        rest,
+    }) = case_statement(line)
+    {
+        if let Err(error) = run_case(word, body, state) {
+            report_line_error(line, &error, state);
+        }
+        // The branch has already run the EXIT trap if it called `exit`.
+        if state.exit_requested {
+            return false;
+        }
+        if state.returning {
+            return true;
+        }
+        let rest = rest.trim_start();
+        return run_line(rest.strip_prefix(';').unwrap_or(rest), state);
+    }
+    if !line.is_empty() {
+        // A definition or statement left here is missing its closing `}` or
+        // `esac`.
+        let parsed = if function_definition(line).is_some() {
+            Err(ParseError::UnterminatedFunction)
+        } else if case_statement(line).is_some() {
+            Err(ParseError::UnterminatedCase)
+        } else {
+            tokenize(line).and_then(split_list)
+        };
+        match parsed {
+            Ok(pipelines) => {
+                // The EXIT trap itself runs after `exit` was requested.
+                let exiting = state.exit_requested;
+                for tokens in pipelines {
+                    run_pipeline(tokens, line, state);
+                    if (state.exit_requested && !exiting) || state.returning {
+                        break;
+                    }
+                }
+            }
+            Err(error) => report_line_error(line, &error, state),
+        }
+    }
+    for notice in state.jobs.take_finished() {
+        eprintln!("{}", notice);
+    }
+    for command in state.traps.take_pending() {
+        run_line(&command, state);
+    }
+    if state.exit_requested {
+        run_exit_trap(state);
+        return false;
+    }
+    true
+}
+
+/// Reports a line that could not be parsed, after its place in the sourced
+/// file or, at the prompt, after the line itself. Like other errors it goes
+/// to the redirected stderr of the function being run, if any.
+fn report_line_error(line: &str, error: &ParseError, state: &mut ShellState) {
+    let message = match &state.location {
+        Some(location) => format!("{}: {}", location, error),
+        None => format!("{}: {}", line, error),
+    };
+    match &mut state.function_streams.stderr {
+        Some(stderr) => stderr.print(&message),
+        None => eprintln!("{}", message),
+    }
+    state.last_status = 1;
+}
+
+/// Runs the commands of the first branch of a `case` statement with a
+/// pattern matching `word`. Like bash, the status is 0 when no branch
+/// matches.
+fn run_case(word: &str, body: &str, state: &mut ShellState) -> Result<(), ParseError> {
+    let branches = case_branches(body)?;
+    let word = expand_text(word, state)?;
+    state.last_status = 0;
+    for branch in branches {
+        for pattern in branch.patterns {
+            if glob_match(&expand_pattern(pattern, state)?, &word) {
+                run_script(branch.commands, state);
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
 
-pub fn handle_pipeline(commands: Vec<Command>, streams: &mut OutputStreams, history: &History) {
-    let mut commands = commands;
-    let len = commands.len();
+/// Parses and runs one pipeline of `line`, which is named in error messages.
+fn run_pipeline(mut tokens: Vec<String>, line: &str, state: &mut ShellState) {
+    // `<(...)` words opened while parsing stay readable until it has run.
+    let substitutions = state.substitutions.len();
+    let background = take_background(&mut tokens);
+    let timed = take_time(&mut tokens);
+    let command = tokens.join(" ");
+    // A bare `time` reports on an empty pipeline.
+    let parsed = if timed && tokens.is_empty() {
+        Ok(Vec::new())
+    } else {
+        parse_pipeline(tokens, state)
+    };
+    if state.options.debugparse
+        && let Ok(stages) = &parsed
+    {
+        eprintln!("{}", describe_pipeline(stages));
+    }
+    match parsed {
+        // `time ... &` times the job in the background, like bash.
+        Ok(stages) if background => {
+            let timer = timed.then(Timer::start);
+            let own = state.substitutions.split_off(substitutions);
+            handle_background(stages, &command, own, timer, state)
+        }
+        Ok(stages) if timed => time_pipeline(stages, state, &mut StdErrOutput::new()),
+        Ok(stages) => handle_pipeline(stages, state),
+        Err(error) => report_line_error(line, &error, state),
+    }
+    state.substitutions.truncate(substitutions);
+}
+
+/// The parsed stages of a pipeline with their redirects, one per line, as
+/// printed by `set -o debugparse`.
+pub fn describe_pipeline(stages: &[(Command, OutputStreams)]) -> String {
+    stages
+        .iter()
+        .map(|(command, streams)| format!("{:?} {:?}", command, streams))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Starts `command` for a `<(command)` word and returns the path that its
+/// output can be read from. The pipe stays open until the pipeline that
+/// uses the path has finished.
+pub(crate) fn process_substitution(
+    command: &str,
+    state: &mut ShellState,
+) -> anyhow::Result<String> {
+    let stages = parse_pipeline(tokenize(command)?, state)?;
+    let outer = std::mem::take(&mut state.foreground);
+    let (_, output) = run_stages(stages, true, state);
+    let job = std::mem::replace(&mut state.foreground, outer);
+    let reader = match output {
+        Some(reader) => reader,
+        None => os_pipe::pipe()?.0,
+    };
+    let substitution = Substitution::new(reader, job)?;
+    let path = substitution.path.clone();
+    state.substitutions.push(substitution);
+    Ok(path)
+}
+
+/// Runs each line of `path` as if it were typed at the prompt (see
+/// `run_script`). Error messages name the file and line they came from.
+pub fn source_file(path: &Path, state: &mut ShellState) -> anyhow::Result<()> {
+    let text =
+        fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let outer = state.location.take();
+    let mut line_number = 1;
+    for line in logical_lines(&text) {
+        state.location = Some(ScriptLocation {
+            file: path.display().to_string(),
+            line: line_number,
+        });
+        line_number += line.matches('\n').count() + 1;
+        if !run_line(&line, state) || state.returning {
+            break;
+        }
+    }
+    state.location = outer;
+    Ok(())
+}
+
+/// Runs each line of `text` as if it were typed at the prompt, stopping
+/// early once a line calls `exit` or `return`. Lines ending in a backslash
+/// continue on the next, and so do function bodies until their closing `}`
+/// and `case` statements until their `esac`.
+pub fn run_script(text: &str, state: &mut ShellState) {
+    for line in logical_lines(text) {
+        if !run_line(&line, state) || state.returning {
+            break;
+        }
+    }
+}
+
+/// Runs the commands read from `reader` in a fresh shell, line by line as
+/// `run_script` does, and returns the status of the last one. Each logical
+/// line runs as soon as it has been read, writing to `streams` as it goes,
+/// and input the commands do not redirect comes from `streams.input`. Bytes
+/// that are not UTF-8 are replaced rather than ending the script.
+pub fn run_script_from_reader(mut reader: impl BufRead, streams: &mut OutputStreams) -> i32 {
+    let mut state = ShellState::new();
+    let stdout = streams
+        .stdout
+        .is_redirected()
+        .then(|| streams.stdout.duplicate());
+    let stderr = streams
+        .stderr
+        .is_redirected()
+        .then(|| streams.stderr.duplicate());
+    let (stdout, stderr) = match (stdout.transpose(), stderr.transpose()) {
+        (Ok(stdout), Ok(stderr)) => (stdout, stderr),
+        (Err(e), _) | (_, Err(e)) => {
+            streams.stderr.print(&e.to_string());
+            return 1;
+        }
+    };
+    state.function_streams = FunctionStreams {
+        stdout,
+        stderr,
+        input: streams.input.take(),
+    };
+    let (mut buffer, mut pending, mut open) = (Vec::new(), String::new(), false);
+    loop {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer) {
+            Ok(0) => {
+                // An unfinished last line is run as it is.
+                if open {
+                    run_line(&pending, &mut state);
+                }
+                break;
+            }
+            Ok(_) => {}
+            Err(error) => {
+                streams.stderr.print(&error.to_string());
+                state.last_status = 1;
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(&buffer);
+        let line = text.strip_suffix('\n').unwrap_or(&text);
+        if open {
+            pending.push('\n');
+        }
+        pending.push_str(line.strip_suffix('\r').unwrap_or(line));
+        open = needs_continuation(&pending);
+        if !open && (!run_line(&std::mem::take(&mut pending), &mut state) || state.returning) {
+            break;
+        }
+    }
+    run_exit_trap(&mut state);
+    state.function_streams = FunctionStreams::default();
+    state.last_status
+}
+
+/// The startup file read by an interactive shell: `$SHELLRC` if set,
+/// otherwise `~/.shellrc`.
+pub fn rc_path(shellrc: Option<&str>, home: Option<&Path>) -> Option<PathBuf> {
+    match shellrc {
+        Some(path) => Some(PathBuf::from(path)),
+        None => home.map(|home| home.join(RC_FILE)),
+    }
+}
+
+/// The files a login shell sources before its first prompt: `system`, then
+/// the first of `PROFILE_FILES` that exists in `home`.
+pub fn profile_paths(system: &Path, home: Option<&Path>) -> Vec<PathBuf> {
+    let mut paths = vec![system.to_path_buf()];
+    paths.extend(home.and_then(|home| {
+        PROFILE_FILES
+            .iter()
+            .map(|name| home.join(name))
+            .find(|path| path.is_file())
+    }));
+    paths
+}
 
-    if len == 0 {
+/// Sources the startup file at `path`. A missing file is silently skipped.
+pub fn source_rc(path: &Path, state: &mut ShellState) {
+    if !path.is_file() {
         return;
     }
+    if let Err(e) = source_file(path, state) {
+        eprintln!("{}", e);
+    }
+}
+
+/// Runs `$PROMPT_COMMAND`, if set, as the REPL does before each prompt.
+/// Errors are reported like any other line's, and `$?` still holds the
+/// status of the last command typed.
+pub fn run_prompt_command(state: &mut ShellState) {
+    let Some(command) = state.vars.get("PROMPT_COMMAND").map(str::to_string) else {
+        return;
+    };
+    let (status, warned) = (state.last_status, state.exit_warned);
+    run_line(&command, state);
+    state.last_status = status;
+    state.exit_warned = warned;
+}
 
-    let last_command = commands.pop().unwrap();
+/// Runs the EXIT trap, if one is set. It runs at most once per shell.
+pub fn run_exit_trap(state: &mut ShellState) {
+    if let Some(command) = state.traps.take_exit() {
+        run_line(&command, state);
+    }
+}
+
+/// Runs a pipeline in the foreground and records the status of its last
+/// stage in `state.last_status`.
+pub fn handle_pipeline(stages: Vec<(Command, OutputStreams)>, state: &mut ShellState) {
+    state.foreground = Job::new(state.interactive);
+    let (statuses, _) = run_stages(stages, false, state);
+    // Wait for every stage, not just the last, before the prompt returns.
+    let waited = state.foreground.wait_all();
+    let statuses: Vec<i32> = statuses
+        .into_iter()
+        .map(|status| match status {
+            StageStatus::Done(status) => status,
+            StageStatus::Process(index) => {
+                waited.get(index).copied().flatten().map_or(0, exit_code)
+            }
+        })
+        .collect();
+    state.last_status = statuses.last().copied().unwrap_or(0);
+    let statuses = statuses.iter().map(i32::to_string).collect();
+    let _ = state.vars.set_array("PIPESTATUS", statuses);
+}
+
+/// How a stage of a pipeline ended, as far as is known before waiting for
+/// the processes it started.
+enum StageStatus {
+    Done(i32),
+    /// The status is that of the job's process at this index.
+    Process(usize),
+}
+
+/// Runs a pipeline that was prefixed with `time`, then writes the timing
+/// summary to `stderr`.
+pub fn time_pipeline(
+    stages: Vec<(Command, OutputStreams)>,
+    state: &mut ShellState,
+    stderr: &mut dyn Output,
+) {
+    let timer = Timer::start();
+    if !stages.is_empty() {
+        handle_pipeline(stages, state);
+    }
+    stderr.write(&timer.report());
+}
+
+/// Starts a pipeline without waiting for it and adds it to the jobs table,
+/// along with the `<(...)` words it reads from and the `timer` of a
+/// `time ... &` pipeline.
+pub fn handle_background(
+    stages: Vec<(Command, OutputStreams)>,
+    command: &str,
+    substitutions: Vec<Substitution>,
+    timer: Option<Timer>,
+    state: &mut ShellState,
+) {
+    state.foreground = Job::background(state.interactive);
+    run_stages(stages, false, state);
+    let job = std::mem::take(&mut state.foreground);
+    // A pipeline of builtins has already finished by now.
+    if job.is_empty() {
+        if let Some(timer) = timer {
+            eprint!("{}", timer.report());
+        }
+        return;
+    }
+    let pid = job.last_pid().unwrap_or_default();
+    state.last_background_pid = Some(pid);
+    let id = state.jobs.add(command, job);
+    state.jobs.keep_substitutions(id, substitutions);
+    if let Some(timer) = timer {
+        state.jobs.keep_timer(id, timer);
+    }
+    if state.interactive {
+        eprintln!("[{}] {}", id, pid);
+    }
+}
+
+/// Runs every stage, returning how each one ended. With `piped`, the last
+/// stage writes to a pipe as well, whose reader is returned.
+///
+/// A stage that fails, e.g. because its program can't be started, counts as
+/// a failed command: later stages still run and read an empty input.
+///
+/// Builtins run in the shell itself wherever they sit in the pipeline, so
+/// `cd dir | cat` changes directory, unlike bash where each stage is a
+/// subshell. A builtin that writes nothing, such as `cd`, `export` or
+/// `exit`, leaves the next stage an empty input rather than the terminal.
+fn run_stages(
+    stages: Vec<(Command, OutputStreams)>,
+    piped: bool,
+    state: &mut ShellState,
+) -> (Vec<StageStatus>, Option<PipeReader>) {
+    let len = stages.len();
+    let programs: Vec<bool> = stages
+        .iter()
+        .map(|(command, _)| command.runs_program())
+        .collect();
     let mut previous_stdout: Option<PipeReader> = None;
+    let mut statuses = Vec::new();
+    let mut deferred = Vec::new();
 
-    for command in commands {
-        match execute_command(
-            command,
-            previous_stdout.take(),
-            None,
-            &mut *streams.stderr,
-            history,
-        ) {
-            Ok(output) => previous_stdout = output,
+    for (i, (command, mut streams)) in stages.into_iter().enumerate() {
+        let is_last = i == len - 1 && !piped;
+        // Inside a function whose call is redirected, output without a
+        // redirect of its own goes where the call's output goes.
+        if is_last
+            && !streams.stdout.is_redirected()
+            && let Some(out) = &state.function_streams.stdout
+            && let (Ok(stdout), Ok(stderr)) = (out.duplicate(), out.duplicate())
+        {
+            streams.stdout = stdout;
+            if streams.stderr.follows_stdout() {
+                streams.stderr = stderr;
+            }
+        }
+        if !streams.stderr.is_redirected()
+            && let Some(err) = &state.function_streams.stderr
+            && let Ok(err) = err.duplicate()
+        {
+            streams.stderr = err;
+        }
+        // A stage whose stdout is redirected writes there instead of the pipe,
+        // leaving the next stage with an empty input.
+        let has_stdout = is_last || streams.stdout.is_redirected();
+
+        // An explicit `<` redirect takes precedence over the pipe, and the
+        // pipe over the input of the function being run.
+        let input = streams.input.take().or(previous_stdout.take()).or_else(|| {
+            let input = state.function_streams.input.as_ref();
+            input.and_then(|input| input.try_clone().ok())
+        });
+        // A function feeding only programs writes into a pipe to them, and
+        // runs once they have all started so that its output streams.
+        if !has_stdout
+            && i + 1 < len
+            && matches!(command, Command::Function { .. })
+            && programs[i + 1..].iter().all(|&program| program)
+        {
+            let pipe = os_pipe::pipe().and_then(|(reader, writer)| {
+                let pipe = PipeOutput::new(writer);
+                if streams.stderr.follows_stdout() {
+                    streams.stderr = pipe.duplicate().map_err(std::io::Error::other)?;
+                }
+                Ok((reader, pipe))
+            });
+            match pipe {
+                Ok((reader, pipe)) => {
+                    previous_stdout = Some(reader);
+                    deferred.push((i, command, streams, input, pipe));
+                    statuses.push(StageStatus::Done(0));
+                    continue;
+                }
+                Err(e) => {
+                    streams.stderr.print(&e.to_string());
+                    statuses.push(StageStatus::Done(1));
+                    return (statuses, None);
+                }
+            }
+        }
+        // Builtins and functions hand their output to the next stage once
+        // they finish, so for `2>&1` into the pipe their output and errors
+        // are collected together, in the order written, and sent on after.
+        let mut merged =
+            (!has_stdout && streams.stderr.follows_stdout() && !command.runs_program())
+                .then(CaptureOutput::new);
+        if let Some(merged) = &merged {
+            streams.stderr = Box::new(merged.clone());
+        }
+        let stdout: Option<&mut dyn Output> = match &mut merged {
+            _ if has_stdout => Some(&mut *streams.stdout),
+            Some(merged) => Some(merged),
+            None => None,
+        };
+        let started_before = state.foreground.len();
+        let result = execute_command(command, input, stdout, &mut *streams.stderr, state);
+        let started = state.foreground.len();
+        let status = match result {
+            Ok(output) => {
+                if !has_stdout || is_last {
+                    previous_stdout = output;
+                }
+                match started.checked_sub(1) {
+                    Some(last) if started > started_before => StageStatus::Process(last),
+                    _ => StageStatus::Done(0),
+                }
+            }
             Err(e) => {
-                streams.stderr.print(&e.to_string());
-                return;
+                report_error(&e, state.location.as_ref(), &mut *streams.stderr);
+                let status = error_status(&e);
+                // A failed stage may still have output for the next one.
+                if let Ok(CommandError::Piped { output, .. }) = e.downcast() {
+                    previous_stdout = Some(output);
+                }
+                StageStatus::Done(status)
+            }
+        };
+        statuses.push(status);
+        if let Some(merged) = merged
+            && !merged.contents().is_empty()
+        {
+            match prepend_text(merged.contents(), previous_stdout.take()) {
+                Ok(reader) => previous_stdout = Some(reader),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    statuses[i] = StageStatus::Done(1);
+                    return (statuses, None);
+                }
+            }
+        }
+        if previous_stdout.is_none() && !is_last {
+            match os_pipe::pipe() {
+                Ok((reader, _)) => previous_stdout = Some(reader),
+                Err(e) => {
+                    streams.stderr.print(&e.to_string());
+                    statuses[i] = StageStatus::Done(1);
+                    return (statuses, None);
+                }
+            }
+        }
+    }
+    for (i, command, mut streams, input, mut pipe) in deferred {
+        // The pipe closes as the function returns, ending the next stage's
+        // input.
+        if let Err(e) =
+            execute_command(command, input, Some(&mut pipe), &mut *streams.stderr, state)
+        {
+            report_error(&e, state.location.as_ref(), &mut *streams.stderr);
+            statuses[i] = StageStatus::Done(error_status(&e));
+        }
+    }
+    (statuses, previous_stdout)
+}
+
+/// A pipe that yields `text` and then whatever `rest` yields.
+fn prepend_text(text: String, rest: Option<PipeReader>) -> std::io::Result<PipeReader> {
+    let (reader, mut writer) = os_pipe::pipe()?;
+    std::thread::spawn(move || {
+        let _ = writer.write_all(&text_bytes(&text));
+        if let Some(mut rest) = rest {
+            let _ = std::io::copy(&mut rest, &mut writer);
+        }
+    });
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_captured(prompt: &str) -> (String, String) {
+        let mut state = ShellState::new();
+        let mut stages = parse_pipeline(tokenize(prompt).unwrap(), &mut state).unwrap();
+        let out = CaptureOutput::new();
+        let err = CaptureOutput::new();
+        stages.last_mut().unwrap().1 =
+            OutputStreams::new(Box::new(out.clone()), Box::new(err.clone()));
+        handle_pipeline(stages, &mut state);
+        (out.contents(), err.contents())
+    }
+
+    #[test]
+    fn test_stderr_to_dev_null_in_pipeline() {
+        let stages = parse_pipeline(
+            tokenize("sh -c 'echo oops >&2' 2>/dev/null | cat").unwrap(),
+            &mut ShellState::new(),
+        )
+        .unwrap();
+        assert!(matches!(&stages[0].0, Command::Exec { args, .. } if args.len() == 2));
+        assert!(stages[0].1.stderr.is_redirected());
+
+        let (out, err) = run_captured("sh -c 'seq 1 20000; echo oops >&2' 2>/dev/null | tail -n 1");
+        assert_eq!(out, "20000\n");
+        assert!(err.is_empty());
+    }
+
+    #[test]
+    fn test_redirected_stage_feeds_empty_input() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("out.txt");
+        let (out, _) = run_captured(&format!("echo hi > {} | wc -c", path.display()));
+        assert_eq!(out.trim(), "0");
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "hi\n");
+    }
+
+    #[test]
+    fn test_mapfile_reads_lines_into_array() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("lines.txt");
+        std::fs::write(&path, "first\nsecond line\nthird\n").unwrap();
+
+        let mut state = ShellState::new();
+        let line = format!("mapfile arr < {}", path.display());
+        let stages = parse_pipeline(tokenize(&line).unwrap(), &mut state).unwrap();
+        handle_pipeline(stages, &mut state);
+
+        let stages = parse_pipeline(
+            tokenize("echo ${arr[1]} ${arr[2]} ${arr[3]}").unwrap(),
+            &mut state,
+        )
+        .unwrap();
+        let out = CaptureOutput::new();
+        let mut stages = stages;
+        stages[0].1.stdout = Box::new(out.clone());
+        handle_pipeline(stages, &mut state);
+
+        assert_eq!(out.contents(), "second line third\n");
+        assert_eq!(state.vars.get_index("arr", 0), Some("first"));
+        assert_eq!(state.vars.get_index("arr", 3), None);
+    }
+
+    #[test]
+    fn test_mapfile_reads_from_pipe() {
+        let mut state = ShellState::new();
+        let stages = parse_pipeline(
+            tokenize("printf 'a\\nb\\n' | readarray").unwrap(),
+            &mut state,
+        )
+        .unwrap();
+        handle_pipeline(stages, &mut state);
+        assert_eq!(state.vars.get_index("MAPFILE", 1), Some("b"));
+
+        // Options come before the name, which defaults to MAPFILE.
+        run_line("printf 'c\\nd\\n' | mapfile -t", &mut state);
+        assert_eq!(state.vars.get_index("MAPFILE", 0), Some("c"));
+        assert_eq!(state.vars.get("-t"), None);
+        run_line("printf 'e\\n' | mapfile -t -- lines", &mut state);
+        assert_eq!(state.vars.get_index("lines", 0), Some("e"));
+        run_line("mapfile -x lines 2> /dev/null", &mut state);
+        assert_eq!(state.last_status, 1);
+    }
+
+    #[test]
+    fn test_exit_trap_runs_on_exit() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("trap.txt");
+        let mut state = ShellState::new();
+
+        assert!(run_line(
+            &format!("trap 'echo cleaned > {}' EXIT", path.display()),
+            &mut state
+        ));
+        assert!(!path.exists());
+        assert!(!run_line("exit", &mut state));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "cleaned\n");
+
+        // The trap only runs once, even if the shell is asked to exit again.
+        std::fs::remove_file(&path).unwrap();
+        run_exit_trap(&mut state);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_trap_p_lists_traps() {
+        let mut state = ShellState::new();
+        run_line("trap 'echo bye' EXIT", &mut state);
+        let stages = parse_pipeline(tokenize("trap -p").unwrap(), &mut state).unwrap();
+        let out = CaptureOutput::new();
+        let mut stages = stages;
+        stages[0].1.stdout = Box::new(out.clone());
+        handle_pipeline(stages, &mut state);
+        assert_eq!(out.contents(), "trap -- 'echo bye' EXIT\n");
+    }
+
+    #[test]
+    fn test_pipeline_waits_for_every_stage() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("done.txt");
+        let mut state = ShellState::new();
+        // `true` finishes at once; the first stage must still be waited for.
+        run_line(
+            &format!("sh -c 'sleep 0.3; echo done > {}' | true", path.display()),
+            &mut state,
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "done\n");
+    }
+
+    #[test]
+    fn test_background_job_and_disown() {
+        let mut state = ShellState::new();
+        run_line("sleep 1 &", &mut state);
+        run_line("sleep 1 &", &mut state);
+        assert_eq!(state.jobs.resolve("%%").unwrap(), 2);
+
+        run_line("disown %1", &mut state);
+        assert!(state.jobs.resolve("%1").is_err());
+        assert_eq!(state.jobs.resolve("%2").unwrap(), 2);
+
+        run_line("disown -a", &mut state);
+        assert!(state.jobs.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_background_job_keeps_its_substitutions() {
+        let mut state = ShellState::new();
+        let started = std::time::Instant::now();
+        run_line("cat <(sleep 1) > /dev/null &", &mut state);
+        assert!(started.elapsed() < std::time::Duration::from_millis(500));
+        assert!(state.substitutions.is_empty());
+
+        run_line("wait", &mut state);
+        assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_time_runs_a_background_job_in_the_background() {
+        let mut state = ShellState::new();
+        let started = std::time::Instant::now();
+        run_line("time sleep 1 &", &mut state);
+        assert!(started.elapsed() < std::time::Duration::from_millis(500));
+
+        let id = state.jobs.resolve("%%").unwrap();
+        let mut entry = state.jobs.remove(id).unwrap();
+        assert_eq!(entry.command, "sleep 1");
+        assert!(entry.timer.is_some());
+        entry.job.wait();
+    }
+
+    #[test]
+    fn test_exit_warns_once_about_running_jobs() {
+        let mut state = ShellState::new();
+        run_line("sleep 1 &", &mut state);
+        state.interactive = true;
+
+        assert!(run_line("exit", &mut state));
+        assert!(!state.exit_requested);
+        assert_eq!(state.last_status, 1);
+        // Any other command in between means the next `exit` warns again.
+        run_line("true", &mut state);
+        assert!(run_line("exit", &mut state));
+        assert!(!run_line("exit", &mut state));
+        assert!(state.exit_requested);
+
+        // Without a terminal there is no warning.
+        let mut state = ShellState::new();
+        run_line("sleep 1 &", &mut state);
+        assert!(!run_line("exit", &mut state));
+        state.jobs.clear();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_background_job_goes_from_running_to_done() {
+        use std::io;
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        /// A process that runs until `done` is raised.
+        #[derive(Debug)]
+        struct FakeProcess(Arc<AtomicBool>);
+
+        impl Process for FakeProcess {
+            fn id(&self) -> u32 {
+                4242
+            }
+
+            fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+                Ok(self
+                    .0
+                    .load(Ordering::SeqCst)
+                    .then(|| ExitStatus::from_raw(0)))
+            }
+
+            fn wait(&mut self) -> io::Result<ExitStatus> {
+                while !self.0.load(Ordering::SeqCst) {
+                    std::thread::yield_now();
+                }
+                Ok(ExitStatus::from_raw(0))
+            }
+        }
+
+        /// Starts a `FakeProcess` in place of every program.
+        struct FakeSpawner(Arc<AtomicBool>);
+
+        impl Spawner for FakeSpawner {
+            fn spawn(&self, _command: &mut std::process::Command) -> io::Result<Spawned> {
+                Ok(Spawned {
+                    process: Box::new(FakeProcess(Arc::clone(&self.0))),
+                    stdout: None,
+                    stderr: None,
+                })
             }
         }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let mut state = ShellState::new();
+        state.spawner = Box::new(FakeSpawner(Arc::clone(&done)));
+
+        run_line("sleep 1000 &", &mut state);
+        assert_eq!(state.last_background_pid, Some(4242));
+        let running = format!("[1]+  {:<24}sleep 1000 &", "Running");
+        assert_eq!(state.jobs.listing(false), running);
+
+        done.store(true, Ordering::SeqCst);
+        let finished = format!("[1]+  {:<24}sleep 1000", "Done");
+        assert_eq!(state.jobs.listing(false), finished);
+        assert!(state.jobs.is_empty());
+    }
+
+    #[test]
+    fn test_wait_blocks_until_background_job_finishes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (listing, done) = (dir.path().join("jobs"), dir.path().join("done"));
+        let mut state = ShellState::new();
+
+        run_line(
+            &format!("sh -c 'sleep 0.3; echo done > {}' &", done.display()),
+            &mut state,
+        );
+        let pid = state.last_background_pid.unwrap();
+        run_line(&format!("jobs -l > {}", listing.display()), &mut state);
+        let listing = fs::read_to_string(&listing).unwrap();
+        assert!(
+            listing.starts_with(&format!("[1]+ {:>5} Running", pid)),
+            "{:?}",
+            listing
+        );
+
+        run_line("wait", &mut state);
+        assert_eq!(fs::read_to_string(&done).unwrap(), "done\n");
+        assert_eq!(state.last_status, 0);
+        assert!(state.jobs.is_empty());
+
+        // `wait %n` gives that job's status; with no jobs it returns at once.
+        run_line("sh -c 'exit 3' &", &mut state);
+        run_line("wait %1", &mut state);
+        assert_eq!(state.last_status, 3);
+        run_line("wait", &mut state);
+        assert_eq!(state.last_status, 0);
+    }
+
+    #[test]
+    fn test_last_background_pid_expands() {
+        let mut state = ShellState::new();
+        run_line("sleep 1 &", &mut state);
+        let pid = state.last_background_pid.unwrap();
+        let id = state.jobs.resolve("%%").unwrap();
+        assert_eq!(state.jobs.remove(id).unwrap().job.last_pid(), Some(pid));
+
+        let mut stages = parse_pipeline(tokenize("echo $!").unwrap(), &mut state).unwrap();
+        let out = CaptureOutput::new();
+        stages[0].1.stdout = Box::new(out.clone());
+        handle_pipeline(stages, &mut state);
+        assert_eq!(out.contents(), format!("{}\n", pid));
+    }
+
+    #[test]
+    fn test_time_reports_and_keeps_stdout() {
+        let mut state = ShellState::new();
+        let mut tokens = tokenize("time printf 'a\\nb\\n' | wc -l").unwrap();
+        assert!(take_time(&mut tokens));
+        let mut stages = parse_pipeline(tokens, &mut state).unwrap();
+        let out = CaptureOutput::new();
+        stages.last_mut().unwrap().1.stdout = Box::new(out.clone());
+        let mut err = CaptureOutput::new();
+        time_pipeline(stages, &mut state, &mut err);
+
+        assert_eq!(out.contents().trim(), "2");
+        let report = err.contents();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "");
+        assert!(lines[1].starts_with("real\t0m"), "{:?}", report);
+        assert!(lines[1].ends_with('s'));
+        assert!(lines[2].starts_with("user\t"));
+        assert!(lines[3].starts_with("sys\t"));
+    }
+
+    #[test]
+    fn test_rc_file_alias_applies_before_first_line() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let rc = dir.path().join("rc");
+        let out = dir.path().join("out.txt");
+        std::fs::write(&rc, "alias greet='echo hello'\nalias shout=greet\n").unwrap();
+
+        let path = rc_path(rc.to_str(), Some(dir.path())).unwrap();
+        let mut state = ShellState::new();
+        source_rc(&path, &mut state);
+        assert!(run_line(
+            &format!("shout world > {}", out.display()),
+            &mut state
+        ));
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "hello world\n");
+    }
+
+    #[test]
+    fn test_login_profile_sets_variable() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let system = dir.path().join("no-such-profile");
+        std::fs::write(dir.path().join(".profile"), "export FROM=profile\n").unwrap();
+        let paths = profile_paths(&system, Some(dir.path()));
+        assert_eq!(paths, [system.clone(), dir.path().join(".profile")]);
+
+        // `.shell_profile` is preferred when both exist.
+        std::fs::write(
+            dir.path().join(".shell_profile"),
+            "export FROM=shell_profile LOGIN=yes\n",
+        )
+        .unwrap();
+        let mut state = ShellState::new();
+        for path in profile_paths(&system, Some(dir.path())) {
+            source_rc(&path, &mut state);
+        }
+        assert_eq!(state.vars.get("FROM"), Some("shell_profile"));
+        assert_eq!(state.vars.get("LOGIN"), Some("yes"));
+    }
+
+    #[test]
+    fn test_sourced_file_skips_comments_and_blank_lines() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let script = dir.path().join("script");
+        std::fs::write(
+            &script,
+            "#!/usr/bin/env shell\n\n# Set up.\nexport FIRST=1 # trailing\n   \n\t# indented\nexport SECOND='#2'\n#export THIRD=3\n",
+        )
+        .unwrap();
+
+        let mut state = ShellState::new();
+        run_line(&format!("source {}", script.display()), &mut state);
+        assert_eq!(state.vars.get("FIRST"), Some("1"));
+        assert_eq!(state.vars.get("SECOND"), Some("#2"));
+        assert_eq!(state.vars.get("THIRD"), None);
+        assert_eq!(state.last_status, 0);
+    }
+
+    #[test]
+    fn test_prompt_command_runs_before_next_command() {
+        let mut state = ShellState::new();
+        run_prompt_command(&mut state);
+
+        run_line(
+            "export PROMPT_COMMAND='export PROMPTS=$PROMPTS.'",
+            &mut state,
+        );
+        run_line("false", &mut state);
+        run_prompt_command(&mut state);
+        assert_eq!(state.vars.get("PROMPTS"), Some("."));
+        assert_eq!(state.last_status, 1);
+        run_prompt_command(&mut state);
+        assert_eq!(state.vars.get("PROMPTS"), Some(".."));
+
+        // A failing hook is reported but leaves the shell running.
+        run_line(
+            "export PROMPT_COMMAND='no-such-hook-command 2> /dev/null'",
+            &mut state,
+        );
+        run_prompt_command(&mut state);
+        assert!(!state.exit_requested);
+        assert_eq!(state.last_status, 0);
+    }
+
+    #[test]
+    fn test_missing_rc_file_is_ignored() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = rc_path(None, Some(dir.path())).unwrap();
+        assert_eq!(path, dir.path().join(RC_FILE));
+        let mut state = ShellState::new();
+        source_rc(&path, &mut state);
+        assert!(!state.exit_requested);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unspawnable_stage_does_not_abort_pipeline() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        // Executable, but neither a binary nor a script with a shebang.
+        let bad = dir.path().join("bad");
+        std::fs::write(&bad, [0u8, 1, 2, 3]).unwrap();
+        std::fs::set_permissions(&bad, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut state = ShellState::new();
+        let line = format!("printf 'a\\nb\\n' | {} | wc -l", bad.display());
+        let mut stages = parse_pipeline(tokenize(&line).unwrap(), &mut state).unwrap();
+        let (out, err) = (CaptureOutput::new(), CaptureOutput::new());
+        stages[1].1.stderr = Box::new(err.clone());
+        stages[2].1.stdout = Box::new(out.clone());
+        handle_pipeline(stages, &mut state);
+        assert_eq!(out.contents().trim(), "0");
+        assert_eq!(
+            err.contents(),
+            format!("{}: Exec format error\n", bad.display())
+        );
+        assert_eq!(state.last_status, 0);
+
+        let (_, err) = run_captured(&format!("echo hi | {}", bad.display()));
+        assert!(err.ends_with("Exec format error\n"));
+        run_line(
+            &format!("echo hi | {} 2> /dev/null", bad.display()),
+            &mut state,
+        );
+        assert_eq!(state.last_status, 126);
+        run_line("no-such-command-here 2> /dev/null", &mut state);
+        assert_eq!(state.last_status, 127);
+        run_line("sh -c 'exit 3'", &mut state);
+        assert_eq!(state.last_status, 3);
+    }
+
+    #[test]
+    fn test_silent_builtin_feeds_empty_input() {
+        let mut state = ShellState::new();
+        // `cd .` keeps the working directory other tests rely on. Only the
+        // pipeline's stdout is captured: `cat` would block on the terminal
+        // if it inherited stdin.
+        for line in ["cd . | cat", "export PIPED=yes | cat"] {
+            let mut stages = parse_pipeline(tokenize(line).unwrap(), &mut state).unwrap();
+            let out = CaptureOutput::new();
+            stages[1].1.stdout = Box::new(out.clone());
+            handle_pipeline(stages, &mut state);
+            assert_eq!(out.contents(), "", "{}", line);
+            assert_eq!(state.last_status, 0);
+        }
+        // The builtin ran in the shell itself.
+        assert_eq!(state.vars.get("PIPED"), Some("yes"));
+    }
+
+    #[test]
+    fn test_piped_builtin_output_is_byte_accurate() {
+        assert_eq!(run_captured("echo -n hi | cat").0, "hi");
+        assert_eq!(run_captured("echo hi | cat").0, "hi\n");
+        assert_eq!(run_captured("printf 'a\\nb'").0, "a\nb");
+
+        let (out, _) = run_captured("printf '\\377ok' | cat");
+        assert_eq!(crate::os_text::text_bytes(&out), b"\xffok");
+    }
+
+    #[test]
+    fn test_status_of_last_builtin_expands_as_question_mark() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (out, status) = (dir.path().join("out"), dir.path().join("status"));
+        let mut state = ShellState::new();
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+
+        let line = format!(
+            "type nope > {}; echo $? > {}",
+            out.display(),
+            status.display()
+        );
+        run_line(&line, &mut state);
+        assert_eq!(read(&out), "nope: not found\n");
+        assert_eq!(read(&status), "1\n");
+
+        let line = format!(
+            "cd /nonexistent 2> /dev/null; echo $? > {}",
+            status.display()
+        );
+        run_line(&line, &mut state);
+        assert_ne!(read(&status), "0\n");
+
+        let line = format!("type echo > /dev/null; echo ${{?}} > {}", status.display());
+        run_line(&line, &mut state);
+        assert_eq!(read(&status), "0\n");
+
+        // Every stage's status is kept, even one whose output is piped on.
+        run_line("f() { echo hi; return 3; }", &mut state);
+        let line = format!(
+            "type nope | f | sh -c 'cat; exit 4' | cat > {}",
+            out.display()
+        );
+        run_line(&line, &mut state);
+        assert_eq!(read(&out), "hi\n");
+        let line = format!("echo ${{PIPESTATUS[*]}} > {}", status.display());
+        run_line(&line, &mut state);
+        assert_eq!(read(&status), "1 3 4 0\n");
+    }
+
+    #[test]
+    fn test_semicolon_runs_pipelines_in_order() {
+        let mut state = ShellState::new();
+        run_line("export FIRST=1; export SECOND=$FIRST;", &mut state);
+        assert_eq!(state.vars.get("SECOND"), Some("1"));
+
+        run_line("export SKIPPED=no; ;", &mut state);
+        assert_eq!(state.vars.get("SKIPPED"), None);
+        assert_eq!(state.last_status, 1);
+
+        assert!(!run_line("exit; export AFTER_EXIT=yes", &mut state));
+        assert_eq!(state.vars.get("AFTER_EXIT"), None);
+    }
+
+    #[test]
+    fn test_function_echoes_first_argument() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut state = ShellState::new();
+        let read = || fs::read_to_string(&out).unwrap();
+
+        run_script("first() {\n  echo \"$1\"\n}\n", &mut state);
+        assert!(state.functions.contains("first"));
+        run_line(&format!("first 'a b' c > {}", out.display()), &mut state);
+        assert_eq!(read(), "a b\n");
+
+        // Calls pipe like commands, and the caller's parameters come back.
+        let line = format!(
+            "function count {{ echo $# \"$*\"; }}; count x y | cat > {}",
+            out.display()
+        );
+        run_line(&line, &mut state);
+        assert_eq!(read(), "2 x y\n");
+        assert!(state.positional.is_empty());
+
+        // The status of a call is that of its last command.
+        run_line("fails() { false; }; fails", &mut state);
+        assert_eq!(state.last_status, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_assignments_alone_and_before_a_command() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let read = || fs::read_to_string(&out).unwrap();
+        let mut state = ShellState::new();
+
+        run_line("FOO=bar SPACED=\"a  b\"", &mut state);
+        assert_eq!(state.vars.get("FOO"), Some("bar"));
+        assert_eq!(state.vars.get("SPACED"), Some("a  b"));
+        assert!(!state.vars.is_exported("FOO"));
+
+        // A prefix is in the command's environment and gone afterwards.
+        run_line(
+            &format!("FOO=scoped sh -c 'echo $FOO' > {}", out.display()),
+            &mut state,
+        );
+        assert_eq!(read(), "scoped\n");
+        assert_eq!(state.vars.get("FOO"), Some("bar"));
+        assert!(!state.vars.is_exported("FOO"));
+
+        // As in bash, the command's own words expand before the prefix
+        // applies.
+        run_line("FOO=old", &mut state);
+        run_line(
+            &format!("FOO=bar sh -c 'echo \"$0 $FOO\"' $FOO > {}", out.display()),
+            &mut state,
+        );
+        assert_eq!(read(), "old bar\n");
+
+        // Values get tilde expansion, and the command is looked up with the
+        // prefix in place.
+        run_line("HOME=/home/user", &mut state);
+        run_line(
+            &format!("DIR=~/docs sh -c 'echo $DIR' > {}", out.display()),
+            &mut state,
+        );
+        assert_eq!(read(), "/home/user/docs\n");
+        let bin = tempfile::TempDir::new().unwrap();
+        fs::write(bin.path().join("only-here"), "#!/bin/sh\necho found\n").unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(
+            bin.path().join("only-here"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        let line = format!(
+            "PATH={}:$PATH only-here > {}",
+            bin.path().display(),
+            out.display()
+        );
+        run_line(&line, &mut state);
+        assert_eq!(read(), "found\n");
+        run_line("only-here 2> /dev/null", &mut state);
+        assert_eq!(state.last_status, 127);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_type_looks_up_the_shell_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let read = || fs::read_to_string(&out).unwrap();
+        let bin = dir.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        let tool = bin.join("only-here");
+        fs::write(&tool, "").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut state = ShellState::new();
+
+        run_line(&format!("export PATH={}:$PATH", bin.display()), &mut state);
+        run_line(&format!("type only-here > {}", out.display()), &mut state);
+        assert_eq!(read(), format!("only-here is {}\n", tool.display()));
+        run_line(
+            &format!("type -a only-here > {}", out.display()),
+            &mut state,
+        );
+        assert_eq!(read(), format!("only-here is {}\n", tool.display()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_type_all_lists_every_match_in_path_order() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut tools = Vec::new();
+        for name in ["first", "second"] {
+            let bin = dir.path().join(name);
+            fs::create_dir(&bin).unwrap();
+            let tool = bin.join("tool");
+            fs::write(&tool, "").unwrap();
+            fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+            tools.push(fs::canonicalize(tool).unwrap());
+        }
+        let mut state = ShellState::new();
+
+        run_line(
+            &format!(
+                "PATH={}:{}",
+                tools[0].parent().unwrap().display(),
+                tools[1].parent().unwrap().display()
+            ),
+            &mut state,
+        );
+        run_line(&format!("type -a tool > {}", out.display()), &mut state);
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            format!(
+                "tool is {}\ntool is {}\n",
+                tools[0].display(),
+                tools[1].display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_substring_with_negative_offset() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let read = || fs::read_to_string(&out).unwrap();
+        let mut state = ShellState::new();
+
+        run_line("v=abcdef", &mut state);
+        run_line(
+            &format!("echo ${{v: -2}} ${{v: -2:1}} > {}", out.display()),
+            &mut state,
+        );
+        assert_eq!(read(), "ef e\n");
+        assert_eq!(state.last_status, 0);
+    }
+
+    #[test]
+    fn test_export_fails_on_invalid_identifier() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let err = dir.path().join("err");
+        let mut state = ShellState::new();
+
+        run_line(
+            &format!("export 1x=y OK=1 2> {}", err.display()),
+            &mut state,
+        );
+        assert_eq!(
+            fs::read_to_string(&err).unwrap(),
+            "export: `1x=y': not a valid identifier\n"
+        );
+        assert_eq!(state.last_status, 1);
+        assert!(state.vars.is_exported("OK"));
+    }
+
+    #[test]
+    fn test_shell_keeps_state_across_lines() {
+        let mut shell = Shell::new();
+        assert!(shell.run_line("export GREETING=hello"));
+        assert!(shell.run_line("greet() { export SEEN=\"$GREETING $1\"; }"));
+        shell.run_script("greet world\nfalse\n");
+        assert_eq!(shell.state.vars.get("SEEN"), Some("hello world"));
+        assert_eq!(shell.last_status(), 1);
+
+        assert!(!shell.run_line("exit"));
+        assert!(shell.state.exit_requested);
+    }
+
+    #[test]
+    fn test_builtin_skips_function_of_same_name() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut state = ShellState::new();
+        run_line("echo() { printf shadowed; }", &mut state);
+
+        run_line(
+            &format!("builtin echo real > {}", out.display()),
+            &mut state,
+        );
+        assert_eq!(fs::read_to_string(&out).unwrap(), "real\n");
+        run_line(
+            &format!("builtin builtin echo twice > {}", out.display()),
+            &mut state,
+        );
+        assert_eq!(fs::read_to_string(&out).unwrap(), "twice\n");
+        run_line(&format!("echo > {}", out.display()), &mut state);
+        assert_eq!(fs::read_to_string(&out).unwrap(), "shadowed");
+
+        // Programs are not builtins.
+        run_line("builtin sh -c true 2> /dev/null", &mut state);
+        assert_eq!(state.last_status, 1);
+    }
+
+    #[test]
+    fn test_command_not_found_handle_gets_missing_command() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut state = ShellState::new();
+        let script = format!(
+            "command_not_found_handle() {{\n  echo \"$# $*\" > {}\n  no_such_command_in_handler 2> /dev/null\n  return 127\n}}\n",
+            out.display()
+        );
+        run_script(&script, &mut state);
+
+        run_line("no_such_command_here 'a b' c", &mut state);
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "3 no_such_command_here a b c\n"
+        );
+        assert_eq!(state.last_status, 127);
+    }
+
+    #[test]
+    fn test_return_stops_function_with_status() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut state = ShellState::new();
+
+        let script = format!(
+            "early() {{\n  echo before > {0}\n  return 2; echo same-line >> {0}\n  echo after >> {0}\n}}\nearly\necho $? >> {0}\n",
+            out.display()
+        );
+        run_script(&script, &mut state);
+        assert_eq!(fs::read_to_string(&out).unwrap(), "before\n2\n");
+        assert_eq!(state.function_depth, 0);
+
+        // Outside a function, `return` fails and the line goes on.
+        run_line("return 2> /dev/null; export STILL=yes", &mut state);
+        assert_eq!(state.vars.get("STILL"), Some("yes"));
+        run_line("return 2> /dev/null", &mut state);
+        assert_eq!(state.last_status, 1);
+    }
+
+    #[test]
+    fn test_local_does_not_leak_to_caller() {
+        let mut state = ShellState::new();
+        run_line("export SCOPED=outer", &mut state);
+        run_script(
+            "scoped() {\n  local SCOPED=inner FRESH=1\n  export SEEN=$SCOPED\n}\nscoped\n",
+            &mut state,
+        );
+        assert_eq!(state.vars.get("SEEN"), Some("inner"));
+        assert_eq!(state.vars.get("SCOPED"), Some("outer"));
+        assert_eq!(state.vars.get("FRESH"), None);
+        assert!(state.vars.environment().contains(&("SCOPED", "outer")));
+
+        run_line("local NOPE=1 2> /dev/null", &mut state);
+        assert_eq!(state.last_status, 1);
+        assert_eq!(state.vars.get("NOPE"), None);
+    }
+
+    #[test]
+    fn test_describe_pipeline_names_commands_and_redirects() {
+        let mut state = ShellState::new();
+        let stages = parse_pipeline(
+            tokenize("echo hi | wc -l 2> /dev/null").unwrap(),
+            &mut state,
+        )
+        .unwrap();
+        let description = describe_pipeline(&stages);
+        let lines: Vec<&str> = description.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("Echo { args: [\"hi\"]"));
+        assert!(lines[1].starts_with("Exec { command: \"wc\", args: [\"-l\"] }"));
+        assert!(lines[1].contains("stderr: NullOutput"));
+    }
+
+    #[test]
+    fn test_fd_duplication_follows_redirects_and_pipes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut state = ShellState::new();
+        let read = || fs::read_to_string(&out).unwrap();
+
+        run_line(
+            &format!("sh -c 'echo o; echo e >&2' > {} 2>&1", out.display()),
+            &mut state,
+        );
+        assert_eq!(read(), "o\ne\n");
+        run_line(&format!("echo moved 2> {} >&2", out.display()), &mut state);
+        assert_eq!(read(), "moved\n");
+
+        // Into a pipe, from an external command and from a builtin.
+        let (piped, _) = run_captured("sh -c 'echo e >&2' 2>&1 | tr e E");
+        assert_eq!(piped, "E\n");
+        let (piped, _) = run_captured("cd /no/such/dir 2>&1 | wc -l");
+        assert_eq!(piped.trim(), "1");
+
+        // Output and errors keep the order they were written in.
+        let (piped, _) = run_captured("type echo no_such_command_here 2>&1 | tr a-z A-Z");
+        assert!(piped.starts_with("ECHO IS A SHELL BUILTIN\n"), "{}", piped);
+        assert!(piped.contains("NO_SUCH_COMMAND_HERE"));
+        run_line("f() { echo out; ls /no/such/dir; }", &mut state);
+        run_line(
+            &format!("f 2>&1 | tr a-z A-Z > {}", out.display()),
+            &mut state,
+        );
+        assert!(read().starts_with("OUT\nLS: "), "{}", read());
+        run_line("f 2>&1 | mapfile lines", &mut state);
+        run_line(
+            &format!("echo \"${{lines[*]}}\" > {}", out.display()),
+            &mut state,
+        );
+        assert!(read().starts_with("out ls: "), "{}", read());
+    }
+
+    #[test]
+    fn test_crlf_mode_translates_redirected_lines() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut state = ShellState::new();
+        run_line("export SHELL_EOL=crlf", &mut state);
+        let line = format!(
+            "echo one > {0}; printf 'two\\r\\n' >> {0}; sh -c 'echo three' >> {0}",
+            out.display()
+        );
+        run_line(&line, &mut state);
+        assert_eq!(fs::read(&out).unwrap(), b"one\r\ntwo\r\nthree\r\n");
+
+        run_line("export SHELL_EOL=lf", &mut state);
+        run_line(&format!("echo four > {}", out.display()), &mut state);
+        assert_eq!(fs::read(&out).unwrap(), b"four\n");
+    }
+
+    #[test]
+    fn test_script_from_reader_captures_output_and_status() {
+        let script = b"echo one\nshout() {\n  echo \"$1!\" | tr a-z A-Z\n}\nshout two\nls /no/such/dir\nfalse\n";
+        let (out, err) = (CaptureOutput::new(), CaptureOutput::new());
+        let mut streams = OutputStreams::new(Box::new(out.clone()), Box::new(err.clone()));
+        let status = run_script_from_reader(&script[..], &mut streams);
+        assert_eq!(out.contents(), "one\nTWO!\n");
+        assert!(err.contents().contains("/no/such/dir"));
+        assert_eq!(status, 1);
+
+        let mut streams = OutputStreams::new(Box::new(out.clone()), Box::new(err.clone()));
+        assert_eq!(run_script_from_reader(&b"true"[..], &mut streams), 0);
+    }
+
+    #[test]
+    fn test_script_from_reader_runs_lines_as_they_arrive() {
+        // A reader that fails once the first line has been handed out.
+        struct FirstLine(Option<&'static [u8]>);
+        impl std::io::Read for FirstLine {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let line = self.0.take().ok_or(std::io::ErrorKind::BrokenPipe)?;
+                buf[..line.len()].copy_from_slice(line);
+                Ok(line.len())
+            }
+        }
+        let (out, err) = (CaptureOutput::new(), CaptureOutput::new());
+        let mut streams = OutputStreams::new(Box::new(out.clone()), Box::new(err.clone()));
+        let reader = std::io::BufReader::new(FirstLine(Some(b"echo caf\xe9\n")));
+        assert_eq!(run_script_from_reader(reader, &mut streams), 1);
+        assert_eq!(out.contents(), "caf\u{fffd}\n");
+        assert!(!err.contents().is_empty());
+    }
+
+    #[test]
+    fn test_sourced_errors_name_file_and_line() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let script = dir.path().join("setup.sh");
+        fs::write(
+            &script,
+            "echo one\n\nno_such_command_here\necho 'two' \\\n  three\necho 'open\n",
+        )
+        .unwrap();
+        let (out, err) = (CaptureOutput::new(), CaptureOutput::new());
+        let mut streams = OutputStreams::new(Box::new(out.clone()), Box::new(err.clone()));
+        let line = format!("source {}\nno_such_command_here", script.display());
+        run_script_from_reader(line.as_bytes(), &mut streams);
+
+        assert_eq!(out.contents(), "one\ntwo three\n");
+        let name = script.display();
+        assert_eq!(
+            err.contents(),
+            format!(
+                "{0}: line 3: no_such_command_here: command not found\n\
+                 {0}: line 6: unexpected EOF while looking for matching `''\n\
+                 no_such_command_here: command not found\n",
+                name
+            )
+        );
+    }
+
+    #[test]
+    fn test_pasted_lines_run_in_order() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut state = ShellState::new();
+        let paste = format!(
+            "echo one > {0}\necho two >> {0}; echo three >> {0}\necho four \\\n  five >> {0}\nf() {{\n  echo six >> {0}\n}}\nf",
+            out.display()
+        );
+        run_script(&paste, &mut state);
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "one\ntwo\nthree\nfour five\nsix\n"
+        );
+
+        // An unfinished last line is reported once the paste ends.
+        run_script("echo ok > /dev/null\ng() {", &mut state);
+        assert_eq!(state.last_status, 1);
+        assert!(!state.functions.contains("g"));
+    }
+
+    #[test]
+    fn test_case_runs_first_matching_branch() {
+        let mut state = ShellState::new();
+        let classify = |value: &str, state: &mut ShellState| {
+            let line = format!(
+                "case \"{}\" in a) export KIND=A;; b|c) export KIND=BC;; *) export KIND=other;; esac",
+                value
+            );
+            run_line(&line, state);
+            state.vars.get("KIND").map(str::to_string)
+        };
+        assert_eq!(classify("a", &mut state).as_deref(), Some("A"));
+        assert_eq!(classify("c", &mut state).as_deref(), Some("BC"));
+        assert_eq!(classify("zz", &mut state).as_deref(), Some("other"));
+        assert_eq!(classify("*", &mut state).as_deref(), Some("other"));
+    }
+
+    #[test]
+    fn test_case_spans_lines_and_quotes_patterns() {
+        let mut state = ShellState::new();
+        run_script(
+            "export WORD='a*'\ncase $WORD in\n  'a*')\n    export MATCHED=literal\n    ;;\n  a*) export MATCHED=glob;;\nesac; export AFTER=yes\n",
+            &mut state,
+        );
+        assert_eq!(state.vars.get("MATCHED"), Some("literal"));
+        assert_eq!(state.vars.get("AFTER"), Some("yes"));
+
+        // No match leaves a status of 0.
+        run_line("false", &mut state);
+        run_line("case x in y) false;; esac", &mut state);
+        assert_eq!(state.last_status, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_process_substitution_reads_command_output() {
+        let (out, err) = run_captured("cat <(echo hi)");
+        assert_eq!((out.as_str(), err.as_str()), ("hi\n", ""));
+
+        let (out, _) = run_captured("paste <(printf 'a\\nb\\n') <(seq 2 | tr 12 xy)");
+        assert_eq!(out, "a\tx\nb\ty\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_function_output_streams_to_the_next_stage() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (out, copy) = (dir.path().join("out"), dir.path().join("copy"));
+        let mut state = ShellState::new();
+        // The reader sees the first line while the function is still running.
+        let script = format!(
+            "f() {{ echo first; sleep 0.5; cat {0} > {1}; }}\nf | sh -c 'read line; echo \"$line\" > {0}'\n",
+            out.display(),
+            copy.display()
+        );
+        run_script(&script, &mut state);
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "first\n");
+    }
+
+    #[test]
+    fn test_getopts_reads_positional_parameters() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut state = ShellState::new();
+        let script = format!(
+            "f() {{ getopts ab opt; echo $opt > {}; }}\nf -b\n",
+            out.display()
+        );
+        run_script(&script, &mut state);
+        assert_eq!(fs::read_to_string(&out).unwrap(), "b\n");
     }
 
-    if let Err(e) = execute_command(
-        last_command,
-        previous_stdout,
-        Some(&mut *streams.stdout),
-        &mut *streams.stderr,
-        history,
-    ) {
-        streams.stderr.print(&e.to_string());
+    #[test]
+    fn test_version_is_semver() {
+        let parts: Vec<&str> = version().split('.').collect();
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.parse::<u64>().is_ok()));
     }
 }
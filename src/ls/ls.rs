@@ -0,0 +1,265 @@
+use anyhow::{Result, anyhow};
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::files::io_message;
+use crate::os_text::{display_lossy, os_to_string, string_to_os};
+
+/// Flags accepted by the `ls` builtin.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LsOptions {
+    /// `-a`: include names starting with `.`, along with `.` and `..`.
+    pub all: bool,
+    /// `-l`: permissions, size and modification time before each name.
+    pub long: bool,
+    /// `-1`: one name per line, even on a terminal.
+    pub one_per_line: bool,
+}
+
+impl LsOptions {
+    /// Applies a flag word such as `-la`, returning `false` if it holds a
+    /// flag `ls` does not know.
+    pub fn apply(&mut self, flags: &str) -> bool {
+        for flag in flags.trim_start_matches('-').chars() {
+            match flag {
+                'a' => self.all = true,
+                'l' => self.long = true,
+                '1' => self.one_per_line = true,
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+struct Entry {
+    name: String,
+    metadata: Metadata,
+}
+
+/// Lists the entries of the directory at `path`, sorted by name, or just
+/// `path` itself when it is a file. On a terminal, short listings go on one
+/// line and directories are colored.
+pub fn list(path: &str, options: &LsOptions, terminal: bool) -> Result<String> {
+    let metadata = fs::metadata(string_to_os(path))
+        .map_err(|e| anyhow!("ls: cannot access '{}': {}", path, io_message(&e)))?;
+    let mut entries = if metadata.is_dir() {
+        read_entries(Path::new(&string_to_os(path)), options.all)
+            .map_err(|e| anyhow!("ls: cannot open directory '{}': {}", path, io_message(&e)))?
+    } else {
+        vec![Entry {
+            name: path.to_string(),
+            metadata,
+        }]
+    };
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let names = entries
+        .iter()
+        .map(|entry| paint(&entry.name, entry.metadata.is_dir(), terminal));
+    if options.long {
+        let width = entries
+            .iter()
+            .map(|entry| entry.metadata.len().to_string().len())
+            .max()
+            .unwrap_or_default();
+        return Ok(entries
+            .iter()
+            .zip(names)
+            .map(|(entry, name)| {
+                format!(
+                    "{} {:>width$} {} {}",
+                    mode_string(&entry.metadata),
+                    entry.metadata.len(),
+                    entry
+                        .metadata
+                        .modified()
+                        .map(format_time)
+                        .unwrap_or_default(),
+                    name,
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"));
+    }
+    let separator = if terminal && !options.one_per_line {
+        "  "
+    } else {
+        "\n"
+    };
+    Ok(names.collect::<Vec<String>>().join(separator))
+}
+
+fn read_entries(dir: &Path, all: bool) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    if all {
+        for name in [".", ".."] {
+            let metadata = fs::metadata(dir.join(name))?;
+            entries.push(Entry {
+                name: name.to_string(),
+                metadata,
+            });
+        }
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = os_to_string(&entry.file_name());
+        if name.starts_with('.') && !all {
+            continue;
+        }
+        // Follow symlinks so a link to a directory is listed like one.
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => entry.metadata()?,
+        };
+        entries.push(Entry { name, metadata });
+    }
+    Ok(entries)
+}
+
+/// Wraps a directory name in bold blue for a terminal.
+fn paint(name: &str, is_dir: bool, terminal: bool) -> String {
+    let name = display_lossy(name);
+    if is_dir && terminal {
+        format!("\x1b[1;34m{}\x1b[0m", name)
+    } else {
+        name
+    }
+}
+
+/// The type and permission column of `ls -l`, e.g. `drwxr-xr-x`.
+fn mode_string(metadata: &Metadata) -> String {
+    let kind = if metadata.is_dir() { 'd' } else { '-' };
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode()
+    };
+    #[cfg(not(unix))]
+    let mode = if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o666
+    };
+    let mut text = String::from(kind);
+    for shift in [6, 3, 0] {
+        let bits = mode >> shift;
+        text.push(if bits & 4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 2 != 0 { 'w' } else { '-' });
+        text.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    text
+}
+
+/// Formats `time` as `YYYY-MM-DD HH:MM` in UTC.
+fn format_time(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default();
+    let (days, rest) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+    let (year, month, day) = civil_date(days);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest / 60 % 60
+    )
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date in the
+/// proleptic Gregorian calendar.
+fn civil_date(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample_dir() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("b.txt"), "").unwrap();
+        fs::write(dir.path().join("a.txt"), "abc").unwrap();
+        fs::write(dir.path().join(".hidden"), "").unwrap();
+        fs::create_dir(dir.path().join("C")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lists_sorted_without_dotfiles() {
+        let dir = sample_dir();
+        let path = dir.path().to_str().unwrap();
+        let text = list(path, &LsOptions::default(), false).unwrap();
+        assert_eq!(text, "C\na.txt\nb.txt");
+
+        let text = list(path, &LsOptions::default(), true).unwrap();
+        assert_eq!(text, "\x1b[1;34mC\x1b[0m  a.txt  b.txt");
+    }
+
+    #[test]
+    fn test_all_includes_dotfiles() {
+        let dir = sample_dir();
+        let mut options = LsOptions::default();
+        assert!(options.apply("-a1"));
+        let text = list(dir.path().to_str().unwrap(), &options, true).unwrap();
+        let names: Vec<&str> = text.lines().collect();
+        assert_eq!(names[0], "\x1b[1;34m.\x1b[0m");
+        assert_eq!(
+            &names[2..],
+            [".hidden", "\x1b[1;34mC\x1b[0m", "a.txt", "b.txt"]
+        );
+    }
+
+    #[test]
+    fn test_long_format_and_errors() {
+        let dir = sample_dir();
+        let file = dir.path().join("a.txt");
+        let options = LsOptions {
+            long: true,
+            ..LsOptions::default()
+        };
+        let text = list(file.to_str().unwrap(), &options, false).unwrap();
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        assert!(fields[0].starts_with("-rw"), "{}", text);
+        assert_eq!(fields[1], "3");
+        assert_eq!(fields[4], file.to_str().unwrap());
+
+        let missing = dir.path().join("missing");
+        let error = list(missing.to_str().unwrap(), &options, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "ls: cannot access '{}': No such file or directory",
+                missing.display()
+            )
+        );
+        assert!(!LsOptions::default().apply("-z"));
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00");
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1_709_210_096);
+        assert_eq!(format_time(time), "2024-02-29 12:34");
+    }
+}
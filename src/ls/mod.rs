@@ -0,0 +1,2 @@
+pub use ls::*;
+pub mod ls;
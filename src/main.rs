@@ -1,14 +1,29 @@
 use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::path::Path;
 
-use codecrafters_shell::completer::ShellCompleter;
+use codecrafters_shell::completer::{ShellCompleter, completion_limit};
 use codecrafters_shell::finder::ExecutablesFinder;
-use codecrafters_shell::parser::{parse_pipeline, parse_prompt};
-use rustyline::error::ReadlineError;
+use codecrafters_shell::terminal::{apply_terminal_size, terminal_size, watch_resize};
 use rustyline::{CompletionType, Config, Editor};
 
-use codecrafters_shell::{History, builtin_commands, handle_pipeline};
+use codecrafters_shell::{
+    SYSTEM_PROFILE, Shell, builtin_commands, next_shell_level, profile_paths, rc_path, version,
+};
 
 fn main() {
+    if std::env::args()
+        .skip(1)
+        .any(|arg| arg == "--version" || arg == "-V")
+    {
+        println!("{}", version());
+        return;
+    }
+
+    let shell_level = next_shell_level(std::env::var("SHLVL").ok().as_deref());
+    // SAFETY: no other threads exist yet to read the environment.
+    unsafe { std::env::set_var("SHLVL", shell_level.to_string()) };
+
     let path_executables = ExecutablesFinder::new().find_executables_in_path().unwrap();
 
     let builtin_commands = builtin_commands();
@@ -19,37 +34,50 @@ fn main() {
         .into_iter()
         .collect::<Vec<String>>();
 
+    // Past the limit the editor asks before listing and pages the matches.
+    let limit = completion_limit(std::env::var("COMPLETION_LIMIT").ok().as_deref());
     let config = Config::builder()
         .completion_type(CompletionType::List)
-        .completion_prompt_limit(100)
+        .completion_prompt_limit(limit)
         .build();
     let mut rl = Editor::with_config(config).unwrap();
-    rl.set_helper(Some(ShellCompleter::new(all_commands)));
-
-    let mut history = History::new();
-    loop {
-        match rl.readline("$ ") {
-            Ok(line) => {
-                let prompt = line.trim();
-                if prompt.is_empty() {
-                    continue;
-                }
-
-                // rl.add_history_entry(&line).ok();
-                history.add_history_item(&line).ok();
-
-                match parse_pipeline(parse_prompt(prompt)) {
-                    Ok((command, mut streams)) => handle_pipeline(command, &mut streams, &history),
-                    Err(error) => eprintln!("{}: {}", prompt, error),
-                }
-            }
-            Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
-                break;
-            }
-            Err(err) => {
-                eprintln!("error: {:?}", err);
-                break;
-            }
+    let mut shell = Shell::new();
+    rl.set_helper(Some(
+        ShellCompleter::new(all_commands).with_specs(shell.state.completions.clone()),
+    ));
+    shell.state.interactive = std::io::stdin().is_terminal();
+    if shell.state.interactive {
+        apply_terminal_size(terminal_size(), &mut shell.state.vars);
+        shell.state.resized = watch_resize().ok();
+    }
+    shell.state.options.posix = std::env::args().skip(1).any(|arg| arg == "--posix");
+    shell.state.options.debugparse = std::env::args().skip(1).any(|arg| arg == "--debug");
+    let norc = std::env::args().skip(1).any(|arg| arg == "--norc");
+    // As with other shells, a leading `-` in argv[0] also means a login shell.
+    let login = std::env::args()
+        .next()
+        .is_some_and(|arg| arg.starts_with('-'))
+        || std::env::args()
+            .skip(1)
+            .any(|arg| arg == "--login" || arg == "-l");
+    if login {
+        // A login shell reads its profiles instead of the rc file, which
+        // they can source themselves.
+        let home = dirs::home_dir();
+        for path in profile_paths(Path::new(SYSTEM_PROFILE), home.as_deref()) {
+            shell.source_rc(&path);
         }
+    } else if shell.state.interactive
+        && !norc
+        && let Some(path) = rc_path(
+            std::env::var("SHELLRC").ok().as_deref(),
+            dirs::home_dir().as_deref(),
+        )
+    {
+        shell.source_rc(&path);
     }
+    shell.run_repl(&mut rl);
+
+    // Also covers leaving via end of input; a trap already run by `exit` is gone.
+    shell.finish();
 }
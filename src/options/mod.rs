@@ -0,0 +1,2 @@
+pub use options::*;
+pub mod options;
@@ -0,0 +1,68 @@
+/// Shell options toggled with `set -o name` / `set +o name`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ShellOptions {
+    /// Refuse to overwrite existing files with `>`; `>|` still can.
+    pub noclobber: bool,
+    /// Expand a glob that matches nothing to no words at all.
+    pub nullglob: bool,
+    /// Treat expanding an unset variable as an error (`set -u`).
+    pub nounset: bool,
+    /// Treat a glob that matches nothing as an error.
+    pub failglob: bool,
+    /// Print the resolved path of each external command to stderr before
+    /// running it.
+    pub showpath: bool,
+    /// Turn off behavior that POSIX does not describe, such as the flags of
+    /// `echo` other than `-n`.
+    pub posix: bool,
+    /// Print each parsed pipeline to stderr before running it.
+    pub debugparse: bool,
+    /// Create the missing parent directories of a `>` or `>>` target
+    /// instead of failing.
+    pub mkdirredirect: bool,
+    /// Make `echo` interpret backslash escapes without `-e`, as bash's
+    /// `xpg_echo`; `-E` still turns them off.
+    pub xpg_echo: bool,
+}
+
+impl ShellOptions {
+    /// Option names in the order `set -o` lists them.
+    pub const NAMES: &[&str] = &[
+        "debugparse",
+        "failglob",
+        "mkdirredirect",
+        "noclobber",
+        "nounset",
+        "nullglob",
+        "posix",
+        "showpath",
+        "xpg_echo",
+    ];
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "noclobber" => Some(&mut self.noclobber),
+            "nullglob" => Some(&mut self.nullglob),
+            "nounset" => Some(&mut self.nounset),
+            "failglob" => Some(&mut self.failglob),
+            "showpath" => Some(&mut self.showpath),
+            "posix" => Some(&mut self.posix),
+            "debugparse" => Some(&mut self.debugparse),
+            "mkdirredirect" => Some(&mut self.mkdirredirect),
+            "xpg_echo" => Some(&mut self.xpg_echo),
+            _ => None,
+        }
+    }
+
+    /// The name of the option set by a single letter, as in `set -u`.
+    pub fn letter_name(letter: char) -> Option<&'static str> {
+        match letter {
+            'u' => Some("nounset"),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,2 @@
+pub use os_text::*;
+pub mod os_text;
@@ -0,0 +1,125 @@
+use std::ffi::{OsStr, OsString};
+
+/// On Unix a file name can be any bytes. Each byte that is not part of valid
+/// UTF-8 is carried in a `String` as the private-use character U+F700 plus
+/// the byte (U+F780 to U+F7FF), and turns back into that byte on its way to
+/// the OS. Names that really contain those characters are mangled, which is
+/// the price of the scheme.
+const RAW_BYTE_BASE: u32 = 0xF700;
+
+/// Converts an OS string to a `String`, encoding invalid bytes so that
+/// [`string_to_os`] restores the original exactly.
+#[cfg(unix)]
+pub fn os_to_string(text: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut decoded = String::new();
+    for chunk in text.as_bytes().utf8_chunks() {
+        decoded.push_str(chunk.valid());
+        // Invalid sequences never contain ASCII bytes, so every byte here
+        // lands inside the private range.
+        decoded.extend(
+            chunk
+                .invalid()
+                .iter()
+                .filter_map(|byte| char::from_u32(RAW_BYTE_BASE + u32::from(*byte))),
+        );
+    }
+    decoded
+}
+
+#[cfg(not(unix))]
+pub fn os_to_string(text: &OsStr) -> String {
+    text.to_string_lossy().to_string()
+}
+
+/// Converts a string from [`os_to_string`] back into the OS string it came
+/// from.
+#[cfg(unix)]
+pub fn string_to_os(text: &str) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match raw_byte(c) {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub fn string_to_os(text: &str) -> OsString {
+    OsString::from(text)
+}
+
+/// The bytes `text` stands for, with encoded raw bytes restored; what a
+/// builtin writes into a pipe or file.
+#[cfg(unix)]
+pub fn text_bytes(text: &str) -> Vec<u8> {
+    use std::os::unix::ffi::OsStringExt;
+    string_to_os(text).into_vec()
+}
+
+#[cfg(not(unix))]
+pub fn text_bytes(text: &str) -> Vec<u8> {
+    text.as_bytes().to_vec()
+}
+
+/// Decodes bytes read from a child process, keeping invalid UTF-8 the way
+/// [`os_to_string`] does.
+#[cfg(unix)]
+pub fn bytes_to_text(bytes: &[u8]) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    os_to_string(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn bytes_to_text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).to_string()
+}
+
+/// `text` for showing to a person: encoded raw bytes become U+FFFD, as
+/// `to_string_lossy` would show them.
+pub fn display_lossy(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if raw_byte(c).is_some() {
+                char::REPLACEMENT_CHARACTER
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+fn raw_byte(c: char) -> Option<u8> {
+    u32::from(c)
+        .checked_sub(RAW_BYTE_BASE)
+        .filter(|byte| (0x80..=0xFF).contains(byte))
+        .map(|byte| byte as u8)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_invalid_bytes_round_trip() {
+        let original = OsStr::from_bytes(b"caf\xe9-\xff\xfe.txt");
+        let text = os_to_string(original);
+        assert_eq!(text.chars().count(), 11);
+        assert_eq!(string_to_os(&text), original);
+        assert_eq!(display_lossy(&text), "caf\u{fffd}-\u{fffd}\u{fffd}.txt");
+        assert_eq!(bytes_to_text(original.as_bytes()), text);
+        assert_eq!(text_bytes(&text), original.as_bytes());
+    }
+
+    #[test]
+    fn test_valid_utf8_is_unchanged() {
+        let text = os_to_string(OsStr::new("naïve file"));
+        assert_eq!(text, "naïve file");
+        assert_eq!(string_to_os(&text), OsStr::new("naïve file"));
+        assert_eq!(display_lossy(&text), text);
+    }
+}
@@ -1,14 +1,49 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
+use os_pipe::{PipeReader, PipeWriter};
+use std::cell::RefCell;
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process::Stdio;
+use std::rc::Rc;
 
-pub trait Output {
-    fn print(&mut self, text: &str);
+use crate::os_text::{string_to_os, text_bytes};
+
+/// Where a command's output goes. The `Debug` output names the target, for
+/// `set -o debugparse`.
+pub trait Output: fmt::Debug {
+    /// Writes `text` as-is, without a trailing newline.
+    fn write(&mut self, text: &str);
+    fn print(&mut self, text: &str) {
+        self.write(text);
+        self.write("\n");
+    }
     fn is_redirected(&self) -> bool {
         false
     }
+    /// Whether output goes straight to a terminal, for builtins that format
+    /// differently there.
+    fn is_terminal(&self) -> bool {
+        false
+    }
+    /// A handle a child process can write to directly, bypassing `print`.
+    fn as_stdio(&self) -> Option<Stdio> {
+        None
+    }
+    /// Another output writing to the same place, for `2>&1` and `>&2`.
+    fn duplicate(&self) -> Result<Box<dyn Output>>;
+    /// Whether this is stderr sent by `2>&1` to a stdout that is not
+    /// redirected, and so goes wherever the stage's stdout ends up.
+    fn follows_stdout(&self) -> bool {
+        false
+    }
+    /// Pushes out anything written but held back. Most outputs write
+    /// straight through, leaving nothing to do.
+    fn flush(&mut self) {}
 }
 
+#[derive(Debug, Default)]
 pub struct StdOutput;
 
 impl StdOutput {
@@ -18,11 +53,29 @@ impl StdOutput {
 }
 
 impl Output for StdOutput {
+    fn write(&mut self, text: &str) {
+        print!("{}", text);
+        let _ = io::stdout().flush();
+    }
+
     fn print(&mut self, text: &str) {
         println!("{}", text);
     }
+
+    fn is_terminal(&self) -> bool {
+        io::stdout().is_terminal()
+    }
+
+    fn flush(&mut self) {
+        let _ = io::stdout().flush();
+    }
+
+    fn duplicate(&self) -> Result<Box<dyn Output>> {
+        Ok(Box::new(StdOutFollower))
+    }
 }
 
+#[derive(Debug, Default)]
 pub struct StdErrOutput;
 
 impl StdErrOutput {
@@ -32,58 +85,283 @@ impl StdErrOutput {
 }
 
 impl Output for StdErrOutput {
+    fn write(&mut self, text: &str) {
+        eprint!("{}", text);
+    }
+
     fn print(&mut self, text: &str) {
         eprintln!("{}", text);
     }
+
+    fn duplicate(&self) -> Result<Box<dyn Output>> {
+        Ok(Box::new(StdErrRedirect))
+    }
+}
+
+/// Stdout sent to the shell's stderr by `>&2`.
+#[derive(Debug)]
+pub struct StdErrRedirect;
+
+impl Output for StdErrRedirect {
+    fn write(&mut self, text: &str) {
+        eprint!("{}", text);
+    }
+
+    fn print(&mut self, text: &str) {
+        eprintln!("{}", text);
+    }
+
+    fn is_redirected(&self) -> bool {
+        true
+    }
+
+    fn as_stdio(&self) -> Option<Stdio> {
+        Some(Stdio::from(io::stderr()))
+    }
+
+    fn duplicate(&self) -> Result<Box<dyn Output>> {
+        Ok(Box::new(StdErrRedirect))
+    }
+}
+
+/// Stderr sent by `2>&1` while stdout is not redirected. It writes to the
+/// shell's stdout, but a command whose stdout feeds the next stage of a
+/// pipeline sends its stderr down the same pipe.
+#[derive(Debug)]
+pub struct StdOutFollower;
+
+impl Output for StdOutFollower {
+    fn write(&mut self, text: &str) {
+        print!("{}", text);
+        let _ = io::stdout().flush();
+    }
+
+    fn is_redirected(&self) -> bool {
+        true
+    }
+
+    fn as_stdio(&self) -> Option<Stdio> {
+        Some(Stdio::from(io::stdout()))
+    }
+
+    fn duplicate(&self) -> Result<Box<dyn Output>> {
+        Ok(Box::new(StdOutFollower))
+    }
+
+    fn follows_stdout(&self) -> bool {
+        true
+    }
 }
 
 pub struct FileOutput {
     file: File,
+    path: String,
+    /// Write each `\n` as `\r\n`, for files meant for Windows tools.
+    crlf: bool,
+    /// Whether the last character written was a `\r`, so that a `\r\n`
+    /// split across two writes is not translated again.
+    after_cr: bool,
 }
 
 impl FileOutput {
     pub fn new(path: &str, append: bool) -> Result<Self> {
+        if Path::new(&string_to_os(path)).is_dir() {
+            return Err(anyhow!("{}: Is a directory", path));
+        }
         let file = if append {
-            OpenOptions::new().create(true).append(true).open(path)
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(string_to_os(path))
         } else {
-            File::create(path)
+            File::create(string_to_os(path))
         }
         .with_context(|| format!("{}: cannot open file", path))?;
 
-        Ok(Self { file })
+        Ok(Self {
+            file,
+            path: path.to_string(),
+            crlf: false,
+            after_cr: false,
+        })
+    }
+
+    /// Translates line endings to `\r\n` when `crlf` is set.
+    pub fn with_crlf(mut self, crlf: bool) -> Self {
+        self.crlf = crlf;
+        self
     }
 
     pub fn try_clone(&self) -> Result<Self> {
         Ok(Self {
             file: self.file.try_clone()?,
+            path: self.path.clone(),
+            crlf: self.crlf,
+            after_cr: false,
         })
     }
 }
 
+impl fmt::Debug for FileOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FileOutput").field(&self.path).finish()
+    }
+}
+
 impl Output for FileOutput {
-    fn print(&mut self, text: &str) {
-        let _ = writeln!(self.file, "{}", text);
+    fn write(&mut self, text: &str) {
+        if !self.crlf {
+            let _ = self.file.write_all(&text_bytes(text));
+            return;
+        }
+        let mut translated = String::with_capacity(text.len());
+        for c in text.chars() {
+            if c == '\n' && !self.after_cr {
+                translated.push('\r');
+            }
+            translated.push(c);
+            self.after_cr = c == '\r';
+        }
+        let _ = self.file.write_all(&text_bytes(&translated));
     }
 
     fn is_redirected(&self) -> bool {
         true
     }
+
+    /// Commands write to the file directly unless line endings need
+    /// translating, in which case their output goes through `write`.
+    fn as_stdio(&self) -> Option<Stdio> {
+        if self.crlf {
+            return None;
+        }
+        self.file.try_clone().ok().map(Stdio::from)
+    }
+
+    fn duplicate(&self) -> Result<Box<dyn Output>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+/// Discards everything, used for redirects to `/dev/null`.
+#[derive(Debug, Default)]
+pub struct NullOutput;
+
+impl NullOutput {
+    pub fn new() -> Self {
+        Self
+    }
 }
 
+impl Output for NullOutput {
+    fn write(&mut self, _text: &str) {}
+
+    fn print(&mut self, _text: &str) {}
+
+    fn is_redirected(&self) -> bool {
+        true
+    }
+
+    fn as_stdio(&self) -> Option<Stdio> {
+        Some(Stdio::null())
+    }
+
+    fn duplicate(&self) -> Result<Box<dyn Output>> {
+        Ok(Box::new(NullOutput))
+    }
+}
+
+/// The writing end of a pipe, for a function whose output feeds the next
+/// stage of a pipeline while it runs.
+#[derive(Debug)]
+pub struct PipeOutput {
+    writer: PipeWriter,
+}
+
+impl PipeOutput {
+    pub fn new(writer: PipeWriter) -> Self {
+        Self { writer }
+    }
+}
+
+impl Output for PipeOutput {
+    fn write(&mut self, text: &str) {
+        let _ = self.writer.write_all(&text_bytes(text));
+    }
+
+    fn is_redirected(&self) -> bool {
+        true
+    }
+
+    fn as_stdio(&self) -> Option<Stdio> {
+        self.writer.try_clone().ok().map(Stdio::from)
+    }
+
+    fn duplicate(&self) -> Result<Box<dyn Output>> {
+        Ok(Box::new(Self::new(self.writer.try_clone()?)))
+    }
+}
+
+/// Collects printed lines in memory. Clones share the same buffer, so a clone
+/// can be handed to the shell while the original is kept to read the result.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureOutput {
+    buffer: Rc<RefCell<String>>,
+}
+
+impl CaptureOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> String {
+        self.buffer.borrow().clone()
+    }
+}
+
+impl Output for CaptureOutput {
+    fn write(&mut self, text: &str) {
+        self.buffer.borrow_mut().push_str(text);
+    }
+
+    fn is_redirected(&self) -> bool {
+        true
+    }
+
+    fn duplicate(&self) -> Result<Box<dyn Output>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+#[derive(Debug)]
 pub struct OutputStreams {
     pub stdout: Box<dyn Output>,
     pub stderr: Box<dyn Output>,
+    /// The file named by a `<` redirect, read instead of the pipe or terminal.
+    pub input: Option<PipeReader>,
 }
 
 impl OutputStreams {
     pub fn new(stdout: Box<dyn Output>, stderr: Box<dyn Output>) -> Self {
-        Self { stdout, stderr }
+        Self {
+            stdout,
+            stderr,
+            input: None,
+        }
     }
 
-    pub fn default() -> Self {
+    pub fn flush(&mut self) {
+        self.stdout.flush();
+        self.stderr.flush();
+    }
+}
+
+impl Default for OutputStreams {
+    fn default() -> Self {
         Self {
             stdout: Box::new(StdOutput::new()),
             stderr: Box::new(StdErrOutput::new()),
+            input: None,
         }
     }
 }
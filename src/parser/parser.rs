@@ -1,8 +1,15 @@
 use crate::{
-    Command, CommandKind,
-    output::{FileOutput, Output, OutputStreams, StdErrOutput, StdOutput},
+    Command, CommandKind, HistorySync, ShellState, Value,
+    expand::{expand_assignment, expand_word, unquote},
+    ls::LsOptions,
+    os_text::string_to_os,
+    output::{FileOutput, NullOutput, Output, OutputStreams, StdErrOutput, StdOutput},
 };
-use anyhow::{Result, anyhow};
+use anyhow::{Context, anyhow};
+use os_pipe::PipeReader;
+use std::fs::{self, File};
+use std::path::Path;
+use thiserror::Error;
 
 pub enum PromptQuote {
     Unquoted,
@@ -10,7 +17,76 @@ pub enum PromptQuote {
     DoubleQuoted,
 }
 
-pub fn parse_prompt(prompt: &str) -> Vec<String> {
+/// Errors produced while turning a command line into commands. The `Display`
+/// output is what the REPL prints.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("empty pipeline")]
+    EmptyPipeline,
+    #[error("Empty command")]
+    EmptyCommand,
+    #[error("redirect path missing")]
+    MissingRedirectTarget,
+    #[error("{0}: ambiguous redirect")]
+    AmbiguousRedirect(String),
+    #[error("syntax error near unexpected token `{0}'")]
+    UnexpectedToken(String),
+    #[error("pipeline too long: more than {limit} stages")]
+    PipelineTooLong { limit: usize },
+    #[error("unexpected EOF while looking for matching `{quote}'")]
+    UnterminatedQuote { quote: char },
+    /// A function body whose closing `}` never came.
+    #[error("syntax error: unexpected end of file")]
+    UnterminatedFunction,
+    /// A `case` statement whose `esac` never came.
+    #[error("syntax error: unexpected end of file")]
+    UnterminatedCase,
+    /// A flag the builtin does not know. Builtins that stand in for an
+    /// external tool run the tool instead.
+    #[error("{builtin}: invalid option -- '{option}'")]
+    UnsupportedOption {
+        builtin: &'static str,
+        option: String,
+    },
+    #[error("{builtin}: {message}")]
+    InvalidArgument {
+        builtin: &'static str,
+        message: &'static str,
+    },
+    /// Failures from expansion or from opening redirect targets.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, ParseError>;
+
+/// Variable that caps the number of stages in one pipeline.
+pub const PIPELINE_LIMIT_VAR: &str = "SHELL_PIPELINE_LIMIT";
+/// Stage cap used when `SHELL_PIPELINE_LIMIT` is unset or not a number.
+pub const DEFAULT_PIPELINE_LIMIT: usize = 1024;
+
+/// Variable that, set to `crlf`, makes redirected files use `\r\n` line
+/// endings.
+pub const EOL_VAR: &str = "SHELL_EOL";
+
+/// Splits a command line into raw tokens. Words keep their quotes and escapes
+/// so they can be expanded later (see `expand_word`); operators such as `|`
+/// and `2>` become tokens of their own.
+pub fn tokenize(prompt: &str) -> Result<Vec<String>> {
+    match scan(prompt) {
+        (_, Some(quote)) => Err(ParseError::UnterminatedQuote { quote }),
+        (tokens, None) => Ok(tokens),
+    }
+}
+
+/// Whether `c` separates words when unquoted. The tokenizer and the
+/// completer share this, so both split a line the same way.
+pub fn is_word_separator(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n')
+}
+
+/// Tokenizes `prompt`, also reporting the quote left open at the end, if any.
+fn scan(prompt: &str) -> (Vec<String>, Option<char>) {
     let mut tokens: Vec<String> = Vec::new();
     let mut buffer = String::new();
     let mut quote = PromptQuote::Unquoted;
@@ -26,38 +102,165 @@ pub fn parse_prompt(prompt: &str) -> Vec<String> {
     while let Some(c) = chars.next() {
         match quote {
             PromptQuote::Unquoted => match c {
-                ' ' | '\t' | '\n' => push(&mut buffer, &mut tokens),
+                c if is_word_separator(c) => push(&mut buffer, &mut tokens),
+                // A `#` starting a word comments out the rest of the line.
+                '#' if buffer.is_empty() => while chars.next_if(|c| *c != '\n').is_some() {},
                 '|' => {
                     push(&mut buffer, &mut tokens);
-                    tokens.push("|".to_string());
+                    if chars.peek() == Some(&'|') {
+                        chars.next();
+                        tokens.push("||".to_string());
+                    } else {
+                        tokens.push("|".to_string());
+                    }
+                }
+                '>' => {
+                    // A lone fd number right before `>` belongs to the operator (`2>`).
+                    let mut operator = if buffer == "1" || buffer == "2" {
+                        std::mem::take(&mut buffer)
+                    } else {
+                        push(&mut buffer, &mut tokens);
+                        String::new()
+                    };
+                    operator.push('>');
+                    match chars.peek() {
+                        Some(&next @ ('>' | '|')) => {
+                            chars.next();
+                            operator.push(next);
+                        }
+                        // `>&N` always names the fd it duplicates onto, as
+                        // `1>&`, and its target becomes a word of its own.
+                        Some('&') => {
+                            chars.next();
+                            if operator == ">" {
+                                operator.insert(0, '1');
+                            }
+                            operator.push('&');
+                        }
+                        _ => {}
+                    }
+                    tokens.push(operator);
+                }
+                // `<(command)` is a word, expanded to a path to read from.
+                '<' if chars.peek() == Some(&'(') => {
+                    buffer.push(c);
+                    let mut depth = 0;
+                    let mut inner_quote: Option<char> = None;
+                    for c in chars.by_ref() {
+                        buffer.push(c);
+                        match inner_quote {
+                            Some(q) if c == q => inner_quote = None,
+                            Some(_) => {}
+                            None if c == '\'' || c == '"' => inner_quote = Some(c),
+                            None if c == '(' => depth += 1,
+                            None if c == ')' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    break;
+                                }
+                            }
+                            None => {}
+                        }
+                    }
+                }
+                // `${...}` is one word, so `${v: -2}` keeps its space.
+                '$' if chars.peek() == Some(&'{') => {
+                    buffer.push(c);
+                    let mut depth = 0;
+                    let mut inner_quote: Option<char> = None;
+                    while let Some(c) = chars.next() {
+                        buffer.push(c);
+                        match inner_quote {
+                            Some(q) if c == q => inner_quote = None,
+                            Some(_) => {}
+                            None if c == '\\' => buffer.extend(chars.next()),
+                            None if c == '\'' || c == '"' => inner_quote = Some(c),
+                            None if c == '{' => depth += 1,
+                            None if c == '}' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    break;
+                                }
+                            }
+                            None => {}
+                        }
+                    }
+                }
+                '<' if chars.peek() == Some(&'&') => {
+                    chars.next();
+                    push(&mut buffer, &mut tokens);
+                    tokens.push("0<&".to_string());
+                }
+                '<' => {
+                    push(&mut buffer, &mut tokens);
+                    tokens.push("<".to_string());
+                }
+                ';' => {
+                    push(&mut buffer, &mut tokens);
+                    tokens.push(";".to_string());
+                }
+                '&' => {
+                    push(&mut buffer, &mut tokens);
+                    if chars.peek() == Some(&'&') {
+                        chars.next();
+                        tokens.push("&&".to_string());
+                    } else {
+                        tokens.push("&".to_string());
+                    }
+                }
+                // `name=(a b c)` is one word, spaces and all.
+                '(' if buffer.strip_suffix('=').is_some_and(is_valid_name) => {
+                    buffer.push(c);
+                    let mut inner_quote: Option<char> = None;
+                    for c in chars.by_ref() {
+                        buffer.push(c);
+                        match inner_quote {
+                            Some(q) if c == q => inner_quote = None,
+                            Some(_) => {}
+                            None if c == '\'' || c == '"' => inner_quote = Some(c),
+                            None if c == ')' => break,
+                            None => {}
+                        }
+                    }
+                }
+                '\'' => {
+                    quote = PromptQuote::SingleQuoted;
+                    buffer.push(c);
+                }
+                '"' => {
+                    quote = PromptQuote::DoubleQuoted;
+                    buffer.push(c);
+                }
+                // A backslash-newline joins the next line onto this one.
+                '\\' if chars.peek() == Some(&'\n') => {
+                    chars.next();
                 }
-                '\'' => quote = PromptQuote::SingleQuoted,
-                '"' => quote = PromptQuote::DoubleQuoted,
                 '\\' => {
+                    buffer.push(c);
                     if let Some(next_char) = chars.next() {
                         buffer.push(next_char)
                     }
                 }
                 _ => buffer.push(c),
             },
-            PromptQuote::SingleQuoted => match c {
-                '\'' => quote = PromptQuote::Unquoted,
-                _ => buffer.push(c),
-            },
+            PromptQuote::SingleQuoted => {
+                if c == '\'' {
+                    quote = PromptQuote::Unquoted;
+                }
+                buffer.push(c);
+            }
             PromptQuote::DoubleQuoted => match c {
-                '"' => quote = PromptQuote::Unquoted,
+                '"' => {
+                    quote = PromptQuote::Unquoted;
+                    buffer.push(c);
+                }
+                '\\' if chars.peek() == Some(&'\n') => {
+                    chars.next();
+                }
                 '\\' => {
-                    if let Some(&next_ch) = chars.peek() {
-                        if matches!(next_ch, '\\' | '"' | '$' | '`' | '\n') {
-                            chars.next();
-                            if next_ch != '\n' {
-                                buffer.push(next_ch);
-                            }
-                        } else {
-                            buffer.push(c);
-                        }
-                    } else {
-                        buffer.push(c);
+                    buffer.push(c);
+                    if let Some(next_char) = chars.next() {
+                        buffer.push(next_char)
                     }
                 }
                 _ => buffer.push(c),
@@ -66,115 +269,1153 @@ pub fn parse_prompt(prompt: &str) -> Vec<String> {
     }
     push(&mut buffer, &mut tokens);
 
-    tokens
+    let unterminated = match quote {
+        PromptQuote::Unquoted => None,
+        PromptQuote::SingleQuoted => Some('\''),
+        PromptQuote::DoubleQuoted => Some('"'),
+    };
+    (tokens, unterminated)
+}
+
+/// Whether the command in `line` goes on in the next line: it ends with an
+/// unescaped backslash, or it starts a function body that is still open.
+/// Inside single quotes a backslash is just a character.
+pub fn needs_continuation(line: &str) -> bool {
+    let trailing = line.chars().rev().take_while(|c| *c == '\\').count();
+    if trailing % 2 == 1 && scan(line).1 != Some('\'') {
+        return true;
+    }
+    function_definition(line).is_some_and(|definition| definition.body.is_none())
+        || case_statement(line).is_some_and(|statement| statement.body.is_none())
+}
+
+/// Splits `text` into logical lines: a line ending in a backslash joins
+/// the next, and so do function bodies and `case` statements until they
+/// close. The last line may still be open, which `needs_continuation`
+/// tells.
+pub fn logical_lines(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pending = String::new();
+    let mut open = false;
+    for line in text.lines() {
+        if open {
+            pending.push('\n');
+        }
+        pending.push_str(line);
+        open = needs_continuation(&pending);
+        if !open {
+            lines.push(std::mem::take(&mut pending));
+        }
+    }
+    if open {
+        lines.push(pending);
+    }
+    lines
+}
+
+/// A function definition at the start of a line, `name() { body; }` or
+/// `function name { body; }`.
+#[derive(Debug, PartialEq)]
+pub struct FunctionDefinition<'a> {
+    pub name: &'a str,
+    /// The text between the braces, or `None` while the closing `}` has not
+    /// been read yet.
+    pub body: Option<&'a str>,
+    /// Whatever follows the closing `}`.
+    pub rest: &'a str,
+}
+
+/// Recognizes a function definition at the start of `line`.
+pub fn function_definition(line: &str) -> Option<FunctionDefinition<'_>> {
+    let line = line.trim_start();
+    let (name, header) = match line.strip_prefix("function") {
+        Some(rest) if rest.starts_with(is_word_separator) => {
+            let rest = rest.trim_start();
+            let end = rest
+                .find(|c: char| is_word_separator(c) || c == '(')
+                .unwrap_or(rest.len());
+            let (name, rest) = rest.split_at(end);
+            (name, strip_parens(rest).unwrap_or(rest))
+        }
+        _ => {
+            let name = line.split_once('(')?.0.trim_end();
+            (name, strip_parens(&line[name.len()..])?)
+        }
+    };
+    if !is_valid_name(name) {
+        return None;
+    }
+    let open = header.trim_start().strip_prefix('{')?;
+    // `{` is a word of its own, so `{echo` does not open a body.
+    if !open.is_empty() && !open.starts_with(is_word_separator) {
+        return None;
+    }
+    let (body, rest) = match closing_brace(open) {
+        Some(close) => (Some(&open[..close]), &open[close + 1..]),
+        None => (None, ""),
+    };
+    Some(FunctionDefinition { name, body, rest })
+}
+
+/// Strips the `()` after a function name, spaces allowed.
+fn strip_parens(text: &str) -> Option<&str> {
+    let rest = text.trim_start().strip_prefix('(')?;
+    rest.trim_start().strip_prefix(')')
+}
+
+/// Finds the `}` that closes a function body, skipping quoted text and
+/// nested bodies. Like bash, a `}` only closes the body as a word of its
+/// own at the start of a command, as in `{ echo hi; }`.
+fn closing_brace(body: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut command_start = true;
+    let mut chars = body.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if let Some(q) = quote {
+            if c == '\\' && q == '"' {
+                chars.next();
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        let word_start = body[..i].ends_with(|c| is_word_separator(c) || c == ';') || i == 0;
+        let word_end = body[i + c.len_utf8()..]
+            .chars()
+            .next()
+            .is_none_or(|c| is_word_separator(c) || matches!(c, ';' | '|' | '&' | '<' | '>'));
+        match c {
+            '#' if word_start => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+            '{' if word_start && word_end => {
+                depth += 1;
+                command_start = true;
+            }
+            '}' if command_start && word_end => {
+                if depth == 0 {
+                    return Some(i);
+                }
+                depth -= 1;
+                command_start = false;
+            }
+            ';' | '&' | '\n' => command_start = true,
+            '\'' | '"' => {
+                quote = Some(c);
+                command_start = false;
+            }
+            '\\' => {
+                chars.next();
+                command_start = false;
+            }
+            c if is_word_separator(c) => {}
+            _ => command_start = false,
+        }
+    }
+    None
+}
+
+/// A `case word in pattern) commands;; ... esac` statement at the start of a
+/// line.
+#[derive(Debug, PartialEq)]
+pub struct CaseStatement<'a> {
+    /// The raw word being matched.
+    pub word: &'a str,
+    /// The text between `in` and `esac` (see `case_branches`), or `None`
+    /// while `esac` has not been read yet.
+    pub body: Option<&'a str>,
+    /// Whatever follows `esac`.
+    pub rest: &'a str,
+}
+
+/// One `pattern | pattern) commands` branch of a `case` statement.
+#[derive(Debug, PartialEq)]
+pub struct CaseBranch<'a> {
+    /// Raw glob patterns, any of which selects the branch.
+    pub patterns: Vec<&'a str>,
+    pub commands: &'a str,
+}
+
+/// Recognizes a `case` statement at the start of `line`.
+pub fn case_statement(line: &str) -> Option<CaseStatement<'_>> {
+    let rest = line.trim_start().strip_prefix("case")?;
+    if !rest.is_empty() && !rest.starts_with(is_word_separator) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let (word, rest) = rest.split_at(word_len(rest));
+    let rest = rest.trim_start();
+    let incomplete = CaseStatement {
+        word,
+        body: None,
+        rest: "",
+    };
+    if word.is_empty() || rest.is_empty() {
+        return Some(incomplete);
+    }
+    let body = rest.strip_prefix("in")?;
+    if !body.is_empty() && !body.starts_with(is_word_separator) {
+        return None;
+    }
+    match case_scan(body).0 {
+        Some(end) => Some(CaseStatement {
+            word,
+            body: Some(&body[..end]),
+            rest: &body[end + "esac".len()..],
+        }),
+        None => Some(incomplete),
+    }
+}
+
+/// Splits the body of a `case` statement into its branches.
+pub fn case_branches(body: &str) -> Result<Vec<CaseBranch<'_>>> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    for separator in case_scan(body).1 {
+        items.push(&body[start..separator]);
+        start = separator + ";;".len();
+    }
+    items.push(&body[start..]);
+
+    let mut branches = Vec::new();
+    for item in items {
+        let item = item.trim_start();
+        if item.is_empty() {
+            continue;
+        }
+        let item = item.strip_prefix('(').unwrap_or(item);
+        let Some(close) = find_unquoted(item, ')') else {
+            let token = item.split(is_word_separator).next().unwrap_or(item);
+            return Err(ParseError::UnexpectedToken(token.to_string()));
+        };
+        let mut patterns = Vec::new();
+        let mut rest = &item[..close];
+        while let Some(bar) = find_unquoted(rest, '|') {
+            patterns.push(rest[..bar].trim());
+            rest = &rest[bar + 1..];
+        }
+        patterns.push(rest.trim());
+        branches.push(CaseBranch {
+            patterns,
+            commands: &item[close + 1..],
+        });
+    }
+    Ok(branches)
+}
+
+/// Finds the `esac` that closes the body of a `case` statement, skipping
+/// quoted text and nested statements, along with the `;;` that end each
+/// branch before it. Like `}`, `esac` only counts as a word of its own at
+/// the start of a command or pattern.
+fn case_scan(body: &str) -> (Option<usize>, Vec<usize>) {
+    let mut separators = Vec::new();
+    let mut depth = 0;
+    // Parentheses of `$(...)` and similar inside a branch's commands.
+    let mut parens = 0;
+    let mut quote: Option<char> = None;
+    let mut command_start = true;
+    // Set between a nested `case` and its `in`.
+    let mut awaiting_in = false;
+    let mut chars = body.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if let Some(q) = quote {
+            if c == '\\' && q == '"' {
+                chars.next();
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        let word_start = body[..i].ends_with(|c| is_word_separator(c) || c == ';') || i == 0;
+        match c {
+            '#' if word_start => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+            ';' if chars.next_if(|(_, c)| *c == ';').is_some() => {
+                if depth == 0 && parens == 0 {
+                    separators.push(i);
+                }
+                command_start = true;
+            }
+            ';' | '&' | '|' | '\n' => command_start = true,
+            // The optional `(` before a pattern.
+            '(' if command_start => {}
+            '(' => parens += 1,
+            ')' if parens > 0 => {
+                parens -= 1;
+                command_start = false;
+            }
+            // The end of a pattern list.
+            ')' => command_start = true,
+            '\'' | '"' => {
+                quote = Some(c);
+                command_start = false;
+            }
+            '\\' => {
+                chars.next();
+                command_start = false;
+            }
+            c if is_word_separator(c) => {}
+            _ if word_start => {
+                let len = body[i..]
+                    .find(|c: char| {
+                        is_word_separator(c) || matches!(c, ';' | '&' | '|' | '(' | ')')
+                    })
+                    .unwrap_or(body.len() - i);
+                let word = &body[i..i + len];
+                // `esac)` is a pattern, not the keyword.
+                let keyword = !body[i + len..].starts_with(')');
+                while chars.next_if(|(j, _)| *j < i + len).is_some() {}
+                match word {
+                    "in" if awaiting_in => {
+                        awaiting_in = false;
+                        command_start = true;
+                        continue;
+                    }
+                    "case" if command_start && keyword => {
+                        depth += 1;
+                        awaiting_in = true;
+                    }
+                    "esac" if command_start && keyword => {
+                        if depth == 0 {
+                            return (Some(i), separators);
+                        }
+                        depth -= 1;
+                    }
+                    _ => {}
+                }
+                command_start = false;
+            }
+            _ => command_start = false,
+        }
+    }
+    (None, separators)
+}
+
+/// The length of the raw word at the start of `text`, up to the first
+/// unquoted separator.
+fn word_len(text: &str) -> usize {
+    let mut quote: Option<char> = None;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('"'), '\\') | (None, '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, c) if is_word_separator(c) || c == ';' => return i,
+            (None, _) => {}
+        }
+    }
+    text.len()
+}
+
+/// The byte position of the first unquoted `target` in `text`.
+fn find_unquoted(text: &str, target: char) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('"'), '\\') | (None, '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, c) if c == target => return Some(i),
+            (None, _) => {}
+        }
+    }
+    None
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Recognizes a raw `name=(items...)` word, returning the name and raw items.
+fn parse_array_assignment(word: &str) -> Option<(&str, Vec<String>)> {
+    let (name, rest) = word.split_once('=')?;
+    let inner = rest.strip_prefix('(')?.strip_suffix(')')?;
+    if !is_valid_name(name) {
+        return None;
+    }
+    Some((name, scan(inner).0))
+}
+
+/// Splits `NAME=value` into its name and raw value. Array assignments are
+/// left to [`parse_array_assignment`].
+fn scalar_assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    (is_valid_name(name) && !value.starts_with('(')).then_some((name, value))
 }
 
-fn extract_redirects(args: &[String]) -> Result<(Vec<String>, Box<dyn Output>, Box<dyn Output>)> {
+fn is_operator(token: &str) -> bool {
+    matches!(
+        token,
+        "|" | "||"
+            | ";"
+            | "&"
+            | "&&"
+            | "<"
+            | ">"
+            | "1>"
+            | "2>"
+            | ">|"
+            | "1>|"
+            | "2>|"
+            | ">>"
+            | "1>>"
+            | "2>>"
+            | "1>&"
+            | "2>&"
+            | "0<&"
+    )
+}
+
+/// Tokenizes a command line and removes quotes, without expanding variables.
+/// Unlike `tokenize`, an unterminated quote simply runs to the end of the line.
+pub fn parse_prompt(prompt: &str) -> Vec<String> {
+    scan(prompt)
+        .0
+        .into_iter()
+        .map(|token| {
+            if is_operator(&token) {
+                token
+            } else {
+                unquote(&token)
+            }
+        })
+        .collect()
+}
+
+fn open_redirect(path: &str, append: bool, state: &ShellState) -> Result<Box<dyn Output>> {
+    if path == "/dev/null" {
+        return Ok(Box::new(NullOutput::new()));
+    }
+    let crlf = state
+        .vars
+        .get(EOL_VAR)
+        .is_some_and(|eol| eol.eq_ignore_ascii_case("crlf"));
+    if state.options.mkdirredirect
+        && let Some(parent) = Path::new(&string_to_os(path)).parent()
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("{}: cannot create directory", parent.display()))?;
+    }
+    Ok(Box::new(FileOutput::new(path, append)?.with_crlf(crlf)))
+}
+
+/// With `noclobber` set, `>` may not truncate an existing regular file.
+fn check_clobber(path: &str, state: &ShellState) -> Result<()> {
+    if state.options.noclobber && Path::new(&string_to_os(path)).is_file() {
+        return Err(anyhow!("{}: cannot overwrite existing file", path).into());
+    }
+    Ok(())
+}
+
+fn redirect_target(word: Option<&String>, state: &mut ShellState) -> Result<String> {
+    let word = word.ok_or(ParseError::MissingRedirectTarget)?;
+    match expand_word(word, state)?.as_slice() {
+        [path] => Ok(path.clone()),
+        _ => Err(ParseError::AmbiguousRedirect(word.clone())),
+    }
+}
+
+fn open_input(path: &str) -> Result<PipeReader> {
+    let file = File::open(string_to_os(path))
+        .with_context(|| format!("{}: No such file or directory", path))?;
+    #[cfg(unix)]
+    let file = std::os::fd::OwnedFd::from(file);
+    #[cfg(windows)]
+    let file = std::os::windows::io::OwnedHandle::from(file);
+    Ok(PipeReader::from(file))
+}
+
+fn extract_redirects(
+    args: &[String],
+    state: &mut ShellState,
+) -> Result<(Vec<String>, OutputStreams)> {
     let mut filtered = Vec::new();
     let mut stdout: Box<dyn Output> = Box::new(StdOutput::new());
     let mut stderr: Box<dyn Output> = Box::new(StdErrOutput::new());
+    let mut input = None;
 
     let mut iter = args.iter().peekable();
     while let Some(arg) = iter.next() {
         match arg.as_str() {
             ">" | "1>" => {
-                let path = iter
-                    .next()
-                    .ok_or_else(|| anyhow!("redirect path missing"))?;
-                let file = FileOutput::new(path, false)?;
-                stdout = Box::new(file);
+                let path = redirect_target(iter.next(), state)?;
+                check_clobber(&path, state)?;
+                stdout = open_redirect(&path, false, state)?;
             }
             "2>" => {
-                let path = iter
-                    .next()
-                    .ok_or_else(|| anyhow!("redirect path missing"))?;
-                let file = FileOutput::new(path, false)?;
-                stderr = Box::new(file);
+                let path = redirect_target(iter.next(), state)?;
+                check_clobber(&path, state)?;
+                stderr = open_redirect(&path, false, state)?;
+            }
+            ">|" | "1>|" => {
+                let path = redirect_target(iter.next(), state)?;
+                stdout = open_redirect(&path, false, state)?;
+            }
+            "2>|" => {
+                let path = redirect_target(iter.next(), state)?;
+                stderr = open_redirect(&path, false, state)?;
             }
             ">>" | "1>>" => {
-                let path = iter
-                    .next()
-                    .ok_or_else(|| anyhow!("redirect path missing"))?;
-                let file = FileOutput::new(path, true)?;
-                stdout = Box::new(file);
+                let path = redirect_target(iter.next(), state)?;
+                stdout = open_redirect(&path, true, state)?;
             }
             "2>>" => {
-                let path = iter
-                    .next()
-                    .ok_or_else(|| anyhow!("redirect path missing"))?;
-                let file = FileOutput::new(path, true)?;
-                stderr = Box::new(file);
+                let path = redirect_target(iter.next(), state)?;
+                stderr = open_redirect(&path, true, state)?;
+            }
+            "<" => {
+                let path = redirect_target(iter.next(), state)?;
+                input = Some(open_input(&path)?);
+            }
+            "1>&" | "2>&" | "0<&" => {
+                let fd = redirect_target(iter.next(), state)?;
+                match (arg.as_str(), fd.as_str()) {
+                    ("1>&", "1") | ("2>&", "2") | ("0<&", "0") => {}
+                    ("1>&", "2") => stdout = stderr.duplicate()?,
+                    ("2>&", "1") => stderr = stdout.duplicate()?,
+                    // `N>&-` closes the stream; writes to it are dropped.
+                    ("1>&", "-") => stdout = Box::new(NullOutput::new()),
+                    ("2>&", "-") => stderr = Box::new(NullOutput::new()),
+                    _ => return Err(anyhow!("{}: bad file descriptor", fd).into()),
+                }
             }
             _ => filtered.push(arg.clone()),
         }
     }
 
-    Ok((filtered, stdout, stderr))
+    let mut streams = OutputStreams::new(stdout, stderr);
+    streams.input = input;
+    Ok((filtered, streams))
 }
 
-pub fn parse_pipeline(tokens: Vec<String>) -> Result<(Vec<Command>, OutputStreams)> {
-    let segments: Vec<Vec<String>> = tokens
-        .split(|t| t == "|")
-        .map(|s| s.to_vec())
-        .filter(|s| !s.is_empty())
-        .collect();
+/// Splits raw tokens at each `;` into the pipelines to run one after the
+/// other. A trailing `;` is allowed, an empty command elsewhere is not.
+pub fn split_list(tokens: Vec<String>) -> Result<Vec<Vec<String>>> {
+    // Nothing but a comment.
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !tokens.iter().any(|token| token == ";") {
+        return Ok(vec![tokens]);
+    }
+    let mut pipelines: Vec<Vec<String>> = tokens.split(|t| t == ";").map(<[_]>::to_vec).collect();
+    if pipelines.last().is_some_and(Vec::is_empty) {
+        pipelines.pop();
+    }
+    if pipelines.is_empty() || pipelines.iter().any(Vec::is_empty) {
+        return Err(ParseError::UnexpectedToken(";".to_string()));
+    }
+    Ok(pipelines)
+}
 
-    if segments.is_empty() {
-        return Err(anyhow!("empty pipeline"));
+/// Removes a trailing `&`, returning whether the pipeline should run in the
+/// background.
+pub fn take_background(tokens: &mut Vec<String>) -> bool {
+    let background = tokens.last().is_some_and(|token| token == "&");
+    if background {
+        tokens.pop();
     }
+    background
+}
+
+/// Removes a leading `time`, returning whether the whole pipeline should be
+/// timed.
+pub fn take_time(tokens: &mut Vec<String>) -> bool {
+    let timed = tokens.first().is_some_and(|token| token == "time");
+    if timed {
+        tokens.remove(0);
+    }
+    timed
+}
 
-    let mut commands = Vec::new();
-    let mut final_streams: Option<OutputStreams> = None;
+/// Splits raw tokens into pipeline stages, expanding each stage's words.
+pub fn parse_pipeline(
+    tokens: Vec<String>,
+    state: &mut ShellState,
+) -> Result<Vec<(Command, OutputStreams)>> {
+    if tokens.is_empty() {
+        return Err(ParseError::EmptyPipeline);
+    }
+    let tokens = expand_aliases(tokens, state)?;
+    // Command lists are not supported; a trailing `&` is handled by the caller.
+    if let Some(token) = tokens
+        .iter()
+        .find(|t| matches!(t.as_str(), "||" | "&&" | "&"))
+    {
+        return Err(ParseError::UnexpectedToken(token.clone()));
+    }
 
-    for (i, segment) in segments.iter().enumerate() {
-        let is_last = i == segments.len() - 1;
-        let (command, streams) = parse_command(segment.clone())?;
-        commands.push(command);
+    let segments: Vec<Vec<String>> = tokens.split(|t| t == "|").map(|s| s.to_vec()).collect();
+    // A leading, trailing or doubled `|` leaves an empty stage behind.
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return Err(ParseError::UnexpectedToken("|".to_string()));
+    }
+    let limit = state
+        .vars
+        .get(PIPELINE_LIMIT_VAR)
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_PIPELINE_LIMIT);
+    if segments.len() > limit {
+        return Err(ParseError::PipelineTooLong { limit });
+    }
 
-        if is_last {
-            final_streams = Some(streams);
+    segments
+        .into_iter()
+        .map(|segment| parse_command(segment, state))
+        .collect()
+}
+
+/// Splits leading flag words such as `-rf` off `args`, returning their
+/// letters and the remaining operands. A `--` ends the flags. Letters
+/// outside `allowed` are an `UnsupportedOption` error, and having no
+/// operands is an error unless `-f` was given.
+fn split_flags(
+    args: Vec<String>,
+    allowed: &str,
+    builtin: &'static str,
+) -> Result<(String, Vec<String>)> {
+    let mut flags = String::new();
+    let mut rest = args.into_iter().peekable();
+    while let Some(arg) = rest.next_if(|arg| arg.len() > 1 && arg.starts_with('-')) {
+        if arg == "--" {
+            break;
         }
+        if let Some(unknown) = arg[1..].chars().find(|c| !allowed.contains(*c)) {
+            // A long option is named whole, as `--verbose`.
+            let option = if unknown == '-' {
+                arg.clone()
+            } else {
+                unknown.to_string()
+            };
+            return Err(ParseError::UnsupportedOption { builtin, option });
+        }
+        flags.push_str(&arg[1..]);
+    }
+    let operands: Vec<String> = rest.collect();
+    if operands.is_empty() && !flags.contains('f') {
+        return Err(ParseError::InvalidArgument {
+            builtin,
+            message: "missing operand",
+        });
     }
+    Ok((flags, operands))
+}
 
-    Ok((
-        commands,
-        final_streams.unwrap_or_else(|| {
-            OutputStreams::new(Box::new(StdOutput::new()), Box::new(StdErrOutput::new()))
+/// Parses the flags of a builtin that stands in for the external tool of
+/// the same name, such as `rm`, building the command with `build`. Flags
+/// the builtin lacks, such as `rm -i` or `mkdir -m 700`, are left to the
+/// real tool.
+fn builtin_or_exec(
+    args: Vec<String>,
+    allowed: &str,
+    builtin: &'static str,
+    build: impl FnOnce(String, Vec<String>) -> Command,
+) -> Result<Command> {
+    match split_flags(args.clone(), allowed, builtin) {
+        Ok((flags, operands)) => Ok(build(flags, operands)),
+        Err(ParseError::UnsupportedOption { .. }) => Ok(Command::Exec {
+            command: builtin.to_string(),
+            args,
         }),
-    ))
+        Err(e) => Err(e),
+    }
+}
+
+/// The one operand a builtin such as `pushd` may take.
+fn optional_operand(args: Vec<String>, builtin: &'static str) -> Result<Option<String>> {
+    if args.len() > 1 {
+        return Err(ParseError::InvalidArgument {
+            builtin,
+            message: "too many arguments",
+        });
+    }
+    Ok(args.into_iter().next())
+}
+
+/// Replaces an alias name at the start of each stage with the tokens of its
+/// value. Only unquoted words match, and an alias is not expanded again
+/// inside its own expansion, so `alias ls='ls -F'` works.
+fn expand_aliases(tokens: Vec<String>, state: &ShellState) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    let mut command_position = true;
+    for token in tokens {
+        let next_position = token == "|";
+        if command_position {
+            expanded.extend(expand_alias(token, state, &mut Vec::new())?);
+        } else {
+            expanded.push(token);
+        }
+        command_position = next_position;
+    }
+    Ok(expanded)
 }
 
-fn parse_command(args: Vec<String>) -> Result<(Command, OutputStreams)> {
-    let (name, rest) = args.split_first().ok_or_else(|| anyhow!("Empty command"))?;
-    let (args, stdout, stderr) = extract_redirects(rest)?;
+fn expand_alias(word: String, state: &ShellState, seen: &mut Vec<String>) -> Result<Vec<String>> {
+    let Some(value) = state.aliases.get(&word).filter(|_| !seen.contains(&word)) else {
+        return Ok(vec![word]);
+    };
+    let mut tokens = tokenize(value)?;
+    seen.push(word);
+    if tokens.is_empty() {
+        return Ok(tokens);
+    }
+    let first = tokens.remove(0);
+    let mut expanded = expand_alias(first, state, seen)?;
+    expanded.extend(tokens);
+    Ok(expanded)
+}
+
+pub(crate) fn parse_command(
+    tokens: Vec<String>,
+    state: &mut ShellState,
+) -> Result<(Command, OutputStreams)> {
+    if let [word] = tokens.as_slice()
+        && let Some((name, raw_items)) = parse_array_assignment(word)
+    {
+        let mut items = Vec::new();
+        for item in &raw_items {
+            items.extend(expand_word(item, state)?);
+        }
+        let command = Command::Assign {
+            name: name.to_string(),
+            value: Value::Array(items),
+        };
+        return Ok((command, OutputStreams::default()));
+    }
+
+    // `time` later in a pipeline times just its own stage.
+    if tokens.len() > 1 && tokens[0] == "time" {
+        let (inner, streams) = parse_command(tokens[1..].to_vec(), state)?;
+        return Ok((Command::Time(Some(Box::new(inner))), streams));
+    }
+
+    let (words, streams) = extract_redirects(&tokens, state)?;
+    let prefix = words
+        .iter()
+        .take_while(|word| scalar_assignment(word).is_some())
+        .count();
+    if prefix > 0 {
+        let mut assignments = Vec::new();
+        for word in &words[..prefix] {
+            let (name, value) = scalar_assignment(word).unwrap_or_default();
+            assignments.push((name.to_string(), expand_assignment(value, state)?));
+        }
+        let command = if words.len() > prefix {
+            let (command, _) = parse_command(words[prefix..].to_vec(), state)?;
+            Some(Box::new(command))
+        } else {
+            None
+        };
+        let command = Command::Assignments {
+            assignments,
+            command,
+        };
+        return Ok((command, streams));
+    }
+    let mut args = Vec::new();
+    for word in &words {
+        args.extend(expand_word(word, state)?);
+    }
+    let (name, args) = match args.split_first() {
+        Some((name, rest)) => (name.clone(), rest.to_vec()),
+        None => return Err(ParseError::EmptyCommand),
+    };
+
+    // `builtin name ...` runs the builtin even when a function shadows it,
+    // and `builtin` may name itself.
+    let (mut name, mut args, mut builtin_only) = (name, args, false);
+    while name == "builtin" && !args.is_empty() {
+        name = args.remove(0);
+        builtin_only = true;
+    }
+    if builtin_only && name.parse::<CommandKind>().is_err() {
+        return Err(anyhow!("builtin: {}: not a shell builtin", name).into());
+    }
+
+    // Functions shadow builtins and programs of the same name.
+    if !builtin_only && state.functions.contains(&name) {
+        return Ok((Command::Function { name, args }, streams));
+    }
 
     let arg_str = args.join(" ");
 
     let command = match name.parse::<CommandKind>() {
         Ok(CommandKind::Exit) => Command::Exit,
+        Ok(CommandKind::Return) => match args.as_slice() {
+            [] => Command::Return(None),
+            // Like an exit status, the value wraps to 0-255.
+            [status] => Command::Return(Some(
+                status
+                    .parse::<i64>()
+                    .map_err(|_| anyhow!("return: {}: numeric argument required", status))?
+                    .rem_euclid(256) as i32,
+            )),
+            _ => {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "return",
+                    message: "too many arguments",
+                });
+            }
+        },
         Ok(CommandKind::Echo) => {
-            let (interpret_escapes, text) = if args.first().map(|arg| arg.as_str()) == Some("-e") {
-                (true, args[1..].join(" "))
-            } else {
-                (false, arg_str)
-            };
+            // Leading words made only of n, e and E are flags; for e and E
+            // the last one given wins, as in bash. `-s SEP` joins the
+            // arguments with SEP instead of a space. A `--` after them ends
+            // the flags. POSIX only has `-n`. With `xpg_echo` escapes are
+            // interpreted unless `-E` says otherwise.
+            let mut interpret_escapes = state.options.xpg_echo;
+            let mut newline = true;
+            let mut separator = " ".to_string();
+            let posix = state.options.posix;
+            let mut operands = 0;
+            while let Some(arg) = args.get(operands) {
+                if !posix
+                    && arg == "-s"
+                    && let Some(sep) = args.get(operands + 1)
+                {
+                    separator = sep.clone();
+                    operands += 2;
+                    continue;
+                }
+                let is_flag = arg.len() > 1
+                    && arg.starts_with('-')
+                    && arg[1..]
+                        .chars()
+                        .all(|c| c == 'n' || (!posix && matches!(c, 'e' | 'E')));
+                if !is_flag {
+                    break;
+                }
+                for flag in arg[1..].chars() {
+                    match flag {
+                        'n' => newline = false,
+                        'e' => interpret_escapes = true,
+                        _ => interpret_escapes = false,
+                    }
+                }
+                operands += 1;
+            }
+            if !posix && args.get(operands).is_some_and(|arg| arg == "--") {
+                operands += 1;
+            }
             Command::Echo {
-                text,
+                args: args[operands..].to_vec(),
                 interpret_escapes,
+                newline,
+                separator,
             }
         }
-        Ok(CommandKind::Type) => Command::Type(arg_str),
-        Ok(CommandKind::Pwd) => Command::Pwd,
-        Ok(CommandKind::Cd) => Command::Cd(arg_str),
+        Ok(CommandKind::Type) => {
+            let all = args.first().map(|arg| arg.as_str()) == Some("-a");
+            let names = if all { args[1..].to_vec() } else { args };
+            Command::Type { names, all }
+        }
+        Ok(CommandKind::Pwd) => {
+            // The last of -L and -P wins.
+            let physical = args
+                .iter()
+                .rev()
+                .find(|arg| matches!(arg.as_str(), "-L" | "-P"))
+                .is_some_and(|arg| arg == "-P");
+            let tilde = args.iter().any(|arg| arg == "-t");
+            Command::Pwd { physical, tilde }
+        }
+        Ok(CommandKind::Cd) => {
+            let physical = args.first().map(|arg| arg.as_str()) == Some("-P");
+            let logical = args.first().map(|arg| arg.as_str()) == Some("-L");
+            let path = if physical || logical {
+                args[1..].join(" ")
+            } else {
+                arg_str
+            };
+            Command::Cd { path, physical }
+        }
         Ok(CommandKind::History) => {
-            let lines_count = match args.first() {
+            let sync = match args.first().map(|arg| arg.as_str()) {
+                Some("-a") => Some(HistorySync::Append),
+                Some("-r") => Some(HistorySync::Read),
+                Some("-w") => Some(HistorySync::Write),
+                _ => None,
+            };
+            if let Some(sync) = sync {
+                let file = args.get(1).cloned();
+                Command::HistoryFile { sync, file }
+            } else {
+                let lines_count = match args.first() {
+                    None => None,
+                    Some(s) => Some(s.parse::<u32>().map_err(|_| ParseError::InvalidArgument {
+                        builtin: "history",
+                        message: "numeric argument required",
+                    })?),
+                };
+                Command::History { lines_count }
+            }
+        }
+        Ok(CommandKind::Builtin) => {
+            return Err(ParseError::InvalidArgument {
+                builtin: "builtin",
+                message: "usage: builtin shell-builtin [arg ...]",
+            });
+        }
+        Ok(CommandKind::Clear) => Command::Clear,
+        Ok(CommandKind::Set) => Command::Set(args),
+        Ok(CommandKind::Trap) => Command::Trap(args),
+        Ok(CommandKind::Jobs) => Command::Jobs {
+            long: args.first().map(|arg| arg.as_str()) == Some("-l"),
+        },
+        Ok(CommandKind::Wait) => Command::Wait(args),
+        Ok(CommandKind::Pushd) => Command::Pushd(optional_operand(args, "pushd")?),
+        Ok(CommandKind::Popd) => Command::Popd(optional_operand(args, "popd")?),
+        Ok(CommandKind::Dirs) => {
+            if !args.iter().all(|arg| {
+                arg.len() > 1
+                    && arg.starts_with('-')
+                    && arg[1..].chars().all(|c| "cvpl".contains(c))
+            }) {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "dirs",
+                    message: "invalid option",
+                });
+            }
+            let flags = args.concat();
+            Command::Dirs {
+                clear: flags.contains('c'),
+                verbose: flags.contains('v'),
+                per_line: flags.contains('p'),
+                long: flags.contains('l'),
+            }
+        }
+        Ok(CommandKind::Printf) => match args.split_first() {
+            Some((format, args)) => Command::Printf {
+                format: format.clone(),
+                args: args.to_vec(),
+            },
+            None => {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "printf",
+                    message: "usage: printf format [arguments]",
+                });
+            }
+        },
+        Ok(CommandKind::Export) => Command::Export(args),
+        Ok(CommandKind::Shift) => match args.as_slice() {
+            [] => Command::Shift(1),
+            [count] => Command::Shift(
+                count
+                    .parse()
+                    .map_err(|_| anyhow!("shift: {}: numeric argument required", count))?,
+            ),
+            _ => {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "shift",
+                    message: "too many arguments",
+                });
+            }
+        },
+        Ok(CommandKind::Local) => Command::Local(args),
+        Ok(CommandKind::Readonly) => Command::Readonly(args),
+        Ok(CommandKind::Unset) => Command::Unset(args),
+        Ok(CommandKind::Alias) => Command::Alias(args),
+        Ok(CommandKind::Unalias) => Command::Unalias(args),
+        Ok(CommandKind::Complete) => Command::Complete(args),
+        Ok(CommandKind::Mkdir) => {
+            builtin_or_exec(args, "p", "mkdir", |flags, paths| Command::Mkdir {
+                parents: flags.contains('p'),
+                paths,
+            })?
+        }
+        Ok(CommandKind::Rmdir) => {
+            builtin_or_exec(args, "", "rmdir", |_, paths| Command::Rmdir(paths))?
+        }
+        Ok(CommandKind::Rm) => builtin_or_exec(args, "rRf", "rm", |flags, paths| Command::Rm {
+            recursive: flags.contains(['r', 'R']),
+            force: flags.contains('f'),
+            paths,
+        })?,
+        Ok(CommandKind::Touch) => {
+            builtin_or_exec(args, "", "touch", |_, paths| Command::Touch(paths))?
+        }
+        Ok(CommandKind::Basename) => match split_flags(args, "", "basename")?.1.as_slice() {
+            [path] => Command::Basename {
+                path: path.clone(),
+                suffix: None,
+            },
+            [path, suffix] => Command::Basename {
+                path: path.clone(),
+                suffix: Some(suffix.clone()),
+            },
+            _ => {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "basename",
+                    message: "extra operand",
+                });
+            }
+        },
+        Ok(CommandKind::Seq) => {
+            let flags = args
+                .iter()
+                .take_while(|arg| matches!(arg.as_str(), "-w" | "-z" | "-0"))
+                .count();
+            let equal_width = args[..flags].iter().any(|arg| arg == "-w");
+            let null_separated = args[..flags].iter().any(|arg| arg != "-w");
+            let numbers = args[flags..]
+                .iter()
+                .map(|arg| {
+                    arg.parse::<i64>()
+                        .map_err(|_| anyhow!("seq: invalid argument: '{}'", arg))
+                })
+                .collect::<anyhow::Result<Vec<i64>>>()?;
+            let (first, step, last) = match numbers.as_slice() {
+                [last] => (1, 1, *last),
+                [first, last] => (*first, 1, *last),
+                [first, step, last] => (*first, *step, *last),
+                [] => {
+                    return Err(ParseError::InvalidArgument {
+                        builtin: "seq",
+                        message: "missing operand",
+                    });
+                }
+                _ => {
+                    return Err(ParseError::InvalidArgument {
+                        builtin: "seq",
+                        message: "extra operand",
+                    });
+                }
+            };
+            if step == 0 {
+                return Err(anyhow!("seq: invalid zero increment value: '0'").into());
+            }
+            Command::Seq {
+                first,
+                step,
+                last,
+                equal_width,
+                null_separated,
+            }
+        }
+        Ok(CommandKind::Repeat) => {
+            // The command keeps its raw words so each run expands them anew.
+            let (Some(count), [_, _, _, ..]) = (args.first(), words.as_slice()) else {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "repeat",
+                    message: "usage: repeat count command",
+                });
+            };
+            let count = count
+                .parse::<u32>()
+                .map_err(|_| anyhow!("repeat: {}: numeric argument required", count))?;
+            Command::Repeat {
+                count,
+                words: words[2..].to_vec(),
+            }
+        }
+        Ok(CommandKind::Getopts) => match args.as_slice() {
+            [optstring, name, args @ ..] if is_valid_name(name) => Command::Getopts {
+                optstring: optstring.clone(),
+                name: name.clone(),
+                args: args.to_vec(),
+            },
+            [_, name, ..] => {
+                return Err(anyhow!("getopts: `{}': not a valid identifier", name).into());
+            }
+            _ => {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "getopts",
+                    message: "usage: getopts optstring name [arg ...]",
+                });
+            }
+        },
+        Ok(CommandKind::Dirname) => Command::Dirname(split_flags(args, "", "dirname")?.1),
+        Ok(CommandKind::Ls) => {
+            let mut options = LsOptions::default();
+            let flags = args
+                .iter()
+                .take_while(|arg| arg.len() > 1 && arg.starts_with('-'))
+                .count();
+            // Flags the builtin lacks, such as `-h`, `-R` or `--color`, are
+            // left to the real `ls`.
+            if args[..flags].iter().all(|flag| options.apply(flag)) {
+                Command::Ls {
+                    options,
+                    paths: args[flags..].to_vec(),
+                }
+            } else {
+                Command::Exec {
+                    command: name.to_string(),
+                    args,
+                }
+            }
+        }
+        Ok(CommandKind::Source) => match args.split_first() {
+            Some((path, args)) => Command::Source {
+                path: path.clone(),
+                args: args.to_vec(),
+            },
+            None => {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "source",
+                    message: "filename argument required",
+                });
+            }
+        },
+        Ok(CommandKind::Time) => Command::Time(None),
+        Ok(CommandKind::Disown) => {
+            let all = args.first().map(|arg| arg.as_str()) == Some("-a");
+            let specs = if all { args[1..].to_vec() } else { args };
+            Command::Disown { all, specs }
+        }
+        Ok(CommandKind::Umask) => {
+            let mask = match args.first() {
                 None => None,
                 Some(s) => Some(
-                    s.parse::<u32>()
-                        .map_err(|_| anyhow!("history: numeric argument required"))?,
+                    u32::from_str_radix(s, 8)
+                        .ok()
+                        .filter(|mask| *mask <= 0o777)
+                        .ok_or(ParseError::InvalidArgument {
+                            builtin: "umask",
+                            message: "octal number out of range",
+                        })?,
                 ),
             };
-            Command::History { lines_count }
+            Command::Umask { mask }
+        }
+        Ok(CommandKind::Mapfile) => {
+            // Lines are always stored without their newline, so `-t` changes
+            // nothing.
+            let mut rest = args.into_iter().peekable();
+            while let Some(arg) = rest.next_if(|arg| arg.len() > 1 && arg.starts_with('-')) {
+                if arg == "--" {
+                    break;
+                }
+                if !arg[1..].chars().all(|c| c == 't') {
+                    return Err(ParseError::InvalidArgument {
+                        builtin: "mapfile",
+                        message: "invalid option",
+                    });
+                }
+            }
+            let name = rest.next().unwrap_or_else(|| "MAPFILE".to_string());
+            if rest.next().is_some() {
+                return Err(ParseError::InvalidArgument {
+                    builtin: "mapfile",
+                    message: "extra operand",
+                });
+            }
+            Command::Mapfile { name }
+        }
+        Ok(CommandKind::Hash) => {
+            let reset = args.first().map(|arg| arg.as_str()) == Some("-r");
+            let names = if reset { args[1..].to_vec() } else { args };
+            Command::Hash { reset, names }
         }
         Err(_) => Command::Exec {
             command: name.to_string(),
@@ -182,7 +1423,7 @@ fn parse_command(args: Vec<String>) -> Result<(Command, OutputStreams)> {
         },
     };
 
-    Ok((command, OutputStreams::new(stdout, stderr)))
+    Ok((command, streams))
 }
 
 #[cfg(test)]
@@ -209,18 +1450,65 @@ mod tests {
 
     #[test]
     fn test_redirect_stdout() {
-        let args = vec!["echo".into(), "hello".into(), ">".into(), "out.txt".into()];
-        let (filtered, _, _) = extract_redirects(&args[1..]).unwrap();
+        let args = ["echo".into(), "hello".into(), ">".into(), "out.txt".into()];
+        let (filtered, _) = extract_redirects(&args[1..], &mut ShellState::new()).unwrap();
         assert_eq!(filtered, vec!["hello"]);
     }
 
     #[test]
     fn test_redirect_stderr() {
-        let args = vec!["cmd".into(), "2>".into(), "err.txt".into()];
-        let (filtered, _, _) = extract_redirects(&args[1..]).unwrap();
+        let args = ["cmd".into(), "2>".into(), "err.txt".into()];
+        let (filtered, _) = extract_redirects(&args[1..], &mut ShellState::new()).unwrap();
         assert!(filtered.is_empty());
     }
 
+    #[test]
+    fn test_fd_duplication_spellings() {
+        let mapping = |line: &str| {
+            let args = tokenize(line).unwrap();
+            let (filtered, streams) =
+                extract_redirects(&args[1..], &mut ShellState::new()).unwrap();
+            format!("{:?} {:?}", filtered, streams)
+        };
+        assert_eq!(tokenize("cmd 2>&1").unwrap(), ["cmd", "2>&", "1"]);
+        assert_eq!(tokenize("cmd >&2").unwrap(), ["cmd", "1>&", "2"]);
+        assert_eq!(tokenize("cmd <&0").unwrap(), ["cmd", "0<&", "0"]);
+
+        let stderr_to_stdout = mapping("cmd 2>&1");
+        assert!(stderr_to_stdout.contains("stderr: StdOutFollower"));
+        assert_eq!(mapping("cmd 2>& 1"), stderr_to_stdout);
+        let stdout_to_stderr = mapping("cmd >&2");
+        assert!(stdout_to_stderr.contains("stdout: StdErrRedirect"));
+        assert_eq!(mapping("cmd 1>&2"), stdout_to_stderr);
+        assert_eq!(mapping("cmd >& 2"), stdout_to_stderr);
+        // As in bash, a detached `2` is an argument and `>&1` does nothing.
+        assert_eq!(
+            mapping("cmd 2 >&1"),
+            format!("[\"2\"] {:?}", OutputStreams::default())
+        );
+        assert!(matches!(
+            extract_redirects(&tokenize("cmd >&3").unwrap()[1..], &mut ShellState::new()),
+            Err(error) if error.to_string() == "3: bad file descriptor"
+        ));
+    }
+
+    #[test]
+    fn test_attached_redirects() {
+        assert_eq!(
+            parse_prompt("find / 2>/dev/null"),
+            vec!["find", "/", "2>", "/dev/null"]
+        );
+        assert_eq!(
+            parse_prompt("echo hi>>out.txt"),
+            vec!["echo", "hi", ">>", "out.txt"]
+        );
+        assert_eq!(
+            parse_prompt("echo a2>out.txt"),
+            vec!["echo", "a2", ">", "out.txt"]
+        );
+        assert_eq!(parse_prompt("echo '2>x'"), vec!["echo", "2>x"]);
+    }
+
     #[test]
     fn test_spaces_in_quotes() {
         assert_eq!(parse_prompt("'hello   world'"), vec!["hello   world"]);
@@ -337,6 +1625,478 @@ mod tests {
         );
     }
 
+    fn run_echo(prompt: &str) -> String {
+        run_echo_in(prompt, &mut ShellState::new())
+    }
+
+    /// Runs the first command of `prompt` with `state`, returning its output.
+    fn run_echo_in(prompt: &str, state: &mut ShellState) -> String {
+        let stages = parse_pipeline(tokenize(prompt).unwrap(), state).unwrap();
+        let (command, _) = stages.into_iter().next().unwrap();
+        let mut out = crate::CaptureOutput::new();
+        let mut err = crate::CaptureOutput::new();
+        crate::execute_command(command, None, Some(&mut out), &mut err, state).unwrap();
+        out.contents()
+    }
+
+    #[test]
+    fn test_redirect_before_command_name() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("lead.txt");
+        let mut state = ShellState::new();
+
+        let line = format!("> {} echo hi there", path.display());
+        let stages = parse_pipeline(tokenize(&line).unwrap(), &mut state).unwrap();
+        assert!(stages[0].1.stdout.is_redirected());
+        let (command, mut streams) = stages.into_iter().next().unwrap();
+        assert!(matches!(&command, Command::Echo { args, .. } if args == &["hi", "there"]));
+        crate::execute_command(
+            command,
+            None,
+            Some(&mut *streams.stdout),
+            &mut *streams.stderr,
+            &mut state,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hi there\n");
+
+        let line = format!("echo hi 2>{} there", path.display());
+        let stages = parse_pipeline(tokenize(&line).unwrap(), &mut state).unwrap();
+        assert!(matches!(&stages[0].0, Command::Echo { args, .. } if args == &["hi", "there"]));
+    }
+
+    #[test]
+    fn test_echo_upper_e_overrides_e() {
+        assert_eq!(run_echo("echo -e -E 'a\\tb'"), "a\\tb\n");
+        assert_eq!(run_echo("echo -E -e 'a\\tb'"), "a\tb\n");
+        assert_eq!(run_echo("echo -eE 'a\\tb'"), "a\\tb\n");
+    }
+
+    #[test]
+    fn test_echo_combined_flags() {
+        assert_eq!(run_echo("echo -nE 'a\\tb'"), "a\\tb");
+        assert_eq!(run_echo("echo -n -e 'a\\tb'"), "a\tb");
+        // Anything else is an ordinary argument, and so is what follows it.
+        assert_eq!(run_echo("echo -x -n"), "-x -n\n");
+        assert_eq!(run_echo("echo - hi"), "- hi\n");
+    }
+
+    #[test]
+    fn test_posix_echo_takes_only_n() {
+        let mut state = ShellState::new();
+        crate::run_line("set -o posix", &mut state);
+        let mut echo = |prompt: &str| run_echo_in(prompt, &mut state);
+        assert_eq!(echo("echo -e '\\n'"), "-e \\n\n");
+        assert_eq!(echo("echo -n -E x"), "-E x");
+    }
+
+    #[test]
+    fn test_xpg_echo_interprets_escapes_by_default() {
+        let mut state = ShellState::new();
+        crate::run_line("set -o xpg_echo", &mut state);
+        let mut echo = |prompt: &str| run_echo_in(prompt, &mut state);
+        assert_eq!(echo("echo 'a\\nb'"), "a\nb\n");
+        assert_eq!(echo("echo -E 'a\\nb'"), "a\\nb\n");
+    }
+
+    #[test]
+    fn test_echo_double_dash_ends_flags() {
+        assert_eq!(run_echo("echo -- -n"), "-n\n");
+        assert_eq!(run_echo("echo -n -- x"), "x");
+        assert_eq!(run_echo("echo -e -- 'a\\tb' --"), "a\tb --\n");
+        assert_eq!(run_echo("echo x --"), "x --\n");
+    }
+
+    #[test]
+    fn test_echo_custom_separator() {
+        assert_eq!(run_echo("echo -s , a b c"), "a,b,c\n");
+        assert_eq!(run_echo("echo -n -s '' a b"), "ab");
+        assert_eq!(run_echo("echo a b c"), "a b c\n");
+        // A trailing `-s` has no separator and is printed.
+        assert_eq!(run_echo("echo -s"), "-s\n");
+    }
+
+    #[test]
+    fn test_echo_preserves_quoted_whitespace() {
+        assert_eq!(run_echo("echo \"a   b\" c"), "a   b c\n");
+    }
+
+    #[test]
+    fn test_echo_collapses_unquoted_whitespace() {
+        assert_eq!(run_echo("echo a    b"), "a b\n");
+    }
+
+    #[test]
+    fn test_echo_escapes_preserve_quoted_whitespace() {
+        assert_eq!(run_echo("echo -e \"a   b\" 'c\\td'"), "a   b c\td\n");
+    }
+
+    fn parse_error(prompt: &str) -> ParseError {
+        let tokens = match tokenize(prompt) {
+            Ok(tokens) => tokens,
+            Err(e) => return e,
+        };
+        match parse_pipeline(tokens, &mut ShellState::new()) {
+            Ok(_) => panic!("expected {:?} to fail", prompt),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn test_error_unterminated_quote() {
+        assert!(matches!(
+            parse_error("echo 'hello"),
+            ParseError::UnterminatedQuote { quote: '\'' }
+        ));
+        assert!(matches!(
+            parse_error("echo \"hello"),
+            ParseError::UnterminatedQuote { quote: '"' }
+        ));
+        assert_eq!(
+            parse_error("echo 'hello").to_string(),
+            "unexpected EOF while looking for matching `''"
+        );
+    }
+
+    #[test]
+    fn test_error_missing_redirect_target() {
+        assert!(matches!(
+            parse_error("echo hi >"),
+            ParseError::MissingRedirectTarget
+        ));
+        assert_eq!(
+            parse_error("echo hi 2>>").to_string(),
+            "redirect path missing"
+        );
+    }
+
+    #[test]
+    fn test_error_leading_pipe() {
+        assert!(matches!(
+            parse_error("| grep foo"),
+            ParseError::UnexpectedToken(token) if token == "|"
+        ));
+        assert_eq!(
+            parse_error("| grep foo").to_string(),
+            "syntax error near unexpected token `|'"
+        );
+    }
+
+    #[test]
+    fn test_error_trailing_pipe() {
+        assert!(matches!(
+            parse_error("ls |"),
+            ParseError::UnexpectedToken(token) if token == "|"
+        ));
+    }
+
+    #[test]
+    fn test_error_doubled_pipe() {
+        assert!(matches!(
+            parse_error("ls | | grep foo"),
+            ParseError::UnexpectedToken(token) if token == "|"
+        ));
+    }
+
+    #[test]
+    fn test_or_operator_is_not_a_pipe() {
+        assert_eq!(tokenize("a || b").unwrap(), vec!["a", "||", "b"]);
+    }
+
+    #[test]
+    fn test_error_empty_pipeline() {
+        assert!(matches!(parse_error(""), ParseError::EmptyPipeline));
+    }
+
+    #[test]
+    fn test_error_history_argument() {
+        let error = parse_error("history abc");
+        assert!(matches!(
+            error,
+            ParseError::InvalidArgument {
+                builtin: "history",
+                ..
+            }
+        ));
+        assert_eq!(error.to_string(), "history: numeric argument required");
+    }
+
+    #[test]
+    fn test_error_umask_argument() {
+        assert_eq!(
+            parse_error("umask 089").to_string(),
+            "umask: octal number out of range"
+        );
+        assert_eq!(
+            parse_error("umask 1777").to_string(),
+            "umask: octal number out of range"
+        );
+    }
+
+    #[test]
+    fn test_background_ampersand() {
+        let mut tokens = tokenize("sleep 1 &").unwrap();
+        assert!(take_background(&mut tokens));
+        assert_eq!(tokens, ["sleep", "1"]);
+
+        let mut tokens = tokenize("echo 'a & b'").unwrap();
+        assert!(!take_background(&mut tokens));
+        assert_eq!(
+            parse_error("sleep 1 & echo hi").to_string(),
+            "syntax error near unexpected token `&'"
+        );
+    }
+
+    #[test]
+    fn test_mkdirredirect_creates_parent_directories() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("logs/today/out.txt");
+        let line = format!("echo hi >> {}", path.display());
+        let mut state = ShellState::new();
+        assert!(parse_pipeline(tokenize(&line).unwrap(), &mut state).is_err());
+        assert!(!dir.path().join("logs").exists());
+
+        state.options.mkdirredirect = true;
+        for (command, mut streams) in parse_pipeline(tokenize(&line).unwrap(), &mut state).unwrap()
+        {
+            crate::execute_command(
+                command,
+                None,
+                Some(&mut *streams.stdout),
+                &mut *streams.stderr,
+                &mut state,
+            )
+            .unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hi\n");
+    }
+
+    #[test]
+    fn test_redirect_to_directory_is_refused() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut state = ShellState::new();
+        for op in [">", ">>"] {
+            let line = format!("echo hi {} {}", op, dir.path().display());
+            let Err(error) = parse_pipeline(tokenize(&line).unwrap(), &mut state) else {
+                panic!("expected {:?} to fail", line);
+            };
+            assert_eq!(
+                error.to_string(),
+                format!("{}: Is a directory", dir.path().display())
+            );
+        }
+        crate::run_line(&format!("echo hi > {}", dir.path().display()), &mut state);
+        assert_eq!(state.last_status, 1);
+    }
+
+    #[test]
+    fn test_noclobber_refuses_existing_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("keep.txt");
+        std::fs::write(&path, "keep").unwrap();
+        let mut state = ShellState::new();
+        state.options.noclobber = true;
+
+        let line = format!("echo hi > {}", path.display());
+        let Err(error) = parse_pipeline(tokenize(&line).unwrap(), &mut state) else {
+            panic!("expected {:?} to fail", line);
+        };
+        assert_eq!(
+            error.to_string(),
+            format!("{}: cannot overwrite existing file", path.display())
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+
+        let line = format!("echo hi >| {}", path.display());
+        assert!(parse_pipeline(tokenize(&line).unwrap(), &mut state).is_ok());
+        assert_eq!(
+            tokenize("echo hi 2>|x").unwrap(),
+            ["echo", "hi", "2>|", "x"]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_limit() {
+        let mut state = ShellState::new();
+        state.vars.set(PIPELINE_LIMIT_VAR, "3").unwrap();
+        let tokens = tokenize("echo a | cat | cat").unwrap();
+        assert_eq!(parse_pipeline(tokens, &mut state).unwrap().len(), 3);
+
+        let tokens = tokenize("echo a | cat | cat | cat").unwrap();
+        let Err(error) = parse_pipeline(tokens, &mut state) else {
+            panic!("expected the pipeline to be rejected");
+        };
+        assert!(matches!(error, ParseError::PipelineTooLong { limit: 3 }));
+        assert_eq!(error.to_string(), "pipeline too long: more than 3 stages");
+    }
+
+    #[test]
+    fn test_line_continuation_joins_lines() {
+        assert!(needs_continuation("echo hello \\"));
+        assert!(!needs_continuation("echo hello \\\\"));
+        assert!(!needs_continuation("echo 'hello \\"));
+        assert!(needs_continuation("echo \"hello \\"));
+
+        assert_eq!(
+            tokenize("echo hello \\\nworld").unwrap(),
+            ["echo", "hello", "world"]
+        );
+        assert_eq!(tokenize("echo hel\\\nlo").unwrap(), ["echo", "hello"]);
+        assert_eq!(tokenize("echo \"a\\\nb\"").unwrap(), ["echo", "\"ab\""]);
+        // Single quotes keep the backslash and the newline.
+        assert_eq!(tokenize("echo 'a\\\nb'").unwrap(), ["echo", "'a\\\nb'"]);
+    }
+
+    #[test]
+    fn test_tabs_separate_words_outside_quotes() {
+        assert_eq!(
+            tokenize("echo\tfoo").unwrap(),
+            tokenize("echo foo").unwrap()
+        );
+        assert_eq!(tokenize("echo \t foo\t").unwrap(), ["echo", "foo"]);
+        assert_eq!(tokenize("echo '\t'").unwrap(), ["echo", "'\t'"]);
+        assert_eq!(run_echo("echo\t'a\tb'\t\"c\td\""), "a\tb c\td\n");
+    }
+
+    #[test]
+    fn test_comments_run_to_end_of_line() {
+        assert_eq!(tokenize("echo hi # a comment").unwrap(), ["echo", "hi"]);
+        assert_eq!(
+            tokenize("  # only a comment").unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            tokenize("echo a#b '#c' \\#d").unwrap(),
+            ["echo", "a#b", "'#c'", "\\#d"]
+        );
+        assert_eq!(tokenize("echo ${#x} $#").unwrap(), ["echo", "${#x}", "$#"]);
+        assert_eq!(
+            tokenize("echo a # b\necho c").unwrap(),
+            ["echo", "a", "echo", "c"]
+        );
+        assert_eq!(
+            function_definition("f() { echo } # }\n}").unwrap().body,
+            Some(" echo } # }\n")
+        );
+    }
+
+    #[test]
+    fn test_function_definition_forms() {
+        let definition = function_definition("greet() { echo \"hi }\"; }; greet").unwrap();
+        assert_eq!(definition.name, "greet");
+        assert_eq!(definition.body, Some(" echo \"hi }\"; "));
+        assert_eq!(definition.rest, "; greet");
+
+        let definition = function_definition("function f { g() { echo; }; }").unwrap();
+        assert_eq!(definition.name, "f");
+        assert_eq!(definition.body, Some(" g() { echo; }; "));
+        assert_eq!(
+            function_definition("function f () {\n echo\n}")
+                .unwrap()
+                .body,
+            Some("\n echo\n")
+        );
+
+        assert!(needs_continuation("f() {"));
+        assert!(needs_continuation("f() {\n  echo }"));
+        assert!(!needs_continuation("f() {\n  echo\n}"));
+        assert_eq!(function_definition("echo (a) {"), None);
+        assert_eq!(function_definition("f() {echo; }"), None);
+        assert_eq!(function_definition("arr=(a b)"), None);
+    }
+
+    #[test]
+    fn test_logical_lines_split_pasted_block() {
+        let paste = "echo one\necho 'two' \\\n  three\nf() {\n  echo f\n}\necho four";
+        assert_eq!(
+            logical_lines(paste),
+            [
+                "echo one",
+                "echo 'two' \\\n  three",
+                "f() {\n  echo f\n}",
+                "echo four",
+            ]
+        );
+        // An unfinished last line is kept whole.
+        assert_eq!(
+            logical_lines("echo a\ncase x in\n"),
+            ["echo a", "case x in"]
+        );
+    }
+
+    #[test]
+    fn test_case_statement_branches() {
+        let statement =
+            case_statement("case \"$x\" in (a) echo A;; b | 'c)') echo;; esac; echo").unwrap();
+        assert_eq!(statement.word, "\"$x\"");
+        assert_eq!(statement.rest, "; echo");
+        let branches = case_branches(statement.body.unwrap()).unwrap();
+        assert_eq!(
+            branches,
+            [
+                CaseBranch {
+                    patterns: vec!["a"],
+                    commands: " echo A",
+                },
+                CaseBranch {
+                    patterns: vec!["b", "'c)'"],
+                    commands: " echo",
+                },
+            ]
+        );
+
+        // A nested statement and an `esac` argument do not close it.
+        let statement =
+            case_statement("case a in a) case b in b) echo esac;; esac;; esac").unwrap();
+        let branches = case_branches(statement.body.unwrap()).unwrap();
+        assert_eq!(branches.len(), 1);
+        assert_eq!(branches[0].commands, " case b in b) echo esac;; esac");
+
+        assert!(needs_continuation("case $x in"));
+        assert!(needs_continuation("case $x in\n  a) echo;;"));
+        assert!(!needs_continuation("case $x in\n  a) echo;;\nesac"));
+        assert_eq!(case_statement("cases x in a) esac"), None);
+        assert!(matches!(
+            case_branches(" oops;; "),
+            Err(ParseError::UnexpectedToken(token)) if token == "oops"
+        ));
+    }
+
+    #[test]
+    fn test_array_assignment() {
+        let mut state = ShellState::new();
+        state.vars.set("x", "from var").unwrap();
+        let stages = parse_pipeline(tokenize("arr=(a 'b c' \"$x\")").unwrap(), &mut state).unwrap();
+        assert!(matches!(
+            &stages[0].0,
+            Command::Assign { name, value: Value::Array(items) }
+                if name == "arr" && items == &["a", "b c", "from var"]
+        ));
+
+        crate::handle_pipeline(stages, &mut state);
+        assert_eq!(state.vars.get_index("arr", 1), Some("b c"));
+        assert_eq!(state.vars.get_index("arr", 3), None);
+    }
+
+    #[test]
+    fn test_array_assignment_is_one_token() {
+        assert_eq!(
+            tokenize("arr=(a \"b ) c\" d) | cat").unwrap(),
+            vec!["arr=(a \"b ) c\" d)", "|", "cat"]
+        );
+        assert_eq!(tokenize("echo (a b)").unwrap(), vec!["echo", "(a", "b)"]);
+    }
+
+    #[test]
+    fn test_scalar_and_array_side_by_side() {
+        let mut state = ShellState::new();
+        state.vars.set("name", "scalar").unwrap();
+        let stages = parse_pipeline(tokenize("arr=(x y)").unwrap(), &mut state).unwrap();
+        crate::handle_pipeline(stages, &mut state);
+        assert_eq!(state.vars.get("name"), Some("scalar"));
+        assert_eq!(state.vars.get("arr"), Some("x"));
+    }
+
     #[test]
     fn test_pipe_in_quotes() {
         // Pipe inside quotes should not be treated as separator
@@ -345,4 +2105,65 @@ mod tests {
             vec!["echo", "hello | world"]
         );
     }
+
+    #[test]
+    fn test_alias_expands_in_command_position() {
+        let mut state = ShellState::new();
+        state.aliases.set("ls", "ls -F");
+        state.aliases.set("ll", "ls -l");
+        let tokens = tokenize("ll ll | ll 'll'").unwrap();
+        assert_eq!(
+            expand_aliases(tokens, &state).unwrap(),
+            vec!["ls", "-F", "-l", "ll", "|", "ls", "-F", "-l", "'ll'"]
+        );
+    }
+
+    #[test]
+    fn test_filesystem_builtin_flags() {
+        let mut state = ShellState::new();
+        let parse = |line: &str, state: &mut ShellState| {
+            parse_pipeline(tokenize(line).unwrap(), state).map(|mut stages| stages.remove(0).0)
+        };
+        assert!(matches!(
+            parse("rm -rf -- -x y", &mut state),
+            Ok(Command::Rm { recursive: true, force: true, paths }) if paths == ["-x", "y"]
+        ));
+        assert!(matches!(
+            parse("rm -f", &mut state),
+            Ok(Command::Rm { paths, .. }) if paths.is_empty()
+        ));
+        assert!(matches!(
+            parse("rm -iv x", &mut state),
+            Ok(Command::Exec { command, args }) if command == "rm" && args == ["-iv", "x"]
+        ));
+        assert!(matches!(
+            parse("ls -la d", &mut state),
+            Ok(Command::Ls { options, paths }) if options.long && options.all && paths == ["d"]
+        ));
+        assert!(matches!(
+            parse("ls -lh --color=never d", &mut state),
+            Ok(Command::Exec { command, args })
+                if command == "ls" && args == ["-lh", "--color=never", "d"]
+        ));
+        assert!(matches!(
+            parse("mkdir -p a", &mut state),
+            Ok(Command::Mkdir { parents: true, .. })
+        ));
+        for line in ["mkdir -m 700 d", "rmdir -p a/b", "touch -d 2020-01-01 f"] {
+            let (tool, args) = line.split_once(' ').unwrap();
+            assert!(matches!(
+                parse(line, &mut state),
+                Ok(Command::Exec { command, args: exec_args })
+                    if command == tool && exec_args.join(" ") == args
+            ));
+        }
+        let Err(error) = parse("basename -z a", &mut state) else {
+            panic!("expected an error");
+        };
+        assert_eq!(error.to_string(), "basename: invalid option -- 'z'");
+        let Err(error) = parse("touch", &mut state) else {
+            panic!("expected an error");
+        };
+        assert_eq!(error.to_string(), "touch: missing operand");
+    }
 }
@@ -0,0 +1,2 @@
+pub use printf::*;
+pub mod printf;
@@ -0,0 +1,482 @@
+use crate::expand::single_quote;
+use crate::os_text::text_bytes;
+
+/// What `printf` produced: the bytes to write, and the messages for
+/// arguments it could not use. Any message makes the status 1.
+#[derive(Debug, Default, PartialEq)]
+pub struct Printed {
+    pub bytes: Vec<u8>,
+    pub errors: Vec<String>,
+}
+
+/// One `%` conversion: its flags, width and precision, and the letter.
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+}
+
+/// Formats `args` the way the `printf` builtin does. The format is reused
+/// while arguments are left, and missing arguments count as empty or zero.
+///
+/// Conversions are `%s`, `%b` (the argument's escapes are interpreted),
+/// `%q` (quoted for reuse as shell input), `%c`, `%d`/`%i`, `%u`, `%o`,
+/// `%x`/`%X`, `%f` and `%e`/`%E`, with the usual flags, width and
+/// precision.
+pub fn printf(format: &str, args: &[String]) -> Printed {
+    let mut printed = Printed::default();
+    let mut args = args.iter();
+    loop {
+        let remaining = args.len();
+        if !format_once(format, &mut args, &mut printed) {
+            break;
+        }
+        // A format without conversions prints once, whatever the arguments.
+        if args.len() == 0 || args.len() == remaining {
+            break;
+        }
+    }
+    printed
+}
+
+/// Runs through `format` once, returning `false` when output must stop,
+/// after `\c` or an invalid conversion.
+fn format_once<'a>(
+    format: &str,
+    args: &mut impl Iterator<Item = &'a String>,
+    printed: &mut Printed,
+) -> bool {
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if !escape(&mut chars, false, &mut printed.bytes) {
+                    return false;
+                }
+            }
+            '%' if chars.peek() == Some(&'%') => {
+                chars.next();
+                printed.bytes.push(b'%');
+            }
+            '%' => {
+                let mut spec = Spec::default();
+                while let Some(&flag @ ('-' | '+' | ' ' | '#' | '0')) = chars.peek() {
+                    match flag {
+                        '-' => spec.left = true,
+                        '+' => spec.plus = true,
+                        ' ' => spec.space = true,
+                        '#' => spec.alternate = true,
+                        _ => spec.zero = true,
+                    }
+                    chars.next();
+                }
+                let width = count(&mut chars, args, printed);
+                let precision = if chars.next_if_eq(&'.').is_some() {
+                    // A negative precision from `*` counts as none, as in C.
+                    Some(count(&mut chars, args, printed).unwrap_or(0)).filter(|&n| n >= 0)
+                } else {
+                    None
+                };
+                if [width, precision]
+                    .into_iter()
+                    .flatten()
+                    .any(|n| n.unsigned_abs() > MAX_COUNT)
+                {
+                    printed.errors.push("invalid width/precision".to_string());
+                    return false;
+                }
+                // A negative width from `*` left-justifies.
+                spec.left |= width.is_some_and(|n| n < 0);
+                spec.width = width.map(|n| n.unsigned_abs() as usize);
+                spec.precision = precision.map(|n| n as usize);
+                let Some(conversion) = chars.next() else {
+                    printed
+                        .errors
+                        .push("`%': missing format character".to_string());
+                    return false;
+                };
+                let arg = args.next().map(String::as_str);
+                if !convert(conversion, &spec, arg, printed) {
+                    return false;
+                }
+            }
+            _ => printed
+                .bytes
+                .extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    true
+}
+
+/// The largest width or precision accepted. Rust's formatter takes no
+/// more, and anything near it is already far wider than any real output.
+const MAX_COUNT: u64 = u16::MAX as u64;
+
+/// Reads a width or precision: digits, or `*` to take it, possibly
+/// negative, from an argument. Digits too large for an `i64` saturate.
+fn count<'a>(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    args: &mut impl Iterator<Item = &'a String>,
+    printed: &mut Printed,
+) -> Option<i64> {
+    if chars.next_if_eq(&'*').is_some() {
+        return Some(number(args.next().map(String::as_str), printed));
+    }
+    let mut digits = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        digits.push(digit);
+    }
+    (!digits.is_empty()).then(|| digits.parse().unwrap_or(i64::MAX))
+}
+
+/// Appends one conversion of `arg`, returning `false` for an unknown one.
+fn convert(conversion: char, spec: &Spec, arg: Option<&str>, printed: &mut Printed) -> bool {
+    let text = arg.unwrap_or_default();
+    let bytes = match conversion {
+        's' => truncate(text_bytes(text), spec.precision),
+        'q' => text_bytes(&quote(text)),
+        'b' => {
+            let mut bytes = Vec::new();
+            let mut chars = text.chars().peekable();
+            let mut more = true;
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    bytes.extend_from_slice(&text_bytes(c.encode_utf8(&mut [0; 4])));
+                } else if !escape(&mut chars, true, &mut bytes) {
+                    more = false;
+                    break;
+                }
+            }
+            printed
+                .bytes
+                .extend(pad(truncate(bytes, spec.precision), spec, false));
+            return more;
+        }
+        'c' => text_bytes(&text.chars().take(1).collect::<String>()),
+        'd' | 'i' => {
+            let value = number(arg, printed);
+            let digits = with_precision(value.unsigned_abs().to_string(), spec.precision);
+            let sign = if value < 0 {
+                "-"
+            } else if spec.plus {
+                "+"
+            } else if spec.space {
+                " "
+            } else {
+                ""
+            };
+            return push_number(sign, &digits, spec, printed);
+        }
+        'u' | 'o' | 'x' | 'X' => {
+            let value = number(arg, printed) as u64;
+            let (digits, prefix) = match conversion {
+                'u' => (value.to_string(), ""),
+                'o' => (format!("{:o}", value), "0"),
+                'x' => (format!("{:x}", value), "0x"),
+                _ => (format!("{:X}", value), "0X"),
+            };
+            let digits = with_precision(digits, spec.precision);
+            let prefix = if spec.alternate && value != 0 && !digits.starts_with('0') {
+                prefix
+            } else {
+                ""
+            };
+            return push_number(prefix, &digits, spec, printed);
+        }
+        'f' | 'F' | 'e' | 'E' => {
+            let value = float(arg, printed);
+            let precision = spec.precision.unwrap_or(6);
+            let digits = match conversion {
+                'f' | 'F' => format!("{:.*}", precision, value.abs()),
+                _ => exponent(value.abs(), precision, conversion == 'E'),
+            };
+            let sign = if value.is_sign_negative() && value != 0.0 {
+                "-"
+            } else if spec.plus {
+                "+"
+            } else if spec.space {
+                " "
+            } else {
+                ""
+            };
+            return push_number(sign, &digits, spec, printed);
+        }
+        other => {
+            printed
+                .errors
+                .push(format!("`{}': invalid format character", other));
+            return false;
+        }
+    };
+    printed.bytes.extend(pad(bytes, spec, false));
+    true
+}
+
+/// Appends a number, padded with zeros after its sign for the `0` flag.
+fn push_number(sign: &str, digits: &str, spec: &Spec, printed: &mut Printed) -> bool {
+    let zeros = spec.zero && !spec.left && spec.precision.is_none();
+    let width = spec.width.unwrap_or(0);
+    let mut text = sign.to_string();
+    if zeros {
+        let len = sign.len() + digits.len();
+        text.push_str(&"0".repeat(width.saturating_sub(len)));
+    }
+    text.push_str(digits);
+    printed.bytes.extend(pad(text.into_bytes(), spec, zeros));
+    true
+}
+
+/// Pads `bytes` with spaces to the spec's width.
+fn pad(bytes: Vec<u8>, spec: &Spec, padded: bool) -> Vec<u8> {
+    let fill = spec.width.unwrap_or(0).saturating_sub(bytes.len());
+    if padded || fill == 0 {
+        return bytes;
+    }
+    let spaces = vec![b' '; fill];
+    if spec.left {
+        [bytes, spaces].concat()
+    } else {
+        [spaces, bytes].concat()
+    }
+}
+
+fn truncate(mut bytes: Vec<u8>, precision: Option<usize>) -> Vec<u8> {
+    if let Some(precision) = precision {
+        bytes.truncate(precision);
+    }
+    bytes
+}
+
+/// Left-pads `digits` with zeros to at least `precision` digits.
+fn with_precision(digits: String, precision: Option<usize>) -> String {
+    match precision {
+        Some(precision) if digits.len() < precision => {
+            format!("{}{}", "0".repeat(precision - digits.len()), digits)
+        }
+        _ => digits,
+    }
+}
+
+/// C-style scientific notation, with at least two exponent digits.
+fn exponent(value: f64, precision: usize, upper: bool) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let e = if upper { 'E' } else { 'e' };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}{}{}{:02}", mantissa, e, sign, exponent.abs())
+}
+
+/// Parses a numeric argument: decimal, `0x` hex, `0` octal, or `'c` for the
+/// code of a character. Anything else is reported and counts as 0.
+fn number(arg: Option<&str>, printed: &mut Printed) -> i64 {
+    let Some(arg) = arg else {
+        return 0;
+    };
+    let text = arg.trim();
+    if let Some(quoted) = text.strip_prefix(['\'', '"']) {
+        return quoted.chars().next().map_or(0, |c| i64::from(u32::from(c)));
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse()
+    };
+    match parsed {
+        Ok(value) if negative => -value,
+        Ok(value) => value,
+        Err(_) => {
+            printed.errors.push(format!("{}: invalid number", arg));
+            0
+        }
+    }
+}
+
+fn float(arg: Option<&str>, printed: &mut Printed) -> f64 {
+    let Some(arg) = arg else {
+        return 0.0;
+    };
+    arg.trim().parse().unwrap_or_else(|_| {
+        printed.errors.push(format!("{}: invalid number", arg));
+        0.0
+    })
+}
+
+/// Appends the byte for the escape after a `\`. In `%b` arguments (`in_arg`)
+/// octal escapes are written `\0NNN`, as in bash. Returns `false` for `\c`,
+/// which ends all output.
+fn escape(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    in_arg: bool,
+    bytes: &mut Vec<u8>,
+) -> bool {
+    let Some(c) = chars.next() else {
+        bytes.push(b'\\');
+        return true;
+    };
+    let byte = match c {
+        'a' => 0x07,
+        'b' => 0x08,
+        'e' | 'E' => 0x1b,
+        'f' => 0x0c,
+        'n' => b'\n',
+        'r' => b'\r',
+        't' => b'\t',
+        'v' => 0x0b,
+        '\\' => b'\\',
+        '"' if !in_arg => b'"',
+        '\'' if !in_arg => b'\'',
+        'c' if in_arg => return false,
+        '0'..='7' => {
+            // `\0NNN` in `%b` arguments, `\NNN` in the format.
+            let mut value = if in_arg && c == '0' {
+                0
+            } else {
+                c.to_digit(8).unwrap_or(0)
+            };
+            for _ in 0..if in_arg && c == '0' { 3 } else { 2 } {
+                match chars.peek().and_then(|c| c.to_digit(8)) {
+                    Some(digit) => {
+                        value = value * 8 + digit;
+                        chars.next();
+                    }
+                    None => break,
+                }
+            }
+            value as u8
+        }
+        'x' => {
+            let mut value = 0;
+            let mut digits = 0;
+            while digits < 2
+                && let Some(digit) = chars.peek().and_then(|c| c.to_digit(16))
+            {
+                value = value * 16 + digit;
+                digits += 1;
+                chars.next();
+            }
+            if digits == 0 {
+                bytes.extend_from_slice(b"\\x");
+                return true;
+            }
+            value as u8
+        }
+        other => {
+            bytes.push(b'\\');
+            bytes.extend_from_slice(&text_bytes(other.encode_utf8(&mut [0; 4])));
+            return true;
+        }
+    };
+    bytes.push(byte);
+    true
+}
+
+/// Quotes `text` so that the shell reads it back as one word, unchanged:
+/// special characters get a backslash, like bash's `%q`. Text with control
+/// characters such as a newline is single-quoted instead, since a
+/// backslash-newline would join lines.
+pub fn quote(text: &str) -> String {
+    if text.is_empty() {
+        return "''".to_string();
+    }
+    if text.chars().any(char::is_control) {
+        return single_quote(text);
+    }
+    let mut quoted = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii() && !c.is_ascii_alphanumeric() && !"_-./,:+@%=".contains(c) {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(format: &str, args: &[&str]) -> String {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let printed = printf(format, &args);
+        assert!(printed.errors.is_empty(), "{:?}", printed.errors);
+        String::from_utf8(printed.bytes).unwrap()
+    }
+
+    #[test]
+    fn test_conversions_and_reuse() {
+        assert_eq!(run("%s-%s\\n", &["a", "b", "c"]), "a-b\nc-\n");
+        assert_eq!(
+            run("[%5s|%-3s|%.2s]", &["ab", "c", "xyz"]),
+            "[   ab|c  |xy]"
+        );
+        assert_eq!(
+            run("%d %+d %05d %x %#o", &["42", "7", "-12", "255", "8"]),
+            "42 +7 -0012 ff 010"
+        );
+        assert_eq!(run("%c%b", &["xyz", "a\\tb"]), "xa\tb");
+        assert_eq!(run("%.2f %e", &["3.14159", "1500"]), "3.14 1.500000e+03");
+        assert_eq!(run("%d", &["'A"]), "65");
+        assert_eq!(run("100%%\\101", &[]), "100%A");
+    }
+
+    #[test]
+    fn test_invalid_arguments_are_reported() {
+        let printed = printf("%d|%z", &["abc".to_string()]);
+        assert_eq!(printed.bytes, b"0|");
+        assert_eq!(
+            printed.errors,
+            ["abc: invalid number", "`z': invalid format character"]
+        );
+    }
+
+    #[test]
+    fn test_star_width_and_precision() {
+        assert_eq!(run("[%*s]", &["4", "ab"]), "[  ab]");
+        assert_eq!(run("[%*s]", &["-4", "ab"]), "[ab  ]");
+        assert_eq!(run("[%.*s]", &["1", "ab"]), "[a]");
+        assert_eq!(run("[%.*s]", &["-1", "ab"]), "[ab]");
+    }
+
+    #[test]
+    fn test_huge_width_or_precision_is_an_error() {
+        for (format, args) in [
+            ("%.999999999999f\\n", vec!["1".to_string()]),
+            ("%99999999999999999999d", vec!["1".to_string()]),
+            ("x%*s", vec!["-9999999".to_string(), "a".to_string()]),
+        ] {
+            let printed = printf(format, &args);
+            assert_eq!(printed.errors, ["invalid width/precision"], "{}", format);
+            assert!(printed.bytes.len() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_q_quotes_for_reuse() {
+        assert_eq!(run("%q", &["plain-word.txt"]), "plain-word.txt");
+        assert_eq!(run("%q", &["two words"]), "two\\ words");
+        assert_eq!(run("%q", &["it's"]), "it\\'s");
+        assert_eq!(run("%q", &["a\nb"]), "'a\nb'");
+        assert_eq!(run("%q", &[""]), "''");
+
+        // Each value reads back as a single, identical word.
+        let mut state = crate::ShellState::new();
+        for value in ["two words", "it's \"$HOME\" & *", "a\nb's\t", "~user", ""] {
+            let tokens = crate::parser::tokenize(&quote(value)).unwrap();
+            assert_eq!(tokens.len(), 1, "{:?}", value);
+            let words = crate::expand::expand_word(&tokens[0], &mut state).unwrap();
+            assert_eq!(words, [value]);
+        }
+    }
+}
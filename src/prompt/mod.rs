@@ -0,0 +1,2 @@
+pub use prompt::*;
+pub mod prompt;
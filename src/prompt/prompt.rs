@@ -0,0 +1,263 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const DEFAULT_PROMPT: &str = "$ ";
+
+/// What a prompt template can refer to. The REPL builds a fresh one before
+/// every prompt so `\w` follows `cd` immediately.
+#[derive(Debug, Clone)]
+pub struct PromptContext {
+    pub cwd: PathBuf,
+    /// `$HOME`, abbreviated to `~` in `\w`.
+    pub home: Option<PathBuf>,
+    pub user: String,
+    pub host: String,
+    /// Turns `\$` into `#`.
+    pub is_root: bool,
+    /// The time shown by `\t`.
+    pub now: SystemTime,
+}
+
+impl PromptContext {
+    /// A context for `cwd` with everything else read from the system.
+    pub fn new(cwd: PathBuf, home: Option<PathBuf>, user: Option<&str>) -> Self {
+        Self {
+            cwd,
+            home,
+            user: user.unwrap_or_default().to_string(),
+            host: hostname(),
+            is_root: is_root(),
+            now: SystemTime::now(),
+        }
+    }
+}
+
+/// Expands the bash prompt escapes in a `PS1`-style template:
+///
+/// - `\w` is the working directory, with the home directory shown as `~`
+/// - `\W` is the last component of the working directory
+/// - `\u` is the user name, `\h` the host name up to the first `.`, and
+///   `\H` the full host name
+/// - `\$` is `#` for root and `$` otherwise
+/// - `\t` is the time as `HH:MM:SS`
+/// - `\n` is a newline, `\e` an escape character and `\\` a backslash
+/// - `\[` and `\]` mark where color sequences start and end; they print
+///   nothing
+///
+/// Unknown escapes are kept as written.
+pub fn expand_prompt(template: &str, ctx: &PromptContext) -> String {
+    let mut rendered = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            rendered.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('w') => rendered.push_str(&working_directory(ctx)),
+            Some('W') => rendered.push_str(&directory_basename(ctx)),
+            Some('u') => rendered.push_str(&ctx.user),
+            Some('h') => rendered.push_str(ctx.host.split('.').next().unwrap_or_default()),
+            Some('H') => rendered.push_str(&ctx.host),
+            Some('$') => rendered.push(if ctx.is_root { '#' } else { '$' }),
+            Some('t') => {
+                let (hours, minutes, seconds) = clock(ctx.now);
+                rendered.push_str(&format!("{:02}:{:02}:{:02}", hours, minutes, seconds));
+            }
+            Some('n') => rendered.push('\n'),
+            Some('e') => rendered.push('\x1b'),
+            Some('\\') => rendered.push('\\'),
+            Some('[' | ']') => {}
+            Some(other) => {
+                rendered.push('\\');
+                rendered.push(other);
+            }
+            None => rendered.push('\\'),
+        }
+    }
+
+    rendered
+}
+
+/// Shows `path` with a leading home directory replaced by `~`, as `\w` and
+/// `pwd -t` do. Paths outside home, and every path when home is `/`, are
+/// shown in full.
+pub fn abbreviate_home(path: &Path, home: Option<&Path>) -> String {
+    if let Some(home) = home.filter(|home| home.parent().is_some())
+        && let Ok(rest) = path.strip_prefix(home)
+    {
+        if rest.as_os_str().is_empty() {
+            return "~".to_string();
+        }
+        return format!("~/{}", rest.display());
+    }
+    path.display().to_string()
+}
+
+fn working_directory(ctx: &PromptContext) -> String {
+    abbreviate_home(&ctx.cwd, ctx.home.as_deref())
+}
+
+fn directory_basename(ctx: &PromptContext) -> String {
+    if ctx.home.as_deref() == Some(ctx.cwd.as_path()) {
+        return "~".to_string();
+    }
+    match ctx.cwd.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        // The root directory has no last component.
+        None => ctx.cwd.display().to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: gethostname writes at most `buffer.len()` bytes into it.
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let len = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).to_string()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// The local time of day as (hours, minutes, seconds).
+#[cfg(unix)]
+fn clock(now: SystemTime) -> (u32, u32, u32) {
+    let seconds = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as libc::time_t)
+        .unwrap_or_default();
+    // SAFETY: localtime_r only writes into the zeroed struct we pass.
+    let local = unsafe {
+        let mut local: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&seconds, &mut local);
+        local
+    };
+    (
+        local.tm_hour as u32,
+        local.tm_min as u32,
+        local.tm_sec as u32,
+    )
+}
+
+/// The time of day in UTC as (hours, minutes, seconds).
+#[cfg(not(unix))]
+fn clock(now: SystemTime) -> (u32, u32, u32) {
+    let seconds = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs() % 86_400)
+        .unwrap_or_default() as u32;
+    (seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(cwd: &str) -> PromptContext {
+        PromptContext {
+            cwd: PathBuf::from(cwd),
+            home: Some(PathBuf::from("/home/user")),
+            user: "user".to_string(),
+            host: "box.example.com".to_string(),
+            is_root: false,
+            now: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_renders_working_directory() {
+        assert_eq!(expand_prompt("\\w $ ", &context("/tmp")), "/tmp $ ");
+    }
+
+    #[test]
+    fn test_abbreviates_home_in_working_directory() {
+        let template = "[\\w]$ ";
+        assert_eq!(expand_prompt(template, &context("/home/user")), "[~]$ ");
+        assert_eq!(
+            expand_prompt(template, &context("/home/user/src")),
+            "[~/src]$ "
+        );
+        // A sibling that merely shares the prefix is not under home.
+        assert_eq!(
+            expand_prompt(template, &context("/home/username")),
+            "[/home/username]$ "
+        );
+    }
+
+    #[test]
+    fn test_abbreviate_home() {
+        let home = Some(Path::new("/home/user"));
+        assert_eq!(abbreviate_home(Path::new("/home/user"), home), "~");
+        assert_eq!(abbreviate_home(Path::new("/home/user/a/b"), home), "~/a/b");
+        assert_eq!(abbreviate_home(Path::new("/srv/data"), home), "/srv/data");
+        assert_eq!(abbreviate_home(Path::new("/srv/data"), None), "/srv/data");
+        // A home of `/` would turn every path into `~/...`.
+        assert_eq!(
+            abbreviate_home(Path::new("/srv"), Some(Path::new("/"))),
+            "/srv"
+        );
+    }
+
+    #[test]
+    fn test_basename_of_working_directory() {
+        assert_eq!(
+            expand_prompt("\\W", &context("/home/user/src/shell")),
+            "shell"
+        );
+        assert_eq!(expand_prompt("\\W", &context("/home/user")), "~");
+        assert_eq!(expand_prompt("\\W", &context("/")), "/");
+    }
+
+    #[test]
+    fn test_user_host_and_symbols() {
+        let mut ctx = context("/");
+        assert_eq!(
+            expand_prompt("\\u@\\h:\\H\\$ ", &ctx),
+            "user@box:box.example.com$ "
+        );
+        ctx.is_root = true;
+        assert_eq!(expand_prompt("\\$", &ctx), "#");
+        assert_eq!(expand_prompt("a\\nb\\\\", &ctx), "a\nb\\");
+        assert_eq!(expand_prompt("\\q", &ctx), "\\q");
+    }
+
+    #[test]
+    fn test_color_markers_print_nothing() {
+        assert_eq!(
+            expand_prompt("\\[\\e[32m\\]$\\[\\e[0m\\] ", &context("/")),
+            "\x1b[32m$\x1b[0m "
+        );
+    }
+
+    #[test]
+    fn test_time_has_clock_format() {
+        let time = expand_prompt("\\t", &context("/"));
+        assert_eq!(time.len(), 8);
+        assert_eq!(&time[2..3], ":");
+        assert_eq!(&time[5..6], ":");
+    }
+
+    #[test]
+    fn test_leaves_other_text_alone() {
+        assert_eq!(expand_prompt(DEFAULT_PROMPT, &context("/")), "$ ");
+    }
+}
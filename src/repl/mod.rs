@@ -0,0 +1,2 @@
+pub use repl::*;
+pub mod repl;
@@ -0,0 +1,211 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use rustyline::error::ReadlineError;
+use rustyline::{Editor, Helper};
+
+use crate::prompt::{DEFAULT_PROMPT, PromptContext, expand_prompt};
+use crate::terminal::{apply_terminal_size, terminal_size};
+use crate::{OutputStreams, ShellState, run_prompt_command, run_script};
+
+/// Where the interactive loop gets its lines: the line editor, or a
+/// scripted stand-in in tests.
+pub trait LineReader {
+    fn read_line(&mut self, prompt: &str) -> Result<String, ReadlineError>;
+
+    /// Offers `line` for recall with the up arrow.
+    fn add_history(&mut self, line: &str);
+}
+
+impl<H: Helper, I: rustyline::history::History> LineReader for Editor<H, I> {
+    fn read_line(&mut self, prompt: &str) -> Result<String, ReadlineError> {
+        self.readline(prompt)
+    }
+
+    fn add_history(&mut self, line: &str) {
+        self.add_history_entry(line).ok();
+    }
+}
+
+/// Whether the loop can go on reading after `error`: bad bytes in one line
+/// or an interrupted read, as opposed to a terminal that has gone away.
+pub fn is_recoverable(error: &ReadlineError) -> bool {
+    match error {
+        ReadlineError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::InvalidData | io::ErrorKind::Interrupted
+        ),
+        ReadlineError::Signal(_) => true,
+        _ => false,
+    }
+}
+
+/// Leaves the prompt line on Ctrl-D, as bash does, so the terminal's own
+/// prompt starts on a fresh line, and flushes what is still held back.
+pub fn end_of_input(streams: &mut OutputStreams) {
+    streams.stdout.write("\n");
+    streams.flush();
+}
+
+/// Prompts for and runs lines until end of input or `exit`.
+pub fn run_repl(reader: &mut impl LineReader, state: &mut ShellState) {
+    // An rc file that calls `exit` ends the shell before the first prompt.
+    while !state.exit_requested {
+        run_prompt_command(state);
+        if state.exit_requested {
+            break;
+        }
+        if state
+            .resized
+            .as_ref()
+            .is_some_and(|resized| resized.swap(false, Ordering::Relaxed))
+        {
+            apply_terminal_size(terminal_size(), &mut state.vars);
+        }
+        // `\w` shows `$PWD`, as `cd` keeps it, so a directory entered
+        // through a symlink is shown by that path.
+        let cwd = match state.vars.get("PWD") {
+            Some(pwd) => PathBuf::from(pwd),
+            None => std::env::current_dir().unwrap_or_default(),
+        };
+        let ctx = PromptContext::new(
+            cwd,
+            state.vars.get("HOME").map(PathBuf::from),
+            state.vars.get("USER"),
+        );
+        let prompt = expand_prompt(state.vars.get("PS1").unwrap_or(DEFAULT_PROMPT), &ctx);
+        match reader.read_line(&prompt) {
+            Ok(line) => {
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                // Both histories get the line exactly as typed, so recalling it
+                // re-parses the same way no matter what expansion does later.
+                if state.history.should_record(&line) {
+                    reader.add_history(&line);
+                    state.history.add_history_item(&line).ok();
+                }
+
+                // A pasted block holds several lines, run one at a time.
+                run_script(&line, state);
+            }
+            Err(ReadlineError::Eof) => {
+                if state.interactive {
+                    end_of_input(&mut OutputStreams::default());
+                }
+                break;
+            }
+            // Ctrl-C drops the line being typed, as in bash.
+            Err(ReadlineError::Interrupted) => state.last_status = 130,
+            Err(error) if is_recoverable(&error) => eprintln!("error: {}", error),
+            Err(error) => {
+                eprintln!("error: {:?}", error);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CaptureOutput, Output};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    /// Hands out a fixed sequence of lines and errors, then end of input.
+    #[derive(Default)]
+    struct ScriptedReader {
+        lines: VecDeque<Result<String, ReadlineError>>,
+        recalled: Vec<String>,
+        prompts: Vec<String>,
+    }
+
+    impl LineReader for ScriptedReader {
+        fn read_line(&mut self, prompt: &str) -> Result<String, ReadlineError> {
+            self.prompts.push(prompt.to_string());
+            self.lines.pop_front().unwrap_or(Err(ReadlineError::Eof))
+        }
+
+        fn add_history(&mut self, line: &str) {
+            self.recalled.push(line.to_string());
+        }
+    }
+
+    /// Holds everything written until it is flushed to `flushed`.
+    #[derive(Debug, Default)]
+    struct BufferedOutput {
+        pending: String,
+        flushed: Rc<RefCell<String>>,
+    }
+
+    impl Output for BufferedOutput {
+        fn write(&mut self, text: &str) {
+            self.pending.push_str(text);
+        }
+
+        fn duplicate(&self) -> anyhow::Result<Box<dyn Output>> {
+            Ok(Box::new(CaptureOutput::new()))
+        }
+
+        fn flush(&mut self) {
+            self.flushed
+                .borrow_mut()
+                .push_str(&std::mem::take(&mut self.pending));
+        }
+    }
+
+    #[test]
+    fn test_end_of_input_flushes_a_newline() {
+        let flushed = Rc::new(RefCell::new(String::new()));
+        let mut stdout = BufferedOutput {
+            flushed: Rc::clone(&flushed),
+            ..Default::default()
+        };
+        stdout.write("partial");
+        let mut streams = OutputStreams::new(Box::new(stdout), Box::new(CaptureOutput::new()));
+        end_of_input(&mut streams);
+        assert_eq!(*flushed.borrow(), "partial\n");
+    }
+
+    #[test]
+    fn test_recoverable_error_keeps_session() {
+        let bad_line = io::Error::new(io::ErrorKind::InvalidData, "invalid utf-8");
+        let mut reader = ScriptedReader {
+            lines: VecDeque::from([
+                Err(ReadlineError::Io(bad_line)),
+                Err(ReadlineError::Interrupted),
+                Ok("export AFTER=yes".to_string()),
+                Err(ReadlineError::Io(io::ErrorKind::BrokenPipe.into())),
+                Ok("export NEVER=yes".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let mut state = ShellState::new();
+        run_repl(&mut reader, &mut state);
+
+        assert_eq!(state.vars.get("AFTER"), Some("yes"));
+        assert_eq!(reader.recalled, ["export AFTER=yes"]);
+        // A terminal that has gone away still ends the session.
+        assert_eq!(state.vars.get("NEVER"), None);
+        assert_eq!(reader.lines.len(), 1);
+    }
+
+    #[test]
+    fn test_prompt_shows_working_directory_from_pwd() {
+        let mut reader = ScriptedReader {
+            lines: VecDeque::from([Ok("export PWD=/srv/linked".to_string())]),
+            ..Default::default()
+        };
+        let mut state = ShellState::new();
+        state.vars.set("PS1", "\\w$ ").unwrap();
+        state.vars.set("HOME", "/home/user").unwrap();
+        state.vars.set("PWD", "/home/user/src").unwrap();
+        run_repl(&mut reader, &mut state);
+
+        assert_eq!(reader.prompts, ["~/src$ ", "/srv/linked$ "]);
+    }
+}
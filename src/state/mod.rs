@@ -0,0 +1,2 @@
+pub use state::*;
+pub mod state;
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use crate::completer::SharedCompletionSpecs;
+use crate::dirstack::DirStack;
+use crate::functions::{FunctionStreams, Functions};
+use crate::getopts::GetoptsCursor;
+use crate::substitution::Substitution;
+use crate::{
+    Aliases, CommandHash, History, Job, JobTable, ShellOptions, Spawner, StdSpawner, Traps,
+    Variables,
+};
+
+/// Mutable state that lives for the whole shell session.
+pub struct ShellState {
+    pub history: History,
+    pub hash: CommandHash,
+    pub vars: Variables,
+    pub options: ShellOptions,
+    pub traps: Traps,
+    pub aliases: Aliases,
+    pub functions: Functions,
+    /// Directories saved by `pushd`.
+    pub dir_stack: DirStack,
+    /// Shared with the line editor's completer.
+    pub completions: SharedCompletionSpecs,
+    /// Set by `exit`; the shell stops once the current line finishes.
+    pub exit_requested: bool,
+    /// Set when `exit` refused to leave because jobs were running; a second
+    /// `exit` straight after then leaves anyway.
+    pub exit_warned: bool,
+    /// Raised when the terminal is resized; `COLUMNS` and `LINES` are
+    /// updated before the next prompt.
+    pub resized: Option<Arc<AtomicBool>>,
+    /// Whether the shell reads from a terminal, which turns on job control.
+    pub interactive: bool,
+    /// The pipeline currently running in the foreground.
+    pub foreground: Job,
+    /// Jobs started in the background with `&`.
+    pub jobs: JobTable,
+    /// Starts the programs commands run.
+    pub spawner: Box<dyn Spawner>,
+    /// PID of the last process started in the background, for `$!`.
+    pub last_background_pid: Option<u32>,
+    /// Exit status of the last foreground pipeline.
+    pub last_status: i32,
+    /// Where `getopts` stopped, valid while `OPTIND` still matches it.
+    pub getopts: GetoptsCursor,
+    /// `$1`, `$2`, ... of the function being run, or as given to `set --`.
+    pub positional: Vec<String>,
+    /// How many function calls are running, for `return`.
+    pub function_depth: usize,
+    /// Set by `return`; the running function stops after the current
+    /// pipeline.
+    pub returning: bool,
+    /// Set while `command_not_found_handle` runs, so a missing command
+    /// inside it is reported rather than handled again.
+    pub handling_not_found: bool,
+    /// Stand-ins for the terminal while a function's output is redirected.
+    pub function_streams: FunctionStreams,
+    /// The `<(...)` words of the pipelines being run.
+    pub substitutions: Vec<Substitution>,
+    /// The file being sourced and its line being run, put before error
+    /// messages.
+    pub location: Option<ScriptLocation>,
+}
+
+/// A line of a sourced file, shown as `file: line N` like bash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptLocation {
+    pub file: String,
+    /// 1-based.
+    pub line: usize,
+}
+
+impl fmt::Display for ScriptLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: line {}", self.file, self.line)
+    }
+}
+
+impl ShellState {
+    pub fn new() -> Self {
+        Self {
+            history: History::new(),
+            hash: CommandHash::new(),
+            vars: Variables::from_env(),
+            options: ShellOptions::new(),
+            traps: Traps::new(),
+            aliases: Aliases::new(),
+            functions: Functions::new(),
+            dir_stack: DirStack::new(),
+            completions: SharedCompletionSpecs::default(),
+            exit_requested: false,
+            exit_warned: false,
+            interactive: false,
+            resized: None,
+            foreground: Job::default(),
+            jobs: JobTable::new(),
+            spawner: Box::new(StdSpawner),
+            last_background_pid: None,
+            last_status: 0,
+            getopts: GetoptsCursor::default(),
+            positional: Vec::new(),
+            function_depth: 0,
+            returning: false,
+            handling_not_found: false,
+            function_streams: FunctionStreams::default(),
+            substitutions: Vec::new(),
+            location: None,
+        }
+    }
+}
+
+impl Default for ShellState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
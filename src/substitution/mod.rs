@@ -0,0 +1,2 @@
+pub use substitution::*;
+pub mod substitution;
@@ -0,0 +1,122 @@
+use anyhow::Result;
+use os_pipe::PipeReader;
+use std::path::PathBuf;
+
+use crate::Job;
+
+/// The output of a `<(command)` word, readable under `path` until the
+/// command that was given the path has run.
+///
+/// Dropping it closes the shell's end of the pipe and waits for the
+/// processes that were writing to it.
+#[derive(Debug)]
+pub struct Substitution {
+    pub path: String,
+    job: Job,
+    reader: Option<PipeReader>,
+    /// The FIFO standing in for `/dev/fd` on systems without it.
+    fifo: Option<PathBuf>,
+}
+
+impl Substitution {
+    /// Makes `reader`, fed by the processes of `job`, readable by path:
+    /// `/dev/fd/N` where the system has it, otherwise a temporary FIFO.
+    pub fn new(reader: PipeReader, job: Job) -> Result<Self> {
+        let mut substitution = Self {
+            path: String::new(),
+            job,
+            reader: None,
+            fifo: None,
+        };
+        if std::path::Path::new("/dev/fd").is_dir() {
+            substitution.path = inheritable_fd_path(&reader)?;
+            substitution.reader = Some(reader);
+        } else {
+            let fifo = make_fifo(reader)?;
+            substitution.path = fifo.display().to_string();
+            substitution.fifo = Some(fifo);
+        }
+        Ok(substitution)
+    }
+}
+
+impl Drop for Substitution {
+    fn drop(&mut self) {
+        self.reader = None;
+        if let Some(fifo) = self.fifo.take() {
+            release_fifo(&fifo);
+        }
+        self.job.wait();
+    }
+}
+
+/// The `/dev/fd` path of `reader`, which is left open in child processes
+/// so that they can open it.
+#[cfg(unix)]
+fn inheritable_fd_path(reader: &PipeReader) -> Result<String> {
+    use std::os::fd::AsRawFd;
+    let fd = reader.as_raw_fd();
+    // SAFETY: `fd` is open for as long as `reader` is; only its
+    // close-on-exec flag changes.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(format!("/dev/fd/{}", fd))
+}
+
+#[cfg(not(unix))]
+fn inheritable_fd_path(_reader: &PipeReader) -> Result<String> {
+    Err(anyhow::anyhow!(
+        "process substitution is not supported on this platform"
+    ))
+}
+
+/// Creates a FIFO in the temporary directory and copies `reader` into it
+/// from a separate thread, once something opens it.
+#[cfg(unix)]
+fn make_fifo(mut reader: PipeReader) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "shell-fifo-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let name = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `name` is a valid NUL-terminated path.
+    if unsafe { libc::mkfifo(name.as_ptr(), 0o600) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let target = path.clone();
+    std::thread::spawn(move || {
+        // Opening for writing waits until the reader opens the FIFO.
+        if let Ok(mut fifo) = std::fs::OpenOptions::new().write(true).open(target) {
+            let _ = std::io::copy(&mut reader, &mut fifo);
+        }
+    });
+    Ok(path)
+}
+
+#[cfg(not(unix))]
+fn make_fifo(_reader: PipeReader) -> Result<PathBuf> {
+    Err(anyhow::anyhow!(
+        "process substitution is not supported on this platform"
+    ))
+}
+
+/// Removes a FIFO, first opening it for reading so that a writer still
+/// waiting for a reader gives up.
+#[cfg(unix)]
+fn release_fifo(fifo: &std::path::Path) {
+    use std::os::unix::fs::OpenOptionsExt;
+    let _ = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(fifo);
+    let _ = std::fs::remove_file(fifo);
+}
+
+#[cfg(not(unix))]
+fn release_fifo(_fifo: &std::path::Path) {}
@@ -0,0 +1,2 @@
+pub use terminal::*;
+pub mod terminal;
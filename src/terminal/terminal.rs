@@ -0,0 +1,83 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use crate::Variables;
+
+/// A terminal's size in character cells.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerminalSize {
+    pub columns: u16,
+    pub lines: u16,
+}
+
+/// The size of the terminal on stdin, or `None` when stdin is not one.
+#[cfg(unix)]
+pub fn terminal_size() -> Option<TerminalSize> {
+    // SAFETY: TIOCGWINSZ only writes into the zeroed struct we pass.
+    let size = unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut size) != 0 {
+            return None;
+        }
+        size
+    };
+    (size.ws_col > 0 && size.ws_row > 0).then_some(TerminalSize {
+        columns: size.ws_col,
+        lines: size.ws_row,
+    })
+}
+
+#[cfg(windows)]
+pub fn terminal_size() -> Option<TerminalSize> {
+    None
+}
+
+/// Sets and exports `COLUMNS` and `LINES` from `size`, so programs started
+/// later see them. Without a size, or when made readonly, they are left as
+/// they are.
+pub fn apply_terminal_size(size: Option<TerminalSize>, vars: &mut Variables) {
+    let Some(size) = size else {
+        return;
+    };
+    for (name, value) in [("COLUMNS", size.columns), ("LINES", size.lines)] {
+        if vars.set(name, &value.to_string()).is_ok() {
+            vars.export(name);
+        }
+    }
+}
+
+/// A flag raised whenever the terminal is resized, for the shell to reread
+/// its size before the next prompt.
+#[cfg(unix)]
+pub fn watch_resize() -> io::Result<Arc<AtomicBool>> {
+    let resized = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(libc::SIGWINCH, Arc::clone(&resized))?;
+    Ok(resized)
+}
+
+#[cfg(windows)]
+pub fn watch_resize() -> io::Result<Arc<AtomicBool>> {
+    Ok(Arc::new(AtomicBool::new(false)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_sets_exported_variables() {
+        let mut vars = Variables::new();
+        apply_terminal_size(None, &mut vars);
+        assert_eq!(vars.get("COLUMNS"), None);
+
+        let size = TerminalSize {
+            columns: 132,
+            lines: 43,
+        };
+        apply_terminal_size(Some(size), &mut vars);
+        assert_eq!(vars.get("COLUMNS"), Some("132"));
+        assert_eq!(vars.get("LINES"), Some("43"));
+        assert!(vars.environment().contains(&("LINES", "43")));
+    }
+}
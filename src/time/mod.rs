@@ -0,0 +1,2 @@
+pub use time::*;
+pub mod time;
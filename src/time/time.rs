@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+/// Measures what `time` reports: wall-clock time plus the user and system CPU
+/// time used by the shell and the children it has waited for.
+#[derive(Debug)]
+pub struct Timer {
+    started: Instant,
+    user: Duration,
+    sys: Duration,
+}
+
+impl Timer {
+    pub fn start() -> Self {
+        let (user, sys) = cpu_times();
+        Self {
+            started: Instant::now(),
+            user,
+            sys,
+        }
+    }
+
+    /// The summary bash prints, preceded by a blank line.
+    pub fn report(&self) -> String {
+        let real = self.started.elapsed();
+        let (user, sys) = cpu_times();
+        format!(
+            "\nreal\t{}\nuser\t{}\nsys\t{}\n",
+            format_duration(real),
+            format_duration(user.saturating_sub(self.user)),
+            format_duration(sys.saturating_sub(self.sys)),
+        )
+    }
+}
+
+/// Formats a duration the way `time` does, e.g. `0m1.234s`.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    format!(
+        "{}m{}.{:03}s",
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// User and system CPU time of this process plus its reaped children.
+#[cfg(unix)]
+fn cpu_times() -> (Duration, Duration) {
+    let mut user = Duration::ZERO;
+    let mut sys = Duration::ZERO;
+    for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+        // SAFETY: getrusage only writes into the zeroed struct we pass.
+        let usage = unsafe {
+            let mut usage: libc::rusage = std::mem::zeroed();
+            if libc::getrusage(who, &mut usage) != 0 {
+                continue;
+            }
+            usage
+        };
+        user += timeval(usage.ru_utime);
+        sys += timeval(usage.ru_stime);
+    }
+    (user, sys)
+}
+
+#[cfg(unix)]
+fn timeval(value: libc::timeval) -> Duration {
+    Duration::from_secs(value.tv_sec as u64) + Duration::from_micros(value.tv_usec as u64)
+}
+
+/// CPU time is not measured here, so `time` reports it as zero.
+#[cfg(not(unix))]
+fn cpu_times() -> (Duration, Duration) {
+    (Duration::ZERO, Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(1234)), "0m1.234s");
+        assert_eq!(format_duration(Duration::from_millis(61_005)), "1m1.005s");
+        assert_eq!(format_duration(Duration::ZERO), "0m0.000s");
+    }
+}
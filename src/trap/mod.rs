@@ -0,0 +1,2 @@
+pub use trap::*;
+pub mod trap;
@@ -0,0 +1,251 @@
+use anyhow::{Result, anyhow};
+#[cfg(unix)]
+use signal_hook::consts::signal::{SIGALRM, SIGHUP, SIGQUIT, SIGUSR1, SIGUSR2};
+use signal_hook::consts::signal::{SIGINT, SIGTERM};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::expand::single_quote;
+
+/// The pseudo-signal whose trap runs when the shell exits.
+pub const EXIT: &str = "EXIT";
+
+/// Signals that can be trapped, by their name without the `SIG` prefix.
+const SIGNALS: &[(&str, i32)] = &[
+    #[cfg(unix)]
+    ("HUP", SIGHUP),
+    ("INT", SIGINT),
+    #[cfg(unix)]
+    ("QUIT", SIGQUIT),
+    #[cfg(unix)]
+    ("USR1", SIGUSR1),
+    #[cfg(unix)]
+    ("USR2", SIGUSR2),
+    #[cfg(unix)]
+    ("ALRM", SIGALRM),
+    ("TERM", SIGTERM),
+];
+
+/// The `trap` command that resets a signal to what it did before it was
+/// trapped. An empty command ignores the signal instead.
+pub const RESET: &str = "-";
+
+/// Normalizes a trap name such as `int`, `SIGINT`, `2` or `0` to its
+/// canonical form (`INT`, `EXIT`).
+pub fn signal_name(name: &str) -> Option<&'static str> {
+    let upper = name.to_ascii_uppercase();
+    let bare = upper.strip_prefix("SIG").unwrap_or(&upper);
+    if bare == EXIT || bare == "0" {
+        return Some(EXIT);
+    }
+    let number = bare.parse::<i32>().ok();
+    SIGNALS
+        .iter()
+        .find(|(signal, signal_number)| *signal == bare || Some(*signal_number) == number)
+        .map(|(signal, _)| *signal)
+}
+
+pub fn signal_number(name: &str) -> Option<i32> {
+    SIGNALS
+        .iter()
+        .find(|(signal, _)| *signal == name)
+        .map(|(_, number)| *number)
+}
+
+/// Commands registered with `trap`, keyed by canonical signal name. An
+/// empty command means the signal is ignored.
+///
+/// Signal handlers only raise a flag; the commands themselves run from the
+/// shell between foreground commands via [`Traps::take_pending`].
+#[derive(Default)]
+pub struct Traps {
+    handlers: BTreeMap<&'static str, String>,
+    installed: BTreeMap<&'static str, Installed>,
+}
+
+/// The flags behind a signal handler, which stays installed once a signal
+/// has been trapped.
+struct Installed {
+    /// Raised by the signal for [`Traps::take_pending`].
+    pending: Arc<AtomicBool>,
+    /// Set by `trap -`: the signal acts as it did before the first trap.
+    /// Only registered when that was not to be ignored.
+    reset: Option<Arc<AtomicBool>>,
+}
+
+impl Traps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `command` for `name`, installing a signal handler the first
+    /// time a real signal is trapped. [`RESET`] removes the trap.
+    pub fn set(&mut self, name: &str, command: &str) -> Result<()> {
+        let name =
+            signal_name(name).ok_or_else(|| anyhow!("{}: invalid signal specification", name))?;
+        if command == RESET {
+            self.handlers.remove(name);
+            if let Some(reset) = self
+                .installed
+                .get(name)
+                .and_then(|flags| flags.reset.as_ref())
+            {
+                reset.store(true, Ordering::SeqCst);
+            }
+            return Ok(());
+        }
+        if let Some(number) = signal_number(name) {
+            let flags = match self.installed.entry(name) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(install(number)?),
+            };
+            if let Some(reset) = &flags.reset {
+                reset.store(false, Ordering::SeqCst);
+            }
+            // A signal that came in while untrapped does not run the new
+            // command.
+            flags.pending.store(false, Ordering::Relaxed);
+        }
+        self.handlers.insert(name, command.to_string());
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        signal_name(name)
+            .and_then(|name| self.handlers.get(name))
+            .map(String::as_str)
+    }
+
+    /// Removes and returns the EXIT trap so that it runs at most once.
+    pub fn take_exit(&mut self) -> Option<String> {
+        self.handlers.remove(EXIT)
+    }
+
+    /// Commands for signals received since the last call, in signal order.
+    pub fn take_pending(&self) -> Vec<String> {
+        self.installed
+            .iter()
+            .filter(|(_, flags)| flags.pending.swap(false, Ordering::Relaxed))
+            .filter_map(|(name, _)| self.handlers.get(name).cloned())
+            .filter(|command| !command.is_empty())
+            .collect()
+    }
+
+    /// `trap -p` output for `names`, or for every trap when `names` is empty.
+    pub fn listing(&self, names: &[String]) -> Result<String> {
+        let selected: Vec<&'static str> = if names.is_empty() {
+            self.handlers.keys().copied().collect()
+        } else {
+            names
+                .iter()
+                .map(|name| {
+                    signal_name(name)
+                        .ok_or_else(|| anyhow!("{}: invalid signal specification", name))
+                })
+                .collect::<Result<_>>()?
+        };
+        Ok(selected
+            .into_iter()
+            .filter_map(|name| {
+                let command = self.handlers.get(name)?;
+                Some(format!("trap -- {} {}", single_quote(command), name))
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+/// Installs the handler for signal `number`, noting whether the signal was
+/// ignored before so that `trap -` can go back to that.
+fn install(number: i32) -> Result<Installed> {
+    let ignored = is_ignored(number);
+    let pending = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(number, Arc::clone(&pending))?;
+    let reset = if ignored {
+        None
+    } else {
+        let reset = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register_conditional_default(number, Arc::clone(&reset))?;
+        Some(reset)
+    };
+    Ok(Installed { pending, reset })
+}
+
+/// Whether signal `number` is currently ignored.
+#[cfg(unix)]
+fn is_ignored(number: i32) -> bool {
+    // SAFETY: a null new action only reads the current disposition.
+    unsafe {
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(number, std::ptr::null(), &mut previous) == 0
+            && previous.sa_sigaction == libc::SIG_IGN
+    }
+}
+
+#[cfg(not(unix))]
+fn is_ignored(_number: i32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_names_are_normalized() {
+        assert_eq!(signal_name("int"), Some("INT"));
+        assert_eq!(signal_name("SIGTERM"), Some("TERM"));
+        assert_eq!(signal_name("0"), Some(EXIT));
+        assert_eq!(signal_name("BOGUS"), None);
+    }
+
+    #[test]
+    fn test_signal_numbers_map_to_names() {
+        assert_eq!(signal_name("2"), Some("INT"));
+        assert_eq!(signal_name("15"), Some("TERM"));
+        assert_eq!(signal_number("HUP"), Some(1));
+        assert_eq!(signal_name("64"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ignore_and_reset() {
+        let mut traps = Traps::new();
+        traps.set("USR2", "").unwrap();
+        assert_eq!(traps.listing(&[]).unwrap(), "trap -- '' USR2");
+        signal_hook::low_level::raise(SIGUSR2).unwrap();
+        assert!(traps.take_pending().is_empty());
+
+        traps.set(&SIGUSR2.to_string(), "echo usr2").unwrap();
+        signal_hook::low_level::raise(SIGUSR2).unwrap();
+        assert_eq!(traps.take_pending(), ["echo usr2"]);
+
+        traps.set("SIGUSR2", RESET).unwrap();
+        assert_eq!(traps.get("USR2"), None);
+        assert!(
+            traps.installed["USR2"]
+                .reset
+                .as_ref()
+                .unwrap()
+                .load(Ordering::SeqCst)
+        );
+        traps.set("exit", "true").unwrap();
+        traps.set("exit", RESET).unwrap();
+        assert!(traps.take_exit().is_none());
+    }
+
+    #[test]
+    fn test_listing_quotes_commands() {
+        let mut traps = Traps::new();
+        traps.set("exit", "echo 'bye'").unwrap();
+        assert_eq!(
+            traps.listing(&[]).unwrap(),
+            "trap -- 'echo '\\''bye'\\''' EXIT"
+        );
+        assert!(traps.set("NOPE", "true").is_err());
+        assert_eq!(traps.take_exit().as_deref(), Some("echo 'bye'"));
+        assert!(traps.take_exit().is_none());
+    }
+}
@@ -0,0 +1,2 @@
+pub use variables::*;
+pub mod variables;
@@ -0,0 +1,267 @@
+use anyhow::{Result, anyhow};
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Scalar(String),
+    Array(Vec<String>),
+}
+
+/// The `SHLVL` for a shell started with `current` in its environment: one
+/// deeper than its parent, starting at 1 when unset or not a number.
+pub fn next_shell_level(current: Option<&str>) -> u32 {
+    let parent = current
+        .and_then(|level| level.trim().parse::<i64>().ok())
+        .unwrap_or(0)
+        .clamp(0, u32::MAX as i64 - 1);
+    parent as u32 + 1
+}
+
+/// Shell variables, seeded from the process environment at startup.
+#[derive(Default)]
+pub struct Variables {
+    values: HashMap<String, Value>,
+    /// Names passed on to child processes; they need not be set.
+    exported: HashSet<String>,
+    /// Names marked with `readonly`, which may no longer be assigned or
+    /// unset.
+    readonly: HashSet<String>,
+    /// One scope per running function: what each of its `local` names held
+    /// before, and whether it was exported, to restore on return.
+    scopes: Vec<Vec<(String, Option<Value>, bool)>>,
+}
+
+impl Variables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Variables from the process environment, all of them exported.
+    pub fn from_env() -> Self {
+        let values: HashMap<String, Value> = env::vars()
+            .map(|(name, value)| (name, Value::Scalar(value)))
+            .collect();
+        let exported = values.keys().cloned().collect();
+        Self {
+            values,
+            exported,
+            readonly: HashSet::new(),
+            scopes: Vec::new(),
+        }
+    }
+
+    /// Returns a scalar's value. For arrays this is the first element, as
+    /// `$arr` is in bash.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match self.values.get(name)? {
+            Value::Scalar(value) => Some(value),
+            Value::Array(items) => items.first().map(|item| item.as_str()),
+        }
+    }
+
+    /// Returns element `index` of an array; a scalar behaves like a
+    /// one-element array.
+    pub fn get_index(&self, name: &str, index: usize) -> Option<&str> {
+        match self.values.get(name)? {
+            Value::Scalar(value) => (index == 0).then_some(value.as_str()),
+            Value::Array(items) => items.get(index).map(|item| item.as_str()),
+        }
+    }
+
+    pub fn get_value(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+
+    /// Assigns `value` to `name`, failing with bash's message when `name`
+    /// is readonly.
+    pub fn set_value(&mut self, name: &str, value: Value) -> Result<()> {
+        self.check_writable(name)?;
+        self.values.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        self.set_value(name, Value::Scalar(value.to_string()))
+    }
+
+    pub fn set_array(&mut self, name: &str, items: Vec<String>) -> Result<()> {
+        self.set_value(name, Value::Array(items))
+    }
+
+    /// Removes `name`, unless it is readonly.
+    pub fn unset(&mut self, name: &str) -> Result<()> {
+        self.check_writable(name)?;
+        self.values.remove(name);
+        self.exported.remove(name);
+        Ok(())
+    }
+
+    /// Starts the scope of a function call.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Ends the innermost scope, putting back every variable it made local.
+    pub fn pop_scope(&mut self) {
+        for (name, value, exported) in self.scopes.pop().unwrap_or_default().into_iter().rev() {
+            match value {
+                Some(value) => {
+                    self.values.insert(name.clone(), value);
+                }
+                None => {
+                    self.values.remove(&name);
+                }
+            }
+            if exported {
+                self.exported.insert(name);
+            } else {
+                self.exported.remove(&name);
+            }
+        }
+    }
+
+    /// Makes `name` local to the innermost scope, starting out unset. Its
+    /// current value comes back when the scope ends. Returns `false` when no
+    /// scope is open, and fails when `name` is readonly.
+    pub fn make_local(&mut self, name: &str) -> Result<bool> {
+        self.check_writable(name)?;
+        let Some(scope) = self.scopes.last_mut() else {
+            return Ok(false);
+        };
+        if !scope.iter().any(|(local, _, _)| local == name) {
+            let exported = self.exported.contains(name);
+            scope.push((name.to_string(), self.values.remove(name), exported));
+        }
+        Ok(true)
+    }
+
+    pub fn set_readonly(&mut self, name: &str) {
+        self.readonly.insert(name.to_string());
+    }
+
+    /// Fails with bash's message when `name` is readonly.
+    fn check_writable(&self, name: &str) -> Result<()> {
+        if self.readonly.contains(name) {
+            return Err(anyhow!("{}: readonly variable", name));
+        }
+        Ok(())
+    }
+
+    /// Readonly names, sorted, with their value if they are set.
+    pub fn readonly(&self) -> Vec<(&str, Option<&str>)> {
+        let mut readonly: Vec<(&str, Option<&str>)> = self
+            .readonly
+            .iter()
+            .map(|name| (name.as_str(), self.get(name)))
+            .collect();
+        readonly.sort_unstable();
+        readonly
+    }
+
+    pub fn export(&mut self, name: &str) {
+        self.exported.insert(name.to_string());
+    }
+
+    pub fn is_exported(&self, name: &str) -> bool {
+        self.exported.contains(name)
+    }
+
+    /// Exported names, sorted, with their value if they are set. Arrays are
+    /// left out since they cannot be exported.
+    pub fn exported(&self) -> Vec<(&str, Option<&str>)> {
+        let mut exported: Vec<(&str, Option<&str>)> = self
+            .exported
+            .iter()
+            .filter_map(|name| match self.values.get(name) {
+                Some(Value::Scalar(value)) => Some((name.as_str(), Some(value.as_str()))),
+                Some(Value::Array(_)) => None,
+                None => Some((name.as_str(), None)),
+            })
+            .collect();
+        exported.sort_unstable();
+        exported
+    }
+
+    /// The environment for a child process: every exported variable that
+    /// has a scalar value. Arrays are not exported, as in bash.
+    pub fn environment(&self) -> Vec<(&str, &str)> {
+        self.exported
+            .iter()
+            .filter_map(|name| match self.values.get(name)? {
+                Value::Scalar(value) => Some((name.as_str(), value.as_str())),
+                Value::Array(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readonly_names_are_not_writable() {
+        let mut vars = Variables::new();
+        vars.set("KEEP", "1").unwrap();
+        vars.set_readonly("KEEP");
+        assert_eq!(
+            vars.set("KEEP", "2").unwrap_err().to_string(),
+            "KEEP: readonly variable"
+        );
+        assert!(vars.set_array("KEEP", Vec::new()).is_err());
+        assert!(vars.unset("KEEP").is_err());
+        vars.push_scope();
+        assert!(vars.make_local("KEEP").is_err());
+        vars.pop_scope();
+        assert_eq!(vars.readonly(), [("KEEP", Some("1"))]);
+    }
+
+    #[test]
+    fn test_shell_level_increments() {
+        assert_eq!(next_shell_level(Some("1")), 2);
+        assert_eq!(next_shell_level(Some("41")), 42);
+    }
+
+    #[test]
+    fn test_exported_variables_reach_environment() {
+        let mut vars = Variables::new();
+        vars.set("LOCAL", "1").unwrap();
+        vars.set("SHARED", "2").unwrap();
+        vars.export("SHARED");
+        vars.export("PENDING");
+        vars.set_array("LIST", vec!["a".into()]).unwrap();
+        vars.export("LIST");
+
+        assert_eq!(vars.environment(), [("SHARED", "2")]);
+        assert_eq!(vars.exported(), [("PENDING", None), ("SHARED", Some("2"))]);
+        vars.unset("SHARED").unwrap();
+        assert!(!vars.is_exported("SHARED"));
+    }
+
+    #[test]
+    fn test_local_is_restored_when_scope_ends() {
+        let mut vars = Variables::new();
+        vars.set("X", "global").unwrap();
+        assert!(!vars.make_local("X").unwrap());
+
+        vars.push_scope();
+        assert!(vars.make_local("X").unwrap());
+        assert_eq!(vars.get("X"), None);
+        vars.set("X", "local").unwrap();
+        assert!(vars.make_local("NEW").unwrap());
+        vars.set("NEW", "1").unwrap();
+        vars.pop_scope();
+
+        assert_eq!(vars.get("X"), Some("global"));
+        assert_eq!(vars.get("NEW"), None);
+    }
+
+    #[test]
+    fn test_shell_level_starts_at_one() {
+        assert_eq!(next_shell_level(None), 1);
+        assert_eq!(next_shell_level(Some("")), 1);
+        assert_eq!(next_shell_level(Some("abc")), 1);
+        assert_eq!(next_shell_level(Some("-3")), 1);
+    }
+}